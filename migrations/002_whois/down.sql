@@ -0,0 +1,3 @@
+DROP TABLE user_ips;
+ALTER TABLE access_tokens DROP COLUMN device_id;
+ALTER TABLE users DROP COLUMN admin;
@@ -0,0 +1,16 @@
+ALTER TABLE users ADD COLUMN admin BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE access_tokens ADD COLUMN device_id TEXT;
+UPDATE access_tokens SET device_id = 'LEGACY' || id;
+ALTER TABLE access_tokens ALTER COLUMN device_id SET NOT NULL;
+
+CREATE TABLE user_ips (
+    access_token_id BIGINT NOT NULL REFERENCES access_tokens (id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    user_agent TEXT NOT NULL,
+    last_seen TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY (access_token_id, ip, user_agent)
+);
+
+CREATE INDEX user_ips_user_id_idx ON user_ips (user_id);
@@ -0,0 +1,2 @@
+DROP INDEX filters_user_id_filter_id_idx;
+ALTER TABLE filters DROP COLUMN filter_id;
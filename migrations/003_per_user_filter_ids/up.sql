@@ -0,0 +1,12 @@
+ALTER TABLE filters ADD COLUMN filter_id BIGINT;
+
+UPDATE filters SET filter_id = numbered.filter_id
+FROM (
+    SELECT id, row_number() OVER (PARTITION BY user_id ORDER BY id) - 1 AS filter_id
+    FROM filters
+) AS numbered
+WHERE filters.id = numbered.id;
+
+ALTER TABLE filters ALTER COLUMN filter_id SET NOT NULL;
+
+CREATE UNIQUE INDEX filters_user_id_filter_id_idx ON filters (user_id, filter_id);
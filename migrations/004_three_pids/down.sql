@@ -0,0 +1 @@
+DROP TABLE three_pids;
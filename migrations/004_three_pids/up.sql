@@ -0,0 +1,9 @@
+CREATE TABLE three_pids (
+    medium TEXT NOT NULL,
+    address TEXT NOT NULL,
+    user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY (medium, address)
+);
+
+CREATE INDEX three_pids_user_id_idx ON three_pids (user_id);
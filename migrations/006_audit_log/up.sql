@@ -0,0 +1,9 @@
+CREATE TABLE audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    user_id TEXT,
+    event_type TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX audit_log_user_id_idx ON audit_log (user_id);
@@ -0,0 +1,8 @@
+CREATE TABLE receipts (
+    room_id TEXT NOT NULL,
+    user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    receipt_type TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY (room_id, user_id, receipt_type)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE access_tokens DROP CONSTRAINT access_tokens_user_id_is_mxid;
+ALTER TABLE users DROP CONSTRAINT users_id_is_mxid;
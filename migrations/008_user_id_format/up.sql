@@ -0,0 +1,2 @@
+ALTER TABLE users ADD CONSTRAINT users_id_is_mxid CHECK (id LIKE '@%:%');
+ALTER TABLE access_tokens ADD CONSTRAINT access_tokens_user_id_is_mxid CHECK (user_id LIKE '@%:%');
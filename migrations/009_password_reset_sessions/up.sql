@@ -0,0 +1,11 @@
+CREATE TABLE password_reset_sessions (
+    sid TEXT NOT NULL PRIMARY KEY,
+    client_secret TEXT NOT NULL,
+    address TEXT NOT NULL,
+    user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    token_hash TEXT NOT NULL,
+    send_attempt BIGINT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    updated_at TIMESTAMP NOT NULL DEFAULT now(),
+    UNIQUE (client_secret, address)
+);
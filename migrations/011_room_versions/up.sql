@@ -0,0 +1 @@
+ALTER TABLE rooms ADD COLUMN version TEXT NOT NULL DEFAULT '1';
@@ -0,0 +1,8 @@
+CREATE TABLE transactions (
+    path TEXT NOT NULL,
+    access_token TEXT NOT NULL,
+    response TEXT NOT NULL,
+    PRIMARY KEY (path, access_token)
+);
+
+DROP TABLE idempotent_responses;
@@ -0,0 +1,21 @@
+CREATE TABLE idempotent_responses (
+    user_id TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    idempotency_key TEXT NOT NULL,
+    status INTEGER NOT NULL,
+    body TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, path, idempotency_key)
+);
+
+INSERT INTO idempotent_responses (user_id, path, idempotency_key, status, body)
+SELECT DISTINCT ON (access_tokens.user_id, transactions.path)
+    access_tokens.user_id,
+    transactions.path,
+    regexp_replace(transactions.path, '^.*/', ''),
+    200,
+    transactions.response
+FROM transactions
+JOIN access_tokens ON access_tokens.value = transactions.access_token;
+
+DROP TABLE transactions;
@@ -0,0 +1,6 @@
+CREATE TABLE server_notices (
+    id BIGSERIAL PRIMARY KEY,
+    body TEXT NOT NULL,
+    sender TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
@@ -0,0 +1,2 @@
+ALTER TABLE events DROP COLUMN redacted_by;
+ALTER TABLE events DROP COLUMN redacts;
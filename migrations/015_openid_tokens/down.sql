@@ -0,0 +1 @@
+DROP TABLE openid_tokens;
@@ -0,0 +1,6 @@
+CREATE TABLE registration_tokens (
+    token TEXT NOT NULL PRIMARY KEY,
+    uses_remaining INTEGER,
+    expires_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
@@ -0,0 +1,4 @@
+DELETE FROM pushers a USING pushers b
+    WHERE a.user_id = b.user_id AND a.app_id = b.app_id AND a.pushkey > b.pushkey;
+ALTER TABLE pushers DROP CONSTRAINT pushers_pkey;
+ALTER TABLE pushers ADD PRIMARY KEY (user_id, app_id);
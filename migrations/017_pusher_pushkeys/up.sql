@@ -0,0 +1,2 @@
+ALTER TABLE pushers DROP CONSTRAINT pushers_pkey;
+ALTER TABLE pushers ADD PRIMARY KEY (user_id, app_id, pushkey);
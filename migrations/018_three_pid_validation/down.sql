@@ -0,0 +1 @@
+ALTER TABLE three_pids DROP COLUMN validated_at;
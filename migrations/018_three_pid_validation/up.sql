@@ -0,0 +1,4 @@
+ALTER TABLE three_pids ADD COLUMN validated_at TIMESTAMP;
+
+-- Identifiers bound so far were bound without a verification step.
+UPDATE three_pids SET validated_at = created_at;
@@ -0,0 +1,8 @@
+DELETE FROM idempotent_responses;
+
+ALTER TABLE idempotent_responses ALTER COLUMN body SET NOT NULL;
+ALTER TABLE idempotent_responses ALTER COLUMN status SET NOT NULL;
+
+ALTER TABLE idempotent_responses DROP CONSTRAINT idempotent_responses_pkey;
+ALTER TABLE idempotent_responses DROP COLUMN device_id;
+ALTER TABLE idempotent_responses ADD PRIMARY KEY (user_id, path, idempotency_key);
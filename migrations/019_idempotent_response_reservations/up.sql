@@ -0,0 +1,11 @@
+-- Stored responses are only kept for a day, and the ones stored so far can't be attributed to a
+-- device.
+DELETE FROM idempotent_responses;
+
+ALTER TABLE idempotent_responses ADD COLUMN device_id TEXT NOT NULL;
+ALTER TABLE idempotent_responses DROP CONSTRAINT idempotent_responses_pkey;
+ALTER TABLE idempotent_responses ADD PRIMARY KEY (user_id, device_id, path, idempotency_key);
+
+-- Requests still being handled reserve their key with a row that has no response yet.
+ALTER TABLE idempotent_responses ALTER COLUMN status DROP NOT NULL;
+ALTER TABLE idempotent_responses ALTER COLUMN body DROP NOT NULL;
@@ -1,22 +1,38 @@
 //! Endpoints for accounts.
-use bodyparser;
+use std::convert::TryFrom;
+
 use diesel::prelude::*;
 use iron::status::Status;
-use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::{Chain, Handler, IronError, IronResult, Request, Response};
+use serde_json::Value;
 
-use crate::crypto::hash_password;
+use crate::config::Config;
+use crate::crypto::{derive_opaque_token, hash_password};
 use crate::db::DB;
+use crate::email::{is_valid_email, normalize_email};
 use crate::error::ApiError;
+use crate::hash_limiter::HashLimiter;
 use crate::middleware::{
-    AccessTokenAuth, DataTypeParam, JsonRequest, MiddlewareChain, RoomIdParam, UserIdParam,
+    parse_body, AccessTokenAuth, DataTypeParam, JsonRequest, MiddlewareChain, RoomIdParam,
+    UserIdParam,
 };
 use crate::models::access_token::AccessToken;
 use crate::models::account_data::{
     AccountData, NewAccountData, NewRoomAccountData, RoomAccountData,
 };
+use crate::models::audit_log::{AuditEvent, AuditLog};
+use crate::models::one_time_key::OneTimeKey;
+use crate::models::openid_token::OpenIdToken;
+use crate::models::password_reset_session::PasswordResetSession;
+use crate::models::profile::Profile;
+use crate::models::pusher::Pusher;
 use crate::models::room_membership::RoomMembership;
+use crate::models::three_pid::ThreePid;
+use crate::models::unix_millis;
 use crate::models::user::User;
-use crate::modifier::EmptyResponse;
+use crate::models::user_ip::UserIp;
+use crate::modifier::{EmptyResponse, SerializableResponse};
+use crate::storage_quota;
 
 /// The `/account/password` endpoint.
 #[derive(Clone, Copy, Debug)]
@@ -33,11 +49,7 @@ middleware_chain!(AccountPassword, [AccessTokenAuth]);
 
 impl Handler for AccountPassword {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let account_password_request =
-            match request.get::<bodyparser::Struct<AccountPasswordRequest>>() {
-                Ok(Some(account_password_request)) => account_password_request,
-                Ok(None) | Err(_) => Err(ApiError::not_json(None))?,
-            };
+        let account_password_request = parse_body::<AccountPasswordRequest>(request)?;
 
         let mut user = request
             .extensions
@@ -45,13 +57,26 @@ impl Handler for AccountPassword {
             .expect("AccessTokenAuth should ensure a user")
             .clone();
 
-        user.password_hash = hash_password(&account_password_request.new_password)?;
+        user.password_hash = {
+            let _permit = HashLimiter::from_request(request)?.acquire()?;
+
+            hash_password(&account_password_request.new_password)?
+        };
 
         let connection = DB::from_request(request)?;
 
         user.save_changes::<User>(&*connection)
             .map_err(|_| ApiError::unauthorized(None))?;
 
+        let config = Config::from_request(request)?;
+        AuditLog::record(
+            &connection,
+            &config,
+            request,
+            Some(&user.id),
+            AuditEvent::PasswordChange,
+        )?;
+
         Ok(Response::with(EmptyResponse(Status::Ok)))
     }
 }
@@ -60,36 +85,353 @@ impl Handler for AccountPassword {
 #[derive(Clone, Copy, Debug)]
 pub struct DeactivateAccount;
 
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct DeactivateAccountRequest {
+    /// Whether or not to erase the user's profile and third party identifiers as well.
+    #[serde(default)]
+    erase: bool,
+}
+
 middleware_chain!(DeactivateAccount, [AccessTokenAuth]);
 
 impl Handler for DeactivateAccount {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let deactivate_request = parse_body::<DeactivateAccountRequest>(request)?;
+
+        let mut user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let user_id = user.id.clone();
         let connection = DB::from_request(request)?;
 
-        {
-            let token = request
-                .extensions
-                .get_mut::<AccessToken>()
-                .expect("AccessTokenAuth should ensure an access token");
+        connection
+            .transaction::<(), ApiError, _>(|| {
+                user.deactivate(&connection)?;
+
+                // Remove every way of acting as the user, on any of their devices.
+                AccessToken::revoke_all_for_user(&connection, &user_id)?;
+                OpenIdToken::delete_by_uid(&connection, &user_id)?;
+                PasswordResetSession::delete_by_uid(&connection, &user_id)?;
+                Pusher::delete_by_uid(&connection, &user_id)?;
+                OneTimeKey::delete_by_uid(&connection, &user_id)?;
+
+                // Delete all the account data associated with the user.
+                AccountData::delete_by_uid(&connection, &user_id)?;
+                RoomAccountData::delete_by_uid(&connection, &user_id)?;
+
+                if deactivate_request.erase {
+                    Profile::delete_by_uid(&connection, &user_id)?;
+                    ThreePid::delete_by_uid(&connection, &user_id)?;
+                }
+
+                Ok(())
+            })
+            .map_err(ApiError::from)?;
+
+        let config = Config::from_request(request)?;
+        AuditLog::record(
+            &connection,
+            &config,
+            request,
+            Some(&user_id),
+            AuditEvent::Deactivation,
+        )?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+/// The `/account/sessions` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct AccountSessions;
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct AccountSessionsResponse {
+    /// The user's sessions, one for each valid access token, oldest first.
+    sessions: Vec<Session>,
+}
+
+/// One of the user's sessions.
+#[derive(Debug, Serialize)]
+struct Session {
+    /// The ID of the device the session belongs to.
+    device_id: String,
+    /// Unix timestamp in milliseconds of when the session started.
+    created_at: i64,
+    /// Whether or not this is the session the request was made with.
+    current: bool,
+    /// Unix timestamp in milliseconds of the most recent request in the session, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_seen: Option<i64>,
+    /// The IP address the most recent request in the session came from, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip: Option<String>,
+    /// The user agent of the most recent request in the session, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_agent: Option<String>,
+}
+
+middleware_chain!(AccountSessions, [AccessTokenAuth]);
+
+impl Handler for AccountSessions {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let current_token_id = request
+            .extensions
+            .get::<AccessToken>()
+            .expect("AccessTokenAuth should ensure an access token")
+            .id;
+
+        let connection = DB::from_request(request)?;
+
+        let access_tokens = AccessToken::find_valid_by_uid(&connection, &user.id)?;
+        let user_ips = UserIp::find_by_uid(&connection, &user.id)?;
+
+        let sessions = access_tokens
+            .into_iter()
+            .map(|access_token| {
+                // Connections are ordered most recent first.
+                let last_connection = user_ips
+                    .iter()
+                    .find(|user_ip| user_ip.access_token_id == access_token.id);
+
+                Session {
+                    device_id: access_token.device_id,
+                    created_at: unix_millis(&access_token.created_at),
+                    current: access_token.id == current_token_id,
+                    last_seen: last_connection.map(|user_ip| unix_millis(&user_ip.last_seen)),
+                    ip: last_connection.map(|user_ip| user_ip.ip.clone()),
+                    user_agent: last_connection.map(|user_ip| user_ip.user_agent.clone()),
+                }
+            })
+            .collect();
+
+        let response = AccountSessionsResponse { sessions };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The `/account/password/email/requestToken` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestPasswordEmailToken;
+
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct RequestPasswordEmailTokenRequest {
+    /// A secret chosen by the client to identify this request and its retries.
+    pub client_secret: String,
+    /// The email address to send the token to.
+    pub email: String,
+    /// The client's counter for requests. The token is only sent again if it increases.
+    pub send_attempt: u64,
+}
 
-            token.revoke(&connection)?;
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct RequestPasswordEmailTokenResponse {
+    /// The ID of the password reset session.
+    pub sid: String,
+}
+
+middleware_chain!(RequestPasswordEmailToken, [JsonRequest]);
+
+impl Handler for RequestPasswordEmailToken {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let token_request = parse_body::<RequestPasswordEmailTokenRequest>(request)?;
+
+        if !is_valid_client_secret(&token_request.client_secret) {
+            Err(ApiError::invalid_param(
+                "client_secret",
+                "The client secret must be 1 to 255 characters from [0-9a-zA-Z.=_-]",
+            ))?;
         }
 
+        if !is_valid_email(&token_request.email) {
+            Err(ApiError::invalid_param(
+                "email",
+                "Not a valid email address",
+            ))?;
+        }
+
+        let send_attempt = i64::try_from(token_request.send_attempt)
+            .map_err(|_| ApiError::invalid_param("send_attempt", "Must be at most 2^63 - 1"))?;
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+
+        let address = normalize_email(&token_request.email);
+
+        let user = match ThreePid::find_user_id(&connection, "email", &address)? {
+            Some(user_id) => User::find_active_user(&connection, &user_id)?,
+            None => None,
+        };
+
+        // Addresses that aren't bound to an active user get a session ID that leads nowhere, so
+        // the response doesn't reveal which addresses are registered. Like a real session ID, it
+        // stays the same when the client repeats the request.
+        let sid = match user {
+            Some(user) => {
+                let (session, token) = PasswordResetSession::request(
+                    &connection,
+                    &token_request.client_secret,
+                    &address,
+                    &user.id,
+                    send_attempt,
+                )?;
+
+                if token.is_some() {
+                    // Sending emails isn't supported yet.
+                    debug!(
+                        "Would send a password reset token for session {}",
+                        session.sid
+                    );
+                }
+
+                session.sid
+            }
+            None => derive_opaque_token(
+                &config.macaroon_secret_key,
+                &["password reset sid", &token_request.client_secret, &address],
+            ),
+        };
+
+        let response = RequestPasswordEmailTokenResponse { sid };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/account/3pid` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetThreePids;
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct GetThreePidsResponse {
+    /// The third party identifiers bound to the user, including those pending validation.
+    threepids: Vec<ThreePidInfo>,
+}
+
+/// A third party identifier bound to the user.
+#[derive(Debug, Serialize)]
+struct ThreePidInfo {
+    /// The medium of the identifier, e.g. "email".
+    medium: String,
+    /// The identifier itself.
+    address: String,
+    /// Unix timestamp in milliseconds of when the identifier was validated, or null while
+    /// validation is pending.
+    validated_at: Option<i64>,
+    /// Unix timestamp in milliseconds of when the identifier was bound to the user.
+    added_at: i64,
+}
+
+middleware_chain!(GetThreePids, [AccessTokenAuth]);
+
+impl Handler for GetThreePids {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
         let user = request
             .extensions
-            .get_mut::<User>()
-            .expect("AccessTokenAuth should ensure a user");
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
 
-        user.deactivate(&connection)?;
+        let connection = DB::from_request(request)?;
 
-        // Delete all the account data associated with the user.
-        AccountData::delete_by_uid(&connection, &user.id)?;
-        RoomAccountData::delete_by_uid(&connection, &user.id)?;
+        let threepids = ThreePid::find_by_uid(&connection, &user.id)?
+            .into_iter()
+            .map(|three_pid| ThreePidInfo {
+                medium: three_pid.medium,
+                address: three_pid.address,
+                validated_at: three_pid.validated_at.as_ref().map(unix_millis),
+                added_at: unix_millis(&three_pid.created_at),
+            })
+            .collect();
 
-        Ok(Response::with(EmptyResponse(Status::Ok)))
+        let response = GetThreePidsResponse { threepids };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The `/account/3pid/delete` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct DeleteThreePid;
+
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct DeleteThreePidRequest {
+    /// The medium of the third party identifier, e.g. "email".
+    medium: String,
+    /// The third party identifier itself.
+    address: String,
+}
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct DeleteThreePidResponse {
+    /// Whether the identifier was also unbound from the identity server. Identity servers
+    /// aren't supported yet, so this is always "no-support".
+    id_server_unbind_result: &'static str,
+}
+
+middleware_chain!(DeleteThreePid, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for DeleteThreePid {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let delete_request = parse_body::<DeleteThreePidRequest>(request)?;
+
+        if delete_request.medium == "email" && !is_valid_email(&delete_request.address) {
+            Err(ApiError::invalid_param(
+                "address",
+                "Not a valid email address",
+            ))?;
+        }
+
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let connection = DB::from_request(request)?;
+
+        // Deleting an identifier that isn't bound to the user succeeds without doing anything.
+        ThreePid::delete(
+            &connection,
+            &user.id,
+            &delete_request.medium,
+            &delete_request.address,
+        )?;
+
+        let response = DeleteThreePidResponse {
+            id_server_unbind_result: "no-support",
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
     }
 }
 
+/// Whether or not the client secret has the format required by the Matrix spec.
+fn is_valid_client_secret(client_secret: &str) -> bool {
+    !client_secret.is_empty()
+        && client_secret.len() <= 255
+        && client_secret
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ".=_-".contains(c))
+}
+
 /// The `/user/:user_id/account_data/:type` endpoint.
 #[derive(Clone, Copy, Debug)]
 pub struct PutAccountData;
@@ -127,20 +469,38 @@ impl Handler for PutAccountData {
             .expect("DataTypeParam should ensure a data type")
             .clone();
 
-        let content = match request.get::<bodyparser::Json>() {
-            Ok(Some(content)) => content.to_string().clone(),
-            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
-        };
-
-        let new_data = NewAccountData {
-            user_id: user.id,
-            data_type: data_type.to_string(),
-            content,
-        };
+        let content = parse_body::<Value>(request)?.to_string();
 
+        let config = Config::from_request(request)?;
         let connection = DB::from_request(request)?;
 
-        AccountData::upsert(&connection, &new_data)?;
+        connection
+            .transaction::<_, ApiError, _>(|| {
+                storage_quota::lock_user(&connection, &user.id)?;
+
+                let replaced_bytes =
+                    AccountData::find_by_uid_and_type(&connection, &user.id, &data_type)
+                        .optional()
+                        .map_err(ApiError::from)?
+                        .map_or(0, |saved| saved.content.len());
+
+                storage_quota::ensure_within_quota(
+                    &connection,
+                    &config,
+                    &user.id,
+                    replaced_bytes,
+                    content.len(),
+                )?;
+
+                let new_data = NewAccountData {
+                    user_id: user.id.clone(),
+                    data_type: data_type.to_string(),
+                    content,
+                };
+
+                AccountData::upsert(&connection, &new_data)
+            })
+            .map_err(ApiError::from)?;
 
         Ok(Response::with(EmptyResponse(Status::Ok)))
     }
@@ -212,19 +572,38 @@ impl Handler for PutRoomAccountData {
             .expect("DataTypeParam should ensure a data type")
             .clone();
 
-        let content = match request.get::<bodyparser::Json>() {
-            Ok(Some(content)) => content.to_string().clone(),
-            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
-        };
+        let content = parse_body::<Value>(request)?.to_string();
 
-        let new_data = NewRoomAccountData {
-            user_id: user.id,
-            room_id,
-            data_type: data_type.to_string(),
-            content,
-        };
+        let config = Config::from_request(request)?;
+
+        connection
+            .transaction::<_, ApiError, _>(|| {
+                storage_quota::lock_user(&connection, &user.id)?;
+
+                let replaced_bytes =
+                    RoomAccountData::find(&connection, &user.id, &room_id, &data_type)
+                        .optional()
+                        .map_err(ApiError::from)?
+                        .map_or(0, |saved| saved.content.len());
+
+                storage_quota::ensure_within_quota(
+                    &connection,
+                    &config,
+                    &user.id,
+                    replaced_bytes,
+                    content.len(),
+                )?;
+
+                let new_data = NewRoomAccountData {
+                    user_id: user.id.clone(),
+                    room_id: room_id.clone(),
+                    data_type: data_type.to_string(),
+                    content,
+                };
 
-        RoomAccountData::upsert(&connection, &new_data)?;
+                RoomAccountData::upsert(&connection, &new_data)
+            })
+            .map_err(ApiError::from)?;
 
         Ok(Response::with(EmptyResponse(Status::Ok)))
     }
@@ -232,8 +611,21 @@ impl Handler for PutRoomAccountData {
 
 #[cfg(test)]
 mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::prelude::*;
+
+    use crate::models::access_token::AccessToken;
+    use crate::models::one_time_key::{NewOneTimeKey, OneTimeKey};
+    use crate::models::openid_token::OpenIdToken;
+    use crate::models::profile::Profile;
+    use crate::models::pusher::{Pusher, PusherData, PusherOptions};
+    use crate::models::three_pid::ThreePid;
+    use crate::schema::password_reset_sessions;
     use crate::test::Test;
     use iron::status::Status;
+    use ruma_identifiers::UserId;
+    use serde_json::Value;
 
     #[test]
     fn change_password() {
@@ -284,7 +676,250 @@ mod tests {
             Status::Forbidden
         );
 
-        assert_eq!(test.post(&deactivate, r#"{}"#).status, Status::Forbidden);
+        assert_eq!(test.post(&deactivate, r#"{}"#).status, Status::Unauthorized);
+    }
+
+    #[test]
+    fn deactivate_account_removes_credentials() {
+        let test = Test::new();
+        let user = test.create_user();
+        let user_id = UserId::try_from(user.id.as_str()).unwrap();
+
+        // A second session on another device.
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            &format!(
+                r#"{{"type": "m.login.password", "user": "{}", "password": "secret", "device_id": "OTHER"}}"#,
+                user.name
+            ),
+        );
+        assert_eq!(response.status, Status::Ok);
+        let other_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/user/{}/openid/request_token?access_token={}",
+                user.id, user.token
+            ),
+            "{}",
+        );
+        assert_eq!(response.status, Status::Ok);
+        let openid_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let pusher = PusherOptions {
+            lang: "en".to_string(),
+            kind: "http".to_string(),
+            data: PusherData {
+                url: Some("https://push.example.com".to_string()),
+            },
+            device_display_name: "device".to_string(),
+            app_id: "app".to_string(),
+            profile_tag: None,
+            pushkey: "pushkey".to_string(),
+            app_display_name: "app".to_string(),
+            append: false,
+        };
+        assert_eq!(test.set_pusher(&user.token, pusher).status, Status::Ok);
+
+        OneTimeKey::create(
+            &test.connection(),
+            &[NewOneTimeKey {
+                user_id: user_id.clone(),
+                device_id: "OTHER".to_string(),
+                algorithm: "signed_curve25519".to_string(),
+                key_id: "AAAAAQ".to_string(),
+                key: r#""key""#.to_string(),
+            }],
+        )
+        .unwrap();
+
+        test.add_three_pid(&user.id, "email", "carl@example.com");
+        request_password_email_token(&test, "carl@example.com", 1);
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/account/deactivate?access_token={}",
+                user.token
+            ),
+            r#"{}"#,
+        );
+        test.check_empty_response(response);
+
+        assert!(AccessToken::find_valid_by_uid(&test.connection(), &user_id)
+            .unwrap()
+            .is_empty());
+        assert!(OpenIdToken::find_valid(&test.connection(), &openid_token)
+            .unwrap()
+            .is_none());
+        assert!(Pusher::find_by_uid(&test.connection(), &user_id)
+            .unwrap()
+            .is_empty());
+        assert!(
+            OneTimeKey::claim(&test.connection(), &user_id, "OTHER", "signed_curve25519")
+                .unwrap()
+                .is_none()
+        );
+        let reset_sessions: i64 = password_reset_sessions::table
+            .filter(password_reset_sessions::user_id.eq(&user_id))
+            .count()
+            .get_result(&*test.connection())
+            .unwrap();
+        assert_eq!(reset_sessions, 0);
+
+        for access_token in &[&user.token, &other_token] {
+            let response = test.get(&format!(
+                "/_matrix/client/r0/account/sessions?access_token={}",
+                access_token
+            ));
+            assert_eq!(response.status, Status::Unauthorized);
+        }
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            &format!(
+                r#"{{"type": "m.login.password", "user": "{}", "password": "secret"}}"#,
+                user.name
+            ),
+        );
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    /// Gives the user a display name and an email address, deactivates them with the given
+    /// request body, and returns whether their profile and email address are still stored.
+    fn deactivate_with_profile(body: &str) -> (bool, bool) {
+        let test = Test::new();
+        let user = test.create_user();
+        let user_id = UserId::try_from(user.id.as_str()).unwrap();
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+                user.id, user.token
+            ),
+            r#"{"displayname": "Carl"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+        test.add_three_pid(&user.id, "email", "carl@example.com");
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/account/deactivate?access_token={}",
+                user.token
+            ),
+            body,
+        );
+        test.check_empty_response(response);
+
+        let connection = test.connection();
+        let profile = Profile::find_by_uid(&connection, &user_id).unwrap();
+        let three_pid = ThreePid::find_user_id(&connection, "email", "carl@example.com").unwrap();
+
+        (profile.is_some(), three_pid.is_some())
+    }
+
+    #[test]
+    fn deactivate_account_with_erase() {
+        assert_eq!(
+            deactivate_with_profile(r#"{"erase": true}"#),
+            (false, false)
+        );
+    }
+
+    #[test]
+    fn deactivate_account_without_erase() {
+        assert_eq!(deactivate_with_profile(r#"{"erase": false}"#), (true, true));
+        assert_eq!(deactivate_with_profile(r#"{}"#), (true, true));
+    }
+
+    fn sessions(test: &Test, access_token: &str) -> Vec<Value> {
+        let response = test.get(&format!(
+            "/_matrix/client/r0/account/sessions?access_token={}",
+            access_token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        response
+            .json()
+            .get("sessions")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn account_sessions() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let login = format!(
+            r#"{{"type": "m.login.password", "user": "{}", "password": "secret", "device_id": "laptop"}}"#,
+            user.name
+        );
+        let response = test.post("/_matrix/client/r0/login", &login);
+        assert_eq!(response.status, Status::Ok);
+        let laptop_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let sessions = sessions(&test, &user.token);
+        assert_eq!(sessions.len(), 2);
+
+        assert!(sessions[0].get("current").unwrap().as_bool().unwrap());
+        assert!(sessions[0].get("created_at").unwrap().as_i64().unwrap() > 0);
+        assert!(sessions[0].get("last_seen").unwrap().as_i64().unwrap() > 0);
+        assert_eq!(
+            sessions[0].get("ip").unwrap().as_str().unwrap(),
+            "127.0.0.1"
+        );
+        assert!(sessions[0].get("user_agent").unwrap().is_string());
+
+        // The laptop's token has not been used yet, so no connection is known.
+        assert_eq!(
+            sessions[1].get("device_id").unwrap().as_str().unwrap(),
+            "laptop"
+        );
+        assert!(!sessions[1].get("current").unwrap().as_bool().unwrap());
+        assert!(sessions[1].get("last_seen").is_none());
+
+        for session in &sessions {
+            let values: Vec<&Value> = session.as_object().unwrap().values().collect();
+            assert!(!values.contains(&&Value::String(user.token.clone())));
+            assert!(!values.contains(&&Value::String(laptop_token.clone())));
+            assert!(session.get("access_token").is_none());
+        }
+    }
+
+    #[test]
+    fn account_sessions_of_other_users_are_not_listed() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}",
+            bob.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        assert_eq!(sessions(&test, &alice.token).len(), 1);
+        assert_eq!(sessions(&test, &bob.token).len(), 1);
     }
 
     #[test]
@@ -328,7 +963,7 @@ mod tests {
         assert_eq!(response.status, Status::BadRequest);
         assert_eq!(
             response.json().get("errcode").unwrap().as_str().unwrap(),
-            "IO_RUMA_INVALID_PARAM"
+            "M_INVALID_PARAM"
         );
         assert_eq!(
             response.json().get("error").unwrap().as_str().unwrap(),
@@ -422,7 +1057,7 @@ mod tests {
         assert_eq!(response.status, Status::BadRequest);
         assert_eq!(
             response.json().get("errcode").unwrap().as_str().unwrap(),
-            "IO_RUMA_INVALID_PARAM"
+            "M_INVALID_PARAM"
         );
         assert_eq!(
             response.json().get("error").unwrap().as_str().unwrap(),
@@ -457,4 +1092,220 @@ mod tests {
             "No membership entry was found."
         );
     }
+
+    fn request_password_email_token(test: &Test, email: &str, send_attempt: u64) -> String {
+        let response = test.post(
+            "/_matrix/client/r0/account/password/email/requestToken",
+            &format!(
+                r#"{{"client_secret": "secret", "email": "{}", "send_attempt": {}}}"#,
+                email, send_attempt
+            ),
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = response.json().as_object().unwrap().clone();
+        assert_eq!(response.len(), 1);
+
+        response.get("sid").unwrap().as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn request_password_email_token_for_bound_email() {
+        let test = Test::new();
+        let user = test.create_user();
+        let user_id = UserId::try_from(user.id.as_ref()).unwrap();
+
+        ThreePid::create(&test.connection(), &user_id, "email", "carl@example.com").unwrap();
+
+        let sid = request_password_email_token(&test, "Carl@example.com", 1);
+        assert!(!sid.is_empty());
+
+        // Retries with the same send attempt belong to the same session.
+        assert_eq!(
+            request_password_email_token(&test, "carl@example.com", 1),
+            sid
+        );
+        assert_eq!(
+            request_password_email_token(&test, "carl@example.com", 2),
+            sid
+        );
+    }
+
+    #[test]
+    fn request_password_email_token_for_unbound_email() {
+        let test = Test::new();
+        let user = test.create_user();
+        let user_id = UserId::try_from(user.id.as_ref()).unwrap();
+
+        ThreePid::create(&test.connection(), &user_id, "email", "carl@example.com").unwrap();
+
+        let bound_sid = request_password_email_token(&test, "carl@example.com", 1);
+        let unbound_sid = request_password_email_token(&test, "nobody@example.com", 1);
+
+        assert_eq!(unbound_sid.len(), bound_sid.len());
+        assert_ne!(unbound_sid, bound_sid);
+
+        // Repeating the request gives the same session ID, as it does for a bound address.
+        assert_eq!(
+            request_password_email_token(&test, "nobody@example.com", 1),
+            unbound_sid
+        );
+        assert_eq!(
+            request_password_email_token(&test, "Nobody@example.com", 2),
+            unbound_sid
+        );
+        assert_ne!(
+            request_password_email_token(&test, "somebody@example.com", 1),
+            unbound_sid
+        );
+    }
+
+    #[test]
+    fn request_password_email_token_with_too_large_send_attempt() {
+        let test = Test::new();
+
+        let response = test.post(
+            "/_matrix/client/r0/account/password/email/requestToken",
+            r#"{"client_secret": "secret", "email": "carl@example.com", "send_attempt": 9223372036854775808}"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn request_password_email_token_with_invalid_client_secret() {
+        let test = Test::new();
+
+        let response = test.post(
+            "/_matrix/client/r0/account/password/email/requestToken",
+            r#"{"client_secret": "not secret!", "email": "carl@example.com", "send_attempt": 1}"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn request_password_email_token_with_invalid_email() {
+        let test = Test::new();
+
+        let response = test.post(
+            "/_matrix/client/r0/account/password/email/requestToken",
+            r#"{"client_secret": "secret", "email": "carl", "send_attempt": 1}"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_PARAM"
+        );
+    }
+
+    fn delete_three_pid(test: &Test, access_token: &str, address: &str) {
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/account/3pid/delete?access_token={}",
+                access_token
+            ),
+            &format!(r#"{{"medium": "email", "address": "{}"}}"#, address),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .get("id_server_unbind_result")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "no-support"
+        );
+    }
+
+    #[test]
+    fn delete_bound_three_pid() {
+        let test = Test::new();
+        let user = test.create_user();
+        test.add_three_pid(&user.id, "email", "carl@example.com");
+        test.add_three_pid(&user.id, "email", "carl@example.org");
+
+        delete_three_pid(&test, &user.token, "Carl@example.com");
+
+        let find = |address| ThreePid::find_user_id(&test.connection(), "email", address).unwrap();
+        assert!(find("carl@example.com").is_none());
+        assert_eq!(find("carl@example.org").unwrap().to_string(), user.id);
+    }
+
+    #[test]
+    fn delete_unbound_three_pid() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        test.add_three_pid(&bob.id, "email", "bob@example.com");
+
+        delete_three_pid(&test, &alice.token, "nobody@example.com");
+        delete_three_pid(&test, &alice.token, "bob@example.com");
+
+        let user_id = ThreePid::find_user_id(&test.connection(), "email", "bob@example.com")
+            .unwrap()
+            .unwrap();
+        assert_eq!(user_id.to_string(), bob.id);
+    }
+
+    #[test]
+    fn list_three_pids_with_validation_state() {
+        let test = Test::new();
+        let user = test.create_user();
+        let other_user = test.create_user();
+        let user_id = UserId::try_from(user.id.as_str()).unwrap();
+
+        test.add_three_pid(&user.id, "email", "carl@example.com");
+        ThreePid::create_pending(&test.connection(), &user_id, "email", "carl@example.org")
+            .unwrap();
+        test.add_three_pid(&other_user.id, "email", "bob@example.com");
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/account/3pid?access_token={}",
+            user.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let threepids = response
+            .json()
+            .get("threepids")
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(threepids.len(), 2);
+
+        let threepid = |address: &str| {
+            threepids
+                .iter()
+                .find(|threepid| threepid.get("address").unwrap().as_str().unwrap() == address)
+                .unwrap()
+        };
+
+        let validated = threepid("carl@example.com");
+        assert_eq!(validated.get("medium").unwrap().as_str().unwrap(), "email");
+        assert!(validated.get("validated_at").unwrap().as_i64().unwrap() > 0);
+        assert!(validated.get("added_at").unwrap().as_i64().unwrap() > 0);
+
+        let pending = threepid("carl@example.org");
+        assert!(pending.get("validated_at").unwrap().is_null());
+        assert!(pending.get("added_at").unwrap().as_i64().unwrap() > 0);
+
+        // Pending identifiers can't be used to find the user.
+        assert!(
+            ThreePid::find_user_id(&test.connection(), "email", "carl@example.org")
+                .unwrap()
+                .is_none()
+        );
+    }
 }
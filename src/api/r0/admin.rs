@@ -0,0 +1,1443 @@
+//! Endpoints for server administration.
+
+use std::collections::BTreeMap;
+
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Request, Response};
+use ruma_identifiers::UserId;
+use url::Url;
+
+use crate::config::{Config, TokenFormat};
+use crate::crypto::is_password_hash;
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{
+    parse_body, AccessTokenAuth, AdminAuth, JsonRequest, MiddlewareChain, UserIdParam,
+};
+use crate::models::access_token::AccessToken;
+use crate::models::profile::Profile;
+use crate::models::registration_token::RegistrationToken;
+use crate::models::server_notice::{NewServerNotice, ServerNotice};
+use crate::models::user::{NewUser, User};
+use crate::models::user_ip::UserIp;
+use crate::models::{pg_timestamp, unix_millis};
+use crate::modifier::SerializableResponse;
+
+/// The `/admin/whois/:user_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct WhoIs;
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct WhoIsResponse {
+    /// The Matrix user ID of the user.
+    user_id: UserId,
+    /// Each key is an identifier for one of the user's devices.
+    devices: BTreeMap<String, DeviceInfo>,
+}
+
+/// Information about one of the user's devices.
+#[derive(Debug, Default, Serialize)]
+struct DeviceInfo {
+    /// A user's sessions, one for each access token issued to the device.
+    sessions: Vec<SessionInfo>,
+}
+
+/// Information about a user's session.
+#[derive(Debug, Serialize)]
+struct SessionInfo {
+    /// Information about particular connections in the session.
+    connections: Vec<ConnectionInfo>,
+}
+
+/// Information about a particular connection.
+#[derive(Debug, Serialize)]
+struct ConnectionInfo {
+    /// Most recently seen IP address of the session.
+    ip: String,
+    /// Unix timestamp that the session was last active.
+    last_seen: i64,
+    /// User agent string last seen in the session.
+    user_agent: String,
+}
+
+middleware_chain!(WhoIs, [UserIdParam, AccessTokenAuth, AdminAuth]);
+
+impl Handler for WhoIs {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user_id = request
+            .extensions
+            .get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId")
+            .clone();
+
+        let connection = DB::from_request(request)?;
+
+        if User::find_registered_user(&connection, &user_id)?.is_none() {
+            Err(ApiError::not_found(format!(
+                "The user {} was not found on this server",
+                user_id
+            )))?;
+        }
+
+        let access_tokens = AccessToken::find_valid_by_uid(&connection, &user_id)?;
+        let user_ips = UserIp::find_by_uid(&connection, &user_id)?;
+
+        let mut devices: BTreeMap<String, DeviceInfo> = BTreeMap::new();
+
+        for access_token in access_tokens {
+            let connections = user_ips
+                .iter()
+                .filter(|user_ip| user_ip.access_token_id == access_token.id)
+                .map(|user_ip| ConnectionInfo {
+                    ip: user_ip.ip.clone(),
+                    last_seen: unix_millis(&user_ip.last_seen),
+                    user_agent: user_ip.user_agent.clone(),
+                })
+                .collect();
+
+            devices
+                .entry(access_token.device_id)
+                .or_default()
+                .sessions
+                .push(SessionInfo { connections });
+        }
+
+        let response = WhoIsResponse { user_id, devices };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The `/admin/users/import` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct ImportUsers;
+
+/// A user to import, one element of the body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct ImportUsersRequest {
+    /// The local part of the user's Matrix ID.
+    username: String,
+    /// An Argon2 hash of the user's password, as stored by Ruma.
+    password_hash: String,
+}
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct ImportUsersResponse {
+    /// The outcome for each user in the request, in the same order.
+    results: Vec<ImportResult>,
+}
+
+/// The outcome of importing one user.
+#[derive(Debug, Serialize)]
+struct ImportResult {
+    /// The username given in the request.
+    username: String,
+    /// Whether or not the user was imported.
+    success: bool,
+    /// The Matrix ID of the imported user.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<UserId>,
+    /// Why the user could not be imported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ApiError>,
+}
+
+middleware_chain!(ImportUsers, [JsonRequest, AccessTokenAuth, AdminAuth]);
+
+impl Handler for ImportUsers {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let import_request = parse_body::<Vec<ImportUsersRequest>>(request)?;
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        let mut results = Vec::with_capacity(import_request.len());
+        let mut new_users = Vec::new();
+
+        for user in import_request {
+            let user_id = User::local_id(&user.username, "username", &config).and_then(|user_id| {
+                if is_password_hash(&user.password_hash) {
+                    Ok(user_id)
+                } else {
+                    Err(ApiError::invalid_param(
+                        "password_hash",
+                        "Not an encoded Argon2 hash",
+                    ))
+                }
+            });
+
+            let error = match user_id {
+                Ok(user_id) => {
+                    new_users.push(NewUser {
+                        id: user_id,
+                        password_hash: user.password_hash,
+                    });
+
+                    None
+                }
+                Err(error) => Some(error),
+            };
+
+            results.push(ImportResult {
+                username: user.username,
+                success: false,
+                user_id: None,
+                error,
+            });
+        }
+
+        let mut imported = User::import(&connection, &new_users)?.into_iter();
+
+        for result in results.iter_mut().filter(|result| result.error.is_none()) {
+            match imported
+                .next()
+                .expect("one import result for each valid user")
+            {
+                Ok(user) => {
+                    result.success = true;
+                    result.user_id = Some(user.id);
+                }
+                Err(error) => result.error = Some(error),
+            }
+        }
+
+        let response = ImportUsersResponse { results };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The number of users returned by `/admin/users` if the request does not give a limit.
+const DEFAULT_SEARCH_LIMIT: i64 = 10;
+
+/// The maximum number of users returned by `/admin/users` at once.
+const MAX_SEARCH_LIMIT: i64 = 100;
+
+/// The `/admin/users` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct SearchUsers;
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct SearchUsersResponse {
+    /// The users matching the search term.
+    users: Vec<UserInfo>,
+    /// A token to pass as `from` to get the next page of users, if there are more.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_batch: Option<String>,
+}
+
+/// Information about a user, one element of the body of the response for this API.
+#[derive(Debug, Serialize)]
+struct UserInfo {
+    /// The Matrix user ID of the user.
+    user_id: UserId,
+    /// Whether or not the user is a server administrator.
+    is_admin: bool,
+    /// Whether or not the user has deactivated their account.
+    deactivated: bool,
+    /// Unix timestamp in milliseconds of when the user was created.
+    created_at: i64,
+}
+
+impl From<User> for UserInfo {
+    fn from(user: User) -> Self {
+        Self {
+            user_id: user.id,
+            is_admin: user.admin,
+            deactivated: !user.active,
+            created_at: unix_millis(&user.created_at),
+        }
+    }
+}
+
+middleware_chain!(SearchUsers, [AccessTokenAuth, AdminAuth]);
+
+impl Handler for SearchUsers {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let url: Url = request.url.clone().into();
+
+        let mut search_term = String::new();
+        let mut from = 0;
+        let mut limit = DEFAULT_SEARCH_LIMIT;
+
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "search_term" => search_term = value.into_owned(),
+                "from" => {
+                    from = value
+                        .parse()
+                        .ok()
+                        .filter(|from| *from >= 0)
+                        .ok_or_else(|| {
+                            ApiError::invalid_param("from", "Invalid pagination token")
+                        })?
+                }
+                "limit" => {
+                    limit = value
+                        .parse()
+                        .ok()
+                        .filter(|limit| *limit > 0)
+                        .ok_or_else(|| {
+                            ApiError::invalid_param("limit", "Must be a positive integer")
+                        })?
+                }
+                _ => {}
+            }
+        }
+
+        let limit = limit.min(MAX_SEARCH_LIMIT);
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        // Fetch one more user than requested to find out whether there is another page.
+        let mut users = User::search(&connection, &config.domain, &search_term, from, limit + 1)?;
+
+        let next_batch = if users.len() as i64 > limit {
+            users.truncate(limit as usize);
+            Some((from + limit).to_string())
+        } else {
+            None
+        };
+
+        let users = users.into_iter().map(UserInfo::from).collect();
+
+        let response = SearchUsersResponse { users, next_batch };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The `/admin/users/:user_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetUser;
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct GetUserResponse {
+    /// The Matrix user ID of the user.
+    user_id: UserId,
+    /// Whether or not the user is a server administrator.
+    is_admin: bool,
+    /// Whether or not the user has deactivated their account.
+    deactivated: bool,
+    /// Unix timestamp in milliseconds of when the user was created.
+    created_at: i64,
+    /// The user's display name, if they have set one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    displayname: Option<String>,
+    /// The user's avatar URL, if they have set one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+}
+
+middleware_chain!(GetUser, [UserIdParam, AccessTokenAuth, AdminAuth]);
+
+impl Handler for GetUser {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user_id = request
+            .extensions
+            .get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId")
+            .clone();
+
+        let connection = DB::from_request(request)?;
+
+        let user = match User::find_registered_user(&connection, &user_id)? {
+            Some(user) => user,
+            None => Err(ApiError::not_found(format!(
+                "The user {} was not found on this server",
+                user_id
+            )))?,
+        };
+
+        let profile = Profile::find_by_uid(&connection, &user_id)?;
+
+        let response = GetUserResponse {
+            user_id: user.id,
+            is_admin: user.admin,
+            deactivated: !user.active,
+            created_at: unix_millis(&user.created_at),
+            displayname: profile
+                .as_ref()
+                .and_then(|profile| profile.displayname.clone()),
+            avatar_url: profile.and_then(|profile| profile.avatar_url),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The `/admin/users/:user_id/rebind_device` endpoint.
+///
+/// Unlike the other administration APIs, users can rebind their own sessions.
+#[derive(Clone, Copy, Debug)]
+pub struct RebindDevice;
+
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct RebindDeviceRequest {
+    /// The device whose sessions are moved.
+    device_id: String,
+    /// The device the sessions are moved to.
+    new_device_id: String,
+}
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct RebindDeviceResponse {
+    /// The device the sessions were moved to.
+    device_id: String,
+    /// The access tokens re-issued for the new device, keyed by the old access tokens they
+    /// replace. Opaque access tokens don't name their device, so they keep working and aren't
+    /// re-issued.
+    access_tokens: BTreeMap<String, String>,
+    /// The access token that replaces the one the request was made with, if the request moved
+    /// its own session.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    access_token: Option<String>,
+}
+
+middleware_chain!(RebindDevice, [JsonRequest, UserIdParam, AccessTokenAuth]);
+
+impl Handler for RebindDevice {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let rebind_request = parse_body::<RebindDeviceRequest>(request)?;
+
+        let user_id = request
+            .extensions
+            .get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId")
+            .clone();
+
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let current_access_token = request
+            .extensions
+            .get::<AccessToken>()
+            .expect("AccessTokenAuth should ensure an access token")
+            .clone();
+
+        if !user.admin && user.id != user_id {
+            Err(ApiError::unauthorized(
+                "Only server administrators can rebind other users' devices".to_string(),
+            ))?;
+        }
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        let access_tokens = AccessToken::rebind_device(
+            &connection,
+            &user_id,
+            &rebind_request.device_id,
+            &rebind_request.new_device_id,
+            &config,
+        )?;
+
+        info!(
+            "{} moved the sessions of device {} of {} to device {}.",
+            user.id, rebind_request.device_id, user_id, rebind_request.new_device_id
+        );
+
+        let access_tokens: BTreeMap<String, String> = match config.token_format {
+            TokenFormat::Macaroon => access_tokens
+                .into_iter()
+                .map(|(old_value, access_token)| (old_value, access_token.value))
+                .collect(),
+            TokenFormat::Opaque => BTreeMap::new(),
+        };
+
+        let access_token = access_tokens.get(&current_access_token.value).cloned();
+
+        let response = RebindDeviceResponse {
+            device_id: rebind_request.new_device_id,
+            access_tokens,
+            access_token,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The POST `/admin/server_notice` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct SetServerNotice;
+
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct SetServerNoticeRequest {
+    /// The text of the notice.
+    body: String,
+}
+
+/// The body of the response for the server notice APIs.
+#[derive(Debug, Serialize)]
+struct ServerNoticeResponse {
+    /// The text of the notice.
+    body: String,
+    /// The administrator who set the notice.
+    sender: UserId,
+    /// Unix timestamp in milliseconds of when the notice was set.
+    created_at: i64,
+}
+
+impl From<ServerNotice> for ServerNoticeResponse {
+    fn from(server_notice: ServerNotice) -> Self {
+        Self {
+            body: server_notice.body,
+            sender: server_notice.sender,
+            created_at: unix_millis(&server_notice.created_at),
+        }
+    }
+}
+
+middleware_chain!(SetServerNotice, [JsonRequest, AccessTokenAuth, AdminAuth]);
+
+impl Handler for SetServerNotice {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let notice_request = parse_body::<SetServerNoticeRequest>(request)?;
+
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let connection = DB::from_request(request)?;
+
+        let new_server_notice = NewServerNotice {
+            body: notice_request.body,
+            sender: user.id,
+        };
+
+        let server_notice = ServerNotice::create(&connection, &new_server_notice)?;
+
+        let response = ServerNoticeResponse::from(server_notice);
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/admin/server_notice` endpoint.
+///
+/// Unlike the other administration APIs, anyone can read the current notice.
+#[derive(Clone, Copy, Debug)]
+pub struct GetServerNotice;
+
+middleware_chain!(GetServerNotice, []);
+
+impl Handler for GetServerNotice {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let connection = DB::from_request(request)?;
+
+        let server_notice = ServerNotice::find_current(&connection)?
+            .ok_or_else(|| ApiError::not_found("No server notice has been set".to_string()))?;
+
+        let response = ServerNoticeResponse::from(server_notice);
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The POST `/admin/registration_tokens` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct CreateRegistrationToken;
+
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct CreateRegistrationTokenRequest {
+    /// The number of registrations the token can be used for, unlimited if not given.
+    uses_allowed: Option<i32>,
+    /// Unix timestamp in milliseconds after which the token can no longer be used, if any.
+    expiry_time: Option<i64>,
+}
+
+/// A registration token, the body of the response for `CreateRegistrationToken` and one element
+/// of the body of the response for `GetRegistrationTokens`.
+#[derive(Debug, Serialize)]
+struct RegistrationTokenInfo {
+    /// The token to pass to `/register`.
+    token: String,
+    /// The number of registrations the token can still be used for, or `null` for no limit.
+    uses_remaining: Option<i32>,
+    /// Unix timestamp in milliseconds after which the token can no longer be used, or `null`.
+    expiry_time: Option<i64>,
+    /// Unix timestamp in milliseconds of when the token was created.
+    created_at: i64,
+}
+
+impl From<RegistrationToken> for RegistrationTokenInfo {
+    fn from(registration_token: RegistrationToken) -> Self {
+        Self {
+            token: registration_token.token,
+            uses_remaining: registration_token.uses_remaining,
+            expiry_time: registration_token.expires_at.as_ref().map(unix_millis),
+            created_at: unix_millis(&registration_token.created_at),
+        }
+    }
+}
+
+middleware_chain!(
+    CreateRegistrationToken,
+    [JsonRequest, AccessTokenAuth, AdminAuth]
+);
+
+impl Handler for CreateRegistrationToken {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let token_request = parse_body::<CreateRegistrationTokenRequest>(request)?;
+
+        if token_request
+            .uses_allowed
+            .filter(|uses| *uses < 0)
+            .is_some()
+        {
+            Err(ApiError::invalid_param(
+                "uses_allowed",
+                "Must be a non-negative integer",
+            ))?;
+        }
+
+        let connection = DB::from_request(request)?;
+
+        let registration_token = RegistrationToken::create(
+            &connection,
+            token_request.uses_allowed,
+            token_request.expiry_time.map(pg_timestamp),
+        )?;
+
+        let response = RegistrationTokenInfo::from(registration_token);
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/admin/registration_tokens` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetRegistrationTokens;
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct GetRegistrationTokensResponse {
+    /// All registration tokens, including used up and expired ones.
+    registration_tokens: Vec<RegistrationTokenInfo>,
+}
+
+middleware_chain!(GetRegistrationTokens, [AccessTokenAuth, AdminAuth]);
+
+impl Handler for GetRegistrationTokens {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let connection = DB::from_request(request)?;
+
+        let registration_tokens = RegistrationToken::list(&connection)?
+            .into_iter()
+            .map(RegistrationTokenInfo::from)
+            .collect();
+
+        let response = GetRegistrationTokensResponse {
+            registration_tokens,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::str::from_utf8;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use base64::decode;
+    use macaroons::token::Token;
+    use macaroons::v1::V1Token;
+
+    use crate::crypto::hash_password;
+    use crate::models::access_token::AccessToken;
+    use crate::test::Test;
+    use iron::status::Status;
+    use ruma_identifiers::UserId;
+
+    /// Registers the user on the given device and returns their access token.
+    fn register_on_device(test: &Test, username: &str, device_id: &str) -> String {
+        let response = test.register_user(&format!(
+            r#"{{"username": "{}", "password": "secret", "device_id": "{}"}}"#,
+            username, device_id
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    /// Returns the first-party caveats of a Base64-encoded macaroon.
+    fn caveats(token: &str) -> Vec<String> {
+        V1Token::deserialize(decode(token).unwrap())
+            .unwrap()
+            .caveats
+            .iter()
+            .map(|caveat| from_utf8(&caveat.caveat_id).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn whois_as_admin() {
+        let test = Test::new();
+        let admin = test.create_admin();
+        let user = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}",
+            user.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/whois/{}?access_token={}",
+            user.id, admin.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("user_id").unwrap().as_str().unwrap(),
+            user.id
+        );
+
+        let devices = response.json().get("devices").unwrap().as_object().unwrap();
+        assert_eq!(devices.len(), 1);
+
+        let sessions = devices
+            .values()
+            .next()
+            .unwrap()
+            .get("sessions")
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(sessions.len(), 1);
+
+        let connections = sessions[0].get("connections").unwrap().as_array().unwrap();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(
+            connections[0].get("ip").unwrap().as_str().unwrap(),
+            "127.0.0.1"
+        );
+        assert!(connections[0].get("last_seen").unwrap().as_i64().unwrap() > 0);
+        assert!(connections[0].get("user_agent").unwrap().is_string());
+    }
+
+    #[test]
+    fn whois_as_non_admin() {
+        let test = Test::new();
+        let user = test.create_user();
+        let other_user = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/whois/{}?access_token={}",
+            other_user.id, user.token
+        ));
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+    }
+
+    #[test]
+    fn whois_unknown_user() {
+        let test = Test::new();
+        let admin = test.create_admin();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/whois/@carl:ruma.test?access_token={}",
+            admin.token
+        ));
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn import_users() {
+        let test = Test::new();
+        let admin = test.create_admin();
+        let password_hash = hash_password("secret").unwrap();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/admin/users/import?access_token={}",
+                admin.token
+            ),
+            &format!(
+                r#"[{{"username": "carl", "password_hash": "{0}"}}, {{"username": "dave", "password_hash": "{0}"}}]"#,
+                password_hash
+            ),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let results = response.json().get("results").unwrap().as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].get("success").unwrap().as_bool().unwrap());
+        assert_eq!(
+            results[0].get("user_id").unwrap().as_str().unwrap(),
+            "@carl:ruma.test"
+        );
+        assert!(results[1].get("success").unwrap().as_bool().unwrap());
+        assert_eq!(
+            results[1].get("user_id").unwrap().as_str().unwrap(),
+            "@dave:ruma.test"
+        );
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "dave", "password": "secret"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn import_users_with_duplicate() {
+        let test = Test::new();
+        let admin = test.create_admin();
+        let password_hash = hash_password("secret").unwrap();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/admin/users/import?access_token={}",
+                admin.token
+            ),
+            &format!(
+                r#"[{{"username": "carl", "password_hash": "{0}"}}, {{"username": "dave", "password_hash": "{0}"}}]"#,
+                password_hash
+            ),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let results = response.json().get("results").unwrap().as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(!results[0].get("success").unwrap().as_bool().unwrap());
+        assert_eq!(
+            results[0]
+                .get("error")
+                .unwrap()
+                .get("errcode")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "M_USER_IN_USE"
+        );
+        assert!(results[1].get("success").unwrap().as_bool().unwrap());
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "dave", "password": "secret"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn import_users_with_invalid_password_hash() {
+        let test = Test::new();
+        let admin = test.create_admin();
+        let password_hash = hash_password("secret").unwrap();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/admin/users/import?access_token={}",
+                admin.token
+            ),
+            &format!(
+                r#"[{{"username": "carl", "password_hash": "secret"}}, {{"username": "dave", "password_hash": "{}"}}]"#,
+                password_hash
+            ),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let results = response.json().get("results").unwrap().as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(!results[0].get("success").unwrap().as_bool().unwrap());
+        assert_eq!(
+            results[0]
+                .get("error")
+                .unwrap()
+                .get("errcode")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "M_INVALID_PARAM"
+        );
+        assert!(results[1].get("success").unwrap().as_bool().unwrap());
+
+        let response = test.get("/_matrix/client/r0/register/available?username=carl");
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn import_users_as_non_admin() {
+        let test = Test::new();
+        let user = test.create_user();
+        let password_hash = hash_password("secret").unwrap();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/admin/users/import?access_token={}",
+                user.token
+            ),
+            &format!(
+                r#"[{{"username": "carl", "password_hash": "{}"}}]"#,
+                password_hash
+            ),
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn search_users() {
+        let test = Test::new();
+        let admin = test.create_admin();
+
+        for username in &["carl", "carlos", "dave"] {
+            let response = test.register_user(&format!(
+                r#"{{"username": "{}", "password": "secret"}}"#,
+                username
+            ));
+            assert_eq!(response.status, Status::Ok);
+        }
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/users?search_term=CARL&access_token={}",
+            admin.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let users = response.json().get("users").unwrap().as_array().unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(
+            users[0].get("user_id").unwrap().as_str().unwrap(),
+            "@carl:ruma.test"
+        );
+        assert_eq!(
+            users[1].get("user_id").unwrap().as_str().unwrap(),
+            "@carlos:ruma.test"
+        );
+        assert!(!users[0].get("is_admin").unwrap().as_bool().unwrap());
+        assert!(!users[0].get("deactivated").unwrap().as_bool().unwrap());
+        assert!(users[0].get("created_at").unwrap().as_i64().unwrap() > 0);
+        assert!(users[0].get("password_hash").is_none());
+        assert!(response.json().get("next_batch").is_none());
+    }
+
+    #[test]
+    fn search_users_with_pagination() {
+        let test = Test::new();
+        let admin = test.create_admin();
+
+        for username in &["carl1", "carl2", "carl3"] {
+            let response = test.register_user(&format!(
+                r#"{{"username": "{}", "password": "secret"}}"#,
+                username
+            ));
+            assert_eq!(response.status, Status::Ok);
+        }
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/users?search_term=carl&limit=2&access_token={}",
+            admin.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let users = response.json().get("users").unwrap().as_array().unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(
+            users[1].get("user_id").unwrap().as_str().unwrap(),
+            "@carl2:ruma.test"
+        );
+
+        let next_batch = response.json().get("next_batch").unwrap().as_str().unwrap();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/users?search_term=carl&limit=2&from={}&access_token={}",
+            next_batch, admin.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let users = response.json().get("users").unwrap().as_array().unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(
+            users[0].get("user_id").unwrap().as_str().unwrap(),
+            "@carl3:ruma.test"
+        );
+        assert!(response.json().get("next_batch").is_none());
+    }
+
+    #[test]
+    fn search_users_as_non_admin() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/users?search_term=carl&access_token={}",
+            user.token
+        ));
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn get_user() {
+        let test = Test::new();
+        let admin = test.create_admin();
+
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/users/@carl:ruma.test?access_token={}",
+            admin.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+        assert_eq!(
+            json.get("user_id").unwrap().as_str().unwrap(),
+            "@carl:ruma.test"
+        );
+        assert!(!json.get("is_admin").unwrap().as_bool().unwrap());
+        assert!(!json.get("deactivated").unwrap().as_bool().unwrap());
+        assert!(json.get("password_hash").is_none());
+
+        // The database clock may be slightly behind, and the whole test runs in one transaction.
+        let created_at = json.get("created_at").unwrap().as_i64().unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        assert!(created_at > before - 60_000);
+        assert!(created_at <= now + 60_000);
+    }
+
+    #[test]
+    fn get_unknown_user() {
+        let test = Test::new();
+        let admin = test.create_admin();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/users/@carl:ruma.test?access_token={}",
+            admin.token
+        ));
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn get_user_as_non_admin() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/users/{}?access_token={}",
+            user.id, user.token
+        ));
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn set_server_notice() {
+        let test = Test::new();
+        let admin = test.create_admin();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/admin/server_notice?access_token={}",
+                admin.token
+            ),
+            r#"{"body": "Scheduled maintenance tonight"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get("/_matrix/client/r0/admin/server_notice");
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("body").unwrap().as_str().unwrap(),
+            "Scheduled maintenance tonight"
+        );
+        assert_eq!(
+            response.json().get("sender").unwrap().as_str().unwrap(),
+            admin.id
+        );
+        assert!(response.json().get("created_at").unwrap().is_i64());
+    }
+
+    #[test]
+    fn newest_server_notice_is_current() {
+        let test = Test::new();
+        let admin = test.create_admin();
+        let path = format!(
+            "/_matrix/client/r0/admin/server_notice?access_token={}",
+            admin.token
+        );
+
+        assert_eq!(test.post(&path, r#"{"body": "First"}"#).status, Status::Ok);
+        assert_eq!(test.post(&path, r#"{"body": "Second"}"#).status, Status::Ok);
+
+        let response = test.get("/_matrix/client/r0/admin/server_notice");
+        assert_eq!(
+            response.json().get("body").unwrap().as_str().unwrap(),
+            "Second"
+        );
+    }
+
+    #[test]
+    fn set_server_notice_as_non_admin() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/admin/server_notice?access_token={}",
+                user.token
+            ),
+            r#"{"body": "Free pizza"}"#,
+        );
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.get("/_matrix/client/r0/admin/server_notice");
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn create_and_list_registration_tokens() {
+        let test = Test::new();
+        let admin = test.create_admin();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/admin/registration_tokens?access_token={}",
+                admin.token
+            ),
+            r#"{"uses_allowed": 1, "expiry_time": 4102444800000}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+        let token = response
+            .json()
+            .get("token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            response
+                .json()
+                .get("expiry_time")
+                .unwrap()
+                .as_i64()
+                .unwrap(),
+            4_102_444_800_000
+        );
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/admin/registration_tokens?access_token={}",
+                admin.token
+            ),
+            r#"{}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("uses_remaining").unwrap().is_null());
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/registration_tokens?access_token={}",
+            admin.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let tokens = response
+            .json()
+            .get("registration_tokens")
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(tokens.len(), 2);
+
+        let listed = tokens
+            .iter()
+            .find(|listed| listed.get("token").unwrap().as_str().unwrap() == token)
+            .unwrap();
+        assert_eq!(listed.get("uses_remaining").unwrap().as_i64().unwrap(), 1);
+    }
+
+    #[test]
+    fn registration_tokens_as_non_admin() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/admin/registration_tokens?access_token={}",
+                user.token
+            ),
+            r#"{}"#,
+        );
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/registration_tokens?access_token={}",
+            user.token
+        ));
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn negative_registration_token_uses() {
+        let test = Test::new();
+        let admin = test.create_admin();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/admin/registration_tokens?access_token={}",
+                admin.token
+            ),
+            r#"{"uses_allowed": -1}"#,
+        );
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn rebind_own_device() {
+        let test = Test::new();
+        let access_token = register_on_device(&test, "carl", "OLD");
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/admin/users/@carl:ruma.test/rebind_device?access_token={}",
+                access_token
+            ),
+            r#"{"device_id": "OLD", "new_device_id": "NEW"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("device_id").unwrap().as_str().unwrap(),
+            "NEW"
+        );
+
+        let access_tokens = response
+            .json()
+            .get("access_tokens")
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .clone();
+        assert_eq!(access_tokens.len(), 1);
+        let new_access_token = access_tokens.get(&access_token).unwrap().as_str().unwrap();
+        assert_eq!(
+            response
+                .json()
+                .get("access_token")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            new_access_token
+        );
+
+        let old_caveats = caveats(&access_token);
+        let caveats = caveats(new_access_token);
+        assert!(caveats.contains(&"device_id = NEW".to_string()));
+        assert!(!caveats.contains(&"device_id = OLD".to_string()));
+        assert_eq!(
+            caveats
+                .iter()
+                .filter(|caveat| caveat.starts_with("time < "))
+                .collect::<Vec<_>>(),
+            old_caveats
+                .iter()
+                .filter(|caveat| caveat.starts_with("time < "))
+                .collect::<Vec<_>>()
+        );
+
+        let sessions = |access_token: &str| {
+            test.get(&format!(
+                "/_matrix/client/r0/account/sessions?access_token={}",
+                access_token
+            ))
+        };
+        assert_eq!(sessions(&access_token).status, Status::Unauthorized);
+        assert_eq!(sessions(new_access_token).status, Status::Ok);
+    }
+
+    #[test]
+    fn rebind_device_maps_each_old_access_token() {
+        let test = Test::new();
+        let first_access_token = register_on_device(&test, "carl", "OLD");
+
+        // Logging in again on the device would issue the same macaroon, so give the second
+        // access token an expiration time.
+        let mut config = test.config().clone();
+        config.access_token_lifetime_seconds = 3600;
+        let second_access_token = AccessToken::create(
+            &test.connection(),
+            &UserId::try_from("@carl:ruma.test").unwrap(),
+            "OLD",
+            &config,
+        )
+        .unwrap()
+        .value;
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/admin/users/@carl:ruma.test/rebind_device?access_token={}",
+                second_access_token
+            ),
+            r#"{"device_id": "OLD", "new_device_id": "NEW"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let access_tokens = response
+            .json()
+            .get("access_tokens")
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .clone();
+        assert_eq!(access_tokens.len(), 2);
+
+        let replacement = |access_token: &str| {
+            access_tokens
+                .get(access_token)
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            response
+                .json()
+                .get("access_token")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            replacement(&second_access_token)
+        );
+
+        for old_access_token in &[first_access_token, second_access_token] {
+            let new_access_token = replacement(old_access_token);
+            assert_eq!(
+                caveats(&new_access_token)
+                    .into_iter()
+                    .filter(|caveat| !caveat.starts_with("device_id = "))
+                    .collect::<Vec<_>>(),
+                caveats(old_access_token)
+                    .into_iter()
+                    .filter(|caveat| !caveat.starts_with("device_id = "))
+                    .collect::<Vec<_>>()
+            );
+
+            let response = test.get(&format!(
+                "/_matrix/client/r0/account/sessions?access_token={}",
+                new_access_token
+            ));
+            assert_eq!(response.status, Status::Ok);
+        }
+    }
+
+    #[test]
+    fn rebind_device_as_admin() {
+        let test = Test::new();
+        let admin = test.create_admin();
+        register_on_device(&test, "carl", "OLD");
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/admin/users/@carl:ruma.test/rebind_device?access_token={}",
+                admin.token
+            ),
+            r#"{"device_id": "OLD", "new_device_id": "NEW"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("access_token").is_none());
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/whois/@carl:ruma.test?access_token={}",
+            admin.token
+        ));
+        let devices = response.json().get("devices").unwrap();
+        assert!(devices.get("NEW").is_some());
+        assert!(devices.get("OLD").is_none());
+    }
+
+    #[test]
+    fn rebind_device_of_another_user() {
+        let test = Test::new();
+        let carl_token = register_on_device(&test, "carl", "CARL");
+        let erin_token = register_on_device(&test, "erin", "ERIN");
+
+        // Users can't rebind the devices of others...
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/admin/users/@carl:ruma.test/rebind_device?access_token={}",
+                erin_token
+            ),
+            r#"{"device_id": "CARL", "new_device_id": "NEW"}"#,
+        );
+        assert_eq!(response.status, Status::Forbidden);
+
+        // ...or move their sessions to a device of another user.
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/admin/users/@carl:ruma.test/rebind_device?access_token={}",
+                carl_token
+            ),
+            r#"{"device_id": "CARL", "new_device_id": "ERIN"}"#,
+        );
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/account/sessions?access_token={}",
+            carl_token
+        ));
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn rebind_unknown_device() {
+        let test = Test::new();
+        let access_token = register_on_device(&test, "carl", "CARL");
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/admin/users/@carl:ruma.test/rebind_device?access_token={}",
+                access_token
+            ),
+            r#"{"device_id": "UNKNOWN", "new_device_id": "NEW"}"#,
+        );
+        assert_eq!(response.status, Status::NotFound);
+    }
+}
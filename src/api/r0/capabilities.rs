@@ -0,0 +1,106 @@
+//! Endpoints for the optional features this homeserver supports.
+
+use std::collections::BTreeMap;
+
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Request, Response};
+
+use crate::config::Config;
+use crate::middleware::{AccessTokenAuth, MiddlewareChain};
+use crate::models::room::SUPPORTED_ROOM_VERSIONS;
+use crate::modifier::SerializableResponse;
+
+/// The `/capabilities` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetCapabilities;
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct GetCapabilitiesResponse {
+    /// The capabilities of the homeserver.
+    capabilities: Capabilities,
+}
+
+/// The capabilities of the homeserver, each keyed by its name.
+#[derive(Debug, Serialize)]
+struct Capabilities {
+    /// The room versions the homeserver can create rooms with.
+    #[serde(rename = "m.room_versions")]
+    room_versions: RoomVersionsCapability,
+}
+
+/// The `m.room_versions` capability.
+#[derive(Debug, Serialize)]
+struct RoomVersionsCapability {
+    /// The version used for new rooms that don't ask for one.
+    default: String,
+    /// The stability of each room version the homeserver supports.
+    available: BTreeMap<&'static str, &'static str>,
+}
+
+middleware_chain!(GetCapabilities, [AccessTokenAuth]);
+
+impl Handler for GetCapabilities {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let config = Config::from_request(request)?;
+
+        let available = SUPPORTED_ROOM_VERSIONS
+            .iter()
+            .map(|version| (*version, "stable"))
+            .collect();
+
+        let response = GetCapabilitiesResponse {
+            capabilities: Capabilities {
+                room_versions: RoomVersionsCapability {
+                    default: config.default_room_version.clone(),
+                    available,
+                },
+            },
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use crate::models::room::SUPPORTED_ROOM_VERSIONS;
+    use crate::test::Test;
+
+    #[test]
+    fn room_versions() {
+        let test = Test::with_config(|config| config.default_room_version = "2".to_string());
+        let user = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/capabilities?access_token={}",
+            user.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let room_versions = response
+            .json()
+            .pointer("/capabilities/m.room_versions")
+            .unwrap();
+        assert_eq!(
+            room_versions.get("default").unwrap().as_str().unwrap(),
+            test.config().default_room_version
+        );
+
+        let available = room_versions.get("available").unwrap().as_object().unwrap();
+        assert_eq!(available.len(), SUPPORTED_ROOM_VERSIONS.len());
+        for version in SUPPORTED_ROOM_VERSIONS {
+            assert_eq!(available.get(*version).unwrap().as_str().unwrap(), "stable");
+        }
+    }
+
+    #[test]
+    fn requires_authentication() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/r0/capabilities");
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
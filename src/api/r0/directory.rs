@@ -1,15 +1,17 @@
 //! Endpoints for managing room aliases.
 
-use bodyparser;
 use iron::status::Status;
-use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::{Chain, Handler, IronResult, Request, Response};
 use ruma_identifiers::RoomId;
 
 use crate::config::Config;
 use crate::db::DB;
 use crate::error::ApiError;
-use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RoomAliasIdParam};
+use crate::middleware::{
+    parse_body, AccessTokenAuth, JsonRequest, MiddlewareChain, RoomAliasIdParam,
+};
 use crate::models::room_alias::{NewRoomAlias, RoomAlias};
+use crate::models::room_membership::RoomMembership;
 use crate::models::user::User;
 use crate::modifier::{EmptyResponse, SerializableResponse};
 
@@ -40,9 +42,17 @@ impl Handler for GetRoomAlias {
 
         let room_alias = RoomAlias::find_by_alias(&connection, &room_alias_id)?;
 
+        // The servers with joined members are the ones able to help a client join the room. If
+        // nobody is left in the room, fall back to the servers recorded with the alias.
+        let mut servers = RoomMembership::find_joined_servers(&connection, &room_alias.room_id)?;
+
+        if servers.is_empty() {
+            servers = room_alias.servers;
+        }
+
         let response = GetRoomAliasResponse {
             room_id: room_alias.room_id,
-            servers: room_alias.servers,
+            servers,
         };
 
         Ok(Response::with((Status::Ok, SerializableResponse(response))))
@@ -110,10 +120,7 @@ impl Handler for PutRoomAlias {
             .expect("RoomAliasIdParam should ensure a RoomAliasId")
             .clone();
 
-        let room_id = match request.get::<bodyparser::Struct<PutRoomAliasRequest>>() {
-            Ok(Some(req)) => req.room_id,
-            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
-        };
+        let room_id = parse_body::<PutRoomAliasRequest>(request)?.room_id;
 
         let user = request
             .extensions
@@ -159,6 +166,26 @@ mod tests {
         assert!(response.json().get("servers").unwrap().is_array());
     }
 
+    #[test]
+    fn get_room_alias_servers_from_members() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_id = test.create_room_with_params(
+            &alice.token,
+            r#"{"room_alias_name": "my_room", "visibility": "public"}"#,
+        );
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.get("/_matrix/client/r0/directory/room/my_room");
+        assert_eq!(response.status, Status::Ok);
+
+        let servers = response.json().get("servers").unwrap().as_array().unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].as_str().unwrap(), "ruma.test");
+    }
+
     #[test]
     fn get_unknown_room_alias() {
         let test = Test::new();
@@ -19,28 +19,33 @@ use ruma_events::room::join_rules::JoinRulesEvent;
 use ruma_events::room::message::MessageEvent;
 use ruma_events::room::name::NameEvent;
 use ruma_events::room::power_levels::PowerLevelsEvent;
+use ruma_events::room::redaction::{RedactionEvent, RedactionEventContent};
 use ruma_events::room::third_party_invite::ThirdPartyInviteEvent;
 use ruma_events::room::topic::TopicEvent;
 use ruma_events::{CustomRoomEvent, CustomStateEvent, EventType};
 use ruma_identifiers::{EventId, RoomId};
 use serde::Deserialize;
-use serde_json::{from_str, from_value, to_string, Value};
+use serde_json::{from_str, from_value, json, Value};
 
+use crate::api::r0::join::ensure_power_level;
 use crate::config::Config;
+use crate::crypto::canonical_json;
 use crate::db::DB;
 use crate::error::{ApiError, MapApiError};
 use crate::middleware::{
-    AccessTokenAuth, EventTypeParam, JsonRequest, MiddlewareChain, RoomIdParam, TransactionIdParam,
+    parse_body, AccessTokenAuth, EventIdParam, EventTypeParam, Idempotent, JsonRequest,
+    MiddlewareChain, RoomIdParam, TransactionIdParam,
 };
-use crate::models::access_token::AccessToken;
-use crate::models::event::NewEvent;
+use crate::models::event::{Event, NewEvent};
 use crate::models::room::Room;
 use crate::models::room_membership::RoomMembership;
-use crate::models::transaction::Transaction;
 use crate::models::user::User;
 use crate::modifier::SerializableResponse;
 use crate::schema::events;
 
+/// The maximum size of an event's canonical JSON in bytes.
+const MAX_EVENT_SIZE: usize = 65_536;
+
 macro_rules! room_event {
     (
         $ty:ident,
@@ -109,7 +114,8 @@ middleware_chain!(
         EventTypeParam,
         TransactionIdParam,
         AccessTokenAuth
-    ]
+    ],
+    [Idempotent]
 );
 
 impl Handler for SendMessageEvent {
@@ -208,20 +214,9 @@ impl Handler for SendMessageEvent {
             }
         };
 
-        let connection = DB::from_request(request)?;
+        ensure_event_size(&room_event)?;
 
-        let path = request.url.path().join("/").to_string();
-        let token = (*request
-            .extensions
-            .get::<AccessToken>()
-            .expect("AccessTokenAuth should ensure an access token"))
-        .clone();
-
-        if let Some(transaction) = Transaction::find(&connection, &path, &token.value)? {
-            let response: EventResponse =
-                from_str(&transaction.response).map_err(ApiError::from)?;
-            return Ok(Response::with((status::Ok, SerializableResponse(response))));
-        }
+        let connection = DB::from_request(request)?;
 
         let response = EventResponse {
             event_id: event_id.opaque_id().to_string(),
@@ -229,24 +224,133 @@ impl Handler for SendMessageEvent {
 
         connection
             .transaction(|| {
-                verify_permissions(&connection, &room_id, &user, &event_type)?;
+                verify_permissions(&connection, &room_id, &user, &event_type, false)?;
 
                 diesel::insert_into(events::table)
                     .values(&room_event)
                     .execute(&*connection)
-                    .map_err(ApiError::from)?;
+                    .map_err(ApiError::from)
+            })
+            .map_err(ApiError::from)?;
+
+        Ok(Response::with((status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The `/rooms/:room_id/redact/:event_id/:transaction_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct RedactEvent;
+
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct RedactEventRequest {
+    /// The reason for the redaction.
+    reason: Option<String>,
+}
+
+middleware_chain!(
+    RedactEvent,
+    [
+        JsonRequest,
+        RoomIdParam,
+        EventIdParam,
+        TransactionIdParam,
+        AccessTokenAuth
+    ],
+    [Idempotent]
+);
+
+impl Handler for RedactEvent {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a RoomId")
+            .clone();
+
+        let redacted_event_id = request
+            .extensions
+            .get::<EventIdParam>()
+            .expect("EventIdParam should ensure an EventId")
+            .clone();
+
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let reason = parse_body::<RedactEventRequest>(request)?.reason;
+
+        let config = Config::from_request(request)?;
+        let event_id = EventId::new(&config.domain).map_api_err(|_| {
+            ApiError::unknown("Failed to generated event ID for the new event.".to_string())
+        })?;
+
+        let redaction_event: NewEvent = RedactionEvent {
+            content: RedactionEventContent { reason },
+            event_id: event_id.clone(),
+            event_type: EventType::RoomRedaction,
+            origin_server_ts: 0,
+            redacts: redacted_event_id.clone(),
+            room_id: Some(room_id.clone()),
+            sender: user.id.clone(),
+            unsigned: None,
+        }
+        .try_into()?;
 
-                let serialized_response = to_string(&response).map_err(ApiError::from)?;
+        ensure_event_size(&redaction_event)?;
 
-                Transaction::create(
+        let connection = DB::from_request(request)?;
+
+        connection
+            .transaction(|| {
+                verify_permissions(
                     &connection,
-                    path.clone(),
-                    token.value.clone(),
-                    serialized_response,
-                )
+                    &room_id,
+                    &user,
+                    &EventType::RoomRedaction,
+                    false,
+                )?;
+
+                let redacted_event = match Event::find(&connection, &redacted_event_id)? {
+                    Some(ref event) if event.room_id.as_ref() == Some(&room_id) => event.clone(),
+                    _ => Err(ApiError::not_found(format!(
+                        "The event {} was not found in the room",
+                        redacted_event_id
+                    )))?,
+                };
+
+                if redacted_event.sender != user.id {
+                    let room = match Room::find(&connection, &room_id)? {
+                        Some(room) => room,
+                        None => Err(ApiError::unauthorized(
+                            "The room was not found on this server".to_string(),
+                        ))?,
+                    };
+
+                    ensure_power_level(
+                        &connection,
+                        &room,
+                        &user.id,
+                        |power_levels| power_levels.redact,
+                        "redact events of other users",
+                    )?;
+                }
+
+                diesel::insert_into(events::table)
+                    .values(&redaction_event)
+                    .execute(&*connection)
+                    .map_err(ApiError::from)?;
+
+                redacted_event.redact(&connection, &event_id)
             })
             .map_err(ApiError::from)?;
 
+        let response = EventResponse {
+            event_id: event_id.to_string(),
+        };
+
         Ok(Response::with((status::Ok, SerializableResponse(response))))
     }
 }
@@ -435,11 +539,13 @@ impl Handler for StateMessageEvent {
             }
         };
 
+        ensure_event_size(&state_event)?;
+
         let connection = DB::from_request(request)?;
 
         connection
             .transaction(|| {
-                verify_permissions(&connection, &room_id, &user, &event_type)?;
+                verify_permissions(&connection, &room_id, &user, &event_type, true)?;
 
                 diesel::insert_into(events::table)
                     .values(&state_event)
@@ -457,11 +563,15 @@ impl Handler for StateMessageEvent {
 }
 
 /// Check if a `User` has permission to create an event in a given `Room`.
+///
+/// Event types without their own power level require `state_default` for state events and
+/// `events_default` for other events.
 fn verify_permissions(
     connection: &PgConnection,
     room_id: &RoomId,
     user: &User,
     event_type: &EventType,
+    is_state_event: bool,
 ) -> Result<(), ApiError> {
     let room = match Room::find(connection, room_id)? {
         Some(room) => room,
@@ -490,10 +600,15 @@ fn verify_permissions(
         .users
         .get(&user.id)
         .unwrap_or(&power_levels.users_default);
+    let default_power_level = if is_state_event {
+        &power_levels.state_default
+    } else {
+        &power_levels.events_default
+    };
     let required_power_level = power_levels
         .events
         .get(event_type)
-        .unwrap_or(&power_levels.events_default);
+        .unwrap_or(default_power_level);
 
     if required_power_level > user_power_level {
         return Err(ApiError::unauthorized(
@@ -504,6 +619,33 @@ fn verify_permissions(
     Ok(())
 }
 
+/// Rejects events whose canonical JSON is larger than the limit set by the Matrix specification.
+fn ensure_event_size(event: &NewEvent) -> Result<(), ApiError> {
+    let mut json = json!({
+        "content": from_str::<Value>(&event.content).map_err(ApiError::from)?,
+        "event_id": event.id.to_string(),
+        "origin_server_ts": 0,
+        "room_id": event.room_id.as_ref().map(ToString::to_string),
+        "sender": event.sender.to_string(),
+        "type": event.event_type,
+    });
+
+    if let Some(ref state_key) = event.state_key {
+        json["state_key"] = Value::String(state_key.clone());
+    }
+
+    let size = canonical_json(&json)?.len();
+
+    if size > MAX_EVENT_SIZE {
+        return Err(ApiError::too_large(format!(
+            "Events must not be larger than {} bytes, but this one is {} bytes.",
+            MAX_EVENT_SIZE, size
+        )));
+    }
+
+    Ok(())
+}
+
 /// Enforces an empty state key for an event type that requires it.
 fn ensure_empty_state_key(state_key: &str, event_type: &EventType) -> Result<(), IronError> {
     if state_key == "" {
@@ -531,8 +673,58 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::test::Test;
+    use std::convert::TryFrom;
+
+    use ruma_identifiers::{EventId, RoomId, UserId};
+
+    use super::{ensure_event_size, MAX_EVENT_SIZE};
+    use crate::models::event::NewEvent;
+    use crate::query::SyncOptions;
+    use crate::test::{Response, Test};
     use iron::status::Status;
+    use serde_json::Value;
+
+    fn redact(test: &Test, access_token: &str, room_id: &str, event_id: &str) -> Response {
+        test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/redact/{}/1?access_token={}",
+                room_id, event_id, access_token
+            ),
+            r#"{"reason": "Spam"}"#,
+        )
+    }
+
+    fn send_message_event_id(test: &Test, access_token: &str, room_id: &str) -> String {
+        let response = test.send_message(access_token, room_id, "Buy cheap watches", 1);
+        assert_eq!(response.status, Status::Ok);
+
+        format!(
+            "${}:ruma.test",
+            response.json().get("event_id").unwrap().as_str().unwrap()
+        )
+    }
+
+    fn timeline_event(test: &Test, access_token: &str, room_id: &str, event_id: &str) -> Value {
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(access_token, options);
+
+        response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|event| event.get("event_id").unwrap().as_str().unwrap() == event_id)
+            .unwrap()
+            .clone()
+    }
 
     #[test]
     fn create_message_event() {
@@ -746,4 +938,217 @@ mod tests {
         let third_event_id = response.json().get("event_id").unwrap().as_str().unwrap();
         assert_ne!(third_event_id, second_event_id);
     }
+
+    #[test]
+    fn redact_own_message() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+        let event_id = send_message_event_id(&test, &alice.token, &room_id);
+
+        let response = redact(&test, &alice.token, &room_id, &event_id);
+        assert_eq!(response.status, Status::Ok);
+        let redaction_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let event = timeline_event(&test, &alice.token, &room_id, &event_id);
+        assert_eq!(
+            event.get("type").unwrap().as_str().unwrap(),
+            "m.room.message"
+        );
+        assert_eq!(
+            event.get("content").unwrap(),
+            &Value::Object(Default::default())
+        );
+
+        let redaction = timeline_event(&test, &alice.token, &room_id, redaction_id);
+        assert_eq!(
+            redaction.get("type").unwrap().as_str().unwrap(),
+            "m.room.redaction"
+        );
+        assert_eq!(
+            redaction.get("redacts").unwrap().as_str().unwrap(),
+            event_id
+        );
+        assert_eq!(
+            redaction
+                .pointer("/content/reason")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Spam"
+        );
+    }
+
+    #[test]
+    fn redact_message_of_other_user() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let event_id = send_message_event_id(&test, &bob.token, &room_id);
+
+        let response = redact(&test, &alice.token, &room_id, &event_id);
+        assert_eq!(response.status, Status::Ok);
+
+        let event = timeline_event(&test, &bob.token, &room_id, &event_id);
+        assert_eq!(
+            event.get("content").unwrap(),
+            &Value::Object(Default::default())
+        );
+    }
+
+    #[test]
+    fn redact_without_power_level() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let event_id = send_message_event_id(&test, &alice.token, &room_id);
+
+        let response = redact(&test, &bob.token, &room_id, &event_id);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Insufficient power level to redact events of other users"
+        );
+
+        let event = timeline_event(&test, &alice.token, &room_id, &event_id);
+        assert_eq!(
+            event.pointer("/content/body").unwrap().as_str().unwrap(),
+            "Buy cheap watches"
+        );
+    }
+
+    #[test]
+    fn redact_topic_and_sync_full_state() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.topic",
+            r#"{"topic": "Cheap watches"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+        let event_id = format!(
+            "${}:ruma.test",
+            response.json().get("event_id").unwrap().as_str().unwrap()
+        );
+
+        let response = redact(&test, &alice.token, &room_id, &event_id);
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: true,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&alice.token, options);
+        assert_eq!(response.status, Status::Ok);
+
+        let topic = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/state/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|event| event.get("type").unwrap().as_str().unwrap() == "m.room.topic")
+            .unwrap()
+            .clone();
+        assert_eq!(
+            topic.get("content").unwrap(),
+            &Value::Object(Default::default())
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id, alice.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn redact_unknown_event() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = redact(&test, &alice.token, &room_id, "$unknown:ruma.test");
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    fn message_event(body: &str) -> NewEvent {
+        NewEvent {
+            event_type: "m.room.message".to_string(),
+            id: EventId::try_from("$event:ruma.test").unwrap(),
+            content: format!(r#"{{"body":"{}","msgtype":"m.text"}}"#, body),
+            room_id: Some(RoomId::try_from("!room:ruma.test").unwrap()),
+            sender: UserId::try_from("@alice:ruma.test").unwrap(),
+            state_key: None,
+            redacts: None,
+        }
+    }
+
+    #[test]
+    fn event_size_limit() {
+        // The size of the event's canonical JSON without its body.
+        let overhead = r#"{"content":{"body":"","msgtype":"m.text"},"event_id":"$event:ruma.test","origin_server_ts":0,"room_id":"!room:ruma.test","sender":"@alice:ruma.test","type":"m.room.message"}"#.len();
+
+        let body = "a".repeat(MAX_EVENT_SIZE - overhead);
+        assert!(ensure_event_size(&message_event(&body)).is_ok());
+
+        let body = "a".repeat(MAX_EVENT_SIZE - overhead + 1);
+        assert!(ensure_event_size(&message_event(&body)).is_err());
+    }
+
+    #[test]
+    fn message_event_under_size_limit() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let body = "a".repeat(MAX_EVENT_SIZE - 500);
+        let response = test.send_message(&alice.token, &room_id, &body, 1);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn message_event_over_size_limit() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let body = "a".repeat(MAX_EVENT_SIZE);
+        let response = test.send_message(&alice.token, &room_id, &body, 1);
+        assert_eq!(response.status, Status::PayloadTooLarge);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_TOO_LARGE"
+        );
+    }
+
+    #[test]
+    fn state_event_over_size_limit() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/state/m.room.topic?access_token={}",
+                room_id, alice.token
+            ),
+            &format!(r#"{{"topic": "{}"}}"#, "a".repeat(MAX_EVENT_SIZE)),
+        );
+        assert_eq!(response.status, Status::PayloadTooLarge);
+    }
 }
@@ -1,17 +1,19 @@
 //! Endpoints for filter rooms.
-use bodyparser;
+use diesel::prelude::*;
 use iron::status::Status;
-use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::{Chain, Handler, IronResult, Request, Response};
 use serde_json::{from_str, to_value};
 
+use crate::config::Config;
 use crate::db::DB;
 use crate::error::ApiError;
 use crate::middleware::{
-    AccessTokenAuth, FilterIdParam, JsonRequest, MiddlewareChain, UserIdParam,
+    parse_body, AccessTokenAuth, FilterIdParam, JsonRequest, MiddlewareChain, UserIdParam,
 };
 use crate::models::filter::{ContentFilter, Filter};
 use crate::models::user::User;
 use crate::modifier::SerializableResponse;
+use crate::storage_quota;
 
 /// The GET `/user/:user_id/filter/:filter_id` endpoint.
 #[derive(Clone, Copy, Debug)]
@@ -27,6 +29,18 @@ impl Handler for GetFilter {
             .expect("UserIdParam should ensure a UserId")
             .clone();
 
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        if user_id != user.id {
+            Err(ApiError::unauthorized(
+                "The given user_id does not correspond to the authenticated user".to_string(),
+            ))?;
+        }
+
         let filter_id = *request
             .extensions
             .get::<FilterIdParam>()
@@ -72,18 +86,27 @@ impl Handler for PostFilter {
             ))?;
         }
 
-        let filter = match request.get::<bodyparser::Struct<ContentFilter>>() {
-            Ok(Some(account_password_request)) => account_password_request,
-            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
-        };
+        let filter = parse_body::<ContentFilter>(request)?;
 
+        let content = to_value(&filter).map_err(ApiError::from)?.to_string();
+
+        let config = Config::from_request(request)?;
         let connection = DB::from_request(request)?;
 
-        let id = Filter::create(
-            &connection,
-            user_id,
-            to_value(&filter).map_err(ApiError::from)?.to_string(),
-        )?;
+        let id = connection
+            .transaction::<_, ApiError, _>(|| {
+                storage_quota::lock_user(&connection, &user_id)?;
+                storage_quota::ensure_within_quota(
+                    &connection,
+                    &config,
+                    &user_id,
+                    0,
+                    content.len(),
+                )?;
+
+                Filter::create(&connection, user_id, content)
+            })
+            .map_err(ApiError::from)?;
 
         let response = PostFilterResponse {
             filter_id: id.to_string(),
@@ -146,4 +169,37 @@ mod tests {
         let response = test.get(&get_filter_path);
         assert_eq!(response.status, Status::NotFound);
     }
+
+    #[test]
+    fn filter_ids_are_per_user() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let alice = test.create_user();
+        let content = r#"{"room":{"timeline":{"limit":10}}}"#;
+
+        assert_eq!(test.create_filter(&carl.token, &carl.id, content), "0");
+        assert_eq!(test.create_filter(&carl.token, &carl.id, content), "1");
+        assert_eq!(test.create_filter(&alice.token, &alice.id, content), "0");
+    }
+
+    #[test]
+    fn get_other_users_filter() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let alice = test.create_user();
+
+        let filter_id = test.create_filter(
+            &carl.token,
+            &carl.id,
+            r#"{"room":{"timeline":{"limit":10}}}"#,
+        );
+
+        let get_filter_path = format!(
+            "/_matrix/client/r0/user/{}/filter/{}?access_token={}",
+            carl.id, filter_id, alice.token
+        );
+
+        let response = test.get(&get_filter_path);
+        assert_eq!(response.status, Status::Forbidden);
+    }
 }
@@ -1,19 +1,18 @@
 //! Endpoints for joining rooms.
 
-use std::error::Error;
-
-use bodyparser;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use iron::status::Status;
-use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::{Chain, Handler, IronResult, Request, Response};
+use ruma_events::room::power_levels::PowerLevelsEventContent;
 use ruma_identifiers::{RoomId, RoomIdOrAliasId, UserId};
 
 use crate::config::Config;
 use crate::db::DB;
 use crate::error::ApiError;
 use crate::middleware::{
-    AccessTokenAuth, JsonRequest, MiddlewareChain, RoomIdOrAliasParam, RoomIdParam,
+    parse_body, AccessTokenAuth, Idempotent, JsonRequest, MiddlewareChain, RoomIdOrAliasParam,
+    RoomIdParam,
 };
 use crate::models::room::Room;
 use crate::models::room_alias::RoomAlias;
@@ -100,6 +99,14 @@ fn join_room(
     connection: &PgConnection,
     config: &Config,
 ) -> IronResult<Response> {
+    if let Some(room_membership) = RoomMembership::find(connection, &room_id, &user.id)? {
+        if room_membership.membership == "ban" {
+            Err(ApiError::unauthorized(
+                "The user is banned from the room".to_string(),
+            ))?;
+        }
+    }
+
     let room_membership_options = RoomMembershipOptions {
         room_id: room_id.clone(),
         user_id: user.id.clone(),
@@ -174,6 +181,46 @@ impl Handler for LeaveRoom {
     }
 }
 
+/// The `/rooms/:room_id/forget` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct ForgetRoom;
+
+middleware_chain!(ForgetRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
+
+impl Handler for ForgetRoom {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let connection = DB::from_request(request)?;
+
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        match RoomMembership::find(&connection, &room_id, &user.id)? {
+            Some(mut room_membership) => match room_membership.membership.as_str() {
+                "leave" | "ban" => {
+                    room_membership.forget(&connection)?;
+                    Ok(Response::with(EmptyResponse(Status::Ok)))
+                }
+                _ => Err(ApiError::unknown(
+                    "The user must leave the room before forgetting it".to_string(),
+                )
+                .with_status(Status::BadRequest))?,
+            },
+            None => Err(ApiError::not_found(
+                "The user has never been in the room".to_string(),
+            ))?,
+        }
+    }
+}
+
 /// The `/rooms/:room_id/kick` endpoint.
 #[derive(Clone, Copy, Debug)]
 pub struct KickFromRoom;
@@ -203,11 +250,7 @@ impl Handler for KickFromRoom {
             .expect("AccessTokenAuth should ensure a user")
             .clone();
 
-        let kickee_id = match request.get::<bodyparser::Struct<KickFromRoomRequest>>() {
-            Ok(Some(req)) => req.user_id,
-            Ok(None) => Err(ApiError::bad_json(None))?,
-            Err(err) => Err(ApiError::bad_json(err.description().to_string()))?,
-        };
+        let kickee_id = parse_body::<KickFromRoomRequest>(request)?.user_id;
 
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
@@ -233,17 +276,14 @@ impl Handler for KickFromRoom {
             ))?,
         };
 
-        let power_levels = room.current_power_levels(&connection)?;
-        let user_power_level = power_levels
-            .users
-            .get(&kicker.id)
-            .unwrap_or(&power_levels.users_default);
-
-        if power_levels.kick > *user_power_level {
-            Err(ApiError::unauthorized(
-                "Insufficient power level to kick a user".to_string(),
-            ))?;
-        }
+        ensure_power_level(
+            &connection,
+            &room,
+            &kicker.id,
+            |power_levels| power_levels.kick,
+            "kick a user",
+        )?;
+        ensure_outranks(&connection, &room, &kicker.id, &kickee_id, "kick a user")?;
 
         let room_membership_options = RoomMembershipOptions {
             room_id,
@@ -258,6 +298,186 @@ impl Handler for KickFromRoom {
     }
 }
 
+/// The `/rooms/:room_id/ban` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct BanFromRoom;
+
+/// The body of the request for this API and `/rooms/:room_id/unban`.
+#[derive(Clone, Debug, Deserialize)]
+struct BanFromRoomRequest {
+    /// The reason the user has been banned or unbanned.
+    pub reason: Option<String>,
+    /// The fully qualified user ID of the user being banned or unbanned.
+    pub user_id: UserId,
+}
+
+middleware_chain!(BanFromRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
+
+impl Handler for BanFromRoom {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let (room, banner, banned_id) = moderation_request(request, "ban a user")?;
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+
+        ensure_outranks(&connection, &room, &banner.id, &banned_id, "ban a user")?;
+
+        let room_membership_options = RoomMembershipOptions {
+            room_id: room.id,
+            user_id: banned_id,
+            sender: banner.id,
+            membership: "ban".to_string(),
+        };
+
+        // Users can be banned before they ever join the room, to keep them out of it.
+        RoomMembership::upsert(&connection, &config.domain, room_membership_options)?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+/// The `/rooms/:room_id/unban` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct UnbanFromRoom;
+
+middleware_chain!(UnbanFromRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
+
+impl Handler for UnbanFromRoom {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let (room, unbanner, unbanned_id) = moderation_request(request, "unban a user")?;
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+
+        ensure_outranks(
+            &connection,
+            &room,
+            &unbanner.id,
+            &unbanned_id,
+            "unban a user",
+        )?;
+
+        let mut unbanned_membership =
+            match RoomMembership::find(&connection, &room.id, &unbanned_id)? {
+                Some(ref membership) if membership.membership == "ban" => membership.clone(),
+                _ => Err(ApiError::unauthorized(
+                    "The user is not banned from the room".to_string(),
+                ))?,
+            };
+
+        let room_membership_options = RoomMembershipOptions {
+            room_id: room.id,
+            user_id: unbanned_id,
+            sender: unbanner.id,
+            membership: "leave".to_string(),
+        };
+
+        unbanned_membership.update(&connection, &config.domain, room_membership_options)?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+/// Checks a `/rooms/:room_id/ban` or `/rooms/:room_id/unban` request, returning the room, the
+/// user making the request and the ID of the user it is about.
+fn moderation_request(
+    request: &mut Request<'_, '_>,
+    action: &str,
+) -> Result<(Room, User, UserId), ApiError> {
+    let room_id = request
+        .extensions
+        .get::<RoomIdParam>()
+        .expect("RoomIdParam should ensure a room_id")
+        .clone();
+
+    let user = request
+        .extensions
+        .get::<User>()
+        .expect("AccessTokenAuth should ensure a user")
+        .clone();
+
+    let target_id = parse_body::<BanFromRoomRequest>(request)?.user_id;
+
+    let connection = DB::from_request(request)?;
+
+    let room = match Room::find(&connection, &room_id)? {
+        Some(room) => room,
+        None => Err(ApiError::unauthorized(
+            "The room was not found on this server".to_string(),
+        ))?,
+    };
+
+    match RoomMembership::find(&connection, &room_id, &user.id)? {
+        Some(ref membership) if membership.membership == "join" => {}
+        _ => Err(ApiError::unauthorized(
+            "The user is not currently in the room".to_string(),
+        ))?,
+    };
+
+    ensure_power_level(
+        &connection,
+        &room,
+        &user.id,
+        |power_levels| power_levels.ban,
+        action,
+    )?;
+
+    Ok((room, user, target_id))
+}
+
+/// Returns an error unless the user's power level in the room is at least the one required for
+/// the given action.
+pub fn ensure_power_level<F>(
+    connection: &PgConnection,
+    room: &Room,
+    user_id: &UserId,
+    required_power_level: F,
+    action: &str,
+) -> Result<(), ApiError>
+where
+    F: FnOnce(&PowerLevelsEventContent) -> u64,
+{
+    let power_levels = room.current_power_levels(connection)?;
+
+    if required_power_level(&power_levels) > user_power_level(&power_levels, user_id) {
+        Err(ApiError::unauthorized(format!(
+            "Insufficient power level to {}",
+            action
+        )))?;
+    }
+
+    Ok(())
+}
+
+/// Returns an error unless the user's power level in the room is higher than the target's, as
+/// required to kick, ban or unban them.
+fn ensure_outranks(
+    connection: &PgConnection,
+    room: &Room,
+    user_id: &UserId,
+    target_id: &UserId,
+    action: &str,
+) -> Result<(), ApiError> {
+    let power_levels = room.current_power_levels(connection)?;
+
+    if user_power_level(&power_levels, target_id) >= user_power_level(&power_levels, user_id) {
+        Err(ApiError::unauthorized(format!(
+            "Insufficient power level to {} with the same or a higher power level",
+            action
+        )))?;
+    }
+
+    Ok(())
+}
+
+/// The power level of a user according to the given power levels.
+fn user_power_level(power_levels: &PowerLevelsEventContent, user_id: &UserId) -> u64 {
+    *power_levels
+        .users
+        .get(user_id)
+        .unwrap_or(&power_levels.users_default)
+}
+
 /// The `/rooms/:room_id/invite` endpoint.
 #[derive(Clone, Copy, Debug)]
 pub struct InviteToRoom;
@@ -269,7 +489,11 @@ struct InviteToRoomRequest {
     pub user_id: UserId,
 }
 
-middleware_chain!(InviteToRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
+middleware_chain!(
+    InviteToRoom,
+    [JsonRequest, RoomIdParam, AccessTokenAuth],
+    [Idempotent]
+);
 
 impl Handler for InviteToRoom {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
@@ -285,11 +509,7 @@ impl Handler for InviteToRoom {
             .expect("AccessTokenAuth should ensure a user")
             .clone();
 
-        let invitee_id = match request.get::<bodyparser::Struct<InviteToRoomRequest>>() {
-            Ok(Some(req)) => req.user_id,
-            Ok(None) => Err(ApiError::missing_param("user_id"))?,
-            Err(err) => Err(ApiError::bad_json(err.description().to_string()))?,
-        };
+        let invitee_id = parse_body::<InviteToRoomRequest>(request)?.user_id;
 
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
@@ -303,11 +523,14 @@ impl Handler for InviteToRoom {
                     )));
                 }
 
-                if Room::find(&connection, &room_id)?.is_none() {
-                    return Err(ApiError::unauthorized(
-                        "The room was not found on this server".to_string(),
-                    ));
-                }
+                let room = match Room::find(&connection, &room_id)? {
+                    Some(room) => room,
+                    None => {
+                        return Err(ApiError::unauthorized(
+                            "The room was not found on this server".to_string(),
+                        ));
+                    }
+                };
 
                 let unauthorized_err =
                     ApiError::unauthorized("The inviter hasn't joined the room yet".to_string());
@@ -323,6 +546,14 @@ impl Handler for InviteToRoom {
                     },
                 )?;
 
+                ensure_power_level(
+                    &connection,
+                    &room,
+                    &inviter.id,
+                    |power_levels| power_levels.invite,
+                    "invite",
+                )?;
+
                 let membership = RoomMembership::find(&connection, &room_id, &invitee_id)?;
 
                 Ok(membership)
@@ -359,8 +590,10 @@ impl Handler for InviteToRoom {
 
 #[cfg(test)]
 mod tests {
+    use crate::query::SyncOptions;
     use crate::test::Test;
     use iron::status::Status;
+    use serde_json::from_str;
 
     #[test]
     fn join_own_public_room_via_join_endpoint() {
@@ -741,6 +974,88 @@ mod tests {
         );
     }
 
+    #[test]
+    fn forget_left_room() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "private"}"#);
+
+        assert_eq!(test.leave_room(&alice.token, &room_id).status, Status::Ok);
+
+        let forget_room_path = format!(
+            "/_matrix/client/r0/rooms/{}/forget?access_token={}",
+            room_id, alice.token
+        );
+
+        let response = test.post(&forget_room_path, r#"{}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: Some(from_str(r#"{"room":{"include_leave":true}}"#).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&alice.token, options);
+        assert_eq!(response.status, Status::Ok);
+        assert!(response
+            .json()
+            .pointer(&format!("/rooms/leave/{}", room_id))
+            .is_none());
+    }
+
+    #[test]
+    fn forget_joined_room() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "private"}"#);
+
+        let forget_room_path = format!(
+            "/_matrix/client/r0/rooms/{}/forget?access_token={}",
+            room_id, alice.token
+        );
+
+        let response = test.post(&forget_room_path, r#"{}"#);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNKNOWN"
+        );
+    }
+
+    #[test]
+    fn rejoin_forgotten_room() {
+        let test = Test::new();
+        let (_, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+
+        let forget_room_path = format!(
+            "/_matrix/client/r0/rooms/{}/forget?access_token={}",
+            room_id, bob.token
+        );
+        assert_eq!(test.post(&forget_room_path, r#"{}"#).status, Status::Ok);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: Some(from_str(r#"{"room":{"include_leave":true}}"#).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&bob.token, options);
+        assert!(response
+            .json()
+            .pointer(&format!("/rooms/leave/{}", room_id))
+            .is_some());
+    }
+
     #[test]
     fn kick_user() {
         let test = Test::new();
@@ -772,6 +1087,32 @@ mod tests {
         assert_eq!(response.status, Status::Forbidden);
     }
 
+    #[test]
+    fn kick_sets_membership_to_leave() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_options = format!(r#"{{"invite": ["{}"]}}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &room_options);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.kick_from_room(&alice.token, &room_id, &bob.id, Some("Spam"));
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/members?access_token={}&membership=leave",
+            room_id, alice.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let chunk = response.json().get("chunk").unwrap().as_array().unwrap();
+        assert_eq!(chunk.len(), 1);
+        assert_eq!(chunk[0].get("state_key").unwrap().as_str().unwrap(), bob.id);
+        assert_eq!(chunk[0].get("sender").unwrap().as_str().unwrap(), alice.id);
+    }
+
     #[test]
     fn kick_user_without_permissions() {
         let test = Test::new();
@@ -858,4 +1199,290 @@ mod tests {
             "The kickee is not currently in the room"
         );
     }
+
+    #[test]
+    fn ban_member() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.ban_from_room(&alice.token, &room_id, &bob.id);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.send_message(&bob.token, &room_id, "Hi", 1);
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/members?access_token={}&membership=ban",
+            room_id, alice.token
+        ));
+        let chunk = response.json().get("chunk").unwrap().as_array().unwrap();
+        assert_eq!(chunk.len(), 1);
+        assert_eq!(chunk[0].get("state_key").unwrap().as_str().unwrap(), bob.id);
+    }
+
+    #[test]
+    fn banned_user_cannot_join() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        // Users can be banned before they have ever joined.
+        let response = test.ban_from_room(&alice.token, &room_id, &bob.id);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.join_room(&bob.token, &room_id);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/join/{}?access_token={}",
+                room_id, bob.token
+            ),
+            "{}",
+        );
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn unban_restores_joinability() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(
+            test.ban_from_room(&alice.token, &room_id, &bob.id).status,
+            Status::Ok
+        );
+        assert_eq!(
+            test.join_room(&bob.token, &room_id).status,
+            Status::Forbidden
+        );
+
+        let response = test.unban_from_room(&alice.token, &room_id, &bob.id);
+        assert_eq!(response.status, Status::Ok);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+    }
+
+    #[test]
+    fn unban_user_who_is_not_banned() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.unban_from_room(&alice.token, &room_id, &bob.id);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "The user is not banned from the room"
+        );
+    }
+
+    #[test]
+    fn ban_user_without_permissions() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.ban_from_room(&bob.token, &room_id, &alice.id);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Insufficient power level to ban a user"
+        );
+    }
+
+    fn set_power_levels(test: &Test, access_token: &str, room_id: &str, users: &str) -> Status {
+        let content = format!(
+            r#"{{
+            "ban": 50,
+            "events": {{ "m.room.power_levels": 100 }},
+            "events_default": 0,
+            "invite": 50,
+            "kick": 50,
+            "redact": 50,
+            "state_default": 0,
+            "users": {{ {} }},
+            "users_default": 0
+        }}"#,
+            users
+        );
+
+        test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/state/m.room.power_levels?access_token={}",
+                room_id, access_token
+            ),
+            &content,
+        )
+        .status
+    }
+
+    #[test]
+    fn raised_power_level_allows_kick() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let carl = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.join_room(&carl.token, &room_id).status, Status::Ok);
+
+        let response = test.kick_from_room(&bob.token, &room_id, &carl.id, None);
+        assert_eq!(response.status, Status::Forbidden);
+
+        let users = format!(r#""{}": 100, "{}": 50"#, alice.id, bob.id);
+        assert_eq!(
+            set_power_levels(&test, &alice.token, &room_id, &users),
+            Status::Ok
+        );
+
+        let response = test.kick_from_room(&bob.token, &room_id, &carl.id, None);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn kick_requires_a_higher_power_level_than_the_kickee() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let carl = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.join_room(&carl.token, &room_id).status, Status::Ok);
+
+        let users = format!(
+            r#""{}": 100, "{}": 50, "{}": 50"#,
+            alice.id, bob.id, carl.id
+        );
+        assert_eq!(
+            set_power_levels(&test, &alice.token, &room_id, &users),
+            Status::Ok
+        );
+
+        let response = test.kick_from_room(&bob.token, &room_id, &carl.id, None);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Insufficient power level to kick a user with the same or a higher power level"
+        );
+
+        let response = test.kick_from_room(&bob.token, &room_id, &alice.id, None);
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.kick_from_room(&alice.token, &room_id, &carl.id, None);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn ban_requires_a_higher_power_level_than_the_banned_user() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let carl = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let users = format!(
+            r#""{}": 100, "{}": 50, "{}": 50"#,
+            alice.id, bob.id, carl.id
+        );
+        assert_eq!(
+            set_power_levels(&test, &alice.token, &room_id, &users),
+            Status::Ok
+        );
+
+        // The power levels of users who haven't joined the room count too.
+        let response = test.ban_from_room(&bob.token, &room_id, &carl.id);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Insufficient power level to ban a user with the same or a higher power level"
+        );
+
+        let response = test.ban_from_room(&bob.token, &room_id, &alice.id);
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.ban_from_room(&alice.token, &room_id, &carl.id);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn unban_requires_a_higher_power_level_than_the_banned_user() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let carl = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let users = format!(
+            r#""{}": 100, "{}": 50, "{}": 50"#,
+            alice.id, bob.id, carl.id
+        );
+        assert_eq!(
+            set_power_levels(&test, &alice.token, &room_id, &users),
+            Status::Ok
+        );
+        assert_eq!(
+            test.ban_from_room(&alice.token, &room_id, &carl.id).status,
+            Status::Ok
+        );
+
+        let response = test.unban_from_room(&bob.token, &room_id, &carl.id);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Insufficient power level to unban a user with the same or a higher power level"
+        );
+
+        let response = test.unban_from_room(&alice.token, &room_id, &carl.id);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn member_cannot_change_power_levels() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let users = format!(r#""{}": 100, "{}": 100"#, alice.id, bob.id);
+        assert_eq!(
+            set_power_levels(&test, &bob.token, &room_id, &users),
+            Status::Forbidden
+        );
+    }
+
+    #[test]
+    fn reinvite_requires_power_level() {
+        let test = Test::new();
+        let (_, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let carl = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.join_room(&carl.token, &room_id).status, Status::Ok);
+        assert_eq!(test.leave_room(&carl.token, &room_id).status, Status::Ok);
+
+        let response = test.invite(&bob.token, &room_id, &carl.id);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Insufficient power level to invite"
+        );
+    }
 }
@@ -0,0 +1,399 @@
+//! Endpoints for end-to-end encryption keys.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Request, Response};
+use ruma_identifiers::UserId;
+use serde_json::{from_str, Map, Value};
+
+use crate::config::Config;
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{parse_body, AccessTokenAuth, JsonRequest, MiddlewareChain};
+use crate::models::access_token::AccessToken;
+use crate::models::one_time_key::{NewOneTimeKey, OneTimeKey};
+use crate::modifier::SerializableResponse;
+
+/// The `/keys/claim` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct ClaimKeys;
+
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct ClaimKeysRequest {
+    /// The algorithm of the key to claim for each device, keyed by user ID and device ID.
+    one_time_keys: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct ClaimKeysResponse {
+    /// Errors for the remote servers that couldn't be asked for keys, keyed by server name.
+    failures: BTreeMap<String, Value>,
+    /// The claimed keys, keyed by user ID, device ID and `<algorithm>:<key_id>`.
+    one_time_keys: BTreeMap<String, BTreeMap<String, Map<String, Value>>>,
+}
+
+middleware_chain!(ClaimKeys, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for ClaimKeys {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let claim_keys_request = parse_body::<ClaimKeysRequest>(request)?;
+
+        let config = Config::from_request(request)?;
+
+        let devices: usize = claim_keys_request
+            .one_time_keys
+            .values()
+            .map(BTreeMap::len)
+            .sum();
+        if config.max_key_batch_size != 0 && devices > config.max_key_batch_size {
+            Err(ApiError::invalid_param(
+                "one_time_keys",
+                &format!(
+                    "Keys can be claimed for at most {} devices at once",
+                    config.max_key_batch_size
+                ),
+            ))?;
+        }
+
+        let connection = DB::from_request(request)?;
+
+        let mut response = ClaimKeysResponse {
+            failures: BTreeMap::new(),
+            one_time_keys: BTreeMap::new(),
+        };
+
+        for (user_id, devices) in claim_keys_request.one_time_keys {
+            let user_id = UserId::try_from(user_id.as_ref())
+                .map_err(|_| ApiError::invalid_param("one_time_keys", "Invalid user ID"))?;
+
+            // Keys of remote users would have to be claimed over federation.
+            if user_id.hostname().to_string() != config.domain {
+                response
+                    .failures
+                    .insert(user_id.hostname().to_string(), Value::Object(Map::new()));
+                continue;
+            }
+
+            for (device_id, algorithm) in devices {
+                let one_time_key =
+                    match OneTimeKey::claim(&connection, &user_id, &device_id, &algorithm)? {
+                        Some(one_time_key) => one_time_key,
+                        None => continue,
+                    };
+
+                let key: Value = from_str(&one_time_key.key).map_err(ApiError::from)?;
+
+                response
+                    .one_time_keys
+                    .entry(user_id.to_string())
+                    .or_default()
+                    .entry(device_id)
+                    .or_default()
+                    .insert(
+                        format!("{}:{}", one_time_key.algorithm, one_time_key.key_id),
+                        key,
+                    );
+            }
+        }
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The `/keys/upload` endpoint.
+///
+/// Only one-time keys are stored. Device keys sent along with them are ignored.
+#[derive(Clone, Copy, Debug)]
+pub struct UploadKeys;
+
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct UploadKeysRequest {
+    /// The one-time keys of the device, keyed by `<algorithm>:<key_id>`.
+    #[serde(default)]
+    one_time_keys: BTreeMap<String, Value>,
+}
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct UploadKeysResponse {
+    /// The number of one-time keys the device has left, keyed by algorithm.
+    one_time_key_counts: BTreeMap<String, u64>,
+}
+
+middleware_chain!(UploadKeys, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for UploadKeys {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let upload_keys_request = parse_body::<UploadKeysRequest>(request)?;
+
+        let access_token = request
+            .extensions
+            .get::<AccessToken>()
+            .expect("AccessTokenAuth should ensure an access token")
+            .clone();
+
+        let mut new_one_time_keys = Vec::new();
+
+        for (algorithm_and_key_id, key) in upload_keys_request.one_time_keys {
+            let mut parts = algorithm_and_key_id.splitn(2, ':');
+
+            let (algorithm, key_id) = match (parts.next(), parts.next()) {
+                (Some(algorithm), Some(key_id)) if !algorithm.is_empty() && !key_id.is_empty() => {
+                    (algorithm.to_string(), key_id.to_string())
+                }
+                _ => Err(ApiError::invalid_param(
+                    "one_time_keys",
+                    "Keys must be named <algorithm>:<key_id>",
+                ))?,
+            };
+
+            new_one_time_keys.push(NewOneTimeKey {
+                user_id: access_token.user_id.clone(),
+                device_id: access_token.device_id.clone(),
+                algorithm,
+                key_id,
+                key: key.to_string(),
+            });
+        }
+
+        let connection = DB::from_request(request)?;
+
+        OneTimeKey::create(&connection, &new_one_time_keys)?;
+
+        let response = UploadKeysResponse {
+            one_time_key_counts: OneTimeKey::count_by_device(
+                &connection,
+                &access_token.user_id,
+                &access_token.device_id,
+            )?,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::json;
+
+    use crate::test::{Response, Test};
+
+    #[test]
+    fn claim_one_time_key() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        test.add_one_time_key(&bob.id, "BOBPHONE", "signed_curve25519", "AAAAHg");
+
+        let claim_path = format!("/_matrix/client/r0/keys/claim?access_token={}", alice.token);
+        let body = format!(
+            r#"{{"one_time_keys": {{"{}": {{"BOBPHONE": "signed_curve25519"}}}}}}"#,
+            bob.id
+        );
+
+        let response = test.post(&claim_path, &body);
+        assert_eq!(response.status, Status::Ok);
+
+        let key = response
+            .json()
+            .pointer(&format!(
+                "/one_time_keys/{}/BOBPHONE/signed_curve25519:AAAAHg",
+                bob.id
+            ))
+            .unwrap()
+            .clone();
+        assert_eq!(key.get("key").unwrap().as_str().unwrap(), "AAAAHg-key");
+
+        let response = test.post(&claim_path, &body);
+        assert_eq!(response.status, Status::Ok);
+        assert!(response
+            .json()
+            .get("one_time_keys")
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn claim_keys_one_at_a_time() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        test.add_one_time_key(&alice.id, "ALICEPHONE", "signed_curve25519", "AAAAAA");
+        test.add_one_time_key(&alice.id, "ALICEPHONE", "signed_curve25519", "AAAAAB");
+
+        let claim_path = format!("/_matrix/client/r0/keys/claim?access_token={}", alice.token);
+        let body = format!(
+            r#"{{"one_time_keys": {{"{}": {{"ALICEPHONE": "signed_curve25519"}}}}}}"#,
+            alice.id
+        );
+        let claimed_key = |response: &Response| {
+            response
+                .json()
+                .pointer(&format!("/one_time_keys/{}/ALICEPHONE", alice.id))
+                .and_then(|keys| keys.as_object())
+                .map(|keys| keys.keys().cloned().collect::<Vec<String>>())
+        };
+
+        let first = claimed_key(&test.post(&claim_path, &body)).unwrap();
+        let second = claimed_key(&test.post(&claim_path, &body)).unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(second.len(), 1);
+        assert_ne!(first, second);
+
+        assert!(claimed_key(&test.post(&claim_path, &body)).is_none());
+    }
+
+    #[test]
+    fn claim_keys_of_remote_user() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/keys/claim?access_token={}", alice.token),
+            r#"{"one_time_keys": {"@carl:example.com": {"CARLPHONE": "signed_curve25519"}}}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().pointer("/failures/example.com").is_some());
+    }
+
+    #[test]
+    fn upload_one_time_keys_and_claim_them() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob_token = test
+            .register_user(r#"{"username": "bob", "password": "secret", "device_id": "BOBPHONE"}"#)
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let upload_path = format!("/_matrix/client/r0/keys/upload?access_token={}", bob_token);
+        let response = test.post(
+            &upload_path,
+            r#"{
+                "device_keys": {"algorithms": ["m.olm.v1.curve25519-aes-sha2"]},
+                "one_time_keys": {
+                    "curve25519:AAAAAQ": "Cr2aNXMZ",
+                    "signed_curve25519:AAAAHg": {"key": "zKbLg+NrIj", "signatures": {}},
+                    "signed_curve25519:AAAAHQ": {"key": "j3fR3HemM1", "signatures": {}}
+                }
+            }"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("one_time_key_counts").unwrap(),
+            &json!({"curve25519": 1, "signed_curve25519": 2})
+        );
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/keys/claim?access_token={}", alice.token),
+            r#"{"one_time_keys": {"@bob:ruma.test": {"BOBPHONE": "curve25519"}}}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .pointer("/one_time_keys/@bob:ruma.test/BOBPHONE/curve25519:AAAAAQ")
+                .unwrap(),
+            "Cr2aNXMZ"
+        );
+
+        // Uploading no keys reports the ones left.
+        let response = test.post(&upload_path, "{}");
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("one_time_key_counts").unwrap(),
+            &json!({"signed_curve25519": 2})
+        );
+    }
+
+    #[test]
+    fn upload_already_uploaded_one_time_key() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let upload_path = format!(
+            "/_matrix/client/r0/keys/upload?access_token={}",
+            alice.token
+        );
+        let body = r#"{"one_time_keys": {"curve25519:AAAAAQ": "Cr2aNXMZ"}}"#;
+
+        assert_eq!(test.post(&upload_path, body).status, Status::Ok);
+        let response = test.post(&upload_path, body);
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("one_time_key_counts").unwrap(),
+            &json!({"curve25519": 1})
+        );
+    }
+
+    #[test]
+    fn upload_one_time_key_without_algorithm() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/keys/upload?access_token={}",
+                alice.token
+            ),
+            r#"{"one_time_keys": {"AAAAAQ": "Cr2aNXMZ"}}"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn claim_keys_within_batch_size() {
+        let test = Test::with_config(|config| config.max_key_batch_size = 2);
+        let alice = test.create_user();
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/keys/claim?access_token={}", alice.token),
+            &format!(
+                r#"{{"one_time_keys": {{"{}": {{"PHONE": "signed_curve25519", "LAPTOP": "signed_curve25519"}}}}}}"#,
+                alice.id
+            ),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn claim_keys_beyond_batch_size() {
+        let test = Test::with_config(|config| config.max_key_batch_size = 2);
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/keys/claim?access_token={}", alice.token),
+            &format!(
+                r#"{{"one_time_keys": {{"{}": {{"PHONE": "signed_curve25519", "LAPTOP": "signed_curve25519"}}, "{}": {{"PHONE": "signed_curve25519"}}}}}}"#,
+                alice.id, bob.id
+            ),
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_PARAM"
+        );
+    }
+}
@@ -1,31 +1,62 @@
 //! Endpoints for logging in users.
 
 use std::convert::TryFrom;
-use std::error::Error;
 use std::fmt::{Formatter, Result as FmtResult};
 
-use bodyparser;
-use iron::{status, Chain, Handler, IronResult, Plugin, Request, Response};
+use diesel::pg::PgConnection;
+use iron::{status, Chain, Handler, IronResult, Request, Response};
 use ruma_identifiers::UserId;
 use serde::de::{Deserialize, Deserializer, Error as SerdeError, Visitor};
 
 use crate::authentication::{AuthParams, PasswordAuthParams};
 use crate::config::Config;
+use crate::crypto::generate_device_id;
 use crate::db::DB;
-use crate::error::ApiError;
-use crate::middleware::{JsonRequest, MiddlewareChain};
+use crate::email::is_valid_email;
+use crate::error::{ApiError, ApiErrorCode};
+use crate::hash_limiter::HashLimiter;
+use crate::login_lockout::LoginLockouts;
+use crate::metrics::Metrics;
+use crate::middleware::{parse_body, JsonRequest, MiddlewareChain, RateLimited};
 use crate::models::access_token::AccessToken;
+use crate::models::audit_log::{AuditEvent, AuditLog};
+use crate::models::three_pid::ThreePid;
+use crate::models::user::User;
 use crate::modifier::SerializableResponse;
+use crate::rate_limiter::RateLimitedAction;
 
-/// The `/login` endpoint.
+/// The POST `/login` endpoint.
 #[derive(Clone, Copy, Debug)]
 pub struct Login;
 
+/// The GET `/login` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetLoginTypes;
+
+/// The body of the response for the GET endpoint.
+#[derive(Debug, Serialize)]
+struct LoginTypesResponse {
+    /// The login types enabled on this server.
+    flows: Vec<LoginFlow>,
+}
+
+/// A login type that can be used with the POST endpoint.
+#[derive(Debug, Serialize)]
+struct LoginFlow {
+    /// The login type, e.g. "m.login.password".
+    #[serde(rename = "type")]
+    login_type: String,
+}
+
 /// The login type specified by the user.
 #[derive(Clone, Debug, PartialEq)]
 enum LoginType {
     /// The m.login.password type.
     Password,
+    /// The m.login.dummy type, which is only meaningful as a stage of interactive authentication.
+    Dummy,
+    /// Any other login type, which this homeserver doesn't support.
+    Unsupported(String),
 }
 
 impl<'de> Deserialize<'de> for LoginType {
@@ -49,9 +80,8 @@ impl<'de> Deserialize<'de> for LoginType {
             {
                 match value {
                     "m.login.password" => Ok(LoginType::Password),
-                    _ => Err(SerdeError::custom(
-                        "Currenlty only m.login.password is supported",
-                    )),
+                    "m.login.dummy" => Ok(LoginType::Dummy),
+                    _ => Ok(LoginType::Unsupported(value.to_string())),
                 }
             }
         }
@@ -61,15 +91,113 @@ impl<'de> Deserialize<'de> for LoginType {
 }
 
 /// The body of the request for this API.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug)]
 struct LoginRequest {
-    /// The login type being used. Currently only "m.login.password" is supported.
-    #[serde(rename = "type")]
+    /// The login type being used. Currently only "m.login.password" can be used to log in.
     pub login_type: LoginType,
-    /// The fully qualified user ID or just local part of the user ID, to log in.
-    pub user: String,
-    /// The user's password.
-    pub password: String,
+    /// Identification information for the user, taken from the legacy `user` field if the request
+    /// has no `identifier`. `None` if the request has neither.
+    pub identifier: Option<UserIdentifier>,
+    /// The user's password. Required for "m.login.password".
+    pub password: Option<String>,
+    /// The ID of the client device. If omitted, the homeserver generates one.
+    pub device_id: Option<String>,
+}
+
+/// The body of the request for this API, as sent by the client.
+#[derive(Deserialize)]
+struct RawLoginRequest {
+    /// See the similarly named field on `LoginRequest`.
+    #[serde(rename = "type")]
+    login_type: LoginType,
+    /// Identification information for the user.
+    identifier: Option<UserIdentifier>,
+    /// The fully qualified user ID or just local part of the user ID, to log in. Deprecated in
+    /// favour of `identifier`.
+    user: Option<String>,
+    /// See the similarly named field on `LoginRequest`.
+    password: Option<String>,
+    /// See the similarly named field on `LoginRequest`.
+    device_id: Option<String>,
+}
+
+impl<'de> Deserialize<'de> for LoginRequest {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let RawLoginRequest {
+            login_type,
+            identifier,
+            user,
+            password,
+            device_id,
+        } = RawLoginRequest::deserialize(deserializer)?;
+
+        Ok(Self {
+            login_type,
+            identifier: identifier.or_else(|| user.map(|user| UserIdentifier::User { user })),
+            password,
+            device_id,
+        })
+    }
+}
+
+/// Identification information for the user logging in.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type")]
+enum UserIdentifier {
+    /// A Matrix user ID.
+    #[serde(rename = "m.id.user")]
+    User {
+        /// The fully qualified user ID or just local part of the user ID.
+        user: String,
+    },
+    /// A third party identifier bound to the user.
+    #[serde(rename = "m.id.thirdparty")]
+    ThirdParty {
+        /// The medium of the identifier, e.g. "email".
+        medium: String,
+        /// The identifier itself, e.g. an email address.
+        address: String,
+    },
+}
+
+impl UserIdentifier {
+    /// Find the ID of the user this identifies, or `None` if no such user can exist.
+    fn resolve(
+        self,
+        connection: &PgConnection,
+        config: &Config,
+    ) -> Result<Option<UserId>, ApiError> {
+        match self {
+            UserIdentifier::User { user } => {
+                let user_id = if let Ok(user_id) = UserId::try_from(user.as_ref()) {
+                    if user_id.hostname().to_string() != config.domain {
+                        return Err(ApiError::unauthorized(
+                            "User cannot be identified by this homeserver".to_string(),
+                        ));
+                    }
+
+                    user_id
+                } else {
+                    User::local_id(&user, "user", config)?
+                };
+
+                Ok(Some(user_id))
+            }
+            UserIdentifier::ThirdParty { medium, address } => {
+                if medium == "email" && !is_valid_email(&address) {
+                    return Err(ApiError::invalid_param(
+                        "address",
+                        "Not a valid email address",
+                    ));
+                }
+
+                ThreePid::find_user_id(connection, &medium, &address)
+            }
+        }
+    }
 }
 
 /// The body of the response for this API.
@@ -77,60 +205,163 @@ struct LoginRequest {
 struct LoginResponse {
     /// An access token for the account. This access token can then be used to authorize other requests.
     pub access_token: String,
+    /// The ID of the device the access token was issued to.
+    pub device_id: String,
     /// The hostname of the homeserver on which the account has been registered.
     pub home_server: String,
     /// The fully-qualified Matrix ID that has been registered.
     pub user_id: UserId,
+    /// Information the client should use to reach the homeserver from now on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub well_known: Option<DiscoveryInformation>,
+}
+
+/// Information clients use to discover the homeserver, as in `/.well-known/matrix/client`.
+#[derive(Debug, Serialize)]
+pub struct DiscoveryInformation {
+    /// Information about the homeserver's client API.
+    #[serde(rename = "m.homeserver")]
+    homeserver: HomeserverInformation,
+}
+
+/// Information about the homeserver's client API.
+#[derive(Debug, Serialize)]
+struct HomeserverInformation {
+    /// The base URL of the client API.
+    base_url: String,
+}
+
+impl DiscoveryInformation {
+    /// Returns the discovery information for the configured `public_base_url`, if there is one.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        config
+            .public_base_url
+            .as_ref()
+            .map(|base_url| DiscoveryInformation {
+                homeserver: HomeserverInformation {
+                    base_url: base_url.clone(),
+                },
+            })
+    }
+}
+
+middleware_chain!(GetLoginTypes, []);
+
+impl Handler for GetLoginTypes {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let config = Config::from_request(request)?;
+
+        let flows = config
+            .login_types
+            .iter()
+            .map(|login_type| LoginFlow {
+                login_type: login_type.clone(),
+            })
+            .collect();
+
+        let response = LoginTypesResponse { flows };
+
+        Ok(Response::with((status::Ok, SerializableResponse(response))))
+    }
 }
 
-middleware_chain!(Login, [JsonRequest]);
+middleware_chain!(Login, [RateLimited(RateLimitedAction::Login), JsonRequest]);
 
 impl Handler for Login {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let login_request = match request.get::<bodyparser::Struct<LoginRequest>>() {
-            Ok(Some(request)) => request,
-            Ok(None) => Err(ApiError::bad_json(None))?,
-            Err(err) => Err(ApiError::bad_json(err.description().to_string()))?,
-        };
+        let login_request = parse_body::<LoginRequest>(request)?;
 
         let config = Config::from_request(request)?;
 
-        let user_id = match UserId::try_from(login_request.user.as_ref()) {
-            Ok(user_id) => {
-                if user_id.hostname().to_string() != config.domain {
+        let password = match login_request.login_type {
+            LoginType::Password => {
+                if !config.login_types.iter().any(|login_type| login_type == "m.login.password") {
                     Err(ApiError::unauthorized(
-                        "User cannot be identified by this homeserver".to_string(),
+                        "m.login.password is disabled on this server".to_string(),
                     ))?;
                 }
 
-                user_id
+                login_request
+                    .password
+                    .ok_or_else(|| ApiError::missing_param("password"))?
             }
-            Err(_) => {
-                UserId::try_from(format!("@{}:{}", login_request.user, &config.domain).as_ref())
-                    .map_err(ApiError::from)?
+            LoginType::Dummy => Err(ApiError::unknown(
+                "m.login.dummy can only be used as a stage of interactive authentication, not to log in"
+                    .to_string(),
+            )
+            .with_status(status::BadRequest))?,
+            LoginType::Unsupported(login_type) => Err(ApiError::unrecognized(format!(
+                "Unsupported login type {}, currently only m.login.password is supported",
+                login_type
+            ))
+            .with_status(status::BadRequest))?,
+        };
+
+        let connection = DB::from_request(request)?;
+        let metrics = Metrics::from_request(request)?;
+        let hash_limiter = HashLimiter::from_request(request)?;
+        let login_lockouts = LoginLockouts::from_request(request)?;
+
+        let identifier = login_request.identifier.ok_or_else(|| {
+            ApiError::invalid_param("identifier", "Either identifier or user must be given")
+        })?;
+
+        let user_id = identifier.resolve(&connection, &config)?;
+
+        let authentication = match user_id.clone() {
+            Some(user_id) => {
+                login_lockouts.ensure_unlocked(&user_id)?;
+
+                let _permit = hash_limiter.acquire()?;
+
+                let authentication = AuthParams::Password(PasswordAuthParams {
+                    password,
+                    user_id: user_id.clone(),
+                })
+                .authenticate(&connection);
+
+                match (&authentication, config.login_lockout) {
+                    (Ok(_), _) => login_lockouts.reset(&user_id)?,
+                    (Err(_), Some(lockout)) => login_lockouts.record_failure(&user_id, &lockout)?,
+                    (Err(_), None) => {}
+                }
+
+                authentication
             }
+            None => Err(ApiError::unauthorized("Invalid credentials".to_string())),
         };
 
-        let auth_params = AuthParams::Password(PasswordAuthParams {
-            password: login_request.password,
-            user_id,
-        });
+        metrics.record_login(authentication.is_ok());
 
-        let connection = DB::from_request(request)?;
-        let registered_user = auth_params
-            .authenticate(&connection)
-            .map_err(|_| ApiError::unauthorized("Invalid credentials".to_string()))?;
+        let audit_event = if authentication.is_ok() {
+            AuditEvent::Login
+        } else {
+            AuditEvent::FailedLogin
+        };
+        AuditLog::record(&connection, &config, request, user_id.as_ref(), audit_event)?;
+
+        let registered_user = authentication.map_err(|error| {
+            if error.errcode() == ApiErrorCode::UserDeactivated {
+                error
+            } else {
+                ApiError::unauthorized("Invalid credentials".to_string())
+            }
+        })?;
+
+        let device_id = match login_request.device_id {
+            Some(device_id) => device_id,
+            None => generate_device_id()?,
+        };
 
-        let access_token = AccessToken::create(
-            &connection,
-            &registered_user.id,
-            &config.macaroon_secret_key,
-        )?;
+        let access_token =
+            AccessToken::create(&connection, &registered_user.id, &device_id, &config)?;
 
         let response = LoginResponse {
             access_token: access_token.value,
+            device_id: access_token.device_id,
             home_server: config.domain.clone(),
             user_id: registered_user.id,
+            well_known: DiscoveryInformation::from_config(&config),
         };
 
         Ok(Response::with((status::Ok, SerializableResponse(response))))
@@ -139,8 +370,19 @@ impl Handler for Login {
 
 #[cfg(test)]
 mod tests {
+    use std::convert::TryFrom;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::config::{LoginLockout, TokenFormat};
+    use crate::models::access_token::AccessToken;
+    use crate::models::audit_log::AuditLog;
     use crate::test::Test;
+    use base64::decode;
     use iron::status::Status;
+    use macaroons::token::Token;
+    use macaroons::v1::V1Token;
+    use ruma_identifiers::UserId;
 
     #[test]
     fn valid_credentials() {
@@ -199,7 +441,87 @@ mod tests {
             r#"{"type": "m.login.email", "user": "carl", "password": "secret"}"#,
         );
 
-        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNRECOGNIZED"
+        );
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Unsupported login type m.login.email, currently only m.login.password is supported"
+        );
+    }
+
+    #[test]
+    fn access_token_user_id_matches_user_id() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "carl", "password": "secret"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap();
+        let access_token = AccessToken::authenticate(&test.connection(), token, test.config())
+            .unwrap()
+            .access_token;
+
+        assert_eq!(access_token.user_id.to_string(), "@carl:ruma.test");
+        assert_eq!(
+            response.json().get("user_id").unwrap().as_str().unwrap(),
+            access_token.user_id.to_string()
+        );
+    }
+
+    #[test]
+    fn dummy_login_type() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.dummy", "user": "carl"}"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNKNOWN"
+        );
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "m.login.dummy can only be used as a stage of interactive authentication, not to log in"
+        );
+    }
+
+    #[test]
+    fn password_login_without_password() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "carl"}"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_MISSING_PARAM"
+        );
     }
 
     #[test]
@@ -213,4 +535,715 @@ mod tests {
 
         assert_eq!(response.status, Status::Forbidden);
     }
+
+    #[test]
+    fn user_id_over_length_limit() {
+        let test = Test::new();
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            &format!(
+                r#"{{"type": "m.login.password", "user": "{}", "password": "secret"}}"#,
+                "a".repeat(256 - "@:ruma.test".len())
+            ),
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_USERNAME"
+        );
+    }
+
+    #[test]
+    fn uppercase_user_id_logs_in_to_lowercase_localpart() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "@Carl:ruma.test", "password": "secret"}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("user_id").unwrap().as_str().unwrap(),
+            "@carl:ruma.test"
+        );
+    }
+
+    #[test]
+    fn uppercase_username_logs_in_to_lowercase_localpart() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "Carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "Carl", "password": "secret"}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("user_id").unwrap().as_str().unwrap(),
+            "@carl:ruma.test"
+        );
+    }
+
+    #[test]
+    fn deactivated_user() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        let access_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/account/deactivate?access_token={}",
+                access_token
+            ),
+            "{}",
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "carl", "password": "secret"}"#,
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_USER_DEACTIVATED"
+        );
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "carl", "password": "wrong"}"#,
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+    }
+
+    #[test]
+    fn macaroon_token_format() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "carl", "password": "secret"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let access_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap();
+        assert!(V1Token::deserialize(decode(access_token).unwrap()).is_ok());
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}",
+            access_token
+        ));
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn opaque_token_format() {
+        let test = Test::with_config(|config| config.token_format = TokenFormat::Opaque);
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "carl", "password": "secret"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let access_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap();
+        assert_eq!(access_token.len(), 43);
+        assert!(decode(access_token)
+            .map(|macaroon| V1Token::deserialize(macaroon).is_err())
+            .unwrap_or(true));
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}",
+            access_token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/logout?access_token={}", access_token),
+            "{}",
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}",
+            access_token
+        ));
+        assert_eq!(response.status, Status::Unauthorized);
+    }
+
+    fn opaque_token_with_prefix(test: &Test) -> String {
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "carl", "password": "secret"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn opaque_token_prefix() {
+        let test = Test::with_config(|config| {
+            config.token_format = TokenFormat::Opaque;
+            config.opaque_token_prefix = "ruma_".to_string();
+        });
+
+        let access_token = opaque_token_with_prefix(&test);
+        assert!(access_token.starts_with("ruma_"));
+        assert_eq!(access_token.len(), "ruma_".len() + 43);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}",
+            access_token
+        ));
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn opaque_token_without_prefix() {
+        let test = Test::with_config(|config| {
+            config.token_format = TokenFormat::Opaque;
+            config.opaque_token_prefix = "ruma_".to_string();
+        });
+
+        let access_token = opaque_token_with_prefix(&test);
+
+        for token in &[
+            access_token.trim_start_matches("ruma_").to_string(),
+            access_token.replacen("ruma_", "syt_", 1),
+        ] {
+            let response = test.get(&format!("/_matrix/client/r0/sync?access_token={}", token));
+            assert_eq!(response.status, Status::Unauthorized);
+            assert_eq!(
+                response.json().get("errcode").unwrap().as_str().unwrap(),
+                "M_UNKNOWN_TOKEN"
+            );
+        }
+    }
+
+    #[test]
+    fn tampered_macaroon_token() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let mut tampered = user.token.clone();
+        let last = tampered.pop().unwrap();
+        tampered.push(if last == 'A' { 'B' } else { 'A' });
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}",
+            tampered
+        ));
+        assert_eq!(response.status, Status::Unauthorized);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}",
+            user.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn login_with_user_identifier() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "identifier": {"type": "m.id.user", "user": "carl"}, "password": "secret"}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("user_id").unwrap().as_str().unwrap(),
+            "@carl:ruma.test"
+        );
+    }
+
+    #[test]
+    fn login_with_email_identifier() {
+        let test = Test::new();
+        let user = test.create_user();
+        test.add_three_pid(&user.id, "email", "carl@example.com");
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "identifier": {"type": "m.id.thirdparty", "medium": "email", "address": "Carl@example.com"}, "password": "secret"}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("user_id").unwrap().as_str().unwrap(),
+            user.id
+        );
+    }
+
+    #[test]
+    fn login_with_unknown_email_identifier() {
+        let test = Test::new();
+        let user = test.create_user();
+        test.add_three_pid(&user.id, "email", "carl@example.com");
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "identifier": {"type": "m.id.thirdparty", "medium": "email", "address": "alice@example.com"}, "password": "secret"}"#,
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn identifier_takes_precedence_over_user() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "identifier": {"type": "m.id.user", "user": "carl"}, "user": "alice", "password": "secret"}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("user_id").unwrap().as_str().unwrap(),
+            "@carl:ruma.test"
+        );
+    }
+
+    #[test]
+    fn missing_identifier() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "password": "secret"}"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn max_devices_per_user_revokes_least_recent_device() {
+        let test = Test::with_config(|config| config.max_devices_per_user = 2);
+
+        let response = test
+            .register_user(r#"{"username": "carl", "password": "secret", "device_id": "FIRST"}"#);
+        assert_eq!(response.status, Status::Ok);
+        let first = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let login = |device_id: &str| {
+            let response = test.post(
+                "/_matrix/client/r0/login",
+                &format!(
+                    r#"{{"type": "m.login.password", "user": "carl", "password": "secret", "device_id": "{}"}}"#,
+                    device_id
+                ),
+            );
+            assert_eq!(response.status, Status::Ok);
+            response
+                .json()
+                .get("access_token")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string()
+        };
+        let sync_status = |access_token: &str| {
+            test.get(&format!(
+                "/_matrix/client/r0/sync?access_token={}",
+                access_token
+            ))
+            .status
+        };
+
+        let second = login("SECOND");
+        assert_eq!(sync_status(&first), Status::Ok);
+
+        let third = login("THIRD");
+        assert_eq!(sync_status(&first), Status::Unauthorized);
+        assert_eq!(sync_status(&second), Status::Ok);
+        assert_eq!(sync_status(&third), Status::Ok);
+
+        // Logging in again on a known device doesn't count as a new device.
+        let second_again = login("SECOND");
+        assert_eq!(sync_status(&second), Status::Ok);
+        assert_eq!(sync_status(&second_again), Status::Ok);
+        assert_eq!(sync_status(&third), Status::Ok);
+    }
+
+    #[test]
+    fn logins_are_audited() {
+        let test = Test::with_config(|config| config.enable_audit_log = true);
+        let user = test.create_user();
+
+        let login = |password: &str| {
+            test.post(
+                "/_matrix/client/r0/login",
+                &format!(
+                    r#"{{"type": "m.login.password", "user": "{}", "password": "{}"}}"#,
+                    user.id, password
+                ),
+            )
+        };
+
+        assert_eq!(login("wrong").status, Status::Forbidden);
+        assert_eq!(login("secret").status, Status::Ok);
+
+        let user_id = UserId::try_from(user.id.as_ref()).unwrap();
+        let audit_log = AuditLog::find_by_uid(&test.connection(), &user_id).unwrap();
+        let events: Vec<&str> = audit_log
+            .iter()
+            .map(|entry| entry.event_type.as_ref())
+            .collect();
+
+        assert_eq!(events, vec!["failed_login", "login"]);
+        assert!(audit_log.iter().all(|entry| !entry.ip.is_empty()));
+    }
+
+    #[test]
+    fn logins_are_not_audited_by_default() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            &format!(
+                r#"{{"type": "m.login.password", "user": "{}", "password": "secret"}}"#,
+                user.id
+            ),
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let user_id = UserId::try_from(user.id.as_ref()).unwrap();
+        assert!(AuditLog::find_by_uid(&test.connection(), &user_id)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn login_types_lists_enabled_types() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/r0/login");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().to_string(),
+            r#"{"flows":[{"type":"m.login.password"}]}"#
+        );
+    }
+
+    #[test]
+    fn login_types_without_enabled_types() {
+        let test = Test::with_config(|config| config.login_types = Vec::new());
+
+        let response = test.get("/_matrix/client/r0/login");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().to_string(), r#"{"flows":[]}"#);
+    }
+
+    #[test]
+    fn disabled_password_login() {
+        let test = Test::with_config(|config| config.login_types = Vec::new());
+        let user = test.create_user();
+
+        let login = format!(
+            r#"{{"type": "m.login.password", "user": "{}", "password": "secret"}}"#,
+            user.id
+        );
+
+        let response = test.post("/_matrix/client/r0/login", &login);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+    }
+
+    #[test]
+    fn empty_body() {
+        let test = Test::new();
+
+        let response = test.post("/_matrix/client/r0/login", "");
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_NOT_JSON"
+        );
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Request body is empty"
+        );
+    }
+
+    #[test]
+    fn malformed_body() {
+        let test = Test::new();
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password","#,
+        );
+
+        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_BAD_JSON"
+        );
+        assert!(response
+            .json()
+            .get("error")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .starts_with("Can't parse body to JSON: "));
+    }
+
+    #[test]
+    fn malformed_user_id() {
+        let test = Test::new();
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "@carl smith:ruma.test", "password": "secret"}"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn well_known_with_public_base_url() {
+        let test = Test::with_config(|config| {
+            config.public_base_url = Some("https://matrix.ruma.test".to_string())
+        });
+        let user = test.create_user();
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            &format!(
+                r#"{{"type": "m.login.password", "user": "{}", "password": "secret"}}"#,
+                user.name
+            ),
+        );
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .pointer("/well_known/m.homeserver/base_url")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "https://matrix.ruma.test"
+        );
+    }
+
+    #[test]
+    fn no_well_known_without_public_base_url() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            &format!(
+                r#"{{"type": "m.login.password", "user": "{}", "password": "secret"}}"#,
+                user.name
+            ),
+        );
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("well_known").is_none());
+    }
+
+    #[test]
+    fn device_id_with_control_characters_is_rejected() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            &format!(
+                r#"{{"type": "m.login.password", "user": "{}", "password": "secret", "device_id": "PHONE\nuser_id = @erin:ruma.test"}}"#,
+                user.id
+            ),
+        );
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_PARAM"
+        );
+    }
+
+    /// Logs in as carl with the given password.
+    fn login_as_carl(test: &Test, password: &str) -> crate::test::Response {
+        test.post(
+            "/_matrix/client/r0/login",
+            &format!(
+                r#"{{"type": "m.login.password", "user": "carl", "password": "{}"}}"#,
+                password
+            ),
+        )
+    }
+
+    /// Creates a test server locking accounts after three failures for the given cooldown, with
+    /// the users carl and erin.
+    fn lockout_fixture(cooldown_ms: u64) -> Test {
+        let test = Test::with_config(|config| {
+            config.login_lockout = Some(LoginLockout {
+                max_failures: 3,
+                window_ms: 60_000,
+                cooldown_ms,
+            })
+        });
+
+        for username in &["carl", "erin"] {
+            let response = test.register_user(&format!(
+                r#"{{"username": "{}", "password": "secret"}}"#,
+                username
+            ));
+            assert_eq!(response.status, Status::Ok);
+        }
+
+        test
+    }
+
+    #[test]
+    fn repeated_failures_lock_the_account() {
+        let test = lockout_fixture(60_000);
+
+        for _ in 0..3 {
+            let response = login_as_carl(&test, "wrong");
+            assert_eq!(response.status, Status::Forbidden);
+            assert_eq!(
+                response.json().get("errcode").unwrap().as_str().unwrap(),
+                "M_FORBIDDEN"
+            );
+        }
+
+        let response = login_as_carl(&test, "secret");
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_USER_LOCKED"
+        );
+        let retry_after_ms = response
+            .json()
+            .get("retry_after_ms")
+            .unwrap()
+            .as_u64()
+            .unwrap();
+        assert!(retry_after_ms > 0 && retry_after_ms <= 60_000);
+
+        // Other accounts aren't affected.
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "erin", "password": "secret"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn lock_expires_after_the_cooldown() {
+        let test = lockout_fixture(200);
+
+        for _ in 0..3 {
+            assert_eq!(login_as_carl(&test, "wrong").status, Status::Forbidden);
+        }
+        assert_eq!(
+            login_as_carl(&test, "secret")
+                .json()
+                .get("errcode")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "M_USER_LOCKED"
+        );
+
+        sleep(Duration::from_millis(200));
+
+        assert_eq!(login_as_carl(&test, "secret").status, Status::Ok);
+    }
+
+    #[test]
+    fn successful_login_resets_the_failures() {
+        let test = lockout_fixture(60_000);
+
+        for _ in 0..2 {
+            assert_eq!(login_as_carl(&test, "wrong").status, Status::Forbidden);
+        }
+        assert_eq!(login_as_carl(&test, "secret").status, Status::Ok);
+
+        for _ in 0..2 {
+            assert_eq!(login_as_carl(&test, "wrong").status, Status::Forbidden);
+        }
+        assert_eq!(login_as_carl(&test, "secret").status, Status::Ok);
+    }
 }
@@ -3,9 +3,11 @@
 use iron::status::Status;
 use iron::{Chain, Handler, IronResult, Request, Response};
 
+use crate::config::Config;
 use crate::db::DB;
 use crate::middleware::{AccessTokenAuth, MiddlewareChain};
 use crate::models::access_token::AccessToken;
+use crate::models::audit_log::{AuditEvent, AuditLog};
 use crate::modifier::EmptyResponse;
 
 /// The `/logout` endpoint.
@@ -17,13 +19,60 @@ middleware_chain!(Logout, [AccessTokenAuth]);
 impl Handler for Logout {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
         let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
 
-        let access_token = request
+        let user_id = {
+            let access_token = request
+                .extensions
+                .get_mut::<AccessToken>()
+                .expect("AccessTokenAuth should ensure an access token");
+
+            access_token.revoke(&connection)?;
+
+            access_token.user_id.clone()
+        };
+
+        AuditLog::record(
+            &connection,
+            &config,
+            request,
+            Some(&user_id),
+            AuditEvent::Logout,
+        )?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+/// The `/logout/all` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct LogoutAll;
+
+middleware_chain!(LogoutAll, [AccessTokenAuth]);
+
+impl Handler for LogoutAll {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+
+        let user_id = request
             .extensions
-            .get_mut::<AccessToken>()
-            .expect("AccessTokenAuth should ensure an access token");
+            .get::<AccessToken>()
+            .expect("AccessTokenAuth should ensure an access token")
+            .user_id
+            .clone();
 
-        access_token.revoke(&connection)?;
+        let revoked = AccessToken::revoke_all_for_user(&connection, &user_id)?;
+
+        debug!("Revoked {} access tokens of {}", revoked, user_id);
+
+        AuditLog::record(
+            &connection,
+            &config,
+            request,
+            Some(&user_id),
+            AuditEvent::Logout,
+        )?;
 
         Ok(Response::with(EmptyResponse(Status::Ok)))
     }
@@ -43,6 +92,53 @@ mod tests {
         let login_path = format!("/_matrix/client/r0/logout?access_token={}", user.token);
 
         assert!(test.post(&login_path, "{}").status.is_success());
-        assert_eq!(test.post(&login_path, "{}").status, Status::Forbidden);
+        assert_eq!(test.post(&login_path, "{}").status, Status::Unauthorized);
+    }
+
+    #[test]
+    fn logout_all_revokes_all_access_tokens() {
+        let test = Test::new();
+        let user = test.create_user();
+        let other_user = test.create_user();
+
+        let mut tokens = vec![user.token.clone()];
+
+        for _ in 0..2 {
+            let response = test.post(
+                "/_matrix/client/r0/login",
+                &format!(
+                    r#"{{"type": "m.login.password", "user": "{}", "password": "secret"}}"#,
+                    user.id
+                ),
+            );
+            assert_eq!(response.status, Status::Ok);
+
+            tokens.push(
+                response
+                    .json()
+                    .get("access_token")
+                    .unwrap()
+                    .as_str()
+                    .unwrap()
+                    .to_string(),
+            );
+        }
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/logout/all?access_token={}", user.token),
+            "{}",
+        );
+        test.check_empty_response(response);
+
+        for token in tokens {
+            let response = test.get(&format!("/_matrix/client/r0/sync?access_token={}", token));
+            assert_eq!(response.status, Status::Unauthorized);
+        }
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}",
+            other_user.token
+        ));
+        assert_eq!(response.status, Status::Ok);
     }
 }
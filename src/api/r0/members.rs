@@ -1,15 +1,25 @@
 //! Endpoints for room members.
 
+use std::collections::BTreeMap;
+
+use diesel::pg::PgConnection;
 use iron::status::Status;
 use iron::{Chain, Handler, IronResult, Request, Response};
 use ruma_events::room::member::MemberEvent;
+use ruma_identifiers::{RoomId, UserId};
+use url::Url;
 
 use crate::db::DB;
+use crate::error::ApiError;
 use crate::middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use crate::models::profile::Profile;
 use crate::models::room_membership::RoomMembership;
 use crate::models::user::User;
 use crate::modifier::SerializableResponse;
 
+/// The memberships that can be used to filter the members of a room.
+const MEMBERSHIPS: [&str; 5] = ["ban", "invite", "join", "knock", "leave"];
+
 /// The `/rooms/:room_id/members` endpoint.
 #[derive(Clone, Copy, Debug)]
 pub struct Members;
@@ -21,14 +31,57 @@ struct MembersResponse {
     chunk: Vec<MemberEvent>,
 }
 
+/// The `/rooms/:room_id/joined_members` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct JoinedMembers;
+
+/// The body of the response for the `/joined_members` API.
+#[derive(Debug, Serialize)]
+struct JoinedMembersResponse {
+    /// The profiles of the joined members, keyed by their user ID.
+    joined: BTreeMap<String, JoinedMember>,
+}
+
+/// The profile of a joined member.
+#[derive(Debug, Default, Serialize)]
+struct JoinedMember {
+    /// The member's display name, if they have set one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+    /// The member's avatar URL, if they have set one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+}
+
+/// Make sure the user has joined the room.
+fn ensure_joined(
+    connection: &PgConnection,
+    room_id: &RoomId,
+    user_id: &UserId,
+) -> Result<(), ApiError> {
+    let is_joined = match RoomMembership::find(connection, room_id, user_id)? {
+        Some(room_membership) => room_membership.membership == "join",
+        None => false,
+    };
+
+    if !is_joined {
+        return Err(ApiError::unauthorized(
+            "The user is not a member of this room".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
 middleware_chain!(Members, [RoomIdParam, AccessTokenAuth]);
 
 impl Handler for Members {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        request
+        let user = request
             .extensions
             .get::<User>()
-            .expect("AccessTokenAuth should ensure a user");
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
 
         let connection = DB::from_request(request)?;
 
@@ -38,7 +91,33 @@ impl Handler for Members {
             .expect("Should have been required by RoomIdParam.")
             .clone();
 
-        let events = RoomMembership::get_events_by_room(&connection, room_id)?;
+        let url: Url = request.url.clone().into();
+
+        let mut membership = None;
+        let mut not_membership = None;
+
+        for (key, value) in url.query_pairs() {
+            let filter = match key.as_ref() {
+                "membership" => &mut membership,
+                "not_membership" => &mut not_membership,
+                _ => continue,
+            };
+
+            if !MEMBERSHIPS.contains(&value.as_ref()) {
+                Err(ApiError::invalid_param(key.as_ref(), "Unknown membership"))?;
+            }
+
+            *filter = Some(value.into_owned());
+        }
+
+        ensure_joined(&connection, &room_id, &user.id)?;
+
+        let events = RoomMembership::get_events_by_room(
+            &connection,
+            room_id,
+            membership.as_ref().map(String::as_ref),
+            not_membership.as_ref().map(String::as_ref),
+        )?;
 
         let response = MembersResponse { chunk: events };
 
@@ -46,6 +125,47 @@ impl Handler for Members {
     }
 }
 
+middleware_chain!(JoinedMembers, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for JoinedMembers {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("Should have been required by RoomIdParam.")
+            .clone();
+
+        let connection = DB::from_request(request)?;
+
+        ensure_joined(&connection, &room_id, &user.id)?;
+
+        let user_ids = RoomMembership::find_joined_user_ids(&connection, &room_id)?;
+        let profiles = Profile::get_profiles(&connection, &user_ids)?;
+
+        let mut joined: BTreeMap<String, JoinedMember> = user_ids
+            .iter()
+            .map(|user_id| (user_id.to_string(), JoinedMember::default()))
+            .collect();
+
+        for profile in profiles {
+            if let Some(member) = joined.get_mut(&profile.id.to_string()) {
+                member.display_name = profile.displayname;
+                member.avatar_url = profile.avatar_url;
+            }
+        }
+
+        let response = JoinedMembersResponse { joined };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::test::Test;
@@ -74,4 +194,162 @@ mod tests {
         let chunk = chunk.as_array().unwrap();
         assert_eq!(chunk.len(), 1);
     }
+
+    fn members(test: &Test, room_id: &str, token: &str, filter: &str) -> Vec<String> {
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/members?access_token={}{}",
+            room_id, token, filter
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let mut members: Vec<String> = response
+            .json()
+            .get("chunk")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| {
+                format!(
+                    "{} {}",
+                    event.get("state_key").unwrap().as_str().unwrap(),
+                    event
+                        .pointer("/content/membership")
+                        .unwrap()
+                        .as_str()
+                        .unwrap()
+                )
+            })
+            .collect();
+        members.sort();
+
+        members
+    }
+
+    #[test]
+    fn membership_filter() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let carl = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        test.invite(&alice.token, &room_id, &carl.id);
+
+        let all = members(&test, &room_id, &alice.token, "");
+        assert_eq!(all.len(), 3);
+        assert!(all.contains(&format!("{} invite", carl.id)));
+
+        let mut joined = vec![format!("{} join", alice.id), format!("{} join", bob.id)];
+        joined.sort();
+
+        assert_eq!(
+            members(&test, &room_id, &alice.token, "&membership=join"),
+            joined
+        );
+        assert_eq!(
+            members(&test, &room_id, &alice.token, "&not_membership=invite"),
+            joined
+        );
+        assert_eq!(
+            members(&test, &room_id, &alice.token, "&membership=invite"),
+            vec![format!("{} invite", carl.id)]
+        );
+    }
+
+    #[test]
+    fn invalid_membership_filter() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/members?access_token={}&membership=joined",
+            room_id, alice.token
+        ));
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn non_members_are_forbidden() {
+        let test = Test::new();
+        let (_, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/members?access_token={}",
+            room_id, bob.token
+        ));
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn joined_members() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let carl = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(
+            test.invite(&alice.token, &room_id, &carl.id).status,
+            Status::Ok
+        );
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+                bob.id, bob.token
+            ),
+            r#"{"displayname": "Bob"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/joined_members?access_token={}",
+            room_id, alice.token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let joined = response.json().get("joined").unwrap().as_object().unwrap();
+        assert_eq!(joined.len(), 2);
+        assert!(joined.get(&carl.id).is_none());
+        assert!(joined
+            .get(&alice.id)
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            joined
+                .get(&bob.id)
+                .unwrap()
+                .get("display_name")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Bob"
+        );
+    }
+
+    #[test]
+    fn joined_members_for_non_members() {
+        let test = Test::new();
+        let (_, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/joined_members?access_token={}",
+            room_id, bob.token
+        ));
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
 }
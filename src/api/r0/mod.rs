@@ -1,37 +1,64 @@
 //! API endpoints for the 0.x.x version of the Matrix spec.
 
-pub use self::account::{AccountPassword, DeactivateAccount, PutAccountData, PutRoomAccountData};
+pub use self::account::{
+    AccountPassword, AccountSessions, DeactivateAccount, DeleteThreePid, GetThreePids,
+    PutAccountData, PutRoomAccountData, RequestPasswordEmailToken,
+};
+pub use self::admin::{
+    CreateRegistrationToken, GetRegistrationTokens, GetServerNotice, GetUser, ImportUsers,
+    RebindDevice, SearchUsers, SetServerNotice, WhoIs,
+};
+pub use self::capabilities::GetCapabilities;
 pub use self::directory::{DeleteRoomAlias, GetRoomAlias, PutRoomAlias};
-pub use self::event_creation::{SendMessageEvent, StateMessageEvent};
+pub use self::event_creation::{RedactEvent, SendMessageEvent, StateMessageEvent};
 pub use self::filter::{GetFilter, PostFilter};
-pub use self::join::{InviteToRoom, JoinRoom, JoinRoomWithIdOrAlias, KickFromRoom, LeaveRoom};
-pub use self::login::Login;
-pub use self::logout::Logout;
-pub use self::members::Members;
+pub use self::join::{
+    BanFromRoom, ForgetRoom, InviteToRoom, JoinRoom, JoinRoomWithIdOrAlias, KickFromRoom,
+    LeaveRoom, UnbanFromRoom,
+};
+pub use self::keys::{ClaimKeys, UploadKeys};
+pub use self::login::{GetLoginTypes, Login};
+pub use self::logout::{Logout, LogoutAll};
+pub use self::members::{JoinedMembers, Members};
+pub use self::openid::RequestOpenIdToken;
 pub use self::presence::{GetPresenceList, GetPresenceStatus, PostPresenceList, PutPresenceStatus};
 pub use self::profile::{GetAvatarUrl, GetDisplayName, Profile, PutAvatarUrl, PutDisplayName};
+pub use self::public_rooms::{GetPublicRooms, PostPublicRooms};
+pub use self::push_rules::GetPushRules;
 pub use self::pushers::{GetPushers, SetPushers};
-pub use self::registration::Register;
+pub use self::receipts::PostReceipt;
+pub use self::registration::{Register, RegisterAvailable};
 pub use self::room_creation::CreateRoom;
-pub use self::room_info::RoomState;
+pub use self::room_info::{GetStateEvent, RoomState};
 pub use self::sync::Sync;
 pub use self::tags::{DeleteTag, GetTags, PutTag};
+pub use self::typing::PutTyping;
+pub use self::user_directory::SearchUserDirectory;
 pub use self::versions::Versions;
 
 mod account;
+mod admin;
+mod capabilities;
 mod directory;
 mod event_creation;
 mod filter;
 mod join;
+mod keys;
 mod login;
 mod logout;
 mod members;
+mod openid;
 mod presence;
 mod profile;
+mod public_rooms;
+mod push_rules;
 mod pushers;
+mod receipts;
 mod registration;
 mod room_creation;
 mod room_info;
 mod sync;
 mod tags;
+mod typing;
+mod user_directory;
 mod versions;
@@ -0,0 +1,136 @@
+//! Endpoints for proving a user's identity to third parties.
+
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Request, Response};
+
+use crate::config::Config;
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, UserIdParam};
+use crate::models::openid_token::{OpenIdToken, OPENID_TOKEN_LIFETIME};
+use crate::models::user::User;
+use crate::modifier::SerializableResponse;
+
+/// The `/user/:user_id/openid/request_token` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestOpenIdToken;
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct RequestOpenIdTokenResponse {
+    /// The token a third party can exchange for the user's ID.
+    access_token: String,
+    /// How the token is to be used, always `Bearer`.
+    token_type: &'static str,
+    /// The server the third party can verify the token with.
+    matrix_server_name: String,
+    /// The number of seconds the token is valid for.
+    expires_in: i32,
+}
+
+middleware_chain!(
+    RequestOpenIdToken,
+    [JsonRequest, UserIdParam, AccessTokenAuth]
+);
+
+impl Handler for RequestOpenIdToken {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let user_id = request
+            .extensions
+            .get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId")
+            .clone();
+
+        if user_id != user.id {
+            Err(ApiError::unauthorized(
+                "The given user_id does not correspond to the authenticated user".to_string(),
+            ))?;
+        }
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        let (_, access_token) = OpenIdToken::create(&connection, &user.id)?;
+
+        let response = RequestOpenIdTokenResponse {
+            access_token,
+            token_type: "Bearer",
+            matrix_server_name: config.domain.clone(),
+            expires_in: OPENID_TOKEN_LIFETIME,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use iron::status::Status;
+    use ruma_identifiers::UserId;
+
+    use crate::models::openid_token::OpenIdToken;
+    use crate::test::Test;
+
+    #[test]
+    fn request_token() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/user/{}/openid/request_token?access_token={}",
+                alice.id, alice.token
+            ),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        let json = response.json();
+        assert_eq!(json.get("token_type").unwrap().as_str().unwrap(), "Bearer");
+        assert_eq!(
+            json.get("matrix_server_name").unwrap().as_str().unwrap(),
+            "ruma.test"
+        );
+        assert_eq!(json.get("expires_in").unwrap().as_i64().unwrap(), 3600);
+
+        let access_token = json.get("access_token").unwrap().as_str().unwrap();
+        assert_ne!(access_token, alice.token);
+
+        let openid_token = OpenIdToken::find_valid(&test.connection(), access_token)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            openid_token.user_id,
+            UserId::try_from(alice.id.as_str()).unwrap()
+        );
+    }
+
+    #[test]
+    fn request_token_for_another_user() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/user/{}/openid/request_token?access_token={}",
+                bob.id, alice.token
+            ),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+    }
+}
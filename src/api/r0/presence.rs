@@ -1,15 +1,14 @@
 //! Endpoints for presence.
 
-use bodyparser;
 use iron::status::Status;
-use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::{Chain, Handler, IronError, IronResult, Request, Response};
 use ruma_events::presence::PresenceState;
 use ruma_identifiers::UserId;
 
 use crate::config::Config;
 use crate::db::DB;
 use crate::error::ApiError;
-use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, UserIdParam};
+use crate::middleware::{parse_body, AccessTokenAuth, JsonRequest, MiddlewareChain, UserIdParam};
 use crate::models::presence_list::PresenceList;
 use crate::models::presence_status::{get_now, PresenceStatus};
 use crate::models::room_membership::RoomMembership;
@@ -48,11 +47,7 @@ impl Handler for PutPresenceStatus {
             .expect("AccessTokenAuth should ensure a user")
             .clone();
 
-        let put_presence_status_request =
-            match request.get::<bodyparser::Struct<PutPresenceStatusRequest>>() {
-                Ok(Some(request)) => request,
-                Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
-            };
+        let put_presence_status_request = parse_body::<PutPresenceStatusRequest>(request)?;
 
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
@@ -168,11 +163,7 @@ middleware_chain!(
 
 impl Handler for PostPresenceList {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let put_presence_list_request =
-            match request.get::<bodyparser::Struct<PostPresenceListRequest>>() {
-                Ok(Some(request)) => request,
-                Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
-            };
+        let put_presence_list_request = parse_body::<PostPresenceListRequest>(request)?;
         let user_id = request
             .extensions
             .get::<UserIdParam>()
@@ -1,13 +1,12 @@
 //! Endpoints for profile.
 
-use bodyparser;
 use iron::status::Status;
-use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::{Chain, Handler, IronError, IronResult, Request, Response};
 
 use crate::config::Config;
 use crate::db::DB;
 use crate::error::ApiError;
-use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, UserIdParam};
+use crate::middleware::{parse_body, AccessTokenAuth, JsonRequest, MiddlewareChain, UserIdParam};
 use crate::models::profile::Profile as DataProfile;
 use crate::models::user::User;
 use crate::modifier::{EmptyResponse, SerializableResponse};
@@ -122,10 +121,7 @@ middleware_chain!(PutAvatarUrl, [JsonRequest, UserIdParam, AccessTokenAuth]);
 
 impl Handler for PutAvatarUrl {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let avatar_url_request = match request.get::<bodyparser::Struct<PutAvatarUrlRequest>>() {
-            Ok(Some(avatar_url_request)) => avatar_url_request,
-            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
-        };
+        let avatar_url_request = parse_body::<PutAvatarUrlRequest>(request)?;
 
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
@@ -226,10 +222,7 @@ middleware_chain!(PutDisplayName, [JsonRequest, UserIdParam, AccessTokenAuth]);
 
 impl Handler for PutDisplayName {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let displayname_request = match request.get::<bodyparser::Struct<PutDisplayNameRequest>>() {
-            Ok(Some(displayname_request)) => displayname_request,
-            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
-        };
+        let displayname_request = parse_body::<PutDisplayNameRequest>(request)?;
 
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
@@ -693,4 +686,17 @@ mod tests {
             "Bogus"
         );
     }
+
+    #[test]
+    fn get_profile_with_malformed_user_id() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/r0/profile/carl");
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_PARAM"
+        );
+    }
 }
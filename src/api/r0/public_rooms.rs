@@ -0,0 +1,438 @@
+//! Endpoints for the public room directory.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use diesel::pg::PgConnection;
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Request, Response};
+use ruma_events::EventType;
+use ruma_identifiers::{RoomAliasId, RoomId};
+use serde_json::{from_str, Value};
+use url::Url;
+
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{parse_body, AccessTokenAuth, JsonRequest, MiddlewareChain};
+use crate::models::event::Event;
+use crate::models::room::Room;
+use crate::models::room_membership::RoomMembership;
+use crate::modifier::SerializableResponse;
+
+/// The GET `/publicRooms` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetPublicRooms;
+
+/// The POST `/publicRooms` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct PostPublicRooms;
+
+/// The body of the request for the POST endpoint.
+#[derive(Clone, Debug, Default, Deserialize)]
+struct PublicRoomsRequest {
+    /// The maximum number of rooms to return.
+    limit: Option<usize>,
+    /// A pagination token from a previous response.
+    since: Option<String>,
+    /// A filter to apply to the results.
+    filter: Option<PublicRoomsFilter>,
+}
+
+/// A filter for the public room directory.
+#[derive(Clone, Debug, Default, Deserialize)]
+struct PublicRoomsFilter {
+    /// A string to search for in the room's name, topic and canonical alias, ignoring case.
+    generic_search_term: Option<String>,
+}
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct PublicRoomsResponse {
+    /// A page of public rooms.
+    chunk: Vec<PublicRoom>,
+    /// A pagination token for the next page, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_batch: Option<String>,
+    /// A pagination token for the previous page, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prev_batch: Option<String>,
+    /// The number of public rooms matching the filter.
+    total_room_count_estimate: usize,
+}
+
+/// A room in the public room directory.
+#[derive(Debug, Serialize)]
+struct PublicRoom {
+    /// The ID of the room.
+    room_id: RoomId,
+    /// The name of the room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// The topic of the room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    /// The canonical alias of the room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    canonical_alias: Option<RoomAliasId>,
+    /// The URL of the room's avatar.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+    /// The number of members who have joined the room.
+    num_joined_members: i64,
+    /// Whether or not the room can be read without joining it.
+    world_readable: bool,
+    /// Whether or not guest users may join the room.
+    guest_can_join: bool,
+}
+
+middleware_chain!(PostPublicRooms, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for GetPublicRooms {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let url: Url = request.url.clone().into();
+        let mut public_rooms_request = PublicRoomsRequest::default();
+
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "limit" => {
+                    public_rooms_request.limit = Some(value.parse().map_err(|_| {
+                        ApiError::invalid_param("limit", "Must be a non-negative integer")
+                    })?)
+                }
+                "since" => public_rooms_request.since = Some(value.into_owned()),
+                _ => {}
+            }
+        }
+
+        let connection = DB::from_request(request)?;
+
+        let response = public_rooms(&connection, public_rooms_request)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+impl Handler for PostPublicRooms {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let public_rooms_request = parse_body::<PublicRoomsRequest>(request)?;
+
+        let connection = DB::from_request(request)?;
+
+        let response = public_rooms(&connection, public_rooms_request)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The state events the directory information of a room is collected from.
+const DIRECTORY_STATE: [EventType; 6] = [
+    EventType::RoomName,
+    EventType::RoomTopic,
+    EventType::RoomCanonicalAlias,
+    EventType::RoomAvatar,
+    EventType::RoomHistoryVisibility,
+    EventType::RoomGuestAccess,
+];
+
+/// Looks up one page of the public rooms matching the request.
+///
+/// A `limit` of 0 is treated like no limit, since a page without rooms could never advance.
+fn public_rooms(
+    connection: &PgConnection,
+    public_rooms_request: PublicRoomsRequest,
+) -> Result<PublicRoomsResponse, ApiError> {
+    let offset: i64 = match public_rooms_request.since {
+        Some(since) => since
+            .parse()
+            .ok()
+            .filter(|offset| *offset >= 0)
+            .ok_or_else(|| ApiError::invalid_param("since", "Invalid pagination token"))?,
+        None => 0,
+    };
+
+    let limit = match public_rooms_request.limit {
+        Some(0) | None => None,
+        Some(limit) => Some(i64::try_from(limit).unwrap_or(i64::max_value())),
+    };
+
+    let search_term = public_rooms_request
+        .filter
+        .and_then(|filter| filter.generic_search_term);
+
+    let (room_ids, total_room_count_estimate) = Room::find_public_page(
+        connection,
+        search_term.as_ref().map(String::as_str),
+        offset,
+        limit,
+    )?;
+
+    let chunk = PublicRoom::find_many(connection, room_ids)?;
+    let end = offset + chunk.len() as i64;
+
+    let next_batch = if end < total_room_count_estimate {
+        Some(end.to_string())
+    } else {
+        None
+    };
+
+    let prev_batch = if offset > 0 {
+        Some(limit.map_or(0, |limit| (offset - limit).max(0)).to_string())
+    } else {
+        None
+    };
+
+    Ok(PublicRoomsResponse {
+        chunk,
+        next_batch,
+        prev_batch,
+        total_room_count_estimate: total_room_count_estimate as usize,
+    })
+}
+
+impl PublicRoom {
+    /// Collects the directory information of the given rooms from their current state, in the
+    /// same order.
+    fn find_many(connection: &PgConnection, room_ids: Vec<RoomId>) -> Result<Vec<Self>, ApiError> {
+        let mut state = HashMap::new();
+
+        for event in Event::find_current_state_events(connection, &room_ids, &DIRECTORY_STATE)? {
+            if let Some(room_id) = event.room_id {
+                let content: Value = from_str(&event.content).map_err(ApiError::from)?;
+
+                state.insert((room_id.to_string(), event.event_type), content);
+            }
+        }
+
+        let joined_members: HashMap<String, i64> =
+            RoomMembership::count_joined_by_room(connection, &room_ids)?
+                .into_iter()
+                .map(|(room_id, count)| (room_id.to_string(), count))
+                .collect();
+
+        Ok(room_ids
+            .into_iter()
+            .map(|room_id| {
+                let value = |event_type: EventType, key: &str| -> Option<String> {
+                    state
+                        .get(&(room_id.to_string(), event_type.to_string()))
+                        .and_then(|content| content.get(key))
+                        .and_then(Value::as_str)
+                        .map(ToString::to_string)
+                };
+
+                let name = value(EventType::RoomName, "name");
+                let topic = value(EventType::RoomTopic, "topic");
+                let canonical_alias = value(EventType::RoomCanonicalAlias, "alias")
+                    .and_then(|alias| RoomAliasId::try_from(alias.as_ref()).ok());
+                let avatar_url = value(EventType::RoomAvatar, "url");
+                let world_readable = value(EventType::RoomHistoryVisibility, "history_visibility")
+                    == Some("world_readable".to_string());
+                let guest_can_join = value(EventType::RoomGuestAccess, "guest_access")
+                    == Some("can_join".to_string());
+                let num_joined_members = joined_members
+                    .get(&room_id.to_string())
+                    .cloned()
+                    .unwrap_or(0);
+
+                Self {
+                    room_id,
+                    name,
+                    topic,
+                    canonical_alias,
+                    avatar_url,
+                    num_joined_members,
+                    world_readable,
+                    guest_can_join,
+                }
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use crate::test::Test;
+
+    fn public_room_ids(response: &crate::test::Response) -> Vec<String> {
+        response
+            .json()
+            .get("chunk")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|room| room.get("room_id").unwrap().as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn public_room_is_listed() {
+        let test = Test::new();
+        let user = test.create_user();
+        let public_room_id = test.create_room_with_params(
+            &user.token,
+            r#"{"visibility": "public", "name": "Ruma", "topic": "Homeservers"}"#,
+        );
+        let private_room_id = test.create_room(&user.token);
+
+        let response = test.get("/_matrix/client/r0/publicRooms");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(public_room_ids(&response), vec![public_room_id]);
+        assert!(!public_room_ids(&response).contains(&private_room_id));
+        assert_eq!(
+            response
+                .json()
+                .get("total_room_count_estimate")
+                .unwrap()
+                .as_u64()
+                .unwrap(),
+            1
+        );
+
+        let room = &response.json().get("chunk").unwrap()[0];
+        assert_eq!(room.get("name").unwrap().as_str().unwrap(), "Ruma");
+        assert_eq!(room.get("topic").unwrap().as_str().unwrap(), "Homeservers");
+        assert_eq!(room.get("num_joined_members").unwrap().as_i64().unwrap(), 1);
+        assert!(!room.get("world_readable").unwrap().as_bool().unwrap());
+        assert!(!room.get("guest_can_join").unwrap().as_bool().unwrap());
+    }
+
+    #[test]
+    fn public_rooms_pagination() {
+        let test = Test::new();
+        let user = test.create_user();
+        let mut room_ids: Vec<String> = (0..3)
+            .map(|_| test.create_public_room(&user.token))
+            .collect();
+
+        let response = test.get("/_matrix/client/r0/publicRooms?limit=2");
+
+        assert_eq!(response.status, Status::Ok);
+        let mut listed_room_ids = public_room_ids(&response);
+        assert_eq!(listed_room_ids.len(), 2);
+        assert!(response.json().get("prev_batch").is_none());
+        assert_eq!(
+            response
+                .json()
+                .get("total_room_count_estimate")
+                .unwrap()
+                .as_u64()
+                .unwrap(),
+            3
+        );
+
+        let next_batch = response.json().get("next_batch").unwrap().as_str().unwrap();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/publicRooms?limit=2&since={}",
+            next_batch
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        listed_room_ids.extend(public_room_ids(&response));
+        assert!(response.json().get("next_batch").is_none());
+        assert_eq!(
+            response.json().get("prev_batch").unwrap().as_str().unwrap(),
+            "0"
+        );
+
+        room_ids.sort();
+        listed_room_ids.sort();
+        assert_eq!(listed_room_ids, room_ids);
+    }
+
+    #[test]
+    fn public_rooms_with_search_term() {
+        let test = Test::new();
+        let user = test.create_user();
+        let room_id = test.create_room_with_params(
+            &user.token,
+            r#"{"visibility": "public", "name": "Ruma developers"}"#,
+        );
+        test.create_room_with_params(&user.token, r#"{"visibility": "public", "name": "Other"}"#);
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/publicRooms?access_token={}", user.token),
+            r#"{"filter": {"generic_search_term": "DEVELOPERS"}}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(public_room_ids(&response), vec![room_id]);
+    }
+
+    #[test]
+    fn public_rooms_with_zero_limit() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        for _ in 0..3 {
+            test.create_public_room(&user.token);
+        }
+
+        let response = test.get("/_matrix/client/r0/publicRooms?limit=0");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(public_room_ids(&response).len(), 3);
+        assert!(response.json().get("next_batch").is_none());
+    }
+
+    #[test]
+    fn search_term_matches_current_state_only() {
+        let test = Test::new();
+        let user = test.create_user();
+        let renamed_room_id = test.create_room_with_params(
+            &user.token,
+            r#"{"visibility": "public", "name": "Ruma developers"}"#,
+        );
+        let topic_room_id = test.create_room_with_params(
+            &user.token,
+            r#"{"visibility": "public", "topic": "For ruma developers_"}"#,
+        );
+
+        let response = test.send_state_event(
+            &user.token,
+            &renamed_room_id,
+            "m.room.name",
+            r#"{"name": "Ruma users"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/publicRooms?access_token={}", user.token),
+            r#"{"filter": {"generic_search_term": "developers"}}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(public_room_ids(&response), vec![topic_room_id.clone()]);
+        assert_eq!(
+            response
+                .json()
+                .get("total_room_count_estimate")
+                .unwrap()
+                .as_u64()
+                .unwrap(),
+            1
+        );
+
+        // `_` is matched literally rather than as a wildcard.
+        let response = test.post(
+            &format!("/_matrix/client/r0/publicRooms?access_token={}", user.token),
+            r#"{"filter": {"generic_search_term": "s_"}}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(public_room_ids(&response), vec![topic_room_id]);
+    }
+
+    #[test]
+    fn post_public_rooms_requires_authentication() {
+        let test = Test::new();
+
+        let response = test.post("/_matrix/client/r0/publicRooms", "{}");
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
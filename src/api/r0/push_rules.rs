@@ -0,0 +1,308 @@
+//! Endpoints for push rules.
+
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Request, Response};
+use ruma_identifiers::UserId;
+use serde_json::Value;
+
+use crate::middleware::{AccessTokenAuth, MiddlewareChain};
+use crate::models::user::User;
+use crate::modifier::SerializableResponse;
+
+/// The GET `/pushrules/` endpoint.
+///
+/// Custom push rules aren't stored yet, so every user gets the server-default rules.
+#[derive(Clone, Copy, Debug)]
+pub struct GetPushRules;
+
+/// The body of the response for this API.
+#[derive(Clone, Debug, Serialize)]
+struct GetPushRulesResponse {
+    /// The global ruleset.
+    global: Ruleset,
+}
+
+/// A set of push rules, by kind, in order of priority.
+#[derive(Clone, Debug, Serialize)]
+struct Ruleset {
+    /// Rules that match on the content of a message.
+    content: Vec<PushRule>,
+    /// Rules that take precedence over all others.
+    #[serde(rename = "override")]
+    override_rules: Vec<PushRule>,
+    /// Rules for all messages in a room.
+    room: Vec<PushRule>,
+    /// Rules for all messages from a user.
+    sender: Vec<PushRule>,
+    /// Rules that apply when no other rule matched.
+    underride: Vec<PushRule>,
+}
+
+/// A push rule.
+#[derive(Clone, Debug, Serialize)]
+struct PushRule {
+    /// What to do when the rule matches.
+    actions: Vec<Action>,
+    /// The conditions that must hold for an event to match. Only for override and underride
+    /// rules.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conditions: Option<Vec<PushCondition>>,
+    /// Whether or not this is a server-default rule.
+    default: bool,
+    /// Whether or not the rule is enabled.
+    enabled: bool,
+    /// The glob pattern to match the message body against. Only for content rules.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pattern: Option<String>,
+    /// The ID of the rule.
+    rule_id: String,
+}
+
+/// An action to take when a push rule matches.
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+enum Action {
+    /// "notify", "dont_notify" or "coalesce".
+    Simple(String),
+    /// Sets a tweak, e.g. the sound to play, on the notification.
+    SetTweak {
+        /// The name of the tweak.
+        set_tweak: String,
+        /// The value of the tweak. Omitted for boolean tweaks that are set to true.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        value: Option<Value>,
+    },
+}
+
+/// A condition of an override or underride push rule.
+#[derive(Clone, Debug, Serialize)]
+struct PushCondition {
+    /// The kind of condition, e.g. "event_match".
+    kind: String,
+    /// The dot-separated path of the event field to match, for "event_match".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    /// The glob pattern to match against, for "event_match".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pattern: Option<String>,
+    /// The number of room members to compare against, for "room_member_count".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is: Option<String>,
+}
+
+middleware_chain!(GetPushRules, [AccessTokenAuth]);
+
+impl Handler for GetPushRules {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let response = GetPushRulesResponse {
+            global: Ruleset::server_default(&user.id),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+impl Ruleset {
+    /// The server-default push rules from the specification for the given user.
+    fn server_default(user_id: &UserId) -> Self {
+        let notify = || Action::Simple("notify".to_string());
+        let dont_notify = || Action::Simple("dont_notify".to_string());
+        let sound = |sound: &str| Action::SetTweak {
+            set_tweak: "sound".to_string(),
+            value: Some(Value::String(sound.to_string())),
+        };
+        let highlight = |highlight: bool| Action::SetTweak {
+            set_tweak: "highlight".to_string(),
+            value: if highlight {
+                None
+            } else {
+                Some(Value::Bool(false))
+            },
+        };
+
+        Self {
+            content: vec![PushRule {
+                actions: vec![notify(), sound("default"), highlight(true)],
+                conditions: None,
+                default: true,
+                enabled: true,
+                pattern: Some(user_id.localpart().to_string()),
+                rule_id: ".m.rule.contains_user_name".to_string(),
+            }],
+            override_rules: vec![
+                PushRule::with_conditions(".m.rule.master", false, vec![dont_notify()], vec![]),
+                PushRule::with_conditions(
+                    ".m.rule.suppress_notices",
+                    true,
+                    vec![dont_notify()],
+                    vec![PushCondition::event_match("content.msgtype", "m.notice")],
+                ),
+                PushRule::with_conditions(
+                    ".m.rule.invite_for_me",
+                    true,
+                    vec![notify(), sound("default"), highlight(false)],
+                    vec![
+                        PushCondition::event_match("type", "m.room.member"),
+                        PushCondition::event_match("content.membership", "invite"),
+                        PushCondition::event_match("state_key", &user_id.to_string()),
+                    ],
+                ),
+                PushRule::with_conditions(
+                    ".m.rule.member_event",
+                    true,
+                    vec![dont_notify()],
+                    vec![PushCondition::event_match("type", "m.room.member")],
+                ),
+                PushRule::with_conditions(
+                    ".m.rule.contains_display_name",
+                    true,
+                    vec![notify(), sound("default"), highlight(true)],
+                    vec![PushCondition::kind("contains_display_name")],
+                ),
+                PushRule::with_conditions(
+                    ".m.rule.roomnotif",
+                    true,
+                    vec![notify(), highlight(true)],
+                    vec![
+                        PushCondition::event_match("content.body", "@room"),
+                        PushCondition::kind("sender_notification_permission"),
+                    ],
+                ),
+            ],
+            room: vec![],
+            sender: vec![],
+            underride: vec![
+                PushRule::with_conditions(
+                    ".m.rule.call",
+                    true,
+                    vec![notify(), sound("ring"), highlight(false)],
+                    vec![PushCondition::event_match("type", "m.call.invite")],
+                ),
+                PushRule::with_conditions(
+                    ".m.rule.room_one_to_one",
+                    true,
+                    vec![notify(), sound("default"), highlight(false)],
+                    vec![
+                        PushCondition::room_member_count("2"),
+                        PushCondition::event_match("type", "m.room.message"),
+                    ],
+                ),
+                PushRule::with_conditions(
+                    ".m.rule.message",
+                    true,
+                    vec![notify(), highlight(false)],
+                    vec![PushCondition::event_match("type", "m.room.message")],
+                ),
+                PushRule::with_conditions(
+                    ".m.rule.encrypted",
+                    true,
+                    vec![notify(), highlight(false)],
+                    vec![PushCondition::event_match("type", "m.room.encrypted")],
+                ),
+            ],
+        }
+    }
+}
+
+impl PushRule {
+    /// A server-default override or underride rule.
+    fn with_conditions(
+        rule_id: &str,
+        enabled: bool,
+        actions: Vec<Action>,
+        conditions: Vec<PushCondition>,
+    ) -> Self {
+        Self {
+            actions,
+            conditions: Some(conditions),
+            default: true,
+            enabled,
+            pattern: None,
+            rule_id: rule_id.to_string(),
+        }
+    }
+}
+
+impl PushCondition {
+    /// A condition without parameters.
+    fn kind(kind: &str) -> Self {
+        Self {
+            kind: kind.to_string(),
+            key: None,
+            pattern: None,
+            is: None,
+        }
+    }
+
+    /// An "event_match" condition.
+    fn event_match(key: &str, pattern: &str) -> Self {
+        Self {
+            key: Some(key.to_string()),
+            pattern: Some(pattern.to_string()),
+            ..Self::kind("event_match")
+        }
+    }
+
+    /// A "room_member_count" condition.
+    fn room_member_count(is: &str) -> Self {
+        Self {
+            is: Some(is.to_string()),
+            ..Self::kind("room_member_count")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use crate::test::Test;
+
+    #[test]
+    fn default_push_rules() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/pushrules/?access_token={}",
+            user.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let global = response.json().get("global").unwrap();
+        for kind in &["content", "override", "room", "sender", "underride"] {
+            assert!(global.get(kind).unwrap().is_array(), "{} is missing", kind);
+        }
+
+        let content_rule = global.pointer("/content/0").unwrap();
+        assert_eq!(
+            content_rule.get("rule_id").unwrap().as_str().unwrap(),
+            ".m.rule.contains_user_name"
+        );
+        assert_eq!(
+            content_rule.get("pattern").unwrap().as_str().unwrap(),
+            user.id.trim_start_matches('@').split(':').next().unwrap()
+        );
+
+        let master_rule = global.pointer("/override/0").unwrap();
+        assert_eq!(
+            master_rule.get("rule_id").unwrap().as_str().unwrap(),
+            ".m.rule.master"
+        );
+        assert!(!master_rule.get("enabled").unwrap().as_bool().unwrap());
+    }
+
+    #[test]
+    fn push_rules_require_authentication() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/r0/pushrules/");
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
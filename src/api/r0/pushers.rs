@@ -1,12 +1,11 @@
 //! Endpoints for pushers.
-use bodyparser;
 use iron::status::Status;
-use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use iron::{Chain, Handler, IronResult, Request, Response};
 use serde_json::{from_value, Value};
 
 use crate::db::DB;
 use crate::error::{ApiError, MapApiError};
-use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
+use crate::middleware::{parse_body, AccessTokenAuth, JsonRequest, MiddlewareChain};
 use crate::models::pusher::{Pusher, PusherOptions};
 use crate::models::user::User;
 use crate::modifier::{EmptyResponse, SerializableResponse};
@@ -58,10 +57,7 @@ impl Handler for SetPushers {
             .expect("AccessTokenAuth should ensure a user")
             .clone();
 
-        let value: Value = match request.get::<bodyparser::Struct<Value>>() {
-            Ok(Some(request)) => request,
-            Ok(None) | Err(_) => Err(IronError::from(ApiError::bad_json(None)))?,
-        };
+        let value: Value = parse_body(request)?;
 
         let connection = DB::from_request(request)?;
 
@@ -77,7 +73,13 @@ impl Handler for SetPushers {
             let app_id = app_id.as_str().ok_or_else(|| {
                 ApiError::bad_json("The app_id parameter should be a string".to_string())
             })?;
-            Pusher::delete(&connection, &user.id, app_id)?;
+            let pushkey = match value.get("pushkey") {
+                Some(pushkey) => Some(pushkey.as_str().ok_or_else(|| {
+                    ApiError::bad_json("The pushkey parameter should be a string".to_string())
+                })?),
+                None => None,
+            };
+            Pusher::delete(&connection, &user.id, app_id, pushkey)?;
         } else {
             let pusher_options = from_value(value).map_api_err(ApiError::from)?;
             Pusher::upsert(&connection, &user.id, &pusher_options)?;
@@ -300,4 +302,89 @@ mod tests {
         let json = response.json();
         assert_eq!(json.get("pushers").unwrap().as_array().unwrap().len(), 1);
     }
+
+    #[test]
+    fn pushers_with_different_pushkeys() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let mut options = PusherOptions {
+            lang: "en".to_string(),
+            kind: "http".to_string(),
+            data: PusherData {
+                url: Some("test.de".to_string()),
+            },
+            device_display_name: "phone".to_string(),
+            app_id: "device".to_string(),
+            profile_tag: None,
+            pushkey: "phone".to_string(),
+            app_display_name: "device".to_string(),
+            append: false,
+        };
+
+        let response = test.set_pusher(&carl.token, options.clone());
+        assert_eq!(response.status, Status::Ok);
+
+        options.device_display_name = "tablet".to_string();
+        options.pushkey = "tablet".to_string();
+        let response = test.set_pusher(&carl.token, options.clone());
+        assert_eq!(response.status, Status::Ok);
+
+        let get_pusher = format!("/_matrix/client/r0/pushers?access_token={}", carl.token);
+        let response = test.get(&get_pusher);
+        assert_eq!(response.status, Status::Ok);
+        let mut pushkeys: Vec<&str> = response
+            .json()
+            .get("pushers")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|pusher| pusher.get("pushkey").unwrap().as_str().unwrap())
+            .collect();
+        pushkeys.sort();
+        assert_eq!(pushkeys, vec!["phone", "tablet"]);
+    }
+
+    #[test]
+    fn delete_pusher_by_pushkey() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let mut options = PusherOptions {
+            lang: "en".to_string(),
+            kind: "http".to_string(),
+            data: PusherData {
+                url: Some("test.de".to_string()),
+            },
+            device_display_name: "phone".to_string(),
+            app_id: "device".to_string(),
+            profile_tag: None,
+            pushkey: "phone".to_string(),
+            app_display_name: "device".to_string(),
+            append: false,
+        };
+
+        let response = test.set_pusher(&carl.token, options.clone());
+        assert_eq!(response.status, Status::Ok);
+
+        options.pushkey = "tablet".to_string();
+        let response = test.set_pusher(&carl.token, options.clone());
+        assert_eq!(response.status, Status::Ok);
+
+        let post_pusher = format!("/_matrix/client/r0/pushers/set?access_token={}", carl.token);
+        let response = test.post(
+            &post_pusher,
+            r#"{"kind": null, "app_id": "device", "pushkey": "phone"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let get_pusher = format!("/_matrix/client/r0/pushers?access_token={}", carl.token);
+        let response = test.get(&get_pusher);
+        assert_eq!(response.status, Status::Ok);
+        let pushers = response.json().get("pushers").unwrap().as_array().unwrap();
+        assert_eq!(pushers.len(), 1);
+        assert_eq!(
+            pushers[0].get("pushkey").unwrap().as_str().unwrap(),
+            "tablet"
+        );
+    }
 }
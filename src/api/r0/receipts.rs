@@ -0,0 +1,186 @@
+//! Endpoints for read receipts.
+
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Request, Response};
+use router::Router;
+
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{AccessTokenAuth, EventIdParam, MiddlewareChain, RoomIdParam};
+use crate::models::event::Event;
+use crate::models::receipt::{NewReceipt, Receipt};
+use crate::models::room_membership::RoomMembership;
+use crate::models::user::User;
+use crate::modifier::EmptyResponse;
+
+/// The only receipt type defined by the spec.
+const READ_RECEIPT: &str = "m.read";
+
+/// The `/rooms/:room_id/receipt/:receipt_type/:event_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct PostReceipt;
+
+middleware_chain!(PostReceipt, [RoomIdParam, EventIdParam, AccessTokenAuth]);
+
+impl Handler for PostReceipt {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a room_id")
+            .clone();
+
+        let event_id = request
+            .extensions
+            .get::<EventIdParam>()
+            .expect("EventIdParam should ensure an event_id")
+            .clone();
+
+        let receipt_type = request
+            .extensions
+            .get::<Router>()
+            .expect("Params object is missing")
+            .find("receipt_type")
+            .unwrap_or("")
+            .to_string();
+
+        if receipt_type != READ_RECEIPT {
+            Err(ApiError::invalid_param(
+                "receipt_type",
+                "Only m.read receipts are supported",
+            ))?
+        }
+
+        let connection = DB::from_request(request)?;
+
+        let membership = RoomMembership::find(&connection, &room_id, &user.id)?;
+
+        if membership.map(|membership| membership.membership) != Some("join".to_string()) {
+            Err(ApiError::unauthorized(
+                "The user is not a member of the room".to_string(),
+            ))?
+        }
+
+        match Event::find(&connection, &event_id)? {
+            Some(ref event) if event.room_id.as_ref() == Some(&room_id) => {}
+            _ => Err(ApiError::not_found(format!(
+                "The room has no event with ID {}",
+                event_id
+            )))?,
+        }
+
+        Receipt::upsert(
+            &connection,
+            &NewReceipt {
+                room_id,
+                user_id: user.id,
+                receipt_type,
+                event_id,
+            },
+        )?;
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use iron::status::Status;
+    use ruma_identifiers::{EventId, RoomId, UserId};
+
+    use crate::models::event::Event;
+    use crate::models::receipt::Receipt;
+    use crate::test::Test;
+
+    fn latest_event_id(test: &Test, room_id: &str) -> EventId {
+        let room_id = RoomId::try_from(room_id).unwrap();
+
+        Event::find_room_events(&test.connection(), &room_id, 0)
+            .unwrap()
+            .pop()
+            .unwrap()
+            .id
+    }
+
+    #[test]
+    fn post_read_receipt() {
+        let test = Test::new();
+        let (user, room_id) = test.initial_fixtures("{}");
+
+        assert!(test
+            .send_message(&user.token, &room_id, "Hi", 1)
+            .status
+            .is_success());
+        let event_id = latest_event_id(&test, &room_id);
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/receipt/m.read/{}?access_token={}",
+                room_id, event_id, user.token
+            ),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.body, "{}");
+
+        let receipt = Receipt::find(
+            &test.connection(),
+            &RoomId::try_from(room_id.as_ref()).unwrap(),
+            &UserId::try_from(user.id.as_ref()).unwrap(),
+            "m.read",
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(receipt.event_id, event_id);
+    }
+
+    #[test]
+    fn post_read_receipt_forbidden_for_non_members() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+        let bob = test.create_user();
+
+        assert!(test
+            .send_message(&alice.token, &room_id, "Hi", 1)
+            .status
+            .is_success());
+        let event_id = latest_event_id(&test, &room_id);
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/receipt/m.read/{}?access_token={}",
+                room_id, event_id, bob.token
+            ),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn post_receipt_of_unknown_type() {
+        let test = Test::new();
+        let (user, room_id) = test.initial_fixtures("{}");
+        let event_id = latest_event_id(&test, &room_id);
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/receipt/m.unknown/{}?access_token={}",
+                room_id, event_id, user.token
+            ),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+}
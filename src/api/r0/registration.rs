@@ -1,21 +1,25 @@
 //! Endpoints for user account registration.
 
-use std::convert::TryFrom;
 use std::fmt::{Formatter, Result as FmtResult};
 
-use bodyparser;
-use iron::{status, Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use diesel::Connection;
+use iron::{status, Chain, Handler, IronError, IronResult, Request, Response};
 use ruma_identifiers::UserId;
 use serde::de::{Deserialize, Deserializer, Error as SerdeError, Visitor};
+use url::Url;
 
+use crate::api::r0::login::DiscoveryInformation;
 use crate::config::Config;
-use crate::crypto::hash_password;
+use crate::crypto::{generate_device_id, hash_password};
 use crate::db::DB;
-use crate::error::ApiError;
-use crate::middleware::{JsonRequest, MiddlewareChain};
+use crate::error::{ApiError, ApiErrorCode};
+use crate::hash_limiter::HashLimiter;
+use crate::middleware::{parse_body, JsonRequest, MiddlewareChain, RateLimited};
 use crate::models::profile::Profile;
+use crate::models::registration_token::RegistrationToken;
 use crate::models::user::{NewUser, User};
 use crate::modifier::SerializableResponse;
+use crate::rate_limiter::RateLimitedAction;
 
 /// The `/register` endpoint.
 #[derive(Clone, Copy, Debug)]
@@ -28,8 +32,13 @@ struct RegistrationRequest {
     pub bind_email: Option<bool>,
     /// The kind of account to register. Defaults to user. One of: ["guest", "user"]
     pub kind: Option<RegistrationKind>,
+    /// The ID of the client device. If omitted, the homeserver generates one.
+    pub device_id: Option<String>,
     /// The desired password for the account.
     pub password: String,
+    /// The token that allows registering an account, if `Config::require_registration_token`
+    /// is enabled.
+    pub registration_token: Option<String>,
     /// The local part of the desired Matrix ID. If omitted, the homeserver
     /// MUST generate a Matrix ID local part.
     pub username: Option<String>,
@@ -49,13 +58,21 @@ enum RegistrationKind {
 struct RegistrationResponse {
     /// An access token for the account. This access token can then be used to authorize other requests.
     pub access_token: String,
+    /// The ID of the device the access token was issued to.
+    pub device_id: String,
     /// The hostname of the homeserver on which the account has been registered.
     pub home_server: String,
     /// The fully-qualified Matrix ID that has been registered.
     pub user_id: UserId,
+    /// Information the client should use to reach the homeserver from now on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub well_known: Option<DiscoveryInformation>,
 }
 
-middleware_chain!(Register, [JsonRequest]);
+middleware_chain!(
+    Register,
+    [RateLimited(RateLimitedAction::Registration), JsonRequest]
+);
 
 impl<'de> Deserialize<'de> for RegistrationKind {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
@@ -92,10 +109,7 @@ impl<'de> Deserialize<'de> for RegistrationKind {
 
 impl Handler for Register {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let registration_request = match request.get::<bodyparser::Struct<RegistrationRequest>>() {
-            Ok(Some(registration_request)) => registration_request,
-            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
-        };
+        let registration_request = parse_body::<RegistrationRequest>(request)?;
 
         if let Some(kind) = registration_request.kind {
             if let RegistrationKind::Guest = kind {
@@ -105,50 +119,203 @@ impl Handler for Register {
 
         let config = Config::from_request(request)?;
 
-        let new_user = NewUser {
-            id: match registration_request.username {
-                Some(username) => {
-                    UserId::try_from(format!("@{}:{}", username, &config.domain).as_ref())
-                        .map_err(ApiError::from)?
-                }
-                None => UserId::new(&config.domain).map_err(ApiError::from)?,
-            },
-            password_hash: hash_password(&registration_request.password)?,
+        if config.require_registration_token && registration_request.registration_token.is_none() {
+            Err(ApiError::invalid_token(
+                "A registration token is required to register".to_string(),
+            ))?;
+        }
+
+        // User IDs always have a lowercase localpart, so keep the username as it was given as
+        // the display name.
+        let displayname = match registration_request.username {
+            Some(ref username)
+                if config.keep_username_as_displayname
+                    && username.chars().any(char::is_uppercase) =>
+            {
+                Some(username.clone())
+            }
+            _ => None,
+        };
+
+        let password_hash = {
+            let _permit = HashLimiter::from_request(request)?.acquire()?;
+
+            hash_password(&registration_request.password)?
         };
 
         let connection = DB::from_request(request)?;
 
-        if User::find_registered_user(&connection, &new_user.id)?.is_some() {
-            let error = ApiError::unauthorized("This user_id already exists".to_string());
+        let device_id = match registration_request.device_id {
+            Some(device_id) => device_id,
+            None => generate_device_id()?,
+        };
 
-            return Err(IronError::from(error));
-        }
+        let registration_token = registration_request.registration_token;
 
-        let (user, access_token) =
-            User::create(&connection, &new_user, &config.macaroon_secret_key)?;
+        // The token is only used up, and a deactivated user replaced, if the registration
+        // succeeds.
+        let register = |user_id: UserId, replace_deactivated: bool| {
+            let new_user = NewUser {
+                id: user_id,
+                password_hash: password_hash.clone(),
+            };
 
-        let new_profile = Profile {
-            id: user.id.clone(),
-            avatar_url: None,
-            displayname: None,
+            connection.transaction::<_, ApiError, _>(|| {
+                if config.require_registration_token {
+                    if let Some(ref token) = registration_token {
+                        RegistrationToken::redeem(&connection, token)?;
+                    }
+                }
+
+                if replace_deactivated {
+                    User::delete_deactivated(&connection, &new_user.id)?;
+                }
+
+                let (user, access_token) =
+                    User::create(&connection, &new_user, &device_id, &config)?;
+
+                let new_profile = Profile {
+                    id: user.id.clone(),
+                    avatar_url: None,
+                    displayname: displayname.clone(),
+                };
+
+                Profile::create(&connection, &new_profile)?;
+
+                Ok((user, access_token))
+            })
         };
 
-        Profile::create(&connection, &new_profile)?;
+        let (user, access_token) = match registration_request.username {
+            Some(username) => {
+                let user_id = User::local_id(&username, "username", &config)?;
+
+                let replace_deactivated = match User::find_registered_user(&connection, &user_id)? {
+                    Some(ref existing_user)
+                        if existing_user.active || !config.reuse_deactivated_localparts =>
+                    {
+                        let error =
+                            ApiError::unauthorized("This user_id already exists".to_string());
+
+                        return Err(IronError::from(error));
+                    }
+                    Some(_) => true,
+                    None => false,
+                };
+
+                register(user_id, replace_deactivated)?
+            }
+            None => random_user_id(
+                config.random_user_id_retries,
+                // This only fails if the configured domain isn't a valid server name.
+                || UserId::new(&config.domain).map_err(|_| ApiError::unknown(None)),
+                |user_id| register(user_id, false),
+            )?,
+        };
 
         let response = RegistrationResponse {
             access_token: access_token.value,
+            device_id: access_token.device_id,
             home_server: config.domain.clone(),
             user_id: user.id,
+            well_known: DiscoveryInformation::from_config(&config),
         };
 
         Ok(Response::with((status::Ok, SerializableResponse(response))))
     }
 }
 
+/// Registers a generated user ID with `register`, generating another one up to `retries` times
+/// if `register` fails with `M_USER_IN_USE`.
+///
+/// The user ID isn't checked beforehand, since a concurrent registration could take it between
+/// the check and the insert.
+fn random_user_id<G, R, T>(retries: u32, mut generate: G, mut register: R) -> Result<T, ApiError>
+where
+    G: FnMut() -> Result<UserId, ApiError>,
+    R: FnMut(UserId) -> Result<T, ApiError>,
+{
+    for _ in 0..=retries {
+        let user_id = generate()?;
+        let generated_user_id = user_id.to_string();
+
+        match register(user_id) {
+            Err(ref error) if error.errcode() == ApiErrorCode::UserInUse => {
+                debug!(
+                    "The generated user ID {} is already taken",
+                    generated_user_id
+                );
+            }
+            result => return result,
+        }
+    }
+
+    Err(ApiError::unknown(
+        "Failed to generate a user ID that isn't taken".to_string(),
+    ))
+}
+
+/// The `/register/available` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct RegisterAvailable;
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct RegisterAvailableResponse {
+    /// Whether or not the username is available. Only ever true, an error is returned otherwise.
+    pub available: bool,
+}
+
+impl Handler for RegisterAvailable {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let url: Url = request.url.clone().into();
+
+        let username = match url.query_pairs().find(|(key, _)| key == "username") {
+            Some((_, username)) => username.into_owned(),
+            None => Err(ApiError::missing_param("username"))?,
+        };
+
+        let config = Config::from_request(request)?;
+
+        let user_id = User::local_id(&username, "username", &config)?;
+
+        let connection = DB::from_request(request)?;
+
+        if let Some(user) = User::find_registered_user(&connection, &user_id)? {
+            if user.active || !config.reuse_deactivated_localparts {
+                Err(ApiError::user_in_use(None))?;
+            }
+
+            User::ensure_reusable(&connection, &user_id)?;
+        }
+
+        let response = RegisterAvailableResponse { available: true };
+
+        Ok(Response::with((status::Ok, SerializableResponse(response))))
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::test::Test;
+    use std::convert::TryFrom;
+
+    use super::random_user_id;
+    use crate::config::RateLimit;
+    use crate::error::ApiError;
+    use crate::models::access_token::AccessToken;
+    use crate::models::account_data::AccountData;
+    use crate::models::registration_token::RegistrationToken;
+    use crate::models::room_membership::RoomMembership;
+    use crate::models::user::User;
+    use crate::schema::{access_tokens, filters, profiles, registration_tokens, room_tags};
+    use crate::test::{Response, Test};
+    use diesel::connection::SimpleConnection;
+    use diesel::pg::data_types::PgTimestamp;
+    use diesel::prelude::*;
+    use diesel::result::Error as DieselError;
+    use diesel::sql_query;
     use iron::status::Status;
+    use ruma_identifiers::UserId;
 
     #[test]
     fn minimum_input_parameters() {
@@ -227,4 +394,825 @@ mod tests {
             "This user_id already exists"
         );
     }
+
+    #[test]
+    fn uppercase_username_is_kept_as_displayname() {
+        let test = Test::with_config(|config| config.keep_username_as_displayname = true);
+
+        let response = test.register_user(r#"{"username": "Carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("user_id").unwrap().as_str().unwrap(),
+            "@carl:ruma.test"
+        );
+
+        let access_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/profile/@carl:ruma.test/displayname?access_token={}",
+            access_token
+        ));
+        assert_eq!(
+            response
+                .json()
+                .get("displayname")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Carl"
+        );
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "This user_id already exists"
+        );
+    }
+
+    #[test]
+    fn uppercase_username_is_not_kept_by_default() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "Carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("user_id").unwrap().as_str().unwrap(),
+            "@carl:ruma.test"
+        );
+
+        let access_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        // The username isn't kept as the display name.
+        let response = test.get(&format!(
+            "/_matrix/client/r0/profile/@carl:ruma.test/displayname?access_token={}",
+            access_token
+        ));
+        assert_eq!(response.status, Status::NotFound);
+
+        // User IDs can't hold uppercase localparts, so "carl" is the same user as "Carl".
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    /// Registers "carl" and deactivates the account.
+    fn register_and_deactivate_carl(test: &Test) {
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        let access_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/account/deactivate?access_token={}",
+                access_token
+            ),
+            "{}",
+        );
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    /// A localpart that makes the user ID `@<localpart>:ruma.test` exactly `length` bytes long.
+    fn localpart_for_user_id_length(length: usize) -> String {
+        "a".repeat(length - "@:ruma.test".len())
+    }
+
+    #[test]
+    fn user_id_at_length_limit() {
+        let test = Test::new();
+        let localpart = localpart_for_user_id_length(255);
+
+        let response = test.register_user(&format!(
+            r#"{{"username": "{}", "password": "secret"}}"#,
+            localpart
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .get("user_id")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .len(),
+            255
+        );
+    }
+
+    #[test]
+    fn user_id_over_length_limit() {
+        let test = Test::new();
+        let localpart = localpart_for_user_id_length(256);
+
+        let response = test.register_user(&format!(
+            r#"{{"username": "{}", "password": "secret"}}"#,
+            localpart
+        ));
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_USERNAME"
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/register/available?username={}",
+            localpart
+        ));
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_USERNAME"
+        );
+    }
+
+    #[test]
+    fn user_id_length_limit_is_configurable() {
+        let test = Test::with_config(|config| config.max_user_id_length = 20);
+
+        let response = test.register_user(&format!(
+            r#"{{"username": "{}", "password": "secret"}}"#,
+            localpart_for_user_id_length(21)
+        ));
+        assert_eq!(response.status, Status::BadRequest);
+
+        let response = test.register_user(&format!(
+            r#"{{"username": "{}", "password": "secret"}}"#,
+            localpart_for_user_id_length(20)
+        ));
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn available_username() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/r0/register/available?username=carl");
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("available").unwrap().as_bool().unwrap());
+    }
+
+    #[test]
+    fn registered_username_unavailable() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get("/_matrix/client/r0/register/available?username=carl");
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_USER_IN_USE"
+        );
+    }
+
+    #[test]
+    fn deactivated_username_unavailable() {
+        let test = Test::new();
+
+        register_and_deactivate_carl(&test);
+
+        let response = test.get("/_matrix/client/r0/register/available?username=carl");
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_USER_IN_USE"
+        );
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn deactivated_username_reusable() {
+        let test = Test::with_config(|config| config.reuse_deactivated_localparts = true);
+
+        register_and_deactivate_carl(&test);
+
+        let response = test.get("/_matrix/client/r0/register/available?username=carl");
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("available").unwrap().as_bool().unwrap());
+
+        let response = test.register_user(r#"{"username": "carl", "password": "new_secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "carl", "password": "new_secret"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn reused_user_id_inherits_nothing() {
+        let test = Test::with_config(|config| config.reuse_deactivated_localparts = true);
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        let access_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let room_id = test.create_room(&access_token);
+        test.create_tag(&access_token, &room_id, "@carl:ruma.test", "work", "{}");
+        assert_eq!(test.leave_room(&access_token, &room_id).status, Status::Ok);
+        test.create_filter(&access_token, "@carl:ruma.test", "{}");
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/user/@carl:ruma.test/account_data/org.ruma.test?access_token={}",
+                access_token
+            ),
+            r#"{"secret": true}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/account/deactivate?access_token={}",
+                access_token
+            ),
+            "{}",
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.register_user(r#"{"username": "carl", "password": "new_secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let connection = test.connection();
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+
+        assert!(RoomMembership::find_all_by_uid(&connection, &user_id)
+            .unwrap()
+            .is_empty());
+        assert!(AccountData::get_by_uid(&connection, &user_id)
+            .unwrap()
+            .is_empty());
+
+        let tags: i64 = room_tags::table
+            .filter(room_tags::user_id.eq(&user_id))
+            .count()
+            .get_result(&*connection)
+            .unwrap();
+        assert_eq!(tags, 0);
+
+        let filters: i64 = filters::table
+            .filter(filters::user_id.eq(&user_id))
+            .count()
+            .get_result(&*connection)
+            .unwrap();
+        assert_eq!(filters, 0);
+    }
+
+    #[test]
+    fn failed_registration_keeps_the_deactivated_user() {
+        let test = Test::with_config(|config| config.reuse_deactivated_localparts = true);
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        let access_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        test.create_filter(&access_token, "@carl:ruma.test", "{}");
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/account/deactivate?access_token={}",
+                access_token
+            ),
+            "{}",
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        // Make creating the new user's profile fail after the old user was deleted.
+        test.connection()
+            .batch_execute(
+                "CREATE FUNCTION pg_temp.fail_profile_creation() RETURNS trigger AS $$
+                 BEGIN
+                     RAISE EXCEPTION 'profile creation failed';
+                 END;
+                 $$ LANGUAGE plpgsql;
+                 CREATE TRIGGER fail_profile_creation BEFORE INSERT ON profiles
+                     FOR EACH ROW EXECUTE PROCEDURE pg_temp.fail_profile_creation();",
+            )
+            .unwrap();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "new_secret"}"#);
+        assert_eq!(response.status, Status::InternalServerError);
+
+        let connection = test.connection();
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+
+        let user = User::find_registered_user(&connection, &user_id)
+            .unwrap()
+            .unwrap();
+        assert!(!user.active);
+
+        let filters: i64 = filters::table
+            .filter(filters::user_id.eq(&user_id))
+            .count()
+            .get_result(&*connection)
+            .unwrap();
+        assert_eq!(filters, 1);
+    }
+
+    #[test]
+    fn deactivated_room_member_is_not_reusable() {
+        let test = Test::with_config(|config| config.reuse_deactivated_localparts = true);
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        let access_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        test.create_room(&access_token);
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/account/deactivate?access_token={}",
+                access_token
+            ),
+            "{}",
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get("/_matrix/client/r0/register/available?username=carl");
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_USER_IN_USE"
+        );
+
+        let response = test.register_user(r#"{"username": "carl", "password": "new_secret"}"#);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_USER_IN_USE"
+        );
+    }
+
+    #[test]
+    fn home_server_is_configured_domain() {
+        let test = Test::with_config(|config| config.domain = "example.org".to_string());
+
+        let response = test.register_user(
+            r#"{"username": "carl", "password": "secret", "device_id": "CARLPHONE"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let registration = response.json().clone();
+        assert_eq!(
+            registration.get("home_server").unwrap().as_str().unwrap(),
+            "example.org"
+        );
+        assert_eq!(
+            registration.get("user_id").unwrap().as_str().unwrap(),
+            "@carl:example.org"
+        );
+        assert_eq!(
+            registration.get("device_id").unwrap().as_str().unwrap(),
+            "CARLPHONE"
+        );
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "carl", "password": "secret"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let login = response.json();
+        assert_eq!(login.get("home_server"), registration.get("home_server"));
+        assert_eq!(login.get("user_id"), registration.get("user_id"));
+        assert!(login.get("device_id").unwrap().as_str().is_some());
+    }
+
+    #[test]
+    fn access_token_user_id_matches_user_id() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap();
+        let connection = test.connection();
+
+        let access_token = AccessToken::authenticate(&connection, token, test.config())
+            .unwrap()
+            .access_token;
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+        let user = User::find_registered_user(&connection, &user_id)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(access_token.user_id.to_string(), "@carl:ruma.test");
+        assert_eq!(access_token.user_id, user.id);
+    }
+
+    #[test]
+    fn bare_localparts_cannot_be_stored() {
+        let test = Test::new();
+        let connection = test.connection();
+
+        // Each insert runs in a savepoint so a failed one doesn't abort the test transaction.
+        let insert = |query: &str| {
+            connection.transaction::<_, DieselError, _>(|| sql_query(query).execute(&*connection))
+        };
+
+        assert!(insert("INSERT INTO users (id, password_hash) VALUES ('carl', 'hash')").is_err());
+        assert!(insert(
+            "INSERT INTO access_tokens (user_id, value, device_id) VALUES ('carl', 'token', 'CARL')"
+        )
+        .is_err());
+
+        assert!(
+            insert("INSERT INTO users (id, password_hash) VALUES ('@carl:ruma.test', 'hash')")
+                .is_ok()
+        );
+        assert!(insert(
+            "INSERT INTO access_tokens (user_id, value, device_id) \
+             VALUES ('@carl:ruma.test', 'token', 'CARL')"
+        )
+        .is_ok());
+    }
+
+    fn register_with_token(test: &Test, username: &str, token: Option<&str>) -> Response {
+        let token = match token {
+            Some(token) => format!(r#", "registration_token": "{}""#, token),
+            None => String::new(),
+        };
+
+        test.register_user(&format!(
+            r#"{{"username": "{}", "password": "secret"{}}}"#,
+            username, token
+        ))
+    }
+
+    fn assert_invalid_token(response: &Response) {
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_TOKEN"
+        );
+    }
+
+    #[test]
+    fn registration_with_token() {
+        let test = Test::with_config(|config| config.require_registration_token = true);
+        let token = RegistrationToken::create(&test.connection(), Some(2), None)
+            .unwrap()
+            .token;
+
+        let response = register_with_token(&test, "carl", Some(&token));
+        assert_eq!(response.status, Status::Ok);
+
+        let uses_remaining: Option<i32> = registration_tokens::table
+            .find(&token)
+            .select(registration_tokens::uses_remaining)
+            .first(&*test.connection())
+            .unwrap();
+        assert_eq!(uses_remaining, Some(1));
+    }
+
+    #[test]
+    fn registration_without_valid_token() {
+        let test = Test::with_config(|config| config.require_registration_token = true);
+        let expired = RegistrationToken::create(&test.connection(), None, Some(PgTimestamp(0)))
+            .unwrap()
+            .token;
+
+        assert_invalid_token(&register_with_token(&test, "carl", None));
+        assert_invalid_token(&register_with_token(&test, "carl", Some("unknown")));
+        assert_invalid_token(&register_with_token(&test, "carl", Some(&expired)));
+
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+        assert!(User::find_registered_user(&test.connection(), &user_id)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn exhausted_token() {
+        let test = Test::with_config(|config| config.require_registration_token = true);
+        let token = RegistrationToken::create(&test.connection(), Some(1), None)
+            .unwrap()
+            .token;
+
+        let response = register_with_token(&test, "carl", Some(&token));
+        assert_eq!(response.status, Status::Ok);
+
+        assert_invalid_token(&register_with_token(&test, "dave", Some(&token)));
+    }
+
+    #[test]
+    fn invalid_token_keeps_the_deactivated_user() {
+        let test = Test::with_config(|config| {
+            config.require_registration_token = true;
+            config.reuse_deactivated_localparts = true;
+        });
+        let token = RegistrationToken::create(&test.connection(), Some(1), None)
+            .unwrap()
+            .token;
+
+        let response = register_with_token(&test, "carl", Some(&token));
+        assert_eq!(response.status, Status::Ok);
+        let access_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        test.create_filter(&access_token, "@carl:ruma.test", "{}");
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/account/deactivate?access_token={}",
+                access_token
+            ),
+            "{}",
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        assert_invalid_token(&register_with_token(&test, "carl", Some("unknown")));
+        assert_invalid_token(&register_with_token(&test, "carl", Some(&token)));
+
+        let connection = test.connection();
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+
+        let user = User::find_registered_user(&connection, &user_id)
+            .unwrap()
+            .unwrap();
+        assert!(!user.active);
+
+        let filters: i64 = filters::table
+            .filter(filters::user_id.eq(&user_id))
+            .count()
+            .get_result(&*connection)
+            .unwrap();
+        assert_eq!(filters, 1);
+
+        let access_tokens: i64 = access_tokens::table
+            .filter(access_tokens::user_id.eq(&user_id))
+            .count()
+            .get_result(&*connection)
+            .unwrap();
+        assert_eq!(access_tokens, 1);
+
+        let profiles: i64 = profiles::table
+            .filter(profiles::id.eq(&user_id))
+            .count()
+            .get_result(&*connection)
+            .unwrap();
+        assert_eq!(profiles, 1);
+    }
+
+    #[test]
+    fn token_not_required() {
+        let test = Test::new();
+
+        let response = register_with_token(&test, "carl", None);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn malformed_username() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl smith", "password": "secret"}"#);
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn random_user_id_retries_taken_user_ids() {
+        let test = Test::new();
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let mut candidates = vec!["@erin:ruma.test", "@carl:ruma.test"];
+        let mut generated = 0;
+
+        let user_id = random_user_id(
+            3,
+            || {
+                generated += 1;
+                Ok(UserId::try_from(candidates.pop().unwrap()).unwrap())
+            },
+            |user_id| match User::find_registered_user(&test.connection(), &user_id)? {
+                Some(_) => Err(ApiError::user_in_use(None)),
+                None => Ok(user_id),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(user_id.to_string(), "@erin:ruma.test");
+        assert_eq!(generated, 2);
+    }
+
+    #[test]
+    fn random_user_id_gives_up() {
+        let test = Test::new();
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let mut generated = 0;
+
+        let error = random_user_id(
+            2,
+            || {
+                generated += 1;
+                Ok(UserId::try_from("@carl:ruma.test").unwrap())
+            },
+            |user_id| match User::find_registered_user(&test.connection(), &user_id)? {
+                Some(_) => Err(ApiError::user_in_use(None)),
+                None => Ok(user_id),
+            },
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Failed to generate a user ID that isn't taken"
+        );
+        assert_eq!(generated, 3);
+    }
+
+    #[test]
+    fn random_user_id_taken_during_registration_is_retried() {
+        let test = Test::new();
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        // Give the first generated user ID to carl when it is inserted, as if a concurrent
+        // registration had taken it after it was generated. Sequences aren't rolled back along
+        // with the failed insert, so only the first insert is changed.
+        test.connection()
+            .batch_execute(
+                "CREATE TEMPORARY SEQUENCE generated_user_ids;
+             CREATE FUNCTION pg_temp.take_generated_user_id() RETURNS trigger AS $$
+             BEGIN
+                 IF nextval('generated_user_ids') = 1 THEN
+                     NEW.id := '@carl:ruma.test';
+                 END IF;
+                 RETURN NEW;
+             END;
+             $$ LANGUAGE plpgsql;
+             CREATE TRIGGER take_generated_user_id BEFORE INSERT ON users
+                 FOR EACH ROW EXECUTE PROCEDURE pg_temp.take_generated_user_id();",
+            )
+            .unwrap();
+
+        let response = test.register_user(r#"{"password": "secret"}"#);
+
+        assert_eq!(response.status, Status::Ok);
+        let user_id = response.json().get("user_id").unwrap().as_str().unwrap();
+        assert_ne!(user_id, "@carl:ruma.test");
+
+        let user_id = UserId::try_from(user_id).unwrap();
+        assert!(User::find_registered_user(&test.connection(), &user_id)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn registration_rate_limit() {
+        let test = Test::with_config(|config| {
+            config.registration_rate_limit = Some(RateLimit {
+                requests: 2,
+                period_ms: 60_000,
+            });
+            config.login_rate_limit = Some(RateLimit {
+                requests: 5,
+                period_ms: 60_000,
+            });
+        });
+
+        let register = |username: &str| {
+            test.register_user(&format!(
+                r#"{{"username": "{}", "password": "secret"}}"#,
+                username
+            ))
+        };
+
+        assert_eq!(register("carl").status, Status::Ok);
+        assert_eq!(register("erin").status, Status::Ok);
+
+        let response = register("dave");
+        assert_eq!(response.status, Status::TooManyRequests);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_LIMIT_EXCEEDED"
+        );
+        let retry_after_ms = response
+            .json()
+            .get("retry_after_ms")
+            .unwrap()
+            .as_u64()
+            .unwrap();
+        assert!(retry_after_ms > 0 && retry_after_ms <= 60_000);
+
+        // Logging in is limited separately.
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "carl", "password": "secret"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn login_rate_limit_does_not_limit_registration() {
+        let test = Test::with_config(|config| {
+            config.login_rate_limit = Some(RateLimit {
+                requests: 1,
+                period_ms: 60_000,
+            });
+        });
+
+        let login = || {
+            test.post(
+                "/_matrix/client/r0/login",
+                r#"{"type": "m.login.password", "user": "carl", "password": "secret"}"#,
+            )
+        };
+
+        assert_eq!(login().status, Status::Forbidden);
+        assert_eq!(login().status, Status::TooManyRequests);
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn well_known_with_public_base_url() {
+        let test = Test::with_config(|config| {
+            config.public_base_url = Some("https://matrix.ruma.test".to_string())
+        });
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .pointer("/well_known/m.homeserver/base_url")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "https://matrix.ruma.test"
+        );
+    }
+
+    #[test]
+    fn no_well_known_without_public_base_url() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("well_known").is_none());
+    }
 }
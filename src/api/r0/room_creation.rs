@@ -2,10 +2,9 @@
 
 use std::convert::From;
 
-use bodyparser;
 use diesel::prelude::*;
 use iron::status::Status;
-use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::{Chain, Handler, IronResult, Request, Response};
 use ruma_events::stripped::StrippedState;
 use ruma_identifiers::{RoomId, UserId};
 use serde_json::{from_value, Value};
@@ -13,8 +12,10 @@ use serde_json::{from_value, Value};
 use crate::config::Config;
 use crate::db::DB;
 use crate::error::ApiError;
-use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
-use crate::models::room::{CreationOptions, NewRoom, Room, RoomPreset, RoomVisibility};
+use crate::middleware::{parse_body, AccessTokenAuth, JsonRequest, MiddlewareChain};
+use crate::models::room::{
+    CreationOptions, NewRoom, Room, RoomPreset, RoomVisibility, SUPPORTED_ROOM_VERSIONS,
+};
 use crate::models::room_membership::{RoomMembership, RoomMembershipOptions};
 use crate::models::user::User;
 use crate::modifier::SerializableResponse;
@@ -39,6 +40,8 @@ struct CreateRoomRequest {
     pub preset: Option<RoomPreset>,
     /// The desired room alias local part.
     pub room_alias_name: Option<String>,
+    /// The version of the room, defaulting to the server's configured default.
+    pub room_version: Option<String>,
     /// Indicates the room's topic.
     pub topic: Option<String>,
     /// Indicates whether or not that the room will be shown in the published room list.
@@ -75,12 +78,25 @@ impl Handler for CreateRoom {
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
 
+        let version = create_room_request
+            .room_version
+            .clone()
+            .unwrap_or_else(|| config.default_room_version.clone());
+
+        if !SUPPORTED_ROOM_VERSIONS.contains(&version.as_str()) {
+            Err(ApiError::unsupported_room_version(format!(
+                "Room version {} is not supported",
+                version
+            )))?;
+        }
+
         let new_room = NewRoom {
             id: RoomId::new(&config.domain).map_err(ApiError::from)?,
             user_id: user.id,
             public: create_room_request
                 .visibility
                 .map_or(false, |v| v == RoomVisibility::Public),
+            version,
         };
 
         let federate = match create_room_request.creation_content {
@@ -146,10 +162,7 @@ fn extract_create_room_request(
     request: &mut Request<'_, '_>,
     user_id: &UserId,
 ) -> Result<CreateRoomRequest, ApiError> {
-    let mut json = match request.get::<bodyparser::Json>() {
-        Ok(Some(json)) => json,
-        Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
-    };
+    let mut json = parse_body::<Value>(request)?;
 
     if let Some(Some(initial_state)) = json.get_mut("initial_state").map(|opt| opt.as_array_mut()) {
         let sender = Value::String(user_id.to_string());
@@ -167,8 +180,12 @@ fn extract_create_room_request(
 
 #[cfg(test)]
 mod tests {
+    use std::convert::TryFrom;
+
+    use crate::models::room::Room;
     use crate::test::Test;
     use iron::status::Status;
+    use ruma_identifiers::RoomId;
 
     #[test]
     fn no_parameters() {
@@ -207,6 +224,65 @@ mod tests {
         );
     }
 
+    #[test]
+    fn with_name_and_topic() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let room_id = test.create_room_with_params(
+            &user.token,
+            r#"{"name": "Ruma developers", "topic": "Building a homeserver"}"#,
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.name?access_token={}",
+            room_id, user.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("name").unwrap().as_str().unwrap(),
+            "Ruma developers"
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.topic?access_token={}",
+            room_id, user.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("topic").unwrap().as_str().unwrap(),
+            "Building a homeserver"
+        );
+    }
+
+    #[test]
+    fn name_takes_precedence_over_initial_state() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let room_id = test.create_room_with_params(
+            &user.token,
+            r#"{
+                "name": "Ruma developers",
+                "initial_state": [{
+                    "state_key": "",
+                    "type": "m.room.name",
+                    "content": { "name": "Something else" }
+                }]
+            }"#,
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.name?access_token={}",
+            room_id, user.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("name").unwrap().as_str().unwrap(),
+            "Ruma developers"
+        );
+    }
+
     #[test]
     fn with_public_visibility() {
         let test = Test::new();
@@ -231,6 +307,108 @@ mod tests {
         assert!(response.json().get("room_id").unwrap().as_str().is_some());
     }
 
+    #[test]
+    fn with_public_chat_preset() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let room_id = test.create_room_with_params(&user.token, r#"{"preset": "public_chat"}"#);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.join_rules?access_token={}",
+            room_id, user.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("join_rule").unwrap().as_str().unwrap(),
+            "public"
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.history_visibility?access_token={}",
+            room_id, user.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .get("history_visibility")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "shared"
+        );
+    }
+
+    #[test]
+    fn with_private_chat_preset() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_id = test.create_room_with_params(&alice.token, r#"{"preset": "private_chat"}"#);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.join_rules?access_token={}",
+            room_id, alice.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("join_rule").unwrap().as_str().unwrap(),
+            "invite"
+        );
+
+        // Bob can't join without an invite.
+        assert_eq!(
+            test.join_room(&bob.token, &room_id).status,
+            Status::Forbidden
+        );
+    }
+
+    #[test]
+    fn with_trusted_private_chat_preset() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let room_id =
+            test.create_room_with_params(&user.token, r#"{"preset": "trusted_private_chat"}"#);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.join_rules?access_token={}",
+            room_id, user.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("join_rule").unwrap().as_str().unwrap(),
+            "invite"
+        );
+    }
+
+    #[test]
+    fn preset_defaults_to_visibility() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let room_id = test.create_room_with_params(&user.token, r#"{"visibility": "public"}"#);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.join_rules?access_token={}",
+            room_id, user.token
+        ));
+        assert_eq!(
+            response.json().get("join_rule").unwrap().as_str().unwrap(),
+            "public"
+        );
+
+        let room = Room::find(
+            &test.connection(),
+            &RoomId::try_from(room_id.as_ref()).unwrap(),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(room.public);
+    }
+
     #[test]
     fn with_invalid_visibility() {
         let test = Test::new();
@@ -487,6 +665,82 @@ mod tests {
         assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
     }
 
+    #[test]
+    fn default_room_version() {
+        let test = Test::with_config(|config| config.default_room_version = "2".to_string());
+        let user = test.create_user();
+
+        let room_id = test.create_room(&user.token);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.create?access_token={}",
+            room_id, user.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .get("room_version")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "2"
+        );
+
+        let room = Room::find(
+            &test.connection(),
+            &RoomId::try_from(room_id.as_ref()).unwrap(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(room.version, "2");
+    }
+
+    #[test]
+    fn with_room_version() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let room_id = test.create_room_with_params(&user.token, r#"{"room_version": "2"}"#);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.create?access_token={}",
+            room_id, user.token
+        ));
+        assert_eq!(
+            response
+                .json()
+                .get("room_version")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "2"
+        );
+
+        let room = Room::find(
+            &test.connection(),
+            &RoomId::try_from(room_id.as_ref()).unwrap(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(room.version, "2");
+    }
+
+    #[test]
+    fn with_unsupported_room_version() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}", user.token);
+
+        let response = test.post(&create_room_path, r#"{"room_version": "999"}"#);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNSUPPORTED_ROOM_VERSION"
+        );
+    }
+
     #[test]
     fn with_increased_power_levels_in_trusted_chats_by_default() {
         let test = Test::new();
@@ -1,14 +1,16 @@
-//! Endpoint for retrieving the state of a room.
+//! Endpoints for retrieving the state of a room.
 
 use std::convert::TryInto;
 
 use iron::status::Status;
 use iron::{Chain, Handler, IronResult, Request, Response};
+use router::Router;
 use ruma_events::collections::all::StateEvent;
+use serde_json::{from_str, Value};
 
 use crate::db::DB;
 use crate::error::ApiError;
-use crate::middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use crate::middleware::{AccessTokenAuth, EventTypeParam, MiddlewareChain, RoomIdParam};
 use crate::models::event::Event;
 use crate::models::room::Room;
 use crate::models::room_membership::RoomMembership;
@@ -85,6 +87,69 @@ impl Handler for RoomState {
     }
 }
 
+/// The `/rooms/:room_id/state/:event_type/:state_key` and `/rooms/:room_id/state/:event_type`
+/// endpoints.
+#[derive(Clone, Copy, Debug)]
+pub struct GetStateEvent;
+
+middleware_chain!(
+    GetStateEvent,
+    [RoomIdParam, EventTypeParam, AccessTokenAuth]
+);
+
+impl Handler for GetStateEvent {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a room_id")
+            .clone();
+
+        let event_type = request
+            .extensions
+            .get::<EventTypeParam>()
+            .expect("EventTypeParam should ensure an EventType")
+            .clone();
+
+        let state_key = request
+            .extensions
+            .get::<Router>()
+            .expect("Params object is missing")
+            .find("state_key")
+            .unwrap_or("")
+            .to_string();
+
+        let connection = DB::from_request(request)?;
+
+        let membership = RoomMembership::find(&connection, &room_id, &user.id)?;
+
+        if membership.map(|membership| membership.membership) != Some("join".to_string()) {
+            Err(ApiError::unauthorized(
+                "The user is not a member of the room".to_string(),
+            ))?
+        }
+
+        let event =
+            match Event::find_room_state_event(&connection, &room_id, &event_type, &state_key)? {
+                Some(event) => event,
+                None => Err(ApiError::not_found(format!(
+                    "The room has no {} state event with state key \"{}\"",
+                    event_type, state_key
+                )))?,
+            };
+
+        let content: Value = from_str(&event.content).map_err(ApiError::from)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(content))))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::test::Test;
@@ -368,4 +433,91 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn get_room_name_state_event() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.name",
+            r#"{"name": "Ruma developers"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let state_event_path = format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.name?access_token={}",
+            room_id, alice.token
+        );
+        let response = test.get(&state_event_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("name").unwrap().as_str().unwrap(),
+            "Ruma developers"
+        );
+
+        let response =
+            test.send_state_event(&alice.token, &room_id, "m.room.name", r#"{"name": "Ruma"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&state_event_path);
+        assert_eq!(
+            response.json().get("name").unwrap().as_str().unwrap(),
+            "Ruma"
+        );
+    }
+
+    #[test]
+    fn get_state_event_with_state_key() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/state/org.ruma.test/first?access_token={}",
+                room_id, alice.token
+            ),
+            r#"{"value": 1}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/org.ruma.test/first?access_token={}",
+            room_id, alice.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.json().get("value").unwrap().as_u64().unwrap(), 1);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/org.ruma.test/second?access_token={}",
+            room_id, alice.token
+        ));
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn get_state_event_forbidden_for_non_members() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.name",
+            r#"{"name": "Ruma developers"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.name?access_token={}",
+            room_id, bob.token
+        ));
+        assert_eq!(response.status, Status::Forbidden);
+    }
 }
@@ -11,8 +11,9 @@ use url::Url;
 
 use crate::config::Config;
 use crate::db::DB;
-use crate::error::ApiError;
-use crate::middleware::{AccessTokenAuth, MiddlewareChain};
+use crate::error::{ApiError, ApiErrorCode};
+use crate::middleware::{AccessTokenAuth, MiddlewareChain, RouteTimeout};
+use crate::models::filter::Filter;
 use crate::models::user::User;
 use crate::modifier::SerializableResponse;
 use crate::query::{self, Batch, SyncOptions};
@@ -21,7 +22,7 @@ use crate::query::{self, Batch, SyncOptions};
 #[derive(Clone, Copy, Debug)]
 pub struct Sync;
 
-middleware_chain!(Sync, [AccessTokenAuth]);
+middleware_chain!(Sync, [AccessTokenAuth, RouteTimeout::disabled()]);
 
 impl Handler for Sync {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
@@ -44,11 +45,28 @@ impl Handler for Sync {
         let mut timeout = 0;
         for tuple in query_pairs {
             match (tuple.0.as_ref(), tuple.1.as_ref()) {
-                ("filter", value) => {
+                ("filter", value) if value.trim_start().starts_with('{') => {
                     let content = from_str(value)
                         .map_err(|err| ApiError::invalid_param("filter", err.description()))?;
                     filter = Some(content);
                 }
+                ("filter", value) => {
+                    let filter_id = value
+                        .parse()
+                        .map_err(|_| ApiError::invalid_param("filter", "Unknown filter ID"))?;
+
+                    let stored_filter = Filter::find(&connection, user.id.clone(), filter_id)
+                        .map_err(|error| {
+                            if error.errcode() == ApiErrorCode::NotFound {
+                                ApiError::invalid_param("filter", "Unknown filter ID")
+                            } else {
+                                error
+                            }
+                        })?;
+
+                    let content = from_str(&stored_filter.content).map_err(ApiError::from)?;
+                    filter = Some(content);
+                }
                 ("since", value) => {
                     let batch = Batch::from_str(value)
                         .map_err(|err| ApiError::invalid_param("since", &err))?;
@@ -101,6 +119,8 @@ impl Handler for Sync {
 mod tests {
     use std::convert::TryFrom;
 
+    use diesel::connection::SimpleConnection;
+
     use crate::test::Test;
     use iron::status::Status;
     use ruma_events::presence::PresenceState;
@@ -1171,6 +1191,116 @@ mod tests {
         assert_eq!(response.status, Status::BadRequest);
     }
 
+    #[test]
+    fn stored_filter_limits_timeline() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        for txn_id in 0..3 {
+            let response = test.send_message(&carl.token, &room_id, "Hi!", txn_id);
+            assert_eq!(response.status, Status::Ok);
+        }
+
+        let filter_id = test.create_filter(
+            &carl.token,
+            &carl.id,
+            r#"{"room":{"timeline":{"limit":2}}}"#,
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?filter={}&access_token={}",
+            filter_id, carl.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let timeline = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline", room_id))
+            .unwrap()
+            .clone();
+        assert_eq!(timeline.get("events").unwrap().as_array().unwrap().len(), 2);
+        assert!(timeline.get("limited").unwrap().as_bool().unwrap());
+    }
+
+    #[test]
+    fn stored_filter_excludes_presence() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let response = test.join_room(&bob.token, &room_id);
+        assert_eq!(response.status, Status::Ok);
+
+        let presence_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            alice.id, alice.token
+        );
+        let response = test.post(
+            &presence_list_path,
+            &format!(r#"{{"invite":["{}"], "drop": []}}"#, bob.id),
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        test.update_presence(&bob.token, &bob.id, r#"{"presence":"online"}"#);
+
+        let filter_id = test.create_filter(
+            &alice.token,
+            &alice.id,
+            r#"{"presence":{"not_types":["*"]}}"#,
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?filter={}&access_token={}",
+            filter_id, alice.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let events = response
+            .json()
+            .pointer("/presence/events")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .len();
+        assert_eq!(events, 0);
+    }
+
+    #[test]
+    fn unknown_filter_id() {
+        let test = Test::new();
+        let (carl, _) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?filter={}&access_token={}",
+            "7", carl.token
+        ));
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_INVALID_PARAM"
+        );
+    }
+
+    #[test]
+    fn filter_lookup_failure_is_not_an_unknown_filter_id() {
+        let test = Test::new();
+        let (carl, _) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        // Make looking up the filter fail with something other than a missing row.
+        test.connection()
+            .batch_execute("ALTER TABLE filters RENAME TO renamed_filters")
+            .unwrap();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?filter={}&access_token={}",
+            "7", carl.token
+        ));
+        assert_eq!(response.status, Status::InternalServerError);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNKNOWN"
+        );
+    }
+
     #[test]
     fn invalid_full_state() {
         let test = Test::new();
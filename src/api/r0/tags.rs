@@ -1,16 +1,15 @@
 //! Endpoints for tags.
 use std::collections::HashMap;
 
-use bodyparser;
 use iron::status::Status;
-use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use iron::{Chain, Handler, IronResult, Request, Response};
 use ruma_events::tag::TagInfo;
 use serde_json::Value;
 
 use crate::db::DB;
 use crate::error::ApiError;
 use crate::middleware::{
-    AccessTokenAuth, JsonRequest, MiddlewareChain, RoomIdParam, TagParam, UserIdParam,
+    parse_body, AccessTokenAuth, JsonRequest, MiddlewareChain, RoomIdParam, TagParam, UserIdParam,
 };
 use crate::models::tags::RoomTag;
 use crate::models::user::User;
@@ -109,11 +108,7 @@ impl Handler for PutTag {
             ))?;
         }
 
-        let content = match request.get::<bodyparser::Struct<Value>>() {
-            Ok(Some(content)) => content.to_string(),
-            Ok(None) => "".to_string(),
-            Err(_) => Err(ApiError::bad_json(None))?,
-        };
+        let content = parse_body::<Value>(request)?.to_string();
 
         let connection = DB::from_request(request)?;
 
@@ -205,6 +200,38 @@ mod tests {
         assert_eq!(content.to_string(), r#"{"order":"test"}"#);
     }
 
+    #[test]
+    fn put_tag_without_body() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = test.create_public_room(&carl.token);
+
+        let put_tag_path = format!(
+            "/_matrix/client/r0/user/{}/rooms/{}/tags/{}?access_token={}",
+            carl.id, room_id, "work", carl.token
+        );
+
+        let response = test.put(&put_tag_path, "");
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_NOT_JSON"
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/user/{}/rooms/{}/tags?access_token={}",
+            carl.id, room_id, carl.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert!(response
+            .json()
+            .get("tags")
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .is_empty());
+    }
+
     #[test]
     fn get_tags_forbidden() {
         let test = Test::new();
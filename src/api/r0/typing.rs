@@ -0,0 +1,165 @@
+//! Endpoints for typing notifications.
+
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Request, Response};
+
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{
+    parse_body, AccessTokenAuth, JsonRequest, MiddlewareChain, RoomIdParam, UserIdParam,
+};
+use crate::models::room_membership::RoomMembership;
+use crate::models::user::User;
+use crate::modifier::EmptyResponse;
+
+/// The PUT `/rooms/:room_id/typing/:user_id` endpoint.
+///
+/// Typing notifications are validated but not yet sent to other members of the room.
+#[derive(Clone, Copy, Debug)]
+pub struct PutTyping;
+
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct PutTypingRequest {
+    /// Whether the user is typing or has stopped typing.
+    typing: bool,
+    /// The length of time in milliseconds to mark the user as typing.
+    timeout: Option<u64>,
+}
+
+middleware_chain!(
+    PutTyping,
+    [JsonRequest, RoomIdParam, UserIdParam, AccessTokenAuth]
+);
+
+impl Handler for PutTyping {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let put_typing_request = parse_body::<PutTypingRequest>(request)?;
+
+        if put_typing_request.typing && put_typing_request.timeout.is_none() {
+            Err(ApiError::missing_param("timeout"))?
+        }
+
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let room_id = request
+            .extensions
+            .get::<RoomIdParam>()
+            .expect("RoomIdParam should ensure a room_id")
+            .clone();
+
+        let user_id = request
+            .extensions
+            .get::<UserIdParam>()
+            .expect("UserIdParam should ensure a UserId")
+            .clone();
+
+        if user_id != user.id {
+            Err(ApiError::unauthorized(
+                "The given user_id does not correspond to the authenticated user".to_string(),
+            ))?
+        }
+
+        let connection = DB::from_request(request)?;
+
+        let membership = RoomMembership::find(&connection, &room_id, &user.id)?;
+
+        if membership.map(|membership| membership.membership) != Some("join".to_string()) {
+            Err(ApiError::unauthorized(
+                "The user is not a member of the room".to_string(),
+            ))?
+        }
+
+        Ok(Response::with(EmptyResponse(Status::Ok)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use crate::test::Test;
+
+    #[test]
+    fn set_own_typing_state() {
+        let test = Test::new();
+        let (user, room_id) = test.initial_fixtures("{}");
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/typing/{}?access_token={}",
+                room_id, user.id, user.token
+            ),
+            r#"{"typing": true, "timeout": 30000}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.body, "{}");
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/typing/{}?access_token={}",
+                room_id, user.id, user.token
+            ),
+            r#"{"typing": false}"#,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn set_typing_state_of_another_user() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/typing/{}?access_token={}",
+                room_id, alice.id, bob.token
+            ),
+            r#"{"typing": true, "timeout": 30000}"#,
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn set_typing_state_as_non_member() {
+        let test = Test::new();
+        let (_, room_id) = test.initial_fixtures("{}");
+        let bob = test.create_user();
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/typing/{}?access_token={}",
+                room_id, bob.id, bob.token
+            ),
+            r#"{"typing": true, "timeout": 30000}"#,
+        );
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn typing_requires_timeout() {
+        let test = Test::new();
+        let (user, room_id) = test.initial_fixtures("{}");
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/typing/{}?access_token={}",
+                room_id, user.id, user.token
+            ),
+            r#"{"typing": true}"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+}
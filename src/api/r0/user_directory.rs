@@ -0,0 +1,303 @@
+//! Endpoints for searching the user directory.
+
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Request, Response};
+use ruma_identifiers::UserId;
+
+use crate::config::Config;
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{parse_body, AccessTokenAuth, JsonRequest, MiddlewareChain};
+use crate::models::profile::Profile;
+use crate::models::user::User;
+use crate::modifier::SerializableResponse;
+
+/// The number of users returned when the request doesn't specify a limit.
+const DEFAULT_SEARCH_LIMIT: i64 = 10;
+
+/// The maximum number of users returned at once.
+const MAX_SEARCH_LIMIT: i64 = 100;
+
+/// The `/user_directory/search` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct SearchUserDirectory;
+
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct SearchUserDirectoryRequest {
+    /// The term to search for in user IDs and display names.
+    search_term: String,
+    /// The maximum number of results to return.
+    limit: Option<i64>,
+}
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct SearchUserDirectoryResponse {
+    /// The users matching the search term, at most `limit` of them.
+    results: Vec<SearchResult>,
+    /// Whether or not there were more matching users than returned.
+    limited: bool,
+}
+
+/// A user matching the search term.
+#[derive(Debug, Serialize)]
+struct SearchResult {
+    /// The Matrix user ID of the user.
+    user_id: UserId,
+    /// The user's display name, if they have set one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+    /// The user's avatar URL, if they have set one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+}
+
+middleware_chain!(SearchUserDirectory, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for SearchUserDirectory {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let search_request = parse_body::<SearchUserDirectoryRequest>(request)?;
+
+        let limit = match search_request.limit {
+            Some(limit) if limit < 0 => Err(ApiError::invalid_param(
+                "limit",
+                "Must be a non-negative integer",
+            ))?,
+            Some(limit) => limit.min(MAX_SEARCH_LIMIT),
+            None => DEFAULT_SEARCH_LIMIT,
+        };
+
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user")
+            .clone();
+
+        let config = Config::from_request(request)?;
+        let connection = DB::from_request(request)?;
+
+        let shared_with = if config.user_directory_search_all_users {
+            None
+        } else {
+            Some(&user.id)
+        };
+
+        // Fetch one more user than requested to find out whether the results were limited.
+        let mut users = User::search_directory(
+            &connection,
+            &config.domain,
+            &search_request.search_term,
+            shared_with,
+            limit + 1,
+        )?;
+
+        let limited = users.len() as i64 > limit;
+        users.truncate(limit as usize);
+
+        let user_ids: Vec<UserId> = users.into_iter().map(|user| user.id).collect();
+        let mut profiles = Profile::get_profiles(&connection, &user_ids)?;
+
+        let results = user_ids
+            .into_iter()
+            .map(|user_id| {
+                let profile = profiles
+                    .iter()
+                    .position(|profile| profile.id == user_id)
+                    .map(|index| profiles.swap_remove(index));
+
+                SearchResult {
+                    user_id,
+                    display_name: profile
+                        .as_ref()
+                        .and_then(|profile| profile.displayname.clone()),
+                    avatar_url: profile.and_then(|profile| profile.avatar_url),
+                }
+            })
+            .collect();
+
+        let response = SearchUserDirectoryResponse { results, limited };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use crate::test::Test;
+
+    fn search(test: &Test, access_token: &str, body: &str) -> Value {
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/user_directory/search?access_token={}",
+                access_token
+            ),
+            body,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        response.json().clone()
+    }
+
+    fn result_user_ids(json: &Value) -> Vec<&str> {
+        json.get("results")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| result.get("user_id").unwrap().as_str().unwrap())
+            .collect()
+    }
+
+    fn set_displayname(test: &Test, user_id: &str, access_token: &str, displayname: &str) {
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+                user_id, access_token
+            ),
+            &format!(r#"{{"displayname": "{}"}}"#, displayname),
+        );
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn search_by_localpart_and_displayname() {
+        let test = Test::with_config(|config| config.user_directory_search_all_users = true);
+        let alice = test.create_user();
+
+        for username in &["carl", "erin"] {
+            let response = test.register_user(&format!(
+                r#"{{"username": "{}", "password": "secret"}}"#,
+                username
+            ));
+            assert_eq!(response.status, Status::Ok);
+        }
+
+        let bob = test.create_user();
+        set_displayname(&test, &bob.id, &bob.token, "Carla");
+
+        let json = search(&test, &alice.token, r#"{"search_term": "CARL"}"#);
+
+        let mut user_ids = result_user_ids(&json);
+        let mut expected = vec!["@carl:ruma.test", bob.id.as_str()];
+        user_ids.sort();
+        expected.sort();
+        assert_eq!(user_ids, expected);
+        assert!(!json.get("limited").unwrap().as_bool().unwrap());
+
+        let result = json
+            .get("results")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|result| result.get("user_id").unwrap().as_str().unwrap() == bob.id)
+            .unwrap();
+        assert_eq!(
+            result.get("display_name").unwrap().as_str().unwrap(),
+            "Carla"
+        );
+    }
+
+    #[test]
+    fn search_limit() {
+        let test = Test::with_config(|config| config.user_directory_search_all_users = true);
+        let alice = test.create_user();
+
+        for username in &["carl1", "carl2", "carl3"] {
+            let response = test.register_user(&format!(
+                r#"{{"username": "{}", "password": "secret"}}"#,
+                username
+            ));
+            assert_eq!(response.status, Status::Ok);
+        }
+
+        let json = search(
+            &test,
+            &alice.token,
+            r#"{"search_term": "carl", "limit": 2}"#,
+        );
+
+        assert_eq!(
+            result_user_ids(&json),
+            vec!["@carl1:ruma.test", "@carl2:ruma.test"]
+        );
+        assert!(json.get("limited").unwrap().as_bool().unwrap());
+
+        let json = search(
+            &test,
+            &alice.token,
+            r#"{"search_term": "carl", "limit": 3}"#,
+        );
+
+        assert_eq!(result_user_ids(&json).len(), 3);
+        assert!(!json.get("limited").unwrap().as_bool().unwrap());
+    }
+
+    #[test]
+    fn deactivated_users_are_excluded() {
+        let test = Test::with_config(|config| config.user_directory_search_all_users = true);
+        let alice = test.create_user();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+        let carl_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let json = search(&test, &alice.token, r#"{"search_term": "carl"}"#);
+        assert_eq!(result_user_ids(&json), vec!["@carl:ruma.test"]);
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/account/deactivate?access_token={}",
+                carl_token
+            ),
+            r#"{}"#,
+        );
+        test.check_empty_response(response);
+
+        let json = search(&test, &alice.token, r#"{"search_term": "carl"}"#);
+        assert!(result_user_ids(&json).is_empty());
+    }
+
+    #[test]
+    fn only_users_sharing_a_room_by_default() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let carl = test.create_user();
+
+        let room_id = test.create_public_room(&alice.token);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        set_displayname(&test, &bob.id, &bob.token, "Searchable Bob");
+        set_displayname(&test, &carl.id, &carl.token, "Searchable Carl");
+
+        let json = search(&test, &alice.token, r#"{"search_term": "searchable"}"#);
+        assert_eq!(result_user_ids(&json), vec![bob.id.as_str()]);
+    }
+
+    #[test]
+    fn negative_limit() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/user_directory/search?access_token={}",
+                alice.token
+            ),
+            r#"{"search_term": "carl", "limit": -1}"#,
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+}
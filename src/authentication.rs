@@ -9,6 +9,9 @@ use serde::{Serialize, Serializer};
 use crate::error::{ApiError, ApiErrorCode};
 use crate::models::user::User;
 
+/// The login types `/login` can be used with.
+pub const SUPPORTED_LOGIN_TYPES: &[&str] = &["m.login.password"];
+
 /// A set of authorization flows the user can follow to authenticate a request.
 #[derive(Clone, Debug, Serialize)]
 pub struct InteractiveAuth {
@@ -5,7 +5,7 @@ extern crate ruma;
 use clap::{App, AppSettings, Arg, SubCommand};
 
 use ruma::config::Config;
-use ruma::crypto::generate_macaroon_secret_key;
+use ruma::crypto::{generate_macaroon_secret_key, generate_server_signing_key};
 use ruma::server::Server;
 
 fn main() {
@@ -32,6 +32,10 @@ fn main() {
             SubCommand::with_name("secret")
                 .about("Generates a random value to be used as a macaroon secret key"),
         )
+        .subcommand(
+            SubCommand::with_name("signing-key")
+                .about("Generates a random value to be used as a server signing key"),
+        )
         .get_matches();
 
     match matches.subcommand() {
@@ -62,6 +66,10 @@ fn main() {
             Ok(key) => println!("{}", key),
             Err(error) => eprintln!("Failed to generate macaroon secret key: {}", error),
         },
+        ("signing-key", Some(_)) => match generate_server_signing_key() {
+            Ok(key) => println!("{}", key),
+            Err(error) => eprintln!("Failed to generate server signing key: {}", error),
+        },
         _ => println!("{}", matches.usage()),
     };
 }
@@ -1,19 +1,143 @@
 //! Cryptographic operations.
 
 use argon2rs::verifier::Encoded;
-use base64::encode;
-use rand::{rngs::OsRng, RngCore};
+use base64::{decode_config, encode, encode_config, STANDARD_NO_PAD, URL_SAFE_NO_PAD};
+use rand::{rngs::OsRng, Rng, RngCore};
+use ring::constant_time::verify_slices_are_equal;
+use ring::digest::{digest, SHA256};
+use ring::hmac::{SigningContext, SigningKey};
+use ring::signature::{verify, Ed25519KeyPair, KeyPair, ED25519};
+use serde_json::{to_string, Value};
+use untrusted::Input;
 
 use crate::error::{ApiError, CliError};
 
 /// Generates a random 32-byte secret key for macaroons.
 pub fn generate_macaroon_secret_key() -> Result<String, CliError> {
+    Ok(encode(&generate_key_bytes()?))
+}
+
+/// Generates a random 32-byte seed for the server's Ed25519 signing key, encoded as Base64.
+pub fn generate_server_signing_key() -> Result<String, CliError> {
+    Ok(encode(&generate_key_bytes()?))
+}
+
+/// Generates 32 cryptographically random bytes.
+pub fn generate_key_bytes() -> Result<[u8; 32], CliError> {
     let mut rng = OsRng::new()?;
     let mut key = [0u8; 32];
 
     rng.fill_bytes(&mut key);
 
-    Ok(encode(&key))
+    Ok(key)
+}
+
+/// Generates a random device ID of ten uppercase letters.
+pub fn generate_device_id() -> Result<String, ApiError> {
+    let mut rng = OsRng::new()?;
+
+    Ok((0..10)
+        .map(|_| char::from(b'A' + rng.gen_range(0, 26)))
+        .collect())
+}
+
+/// Generates a random 32-byte opaque access token, encoded as URL-safe Base64.
+pub fn generate_opaque_token() -> Result<String, ApiError> {
+    let mut rng = OsRng::new()?;
+    let mut token = [0u8; 32];
+
+    rng.fill_bytes(&mut token);
+
+    Ok(encode_config(&token, URL_SAFE_NO_PAD))
+}
+
+/// Derives a token that looks like one from `generate_opaque_token` from `parts` with
+/// HMAC-SHA-256, so the same parts always give the same token. Without `secret_key` it can't be
+/// told apart from a random token.
+pub fn derive_opaque_token(secret_key: &[u8], parts: &[&str]) -> String {
+    let key = SigningKey::new(&SHA256, secret_key);
+    let mut context = SigningContext::with_key(&key);
+
+    for part in parts {
+        context.update(part.as_bytes());
+        context.update(&[0]);
+    }
+
+    encode_config(context.sign().as_ref(), URL_SAFE_NO_PAD)
+}
+
+/// Hash an opaque access token with SHA-256 so it can be stored and looked up.
+///
+/// The token is already random, so unlike passwords it doesn't need a salt.
+pub fn hash_opaque_token(token: &str) -> String {
+    encode(digest(&SHA256, token.as_bytes()).as_ref())
+}
+
+/// Compares two secrets, e.g. access tokens, in an amount of time that doesn't depend on how
+/// much of them matches.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    verify_slices_are_equal(a, b).is_ok()
+}
+
+/// The Ed25519 key this server signs federation requests and responses with, generated from the
+/// 32-byte seed in `server_signing_key`.
+pub fn server_signing_key(seed: &[u8]) -> Result<Ed25519KeyPair, ApiError> {
+    Ed25519KeyPair::from_seed_unchecked(Input::from(seed))
+        .map_err(|_| ApiError::unknown("Failed to load the server signing key.".to_string()))
+}
+
+/// The public half of a signing key, encoded as Unpadded Base64.
+pub fn public_key_base64(key_pair: &Ed25519KeyPair) -> String {
+    encode_config(key_pair.public_key().as_ref(), STANDARD_NO_PAD)
+}
+
+/// Signs the [canonical JSON](https://matrix.org/docs/spec/appendices#canonical-json) form of a
+/// JSON object, returning the signature encoded as Unpadded Base64.
+///
+/// `signatures` and `unsigned` are left out of the signed data, as the spec requires.
+pub fn sign_json(key_pair: &Ed25519KeyPair, value: &Value) -> Result<String, ApiError> {
+    let signature = key_pair.sign(canonical_json(value)?.as_bytes());
+
+    Ok(encode_config(signature.as_ref(), STANDARD_NO_PAD))
+}
+
+/// Whether or not `signature` is a valid signature of the canonical JSON form of a JSON object
+/// by the Ed25519 key `public_key`, both encoded as Unpadded Base64.
+pub fn verify_json(public_key: &str, signature: &str, value: &Value) -> bool {
+    let public_key = match decode_config(public_key, STANDARD_NO_PAD) {
+        Ok(public_key) => public_key,
+        Err(_) => return false,
+    };
+    let signature = match decode_config(signature, STANDARD_NO_PAD) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    let signed = match canonical_json(value) {
+        Ok(signed) => signed,
+        Err(_) => return false,
+    };
+
+    verify(
+        &ED25519,
+        Input::from(&public_key),
+        Input::from(signed.as_bytes()),
+        Input::from(&signature),
+    )
+    .is_ok()
+}
+
+/// Serializes a JSON object without its `signatures` and `unsigned` fields, with sorted keys and
+/// no insignificant whitespace.
+pub fn canonical_json(value: &Value) -> Result<String, ApiError> {
+    let mut value = value.clone();
+
+    if let Some(object) = value.as_object_mut() {
+        object.remove("signatures");
+        object.remove("unsigned");
+    }
+
+    // `serde_json` keeps object keys sorted and serializes without whitespace.
+    to_string(&value).map_err(ApiError::from)
 }
 
 /// Hash a password with Argon2.
@@ -31,6 +155,11 @@ pub fn verify_password(encoded_hash: &[u8], plaintext_password: &str) -> Result<
     Ok(encoded.verify(plaintext_password.as_bytes()))
 }
 
+/// Whether or not the given string is an encoded Argon2 hash, as produced by `hash_password`.
+pub fn is_password_hash(encoded_hash: &str) -> bool {
+    Encoded::from_u8(encoded_hash.as_bytes()).is_ok()
+}
+
 /// Generates a random salt for Argon2.
 fn generate_salt() -> Result<[u8; 16], ApiError> {
     let mut rng = OsRng::new()?;
@@ -40,3 +169,17 @@ fn generate_salt() -> Result<[u8; 16], ApiError> {
 
     Ok(salt)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::constant_time_eq;
+
+    #[test]
+    fn constant_time_eq_compares_contents() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(!constant_time_eq(b"secret", b""));
+    }
+}
@@ -24,15 +24,24 @@ impl DB {
     }
 
     /// Extract a database conection from the pool stored in the request.
+    ///
+    /// Fails once the pool has been closed during shutdown.
     pub fn from_request(
         request: &mut Request<'_, '_>,
     ) -> Result<PooledConnection<ConnectionManager<PgConnection>>, ApiError> {
         let mutex = request.get::<Write<Self>>().map_err(ApiError::from)?;
         let pool = mutex.lock().map_err(ApiError::from)?;
-        pool.get().map_err(ApiError::from)
+
+        match *pool {
+            Some(ref pool) => pool.get().map_err(ApiError::from),
+            None => Err(ApiError::unavailable(
+                "The server is shutting down.".to_string(),
+            )),
+        }
     }
 }
 
 impl Key for DB {
-    type Value = Pool<ConnectionManager<PgConnection>>;
+    /// The connection pool, or `None` once it has been closed.
+    type Value = Option<Pool<ConnectionManager<PgConnection>>>;
 }
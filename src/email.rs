@@ -0,0 +1,78 @@
+//! Normalizing and validating email addresses, e.g. those bound as third party identifiers.
+
+/// The maximum length of an email address in bytes, as limited by SMTP.
+const MAX_EMAIL_LENGTH: usize = 254;
+
+/// Returns the form in which an email address is stored and compared.
+///
+/// Surrounding whitespace is removed and the address is lowercased. The local part is lowercased
+/// along with the domain: it is case-sensitive in theory, but mail providers treat it as
+/// case-insensitive, and users don't expect "Carl@example.com" and "carl@example.com" to be
+/// different addresses.
+pub fn normalize_email(address: &str) -> String {
+    address.trim().to_lowercase()
+}
+
+/// Whether or not the address looks like an email address, after normalization.
+///
+/// This only checks the overall shape, a non-empty local part and a domain made of non-empty
+/// labels, rather than the full syntax of RFC 5322.
+pub fn is_valid_email(address: &str) -> bool {
+    let address = address.trim();
+
+    if address.len() > MAX_EMAIL_LENGTH
+        || address.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return false;
+    }
+
+    let (local_part, domain) = match address.rfind('@') {
+        Some(index) => (&address[..index], &address[index + 1..]),
+        None => return false,
+    };
+
+    !local_part.is_empty()
+        && !domain.is_empty()
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_alphanumeric() || c == '-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_email, normalize_email};
+
+    #[test]
+    fn normalization() {
+        assert_eq!(normalize_email("carl@example.com"), "carl@example.com");
+        assert_eq!(normalize_email("Carl@Example.COM"), "carl@example.com");
+        assert_eq!(normalize_email("  carl@example.com\n"), "carl@example.com");
+        assert_eq!(normalize_email("A@B.COM"), normalize_email("a@b.com"));
+    }
+
+    #[test]
+    fn valid_addresses() {
+        assert!(is_valid_email("carl@example.com"));
+        assert!(is_valid_email("carl.smith+matrix@mail.example.co.uk"));
+        assert!(is_valid_email("carl@localhost"));
+        assert!(is_valid_email(" Carl@Example.com "));
+        assert!(is_valid_email("\"carl@home\"@example.com"));
+    }
+
+    #[test]
+    fn invalid_addresses() {
+        assert!(!is_valid_email(""));
+        assert!(!is_valid_email("carl"));
+        assert!(!is_valid_email("@example.com"));
+        assert!(!is_valid_email("carl@"));
+        assert!(!is_valid_email("carl smith@example.com"));
+        assert!(!is_valid_email("carl@example..com"));
+        assert!(!is_valid_email("carl@.example.com"));
+        assert!(!is_valid_email("carl@-example.com"));
+        assert!(!is_valid_email("carl@exa_mple.com"));
+        assert!(!is_valid_email(&format!("{}@example.com", "a".repeat(250))));
+    }
+}
@@ -6,11 +6,11 @@ use std::fmt::{Debug, Display, Formatter};
 use std::io::Error as IoError;
 use std::string::FromUtf8Error;
 use std::sync::PoisonError;
-use std::time::SystemTimeError;
+use std::time::{Duration, SystemTimeError};
 
 use argon2rs::verifier::DecodeError;
 use diesel::r2d2::PoolError as R2d2Error;
-use diesel::result::Error as DieselError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use iron::headers::ContentType;
 use iron::modifier::Modifier;
 use iron::status::Status;
@@ -29,10 +29,17 @@ pub struct ApiError {
     errcode: ApiErrorCode,
     /// A human-readable message describing the error.
     error: String,
+    /// The HTTP status code of the response, if it differs from the one implied by `errcode`.
+    #[serde(skip)]
+    status: Option<Status>,
+    /// How long the client should wait before making the request again, for rate-limited
+    /// requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_ms: Option<u64>,
 }
 
 /// The error code for a client-facing error.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ApiErrorCode {
     /// The requested room alias is already taken.
     AliasTaken,
@@ -41,26 +48,51 @@ pub enum ApiErrorCode {
     /// The request contained valid JSON, but it was malformed in some way,
     /// e.g. missing required keys, invalid values for keys.
     BadJson,
+    /// The request would have created a resource that already exists.
+    Conflict,
     /// Forbidden access, e.g. joining a room without permission, failed login.
     Forbidden,
     /// Guests are not allowed to perform the requested operation.
     GuestAccessForbidden,
     /// An input parameter didn't have a valid format.
     InvalidParam,
+    /// A registration token was missing or not valid.
+    InvalidToken,
+    /// The request referred to a resource that does not exist.
+    InvalidReference,
+    /// The desired user ID is not a valid user ID.
+    InvalidUsername,
     /// Too many requests have been sent in a short period of time. Wait a while then try again.
     LimitExceeded,
     /// A required input parameter was not supplied, e.g. query string or URL path-based parameter.
     MissingParam,
+    /// The request's credentials were not given in a form the server understands, e.g. an
+    /// Authorization header with a scheme other than Bearer.
+    MissingToken,
     /// No resource was found for this request.
     NotFound,
     /// Request did not contain valid JSON.
     NotJson,
+    /// The request or the resource it would create is too large.
+    TooLarge,
     /// Ruma does not implement the requested API.
     Unimplemented,
+    /// The request is not for any API that Ruma knows about.
+    Unrecognized,
+    /// The server is temporarily unable to handle the request, e.g. because it is shutting down.
+    Unavailable,
     /// Errors not fitting into another category.
     Unknown,
     /// The access token specified was not recognised.
     UnknownToken,
+    /// The requested room version is not supported by the server.
+    UnsupportedRoomVersion,
+    /// The user has deactivated their account.
+    UserDeactivated,
+    /// The account is temporarily locked, e.g. after too many failed attempts to log in.
+    UserLocked,
+    /// The desired user ID is already taken.
+    UserInUse,
 }
 
 /// An operator-facing error.
@@ -87,123 +119,291 @@ pub trait MapApiError {
 }
 
 impl ApiError {
-    /// Create an error for requests that try to create a room alias that is already taken.
-    pub fn alias_taken<T: Into<Option<String>>>(message: T) -> Self {
-        let message = message.into();
+    /// Create an error with the given error code and message.
+    fn new(errcode: ApiErrorCode, error: String) -> Self {
         Self {
-            errcode: ApiErrorCode::AliasTaken,
-            error: message.unwrap_or_else(|| "Alias already taken.".to_string()),
+            errcode,
+            error,
+            status: None,
+            retry_after_ms: None,
         }
     }
 
+    /// Create an error for requests that try to create a room alias that is already taken.
+    pub fn alias_taken<T: Into<Option<String>>>(message: T) -> Self {
+        Self::new(
+            ApiErrorCode::AliasTaken,
+            message
+                .into()
+                .unwrap_or_else(|| "Alias already taken.".to_string()),
+        )
+    }
+
     /// Create an error for invalid or incomplete input to event creation API endpoints.
     pub fn bad_event<T: Into<Option<String>>>(message: T) -> Self {
-        let message = message.into();
-        Self {
-            errcode: ApiErrorCode::BadEvent,
-            error: message.unwrap_or_else(|| "Invalid event data.".to_string()),
-        }
+        Self::new(
+            ApiErrorCode::BadEvent,
+            message
+                .into()
+                .unwrap_or_else(|| "Invalid event data.".to_string()),
+        )
     }
 
     /// Create an error for invalid or incomplete JSON in request bodies.
     pub fn bad_json<T: Into<Option<String>>>(message: T) -> Self {
-        let message = message.into();
-        Self {
-            errcode: ApiErrorCode::BadJson,
-            error: message
+        Self::new(
+            ApiErrorCode::BadJson,
+            message
+                .into()
                 .unwrap_or_else(|| "Invalid or missing key-value pairs in JSON.".to_string()),
-        }
+        )
+    }
+
+    /// Create an error for requests that would create a resource that already exists.
+    pub fn conflict<T: Into<Option<String>>>(message: T) -> Self {
+        Self::new(
+            ApiErrorCode::Conflict,
+            message
+                .into()
+                .unwrap_or_else(|| "The resource already exists.".to_string()),
+        )
     }
 
     /// Create an error for endpoints where guest accounts are not supported.
     pub fn guest_forbidden<T: Into<Option<String>>>(message: T) -> Self {
-        let message = message.into();
-        Self {
-            errcode: ApiErrorCode::GuestAccessForbidden,
-            error: message.unwrap_or_else(|| "Guest accounts are forbidden.".to_string()),
-        }
+        Self::new(
+            ApiErrorCode::GuestAccessForbidden,
+            message
+                .into()
+                .unwrap_or_else(|| "Guest accounts are forbidden.".to_string()),
+        )
     }
 
     /// Create an error for invalid input parameters.
     pub fn invalid_param(param_name: &str, msg: &str) -> Self {
-        Self {
-            errcode: ApiErrorCode::InvalidParam,
-            error: format!("Parameter '{}' is not valid: {}", param_name, msg),
-        }
+        Self::new(
+            ApiErrorCode::InvalidParam,
+            format!("Parameter '{}' is not valid: {}", param_name, msg),
+        )
+    }
+
+    /// Create an error for registrations without a valid registration token.
+    pub fn invalid_token<T: Into<Option<String>>>(message: T) -> Self {
+        Self::new(
+            ApiErrorCode::InvalidToken,
+            message
+                .into()
+                .unwrap_or_else(|| "Invalid registration token.".to_string()),
+        )
+    }
+
+    /// Create an error for user IDs that cannot be registered, e.g. because they are too long.
+    pub fn invalid_username<T: Into<Option<String>>>(message: T) -> Self {
+        Self::new(
+            ApiErrorCode::InvalidUsername,
+            message
+                .into()
+                .unwrap_or_else(|| "The desired user ID is not valid.".to_string()),
+        )
+    }
+
+    /// Create an error for requests that refer to a resource that does not exist.
+    pub fn invalid_reference<T: Into<Option<String>>>(message: T) -> Self {
+        Self::new(
+            ApiErrorCode::InvalidReference,
+            message.into().unwrap_or_else(|| {
+                "The request refers to a resource that does not exist.".to_string()
+            }),
+        )
     }
 
     /// Create an error for requests missing a value for a required parameter.
     pub fn missing_param(param_name: &str) -> Self {
-        Self {
-            errcode: ApiErrorCode::MissingParam,
-            error: format!("Missing value for required parameter: {}.", param_name),
-        }
+        Self::new(
+            ApiErrorCode::MissingParam,
+            format!("Missing value for required parameter: {}.", param_name),
+        )
+    }
+
+    /// Create an error for requests whose access token could not be found in the request.
+    pub fn missing_token<T: Into<Option<String>>>(message: T) -> Self {
+        Self::new(
+            ApiErrorCode::MissingToken,
+            message
+                .into()
+                .unwrap_or_else(|| "No access token was given.".to_string()),
+        )
     }
 
     /// Create an error for requests that do not map to a resource.
     pub fn not_found<T: Into<Option<String>>>(message: T) -> Self {
-        let message = message.into();
-        Self {
-            errcode: ApiErrorCode::NotFound,
-            error: message.unwrap_or_else(|| "No resource was found for this request.".to_string()),
-        }
+        Self::new(
+            ApiErrorCode::NotFound,
+            message
+                .into()
+                .unwrap_or_else(|| "No resource was found for this request.".to_string()),
+        )
     }
 
     /// Create an error for requests without JSON bodies.
     pub fn not_json<T: Into<Option<String>>>(message: T) -> Self {
-        let message = message.into();
-        Self {
-            errcode: ApiErrorCode::NotJson,
-            error: message.unwrap_or_else(|| "No JSON found in request body.".to_string()),
-        }
+        Self::new(
+            ApiErrorCode::NotJson,
+            message
+                .into()
+                .unwrap_or_else(|| "No JSON found in request body.".to_string()),
+        )
     }
 
     /// Create an error for requests that are not marked as containing JSON.
     pub fn wrong_content_type<T: Into<Option<String>>>(message: T) -> Self {
-        let message = message.into();
-        Self {
-            errcode: ApiErrorCode::NotJson,
-            error: message.unwrap_or_else(|| {
+        Self::new(
+            ApiErrorCode::NotJson,
+            message.into().unwrap_or_else(|| {
                 "Request's Content-Type header must be application/json.".to_string()
             }),
-        }
+        )
     }
 
     /// Create an error for requests that did not provide required authentication parameters.
     pub fn unauthorized<T: Into<Option<String>>>(message: T) -> Self {
-        let message = message.into();
-        Self {
-            errcode: ApiErrorCode::Forbidden,
-            error: message.unwrap_or_else(|| "Authentication is required.".to_string()),
-        }
+        Self::new(
+            ApiErrorCode::Forbidden,
+            message
+                .into()
+                .unwrap_or_else(|| "Authentication is required.".to_string()),
+        )
+    }
+
+    /// Create an error for access tokens that weren't issued by this server, have expired or have
+    /// been revoked.
+    pub fn unknown_token<T: Into<Option<String>>>(message: T) -> Self {
+        Self::new(
+            ApiErrorCode::UnknownToken,
+            message
+                .into()
+                .unwrap_or_else(|| "Unrecognized access token.".to_string()),
+        )
     }
 
     /// Create an error for Matrix APIs that Ruma intentionally does not implement.
     pub fn unimplemented<T: Into<Option<String>>>(message: T) -> Self {
-        let message = message.into();
-        Self {
-            errcode: ApiErrorCode::Unimplemented,
-            error: message
+        Self::new(
+            ApiErrorCode::Unimplemented,
+            message
+                .into()
                 .unwrap_or_else(|| "The homeserver does not implement this API.".to_string()),
-        }
+        )
+    }
+
+    /// Create an error for requests the server doesn't recognize, e.g. to unknown endpoints.
+    pub fn unrecognized<T: Into<Option<String>>>(message: T) -> Self {
+        Self::new(
+            ApiErrorCode::Unrecognized,
+            message
+                .into()
+                .unwrap_or_else(|| "Unrecognized request".to_string()),
+        )
     }
 
     /// Create an error for Matrix APIs that Ruma intentionally does not implement.
     pub fn limited_rate<T: Into<Option<String>>>(message: T) -> Self {
-        let message = message.into();
-        Self {
-            errcode: ApiErrorCode::LimitExceeded,
-            error: message.unwrap_or_else(|| "Too many retry!".to_string()),
-        }
+        Self::new(
+            ApiErrorCode::LimitExceeded,
+            message
+                .into()
+                .unwrap_or_else(|| "Too many retry!".to_string()),
+        )
+    }
+
+    /// Create an error for requests the server is temporarily unable to handle.
+    pub fn unavailable<T: Into<Option<String>>>(message: T) -> Self {
+        Self::new(
+            ApiErrorCode::Unavailable,
+            message
+                .into()
+                .unwrap_or_else(|| "The server is temporarily unavailable.".to_string()),
+        )
+    }
+
+    /// Create an error for requests made on behalf of a user who has deactivated their account.
+    pub fn user_deactivated<T: Into<Option<String>>>(message: T) -> Self {
+        Self::new(
+            ApiErrorCode::UserDeactivated,
+            message
+                .into()
+                .unwrap_or_else(|| "This account has been deactivated.".to_string()),
+        )
+    }
+
+    /// Create an error for requests made on behalf of a user whose account is temporarily locked.
+    pub fn user_locked<T: Into<Option<String>>>(message: T) -> Self {
+        Self::new(
+            ApiErrorCode::UserLocked,
+            message
+                .into()
+                .unwrap_or_else(|| "This account has been locked.".to_string()),
+        )
+    }
+
+    /// Create an error for requests that are, or would create something that is, too large.
+    pub fn too_large<T: Into<Option<String>>>(message: T) -> Self {
+        Self::new(
+            ApiErrorCode::TooLarge,
+            message.into().unwrap_or_else(|| "Too large".to_string()),
+        )
+    }
+
+    /// Create an error for requests for a room version the server does not support.
+    pub fn unsupported_room_version<T: Into<Option<String>>>(message: T) -> Self {
+        Self::new(
+            ApiErrorCode::UnsupportedRoomVersion,
+            message
+                .into()
+                .unwrap_or_else(|| "Unsupported room version".to_string()),
+        )
+    }
+
+    /// Create an error for requests for a user ID that is already taken.
+    pub fn user_in_use<T: Into<Option<String>>>(message: T) -> Self {
+        Self::new(
+            ApiErrorCode::UserInUse,
+            message
+                .into()
+                .unwrap_or_else(|| "This user ID is already taken.".to_string()),
+        )
     }
 
     /// Create a generic error for anything not specifically covered by the Matrix spec.
     pub fn unknown<T: Into<Option<String>>>(message: T) -> Self {
-        let message = message.into();
-        Self {
-            errcode: ApiErrorCode::Unknown,
-            error: message.unwrap_or_else(|| "An unknown server-side error occurred.".to_string()),
-        }
+        Self::new(
+            ApiErrorCode::Unknown,
+            message
+                .into()
+                .unwrap_or_else(|| "An unknown server-side error occurred.".to_string()),
+        )
+    }
+
+    /// Respond with the given HTTP status code instead of the one implied by the error code.
+    pub fn with_status(mut self, status: Status) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Tells the client how long to wait before making the request again.
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after_ms = Some(retry_after.as_millis() as u64);
+        self
+    }
+
+    /// The error code categorizing the error.
+    pub fn errcode(&self) -> ApiErrorCode {
+        self.errcode
+    }
+
+    /// The HTTP status code that should be used to represent the error.
+    pub fn status_code(&self) -> Status {
+        self.status.unwrap_or_else(|| self.errcode.status_code())
     }
 }
 
@@ -239,10 +439,46 @@ impl From<DieselError> for ApiError {
     fn from(error: DieselError) -> Self {
         debug!("Converting to ApiError from: {:?}", error);
 
-        Self::unknown(None)
+        match error {
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                Self::conflict(None)
+            }
+            DieselError::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+                Self::invalid_reference(None)
+            }
+            DieselError::DatabaseError(DatabaseErrorKind::SerializationFailure, _) => {
+                Self::unavailable("A concurrent request conflicted with this one.".to_string())
+            }
+            DieselError::DatabaseError(DatabaseErrorKind::UnableToSendCommand, _) => {
+                Self::unavailable("Lost the connection to the database.".to_string())
+            }
+            DieselError::DatabaseError(_, ref information)
+                if is_connection_lost(information.message()) =>
+            {
+                Self::unavailable("Lost the connection to the database.".to_string())
+            }
+            _ => Self::unknown(None),
+        }
     }
 }
 
+/// Whether or not a database error with the given message means the connection to the database
+/// was lost, e.g. because the database server was restarted.
+///
+/// Diesel doesn't expose the SQLSTATE of such errors, and libpq doesn't give them one at all when
+/// the connection breaks, so they can only be told apart by their messages.
+fn is_connection_lost(message: &str) -> bool {
+    const MESSAGES: [&str; 5] = [
+        "server closed the connection unexpectedly",
+        "terminating connection",
+        "no connection to the server",
+        "could not receive data from server",
+        "could not send data to server",
+    ];
+
+    MESSAGES.iter().any(|lost| message.contains(lost))
+}
+
 impl From<SystemTimeError> for ApiError {
     fn from(error: SystemTimeError) -> Self {
         debug!("Converting to ApiError from: {:?}", error);
@@ -271,7 +507,7 @@ impl From<R2d2Error> for ApiError {
     fn from(error: R2d2Error) -> Self {
         debug!("Converting to ApiError from: {:?}", error);
 
-        Self::unknown(None)
+        Self::unavailable("Could not connect to the database.".to_string())
     }
 }
 
@@ -287,7 +523,10 @@ impl From<RumaIdentifiersError> for ApiError {
     fn from(error: RumaIdentifiersError) -> Self {
         debug!("Converting to ApiError from: {:?}", error);
 
-        Self::unknown(None)
+        Self::new(
+            ApiErrorCode::InvalidParam,
+            format!("Not a valid Matrix identifier: {}", error),
+        )
     }
 }
 
@@ -324,7 +563,17 @@ impl From<ApiError> for IronError {
 impl Modifier<Response> for ApiError {
     fn modify(self, response: &mut Response) {
         response.headers.set(ContentType::json());
-        response.status = Some(self.errcode.status_code());
+        response.status = Some(self.status_code());
+
+        // Retry-After is given in whole seconds, rounded up so clients don't retry too early.
+        if let Some(retry_after_ms) = self.retry_after_ms {
+            let retry_after = retry_after_ms / 1000 + u64::from(retry_after_ms % 1000 != 0);
+
+            response
+                .headers
+                .set_raw("Retry-After", vec![retry_after.to_string().into_bytes()]);
+        }
+
         response.body = Some(Box::new(
             to_string(&self).expect("ApiError should always serialize"),
         ));
@@ -335,16 +584,29 @@ impl ApiErrorCode {
     /// The HTTP status code that should be used to represent the `ApiErrorCode`.
     pub fn status_code(self) -> Status {
         match self {
-            ApiErrorCode::AliasTaken => Status::Conflict,
+            ApiErrorCode::AliasTaken | ApiErrorCode::Conflict => Status::Conflict,
             ApiErrorCode::BadEvent | ApiErrorCode::BadJson => Status::UnprocessableEntity,
-            ApiErrorCode::Forbidden | ApiErrorCode::GuestAccessForbidden => Status::Forbidden,
-            ApiErrorCode::InvalidParam | ApiErrorCode::MissingParam | ApiErrorCode::NotJson => {
-                Status::BadRequest
-            }
+            ApiErrorCode::Forbidden
+            | ApiErrorCode::GuestAccessForbidden
+            | ApiErrorCode::UserDeactivated
+            | ApiErrorCode::UserLocked => Status::Forbidden,
+            ApiErrorCode::InvalidParam
+            | ApiErrorCode::InvalidReference
+            | ApiErrorCode::InvalidUsername
+            | ApiErrorCode::MissingParam
+            | ApiErrorCode::NotJson
+            | ApiErrorCode::UnsupportedRoomVersion
+            | ApiErrorCode::UserInUse => Status::BadRequest,
             ApiErrorCode::LimitExceeded => Status::TooManyRequests,
-            ApiErrorCode::NotFound | ApiErrorCode::Unimplemented => Status::NotFound,
+            ApiErrorCode::TooLarge => Status::PayloadTooLarge,
+            ApiErrorCode::NotFound | ApiErrorCode::Unimplemented | ApiErrorCode::Unrecognized => {
+                Status::NotFound
+            }
+            ApiErrorCode::Unavailable => Status::ServiceUnavailable,
             ApiErrorCode::Unknown => Status::InternalServerError,
-            ApiErrorCode::UnknownToken => Status::Unauthorized,
+            ApiErrorCode::InvalidToken
+            | ApiErrorCode::MissingToken
+            | ApiErrorCode::UnknownToken => Status::Unauthorized,
         }
     }
 }
@@ -358,16 +620,28 @@ impl Serialize for ApiErrorCode {
             ApiErrorCode::AliasTaken => "IO_RUMA_ALIAS_TAKEN",
             ApiErrorCode::BadEvent => "IO_RUMA_BAD_EVENT",
             ApiErrorCode::BadJson => "M_BAD_JSON",
+            ApiErrorCode::Conflict => "IO_RUMA_CONFLICT",
             ApiErrorCode::Forbidden => "M_FORBIDDEN",
             ApiErrorCode::GuestAccessForbidden => "M_GUEST_ACCESS_FORBIDDEN",
-            ApiErrorCode::InvalidParam => "IO_RUMA_INVALID_PARAM",
+            ApiErrorCode::InvalidParam => "M_INVALID_PARAM",
+            ApiErrorCode::InvalidToken => "M_INVALID_TOKEN",
+            ApiErrorCode::InvalidReference => "IO_RUMA_INVALID_REFERENCE",
+            ApiErrorCode::InvalidUsername => "M_INVALID_USERNAME",
             ApiErrorCode::LimitExceeded => "M_LIMIT_EXCEEDED",
             ApiErrorCode::MissingParam => "M_MISSING_PARAM",
+            ApiErrorCode::MissingToken => "M_MISSING_TOKEN",
             ApiErrorCode::NotFound => "M_NOT_FOUND",
             ApiErrorCode::NotJson => "M_NOT_JSON",
+            ApiErrorCode::TooLarge => "M_TOO_LARGE",
             ApiErrorCode::Unimplemented => "IO_RUMA_UNIMPLEMENTED",
+            ApiErrorCode::Unrecognized => "M_UNRECOGNIZED",
+            ApiErrorCode::Unavailable => "IO_RUMA_UNAVAILABLE",
             ApiErrorCode::Unknown => "M_UNKNOWN",
             ApiErrorCode::UnknownToken => "M_UNKNOWN_TOKEN",
+            ApiErrorCode::UnsupportedRoomVersion => "M_UNSUPPORTED_ROOM_VERSION",
+            ApiErrorCode::UserDeactivated => "M_USER_DEACTIVATED",
+            ApiErrorCode::UserLocked => "M_USER_LOCKED",
+            ApiErrorCode::UserInUse => "M_USER_IN_USE",
         };
 
         serializer.serialize_str(value)
@@ -427,10 +701,30 @@ where
 #[cfg(test)]
 mod tests {
     use crate::error::ApiError;
+    use crate::test::Test;
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+    use diesel::{sql_query, RunQueryDsl};
     use iron::headers::ContentType;
     use iron::modifier::Modifier;
     use iron::status::Status;
     use iron::Response;
+    use serde_json::{from_str, Value};
+
+    /// Apply the `ApiError` converted from `error` to a response, returning its status and
+    /// errcode.
+    fn status_and_errcode(error: DieselError) -> (Status, String) {
+        let mut response = Response::new();
+        ApiError::from(error).modify(&mut response);
+
+        let mut body = Vec::new();
+        response.body.unwrap().write_body(&mut body).unwrap();
+        let json: Value = from_str(&String::from_utf8(body).unwrap()).unwrap();
+
+        (
+            response.status.unwrap(),
+            json["errcode"].as_str().unwrap().to_string(),
+        )
+    }
 
     #[test]
     fn api_error_status_and_headers_modified() {
@@ -444,4 +738,88 @@ mod tests {
         );
         assert_eq!(response.status.unwrap(), Status::Forbidden);
     }
+
+    #[test]
+    fn diesel_unique_violation_is_conflict() {
+        let error = DieselError::DatabaseError(
+            DatabaseErrorKind::UniqueViolation,
+            Box::new("duplicate key value violates unique constraint".to_string()),
+        );
+
+        assert_eq!(
+            status_and_errcode(error),
+            (Status::Conflict, "IO_RUMA_CONFLICT".to_string())
+        );
+    }
+
+    #[test]
+    fn diesel_foreign_key_violation_is_bad_request() {
+        let error = DieselError::DatabaseError(
+            DatabaseErrorKind::ForeignKeyViolation,
+            Box::new("insert or update violates foreign key constraint".to_string()),
+        );
+
+        assert_eq!(
+            status_and_errcode(error),
+            (Status::BadRequest, "IO_RUMA_INVALID_REFERENCE".to_string())
+        );
+    }
+
+    #[test]
+    fn diesel_not_found_is_internal_server_error() {
+        // Handlers expecting a missing row look for one with `optional` and respond with their
+        // own error, so a row missing here is unexpected.
+        assert_eq!(
+            status_and_errcode(DieselError::NotFound),
+            (Status::InternalServerError, "M_UNKNOWN".to_string())
+        );
+    }
+
+    #[test]
+    fn diesel_serialization_failure_is_service_unavailable() {
+        let error = DieselError::DatabaseError(
+            DatabaseErrorKind::SerializationFailure,
+            Box::new("could not serialize access due to concurrent update".to_string()),
+        );
+
+        assert_eq!(
+            status_and_errcode(error),
+            (
+                Status::ServiceUnavailable,
+                "IO_RUMA_UNAVAILABLE".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn diesel_query_error_is_internal_server_error() {
+        let test = Test::new();
+
+        let error = sql_query("SELECT * FROM no_such_table")
+            .execute(&*test.connection())
+            .unwrap_err();
+
+        assert_eq!(
+            status_and_errcode(error),
+            (Status::InternalServerError, "M_UNKNOWN".to_string())
+        );
+    }
+
+    #[test]
+    fn diesel_lost_connection_is_service_unavailable() {
+        let test = Test::new();
+        let connection = test.connection();
+
+        let error = sql_query("SELECT pg_terminate_backend(pg_backend_pid())")
+            .execute(&*connection)
+            .unwrap_err();
+
+        assert_eq!(
+            status_and_errcode(error),
+            (
+                Status::ServiceUnavailable,
+                "IO_RUMA_UNAVAILABLE".to_string()
+            )
+        );
+    }
 }
@@ -0,0 +1,151 @@
+//! Limits on how many passwords are hashed at the same time.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use iron::typemap::Key;
+use iron::{Plugin, Request};
+use persistent::Read as PersistentRead;
+
+use crate::error::ApiError;
+
+/// Bounds the number of concurrent password hashes and verifications, which are expensive
+/// enough that a burst of them can starve the server.
+#[derive(Clone, Debug)]
+pub struct HashLimiter {
+    /// The maximum number of concurrent hashes, or zero for no limit.
+    max_concurrent_hashes: usize,
+    /// The number of hashes currently being computed.
+    in_progress: Arc<AtomicUsize>,
+}
+
+/// Permission to hash a password. The slot is released when the permit is dropped.
+#[derive(Debug)]
+pub struct HashPermit {
+    /// The counter of the limiter the permit was acquired from.
+    in_progress: Arc<AtomicUsize>,
+}
+
+impl HashLimiter {
+    /// Creates a new `HashLimiter` allowing up to `max_concurrent_hashes` at once. Zero means
+    /// unlimited.
+    pub fn new(max_concurrent_hashes: usize) -> Self {
+        Self {
+            max_concurrent_hashes,
+            in_progress: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Reserves a slot for hashing a password.
+    ///
+    /// Rather than waiting for a slot to free up, this fails with `M_LIMIT_EXCEEDED` if all of
+    /// them are taken.
+    pub fn acquire(&self) -> Result<HashPermit, ApiError> {
+        let in_progress = self.in_progress.fetch_add(1, Ordering::SeqCst);
+        let permit = HashPermit {
+            in_progress: self.in_progress.clone(),
+        };
+
+        if self.max_concurrent_hashes != 0 && in_progress >= self.max_concurrent_hashes {
+            return Err(ApiError::limited_rate(
+                "Too many concurrent requests, try again later".to_string(),
+            ));
+        }
+
+        Ok(permit)
+    }
+
+    /// Extract the `HashLimiter` stored in the request.
+    pub fn from_request(request: &mut Request<'_, '_>) -> Result<Arc<Self>, ApiError> {
+        request
+            .get::<PersistentRead<Self>>()
+            .map_err(ApiError::from)
+    }
+}
+
+impl Drop for HashPermit {
+    fn drop(&mut self) {
+        self.in_progress.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Key for HashLimiter {
+    type Value = Self;
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use crate::test::Test;
+
+    #[test]
+    fn registration_beyond_limit() {
+        let test = Test::with_config(|config| config.max_concurrent_hashes = 1);
+
+        let permit = test.hash_limiter().acquire().unwrap();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::TooManyRequests);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_LIMIT_EXCEEDED"
+        );
+
+        drop(permit);
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn login_beyond_limit() {
+        let test = Test::with_config(|config| config.max_concurrent_hashes = 2);
+        let user = test.create_user();
+
+        let first = test.hash_limiter().acquire().unwrap();
+        let _second = test.hash_limiter().acquire().unwrap();
+
+        let login = format!(
+            r#"{{"type": "m.login.password", "user": "{}", "password": "secret"}}"#,
+            user.id
+        );
+
+        let response = test.post("/_matrix/client/r0/login", &login);
+        assert_eq!(response.status, Status::TooManyRequests);
+
+        drop(first);
+
+        let response = test.post("/_matrix/client/r0/login", &login);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn password_change_beyond_limit() {
+        let test = Test::with_config(|config| config.max_concurrent_hashes = 1);
+        let user = test.create_user();
+
+        let _permit = test.hash_limiter().acquire().unwrap();
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/account/password?access_token={}",
+                user.token
+            ),
+            r#"{"new_password": "hidden"}"#,
+        );
+        assert_eq!(response.status, Status::TooManyRequests);
+    }
+
+    #[test]
+    fn unlimited() {
+        let test = Test::with_config(|config| config.max_concurrent_hashes = 0);
+
+        let _permits: Vec<_> = (0..10)
+            .map(|_| test.hash_limiter().acquire().unwrap())
+            .collect();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+    }
+}
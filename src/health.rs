@@ -0,0 +1,92 @@
+//! A healthcheck for load balancers and process supervisors.
+
+use diesel::{sql_query, RunQueryDsl};
+use iron::status::Status;
+use iron::{Handler, IronResult, Request, Response};
+
+use crate::db::DB;
+use crate::modifier::SerializableResponse;
+
+/// The `/health` endpoint.
+///
+/// Responds with `200 {"status": "ok"}` if a trivial query can be run against the database. If a
+/// connection can't be taken from the pool (because PostgreSQL is unreachable, the pool timed
+/// out, or the server is shutting down) or the query fails, responds with
+/// `503 {"status": "degraded"}` instead.
+#[derive(Clone, Copy, Debug)]
+pub struct GetHealth;
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct HealthResponse {
+    /// Either "ok" or "degraded".
+    status: &'static str,
+}
+
+impl Handler for GetHealth {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let healthy = match DB::from_request(request) {
+            Ok(connection) => match sql_query("SELECT 1").execute(&*connection) {
+                Ok(_) => true,
+                Err(error) => {
+                    debug!("Healthcheck query failed: {:?}", error);
+
+                    false
+                }
+            },
+            Err(error) => {
+                debug!("Healthcheck could not connect to the database: {:?}", error);
+
+                false
+            }
+        };
+
+        let (status, response) = if healthy {
+            (Status::Ok, HealthResponse { status: "ok" })
+        } else {
+            (
+                Status::ServiceUnavailable,
+                HealthResponse { status: "degraded" },
+            )
+        };
+
+        Ok(Response::with((status, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use iron::status::Status;
+
+    use crate::test::Test;
+
+    #[test]
+    fn healthy_database() {
+        let test = Test::new();
+
+        let response = test.get("/health");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("status").unwrap().as_str().unwrap(),
+            "ok"
+        );
+    }
+
+    #[test]
+    fn unreachable_database() {
+        let test = Test::new();
+
+        assert!(test.shutdown().drain(Duration::from_secs(1)).unwrap());
+
+        let response = test.get("/health");
+
+        assert_eq!(response.status, Status::ServiceUnavailable);
+        assert_eq!(
+            response.json().get("status").unwrap().as_str().unwrap(),
+            "degraded"
+        );
+    }
+}
@@ -49,15 +49,26 @@ pub mod authentication;
 pub mod config;
 pub mod crypto;
 pub mod db;
+pub mod email;
 pub mod error;
+pub mod hash_limiter;
+pub mod health;
+pub mod login_lockout;
+pub mod metrics;
 /// Models for the API's domain objects.
 pub mod models;
 pub mod modifier;
 pub mod query;
+pub mod rate_limiter;
+pub mod retry;
 pub mod schema;
 pub mod server;
+pub mod server_keys;
+pub mod shutdown;
+pub mod storage_quota;
 pub mod swagger;
 #[cfg(test)]
 pub mod test;
+pub mod user_ip_throttle;
 
 embed_migrations!();
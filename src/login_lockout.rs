@@ -0,0 +1,170 @@
+//! Temporarily locking accounts after repeated failed attempts to log in to them.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use iron::typemap::Key;
+use iron::{Plugin, Request};
+use persistent::Read as PersistentRead;
+use ruma_identifiers::UserId;
+
+use crate::config::LoginLockout;
+use crate::error::ApiError;
+
+/// The number of accounts to track before forgetting those whose failures no longer count.
+const MAX_TRACKED_USERS: usize = 10_000;
+
+/// Counts the failed attempts to log in to each account and which accounts are locked.
+#[derive(Clone, Debug, Default)]
+pub struct LoginLockouts {
+    /// The recent failed attempts to log in to each account.
+    failures: Arc<Mutex<HashMap<UserId, Failures>>>,
+}
+
+/// The consecutive failed attempts to log in to an account.
+#[derive(Clone, Copy, Debug)]
+struct Failures {
+    /// When the first of the failures happened.
+    first_failed_at: Instant,
+    /// The number of failures since then.
+    count: u32,
+    /// When the account is unlocked again, if the failures locked it.
+    locked_until: Option<Instant>,
+}
+
+impl Failures {
+    /// Whether or not the failures still count against the account at the given time.
+    fn is_current(&self, lockout: &LoginLockout, now: Instant) -> bool {
+        match self.locked_until {
+            Some(locked_until) => locked_until > now,
+            None => now < self.first_failed_at + Duration::from_millis(lockout.window_ms),
+        }
+    }
+}
+
+impl LoginLockouts {
+    /// Fails with `M_USER_LOCKED` if the account is locked.
+    pub fn ensure_unlocked(&self, user_id: &UserId) -> Result<(), ApiError> {
+        let now = Instant::now();
+        let mut failures = self.failures.lock()?;
+
+        let locked_until = match failures
+            .get(user_id)
+            .and_then(|failures| failures.locked_until)
+        {
+            Some(locked_until) => locked_until,
+            None => return Ok(()),
+        };
+
+        if locked_until <= now {
+            // The account starts over with no failures once the cooldown has elapsed.
+            failures.remove(user_id);
+
+            return Ok(());
+        }
+
+        Err(ApiError::user_locked(
+            "Too many failed attempts to log in, try again later".to_string(),
+        )
+        .with_retry_after(locked_until - now))
+    }
+
+    /// Counts a failed attempt to log in to the account, locking it if that was one failure too
+    /// many.
+    pub fn record_failure(&self, user_id: &UserId, lockout: &LoginLockout) -> Result<(), ApiError> {
+        let now = Instant::now();
+        let mut failures = self.failures.lock()?;
+
+        if failures.len() >= MAX_TRACKED_USERS {
+            failures.retain(|_, failures| failures.is_current(lockout, now));
+        }
+
+        let user_failures = failures.entry(user_id.clone()).or_insert(Failures {
+            first_failed_at: now,
+            count: 0,
+            locked_until: None,
+        });
+
+        if !user_failures.is_current(lockout, now) {
+            *user_failures = Failures {
+                first_failed_at: now,
+                count: 0,
+                locked_until: None,
+            };
+        }
+
+        user_failures.count += 1;
+
+        if user_failures.count >= lockout.max_failures {
+            user_failures.locked_until = Some(now + Duration::from_millis(lockout.cooldown_ms));
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the failed attempts to log in to the account, e.g. after a successful login.
+    pub fn reset(&self, user_id: &UserId) -> Result<(), ApiError> {
+        self.failures.lock()?.remove(user_id);
+
+        Ok(())
+    }
+
+    /// Extract the `LoginLockouts` stored in the request.
+    pub fn from_request(request: &mut Request<'_, '_>) -> Result<Arc<Self>, ApiError> {
+        request
+            .get::<PersistentRead<Self>>()
+            .map_err(ApiError::from)
+    }
+}
+
+impl Key for LoginLockouts {
+    type Value = Self;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use ruma_identifiers::UserId;
+
+    use super::LoginLockouts;
+    use crate::config::LoginLockout;
+
+    const LOCKOUT: LoginLockout = LoginLockout {
+        max_failures: 2,
+        window_ms: 100,
+        cooldown_ms: 60_000,
+    };
+
+    fn user_id(localpart: &str) -> UserId {
+        UserId::try_from(format!("@{}:ruma.test", localpart).as_str()).unwrap()
+    }
+
+    #[test]
+    fn lockout_is_per_account() {
+        let lockouts = LoginLockouts::default();
+
+        for _ in 0..2 {
+            lockouts.ensure_unlocked(&user_id("carl")).unwrap();
+            lockouts.record_failure(&user_id("carl"), &LOCKOUT).unwrap();
+        }
+
+        let error = lockouts.ensure_unlocked(&user_id("carl")).unwrap_err();
+        assert_eq!(error.status_code(), iron::status::Status::Forbidden);
+        assert!(lockouts.ensure_unlocked(&user_id("erin")).is_ok());
+    }
+
+    #[test]
+    fn failures_outside_the_window_do_not_count() {
+        let lockouts = LoginLockouts::default();
+
+        lockouts.record_failure(&user_id("carl"), &LOCKOUT).unwrap();
+        sleep(Duration::from_millis(LOCKOUT.window_ms));
+        lockouts.record_failure(&user_id("carl"), &LOCKOUT).unwrap();
+
+        assert!(lockouts.ensure_unlocked(&user_id("carl")).is_ok());
+    }
+}
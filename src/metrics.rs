@@ -0,0 +1,201 @@
+//! Prometheus metrics about the requests handled by the server.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use iron::headers::ContentType;
+use iron::modifiers::Header;
+use iron::status::Status;
+use iron::typemap::Key;
+use iron::{Handler, IronResult, Plugin, Request, Response};
+use persistent::Read as PersistentRead;
+
+use crate::error::ApiError;
+
+/// Counters shared by all requests to the server.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// The number of handled requests, keyed by HTTP method, route and status code.
+    requests: Mutex<BTreeMap<(String, String, u16), usize>>,
+    /// The number of successful login attempts.
+    login_successes: AtomicUsize,
+    /// The number of failed login attempts.
+    login_failures: AtomicUsize,
+}
+
+impl Metrics {
+    /// Count a request to the given route that was answered with the given status.
+    pub fn record_request(
+        &self,
+        method: &str,
+        route: &str,
+        status: Status,
+    ) -> Result<(), ApiError> {
+        let mut requests = self.requests.lock().map_err(ApiError::from)?;
+
+        *requests
+            .entry((method.to_string(), route.to_string(), status.to_u16()))
+            .or_insert(0) += 1;
+
+        Ok(())
+    }
+
+    /// Count a login attempt.
+    pub fn record_login(&self, successful: bool) {
+        let counter = if successful {
+            &self.login_successes
+        } else {
+            &self.login_failures
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render all counters in the Prometheus text exposition format.
+    pub fn render(&self) -> Result<String, ApiError> {
+        let requests = self.requests.lock().map_err(ApiError::from)?;
+        let mut output = String::new();
+
+        output.push_str("# HELP ruma_http_requests_total Number of HTTP requests handled.\n");
+        output.push_str("# TYPE ruma_http_requests_total counter\n");
+
+        for ((method, route, status), count) in requests.iter() {
+            output.push_str(&format!(
+                "ruma_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}\n",
+                method, route, status, count
+            ));
+        }
+
+        output.push_str("# HELP ruma_logins_total Number of login attempts.\n");
+        output.push_str("# TYPE ruma_logins_total counter\n");
+
+        output.push_str(&format!(
+            "ruma_logins_total{{result=\"success\"}} {}\n",
+            self.login_successes.load(Ordering::Relaxed)
+        ));
+        output.push_str(&format!(
+            "ruma_logins_total{{result=\"failure\"}} {}\n",
+            self.login_failures.load(Ordering::Relaxed)
+        ));
+
+        Ok(output)
+    }
+
+    /// Extract the `Metrics` stored in the request.
+    pub fn from_request(request: &mut Request<'_, '_>) -> Result<Arc<Self>, ApiError> {
+        request
+            .get::<PersistentRead<Self>>()
+            .map_err(ApiError::from)
+    }
+}
+
+impl Key for Metrics {
+    type Value = Self;
+}
+
+/// The `/metrics` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetMetrics;
+
+impl Handler for GetMetrics {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let metrics = Metrics::from_request(request)?;
+
+        Ok(Response::with((
+            Status::Ok,
+            Header(ContentType(
+                "text/plain; version=0.0.4"
+                    .parse()
+                    .expect("valid MIME type"),
+            )),
+            metrics.render()?,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use crate::test::Test;
+
+    #[test]
+    fn metrics_count_requests_by_route() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        for _ in 0..2 {
+            let response = test.get(&format!(
+                "/_matrix/client/r0/sync?access_token={}",
+                user.token
+            ));
+            assert_eq!(response.status, Status::Ok);
+        }
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            &format!(
+                r#"{{"type": "m.login.password", "user": "{}", "password": "wrong"}}"#,
+                user.id
+            ),
+        );
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.get("/metrics");
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response
+            .body
+            .contains(r#"ruma_http_requests_total{method="POST",route="/_matrix/client/r0/register",status="200"} 1"#));
+        assert!(response
+            .body
+            .contains(r#"ruma_http_requests_total{method="GET",route="/_matrix/client/r0/sync",status="200"} 2"#));
+        assert!(response
+            .body
+            .contains(r#"ruma_http_requests_total{method="POST",route="/_matrix/client/r0/login",status="403"} 1"#));
+        assert!(response
+            .body
+            .contains(r#"ruma_logins_total{result="failure"} 1"#));
+    }
+
+    #[test]
+    fn metrics_route_uses_path_parameter_names() {
+        let test = Test::new();
+        let (user, room_id) = test.initial_fixtures("{}");
+
+        test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/members?access_token={}",
+            room_id, user.token
+        ));
+
+        let response = test.get("/metrics");
+
+        assert!(response
+            .body
+            .contains(r#"route="/_matrix/client/r0/rooms/:room_id/members",status="200"} 1"#));
+        assert!(!response.body.contains(&room_id));
+    }
+
+    #[test]
+    fn metrics_require_admin() {
+        let test = Test::with_config(|config| config.metrics_require_admin = true);
+        let user = test.create_user();
+        let admin = test.create_admin();
+
+        let response = test.get(&format!("/metrics?access_token={}", user.token));
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = test.get(&format!("/metrics?access_token={}", admin.token));
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn metrics_disabled() {
+        let test = Test::with_config(|config| config.enable_metrics = false);
+
+        let response = test.get("/metrics");
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+}
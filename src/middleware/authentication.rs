@@ -1,24 +1,36 @@
 //! Iron middleware to handle user authentication.
 
 use std::convert::TryFrom;
+use std::str::from_utf8;
 
 use bodyparser;
+use iron::headers::UserAgent;
 use iron::{BeforeMiddleware, IronError, IronResult, Plugin, Request};
 use ruma_identifiers::UserId;
 use serde_json::Value;
+use url::percent_encoding::percent_decode;
 use url::Url;
 
 use crate::authentication::{AuthParams, InteractiveAuth, PasswordAuthParams};
 use crate::config::Config;
 use crate::db::DB;
 use crate::error::ApiError;
-use crate::models::access_token::AccessToken;
+use crate::hash_limiter::HashLimiter;
+use crate::models::access_token::{AccessToken, AuthedUser};
 use crate::models::user::User;
+use crate::models::user_ip::UserIp;
+use crate::user_ip_throttle::UserIpThrottle;
 
 /// Handles access token authentication for all API endpoints that require it.
 #[derive(Clone, Copy, Debug)]
 pub struct AccessTokenAuth;
 
+/// Restricts an endpoint to server administrators.
+///
+/// This must be linked after `AccessTokenAuth`.
+#[derive(Clone, Copy, Debug)]
+pub struct AdminAuth;
+
 /// Handles Matrix's interactive authentication protocol for all API endpoints that require it.
 #[derive(Clone, Debug)]
 pub struct UIAuth {
@@ -36,32 +48,57 @@ impl UIAuth {
 impl BeforeMiddleware for AccessTokenAuth {
     fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
         let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+        let user_ip_throttle = UserIpThrottle::from_request(request)?;
         let url: Url = request.url.clone().into();
-        let mut query_pairs = url.query_pairs();
 
-        if let Some((_, ref token)) = query_pairs.find(|&(ref key, _)| key == "access_token") {
-            let access_token = match AccessToken::find_valid_by_token(&connection, token)? {
-                Some(access_token) => access_token,
-                None => Err(ApiError::unauthorized("Unknown token".to_string()))?,
-            };
+        let token = match access_token_header(request)? {
+            Some(token) => Some(token),
+            None => access_token_param(&url),
+        };
 
-            match User::find_active_user(&connection, &access_token.user_id)? {
-                Some(user) => {
-                    request.extensions.insert::<AccessToken>(access_token);
-                    request.extensions.insert::<User>(user);
+        if let Some(token) = token {
+            let AuthedUser { user, access_token } =
+                AccessToken::authenticate(&connection, &token, &config)?;
 
-                    return Ok(());
-                }
-                None => Err(ApiError::unauthorized(
-                    "No user with the given token was found".to_string(),
-                ))?,
+            let ip = request.remote_addr.ip().to_string();
+            let user_agent = request
+                .headers
+                .get::<UserAgent>()
+                .map(|user_agent| user_agent.to_string())
+                .unwrap_or_default();
+
+            if user_ip_throttle.should_record(access_token.id, &ip, &user_agent)? {
+                UserIp::record(&connection, &access_token, ip, user_agent)?;
             }
+
+            request.extensions.insert::<AccessToken>(access_token);
+            request.extensions.insert::<User>(user);
+
+            return Ok(());
         }
 
         Err(IronError::from(ApiError::unauthorized(None)))
     }
 }
 
+impl BeforeMiddleware for AdminAuth {
+    fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
+        let user = request
+            .extensions
+            .get::<User>()
+            .expect("AccessTokenAuth should ensure a user");
+
+        if !user.admin {
+            Err(ApiError::unauthorized(
+                "Only server administrators can use this API".to_string(),
+            ))?;
+        }
+
+        Ok(())
+    }
+}
+
 impl BeforeMiddleware for UIAuth {
     fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
         let json = request
@@ -77,6 +114,7 @@ impl BeforeMiddleware for UIAuth {
                         AuthParams::Password(PasswordAuthParams { password, user_id });
 
                     let connection = DB::from_request(request)?;
+                    let _permit = HashLimiter::from_request(request)?.acquire()?;
 
                     if let Ok(user) = auth_params.authenticate(&connection) {
                         request.extensions.insert::<User>(user);
@@ -91,6 +129,65 @@ impl BeforeMiddleware for UIAuth {
     }
 }
 
+/// Extracts the access token from an `Authorization: Bearer <token>` header, if there is one.
+///
+/// The scheme is matched case-insensitively and whitespace around the scheme and the token is
+/// ignored. A header with any other scheme, or without a token, is rejected.
+fn access_token_header(request: &Request<'_, '_>) -> Result<Option<String>, ApiError> {
+    let header = match request.headers.get_raw("Authorization") {
+        Some(values) => values,
+        None => return Ok(None),
+    };
+
+    let header = match header {
+        [value] => from_utf8(value).map_err(|_| {
+            ApiError::missing_token("The Authorization header is not valid UTF-8".to_string())
+        })?,
+        _ => {
+            return Err(ApiError::missing_token(
+                "Only one Authorization header may be given".to_string(),
+            ))
+        }
+    };
+
+    let mut parts = header.trim().splitn(2, char::is_whitespace);
+    let scheme = parts.next().unwrap_or_default();
+    let token = parts.next().unwrap_or_default().trim();
+
+    if !scheme.eq_ignore_ascii_case("Bearer") {
+        return Err(ApiError::missing_token(
+            "The Authorization header must use the Bearer scheme".to_string(),
+        ));
+    }
+
+    if token.is_empty() {
+        return Err(ApiError::missing_token(
+            "The Authorization header has no access token".to_string(),
+        ));
+    }
+
+    Ok(Some(token.to_string()))
+}
+
+/// Extracts the percent-decoded `access_token` query parameter.
+///
+/// Unlike form decoding, this keeps a literal `+` as it is rather than turning it into a space,
+/// since access tokens can be base64 encoded and clients don't always escape them.
+fn access_token_param(url: &Url) -> Option<String> {
+    url.query()?.split('&').find_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+
+        if parts.next()? != "access_token" {
+            return None;
+        }
+
+        percent_decode(parts.next()?.as_bytes())
+            .decode_utf8()
+            .ok()
+            .map(|token| token.into_owned())
+    })
+}
+
 /// Attempts to extract a user ID and password from the supplied JSON value.
 fn get_user_id_and_password(json: &Value, config: &Config) -> Result<(UserId, String), ()> {
     let username = json
@@ -122,3 +219,153 @@ fn is_m_login_password(json: &Value) -> bool {
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::prelude::*;
+    use iron::headers::Headers;
+    use iron::method::Method;
+    use iron::status::Status;
+    use ruma_identifiers::UserId;
+
+    use crate::config::TokenFormat;
+    use crate::crypto::hash_opaque_token;
+    use crate::models::access_token::NewAccessToken;
+    use crate::schema::access_tokens;
+    use crate::test::{Response, Test};
+
+    /// An opaque access token with characters that have a special meaning in query strings.
+    const TOKEN: &str = "ab+cd/ef==";
+
+    /// Stores `TOKEN` as an opaque access token for a new user.
+    fn opaque_token_fixture() -> Test {
+        let test = Test::with_config(|config| config.token_format = TokenFormat::Opaque);
+        let user = test.create_user();
+
+        diesel::insert_into(access_tokens::table)
+            .values(&NewAccessToken {
+                user_id: UserId::try_from(user.id.as_ref()).unwrap(),
+                value: hash_opaque_token(TOKEN),
+                device_id: "special".to_string(),
+            })
+            .execute(&*test.connection())
+            .unwrap();
+
+        test
+    }
+
+    fn sync_status(test: &Test, token: &str) -> Status {
+        test.get(&format!("/_matrix/client/r0/sync?access_token={}", token))
+            .status
+    }
+
+    /// Makes a sync request with the given `Authorization` header.
+    fn sync_with_authorization(test: &Test, authorization: &str) -> Response {
+        let mut headers = Headers::new();
+        headers.set_raw("Authorization", vec![authorization.as_bytes().to_vec()]);
+
+        test.request_with_headers(Method::Get, "/_matrix/client/r0/sync", "", headers)
+    }
+
+    #[test]
+    fn raw_access_token() {
+        let test = opaque_token_fixture();
+
+        assert_eq!(sync_status(&test, TOKEN), Status::Ok);
+    }
+
+    #[test]
+    fn percent_encoded_access_token() {
+        let test = opaque_token_fixture();
+
+        assert_eq!(sync_status(&test, "ab%2Bcd%2Fef%3D%3D"), Status::Ok);
+    }
+
+    #[test]
+    fn access_token_with_space_for_plus() {
+        let test = opaque_token_fixture();
+
+        assert_eq!(sync_status(&test, "ab%20cd/ef=="), Status::Unauthorized);
+    }
+
+    #[test]
+    fn url_safe_macaroon() {
+        let test = Test::new();
+        let user = test.create_user();
+        let token = user
+            .token
+            .replace('+', "-")
+            .replace('/', "_")
+            .trim_end_matches('=')
+            .to_string();
+
+        assert_eq!(sync_status(&test, &user.token), Status::Ok);
+        assert_eq!(sync_status(&test, &token), Status::Ok);
+    }
+
+    #[test]
+    fn bearer_authorization_header() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        for authorization in &[
+            format!("Bearer {}", user.token),
+            format!("bearer {}", user.token),
+            format!("BEARER {}", user.token),
+        ] {
+            assert_eq!(
+                sync_with_authorization(&test, authorization).status,
+                Status::Ok
+            );
+        }
+    }
+
+    #[test]
+    fn authorization_header_with_extra_whitespace() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        for authorization in &[
+            format!("  Bearer {}", user.token),
+            format!("Bearer {}  ", user.token),
+            format!("Bearer   {}", user.token),
+            format!("\tBearer\t{} ", user.token),
+        ] {
+            assert_eq!(
+                sync_with_authorization(&test, authorization).status,
+                Status::Ok
+            );
+        }
+    }
+
+    #[test]
+    fn non_bearer_authorization_header() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        for authorization in &[
+            format!("Basic {}", user.token),
+            "Bearer".to_string(),
+            "Bearer   ".to_string(),
+            user.token.clone(),
+        ] {
+            let response = sync_with_authorization(&test, authorization);
+            assert_eq!(response.status, Status::Unauthorized);
+            assert_eq!(
+                response.json().get("errcode").unwrap().as_str().unwrap(),
+                "M_MISSING_TOKEN"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_bearer_token() {
+        let test = Test::new();
+        test.create_user();
+
+        let response = sync_with_authorization(&test, "Bearer not-a-token");
+        assert_eq!(response.status, Status::Unauthorized);
+    }
+}
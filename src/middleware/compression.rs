@@ -0,0 +1,154 @@
+//! Iron middleware to compress response bodies.
+
+use std::io::Write;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use iron::headers::{AcceptEncoding, ContentEncoding, Encoding, Vary};
+use iron::{AfterMiddleware, IronResult, Request, Response};
+use unicase::UniCase;
+
+/// Responses with bodies smaller than this many bytes are sent uncompressed, since the savings
+/// would not be worth the cost of compressing them.
+const MINIMUM_COMPRESSED_SIZE: usize = 1024;
+
+/// Compresses large response bodies with gzip for clients that accept it.
+#[derive(Clone, Copy, Debug)]
+pub struct ResponseCompression;
+
+/// Whether or not the client sent an `Accept-Encoding` header that allows gzip.
+fn accepts_gzip(request: &Request<'_, '_>) -> bool {
+    match request.headers.get::<AcceptEncoding>() {
+        Some(AcceptEncoding(encodings)) => encodings
+            .iter()
+            .any(|encoding| encoding.item == Encoding::Gzip && encoding.quality.0 > 0),
+        None => false,
+    }
+}
+
+impl AfterMiddleware for ResponseCompression {
+    fn after(&self, request: &mut Request<'_, '_>, mut response: Response) -> IronResult<Response> {
+        if response.headers.has::<ContentEncoding>() {
+            return Ok(response);
+        }
+
+        let mut body = match response.body.take() {
+            Some(body) => body,
+            None => return Ok(response),
+        };
+
+        let mut buffer = Vec::new();
+
+        if let Err(error) = body.write_body(&mut buffer) {
+            debug!("Failed to buffer the response body: {:?}", error);
+            response.body = Some(body);
+            return Ok(response);
+        }
+
+        response
+            .headers
+            .set(Vary::Items(vec![UniCase("Accept-Encoding".to_string())]));
+
+        if buffer.len() < MINIMUM_COMPRESSED_SIZE || !accepts_gzip(request) {
+            response.body = Some(Box::new(buffer));
+            return Ok(response);
+        }
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+
+        match encoder.write_all(&buffer).and_then(|_| encoder.finish()) {
+            Ok(compressed) => {
+                response.headers.set(ContentEncoding(vec![Encoding::Gzip]));
+                response.body = Some(Box::new(compressed));
+            }
+            Err(error) => {
+                debug!("Failed to compress the response body: {:?}", error);
+                response.body = Some(Box::new(buffer));
+            }
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::headers::{AcceptEncoding, ContentEncoding, Encoding, Headers, QualityItem};
+    use iron::method::Method;
+    use iron::status::Status;
+
+    use crate::test::Test;
+
+    fn accept_gzip() -> Headers {
+        let mut headers = Headers::new();
+        headers.set(AcceptEncoding(vec![QualityItem::new(
+            Encoding::Gzip,
+            Default::default(),
+        )]));
+        headers
+    }
+
+    fn sync_path(test: &Test) -> String {
+        let (user, room_id) = test.initial_fixtures("{}");
+
+        for txn_id in 0..10 {
+            test.send_message(&user.token, &room_id, "Hello, compression!", txn_id);
+        }
+
+        format!("/_matrix/client/r0/sync?access_token={}", user.token)
+    }
+
+    #[test]
+    fn large_response_is_compressed() {
+        let test = Test::new();
+        let path = sync_path(&test);
+
+        let response = test.request_with_headers(Method::Get, &path, "", accept_gzip());
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.headers.get::<ContentEncoding>(),
+            Some(&ContentEncoding(vec![Encoding::Gzip]))
+        );
+        assert!(response.json().get("rooms").is_some());
+    }
+
+    #[test]
+    fn response_is_not_compressed_without_accept_encoding() {
+        let test = Test::new();
+        let path = sync_path(&test);
+
+        let response = test.get(&path);
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.headers.get::<ContentEncoding>().is_none());
+        assert!(response.json().get("rooms").is_some());
+    }
+
+    #[test]
+    fn small_response_is_not_compressed() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let response = test.request_with_headers(
+            Method::Get,
+            &format!("/_matrix/client/r0/pushers?access_token={}", user.token),
+            "",
+            accept_gzip(),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.headers.get::<ContentEncoding>().is_none());
+    }
+
+    #[test]
+    fn compression_disabled() {
+        let test = Test::with_config(|config| config.enable_response_compression = false);
+        let path = sync_path(&test);
+
+        let response = test.request_with_headers(Method::Get, &path, "", accept_gzip());
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.headers.get::<ContentEncoding>().is_none());
+    }
+}
@@ -0,0 +1,353 @@
+//! Iron middleware to replay the responses of retried requests.
+
+use std::str::from_utf8;
+
+use iron::headers::ContentType;
+use iron::status::Status;
+use iron::{AroundMiddleware, Handler, IronResult, Request, Response};
+
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::TransactionIdParam;
+use crate::models::access_token::AccessToken;
+use crate::models::idempotent_response::{IdempotentResponse, NewIdempotentResponse};
+use crate::models::user::User;
+
+/// The header clients can use to make requests to endpoints without a transaction ID safe to
+/// retry.
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// Makes an endpoint idempotent for authenticated users.
+///
+/// Requests are identified by the user, the device of their access token, the path and either
+/// the path's `transaction_id` parameter or the `Idempotency-Key` header. The identity is reserved
+/// before the endpoint runs. When a request with the same identity has already succeeded, the
+/// stored response is returned instead of running the endpoint again, and while one is still
+/// running, the request fails with `409`. Requests without a key are passed through unchanged.
+///
+/// This must be linked after `AccessTokenAuth` and, for endpoints with a transaction ID, after
+/// `TransactionIdParam`.
+#[derive(Clone, Copy, Debug)]
+pub struct Idempotent;
+
+/// The handler wrapped by `Idempotent`.
+struct IdempotentHandler {
+    /// The endpoint's handler.
+    handler: Box<dyn Handler>,
+}
+
+impl AroundMiddleware for Idempotent {
+    fn around(self, handler: Box<dyn Handler>) -> Box<dyn Handler> {
+        Box::new(IdempotentHandler { handler })
+    }
+}
+
+/// Returns the transaction ID or `Idempotency-Key` header of the request, if any.
+fn idempotency_key(request: &Request<'_, '_>) -> Option<String> {
+    if let Some(transaction_id) = request.extensions.get::<TransactionIdParam>() {
+        return Some(transaction_id.clone());
+    }
+
+    request
+        .headers
+        .get_raw(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|values| values.first())
+        .and_then(|value| from_utf8(value).ok())
+        .map(ToString::to_string)
+}
+
+impl Handler for IdempotentHandler {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let (user_id, device_id, idempotency_key) = match (
+            request.extensions.get::<User>(),
+            request.extensions.get::<AccessToken>(),
+            idempotency_key(request),
+        ) {
+            (Some(user), Some(access_token), Some(idempotency_key)) => (
+                user.id.clone(),
+                access_token.device_id.clone(),
+                idempotency_key,
+            ),
+            _ => return self.handler.handle(request),
+        };
+
+        let key = NewIdempotentResponse {
+            user_id,
+            device_id,
+            path: request.url.path().join("/"),
+            idempotency_key,
+        };
+
+        // The connection is released before calling the handler, which needs one of its own.
+        if let Some(previous) = IdempotentResponse::reserve(&*DB::from_request(request)?, &key)? {
+            let (status, body) = match (previous.status, previous.body) {
+                (Some(status), Some(body)) => (status, body),
+                _ => Err(ApiError::conflict(
+                    "A request with the same transaction ID is still being handled.".to_string(),
+                ))?,
+            };
+
+            let mut response = Response::with((Status::from_u16(status as u16), body));
+            response.headers.set(ContentType::json());
+
+            return Ok(response);
+        }
+
+        let mut response = match self.handler.handle(request) {
+            Ok(response) => response,
+            Err(error) => {
+                // The handler's error is more useful to the client than one releasing the key.
+                if let Err(release_error) =
+                    IdempotentResponse::release(&*DB::from_request(request)?, &key)
+                {
+                    warn!("Failed to release an idempotency key: {}", release_error);
+                }
+
+                return Err(error);
+            }
+        };
+
+        let status = match response.status {
+            Some(status) if status.is_success() => status,
+            _ => {
+                IdempotentResponse::release(&*DB::from_request(request)?, &key)?;
+
+                return Ok(response);
+            }
+        };
+
+        let mut buffer = Vec::new();
+
+        if let Some(mut body) = response.body.take() {
+            body.write_body(&mut buffer).map_err(ApiError::from)?;
+        }
+
+        let body = String::from_utf8(buffer).map_err(|_| {
+            ApiError::unknown("Idempotent endpoints must respond with JSON.".to_string())
+        })?;
+
+        IdempotentResponse::complete(
+            &*DB::from_request(request)?,
+            &key,
+            i32::from(status.to_u16()),
+            &body,
+        )?;
+
+        response.body = Some(Box::new(body));
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::prelude::*;
+    use iron::headers::{ContentType, Headers};
+    use iron::method::Method;
+    use iron::status::Status;
+    use ruma_identifiers::{RoomId, UserId};
+
+    use crate::models::idempotent_response::{IdempotentResponse, NewIdempotentResponse};
+    use crate::models::room_membership::RoomMembership;
+    use crate::schema::{access_tokens, events};
+    use crate::test::{Response, Test};
+
+    fn invite_with_key(
+        test: &Test,
+        token: &str,
+        room_id: &str,
+        user_id: &str,
+        key: &str,
+    ) -> Response {
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+        headers.set_raw("Idempotency-Key", vec![key.as_bytes().to_vec()]);
+
+        test.request_with_headers(
+            Method::Post,
+            &format!(
+                "/_matrix/client/r0/rooms/{}/invite?access_token={}",
+                room_id, token
+            ),
+            &format!(r#"{{"user_id": "{}"}}"#, user_id),
+            headers,
+        )
+    }
+
+    fn membership(test: &Test, room_id: &str, user_id: &str) -> String {
+        RoomMembership::find(
+            &test.connection(),
+            &RoomId::try_from(room_id).unwrap(),
+            &UserId::try_from(user_id).unwrap(),
+        )
+        .unwrap()
+        .unwrap()
+        .membership
+    }
+
+    #[test]
+    fn replayed_transaction_is_not_repeated() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let first = test.send_message(&alice.token, &room_id, "Hi", 1);
+        assert_eq!(first.status, Status::Ok);
+
+        let second = test.send_message(&alice.token, &room_id, "Hi again", 1);
+        assert_eq!(second.status, Status::Ok);
+        assert_eq!(first.json(), second.json());
+
+        let messages: i64 = events::table
+            .filter(events::room_id.eq(&room_id))
+            .filter(events::event_type.eq("m.room.message"))
+            .count()
+            .get_result(&*test.connection())
+            .unwrap();
+        assert_eq!(messages, 1);
+    }
+
+    #[test]
+    fn distinct_transactions_are_executed() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let first = test.send_message(&alice.token, &room_id, "Hi", 1);
+        let second = test.send_message(&alice.token, &room_id, "Hi", 2);
+
+        assert_eq!(first.status, Status::Ok);
+        assert_eq!(second.status, Status::Ok);
+        assert_ne!(
+            first.json().get("event_id").unwrap(),
+            second.json().get("event_id").unwrap()
+        );
+    }
+
+    #[test]
+    fn replayed_idempotency_key_is_not_repeated() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = invite_with_key(&test, &alice.token, &room_id, &bob.id, "invite-bob");
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(membership(&test, &room_id, &bob.id), "invite");
+
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(membership(&test, &room_id, &bob.id), "leave");
+
+        let response = invite_with_key(&test, &alice.token, &room_id, &bob.id, "invite-bob");
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(membership(&test, &room_id, &bob.id), "leave");
+
+        let response = invite_with_key(&test, &alice.token, &room_id, &bob.id, "invite-bob-again");
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(membership(&test, &room_id, &bob.id), "invite");
+    }
+
+    #[test]
+    fn keys_are_scoped_to_the_user() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let alice_response = test.send_message(&alice.token, &room_id, "Hi", 1);
+        let bob_response = test.send_message(&bob.token, &room_id, "Hi", 1);
+
+        assert_eq!(bob_response.status, Status::Ok);
+        assert_ne!(
+            alice_response.json().get("event_id").unwrap(),
+            bob_response.json().get("event_id").unwrap()
+        );
+    }
+
+    #[test]
+    fn keys_are_scoped_to_the_device() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let login = test.post(
+            "/_matrix/client/r0/login",
+            &format!(
+                r#"{{"type": "m.login.password", "user": "{}", "password": "secret", "device_id": "PHONE"}}"#,
+                alice.id
+            ),
+        );
+        assert_eq!(login.status, Status::Ok);
+        let phone_token = login.json().get("access_token").unwrap().as_str().unwrap();
+
+        let first = test.send_message(&alice.token, &room_id, "Hi", 1);
+        let second = test.send_message(phone_token, &room_id, "Hi", 1);
+
+        assert_eq!(first.status, Status::Ok);
+        assert_eq!(second.status, Status::Ok);
+        assert_ne!(
+            first.json().get("event_id").unwrap(),
+            second.json().get("event_id").unwrap()
+        );
+    }
+
+    #[test]
+    fn request_with_a_reserved_key_is_not_handled() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let user_id = UserId::try_from(alice.id.as_ref()).unwrap();
+        let device_id = access_tokens::table
+            .filter(access_tokens::user_id.eq(&user_id))
+            .select(access_tokens::device_id)
+            .first::<String>(&*test.connection())
+            .unwrap();
+
+        // A request with the same key that is still being handled.
+        let key = NewIdempotentResponse {
+            user_id,
+            device_id,
+            path: format!("rooms/{}/send/m.room.message/1", room_id),
+            idempotency_key: "1".to_string(),
+        };
+        assert!(IdempotentResponse::reserve(&test.connection(), &key)
+            .unwrap()
+            .is_none());
+
+        let response = test.send_message(&alice.token, &room_id, "Hi", 1);
+        assert_eq!(response.status, Status::Conflict);
+
+        let messages: i64 = events::table
+            .filter(events::room_id.eq(&room_id))
+            .filter(events::event_type.eq("m.room.message"))
+            .count()
+            .get_result(&*test.connection())
+            .unwrap();
+        assert_eq!(messages, 0);
+
+        // Once the other request failed, the key can be used again.
+        IdempotentResponse::release(&test.connection(), &key).unwrap();
+
+        let response = test.send_message(&alice.token, &room_id, "Hi", 1);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn failed_request_can_be_retried() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = invite_with_key(&test, &bob.token, &room_id, &alice.id, "invite");
+        assert_eq!(response.status, Status::Forbidden);
+
+        let response = invite_with_key(&test, &alice.token, &room_id, &bob.id, "invite");
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(membership(&test, &room_id, &bob.id), "invite");
+    }
+}
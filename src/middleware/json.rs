@@ -1,15 +1,22 @@
 //! Iron middleware to handle verifying the presence of valid JSON in a request.
 
-use bodyparser;
+use std::any::Any;
+
+use bodyparser::{self, BodyError, BodyErrorCause};
 use iron::headers::ContentType;
 use iron::mime::{Mime, SubLevel, TopLevel};
 use iron::typemap::Key;
 use iron::{BeforeMiddleware, IronResult, Plugin, Request};
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 
+use crate::config::Config;
 use crate::error::ApiError;
 
 /// Ensures that requests contain valid JSON and stores the parsed JSON in the Iron request.
+///
+/// Requests must be marked as containing JSON with a `Content-Type` header, unless
+/// `Config::require_json_content_type` is disabled, in which case the header may be omitted.
 #[derive(Clone, Copy, Debug)]
 pub struct JsonRequest;
 
@@ -19,21 +26,131 @@ impl Key for JsonRequest {
 
 impl BeforeMiddleware for JsonRequest {
     fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
-        if request
-            .headers
-            .get::<ContentType>()
-            .and_then(|content_type| match **content_type {
-                Mime(TopLevel::Application, SubLevel::Json, _) => Some(()),
-                _ => None,
-            })
-            .is_none()
-        {
+        let is_json = match request.headers.get::<ContentType>() {
+            Some(&ContentType(Mime(TopLevel::Application, SubLevel::Json, _))) => true,
+            Some(_) => false,
+            None => !Config::from_request(request)?.require_json_content_type,
+        };
+
+        if !is_json {
             Err(ApiError::wrong_content_type(None))?
         }
 
+        // The body parser only reads bodies that are marked with a content type.
+        if !request.headers.has::<ContentType>() {
+            request.headers.set(ContentType::json());
+        }
+
         match request.get::<bodyparser::Json>() {
             Ok(Some(_)) => Ok(()),
-            Ok(_) | Err(_) => Err(ApiError::not_json(None))?,
+            Ok(None) => Err(empty_body())?,
+            Err(error) => Err(body_error(request, error))?,
+        }
+    }
+}
+
+/// Parses the body of the request as a `T`.
+///
+/// Requests without a body are rejected with `M_NOT_JSON`, requests whose body is not valid JSON
+/// or does not match `T` with `M_BAD_JSON` describing what went wrong.
+pub fn parse_body<T>(request: &mut Request<'_, '_>) -> Result<T, ApiError>
+where
+    T: DeserializeOwned + Any + Clone,
+{
+    match request.get::<bodyparser::Struct<T>>() {
+        Ok(Some(body)) => Ok(body),
+        Ok(None) => Err(empty_body()),
+        Err(error) => Err(body_error(request, error)),
+    }
+}
+
+/// The error for requests without a body.
+fn empty_body() -> ApiError {
+    ApiError::not_json("Request body is empty".to_string())
+}
+
+/// Converts an error of the body parser into an `ApiError`.
+fn body_error(request: &mut Request<'_, '_>, error: BodyError) -> ApiError {
+    // An empty body is read as an empty string, which fails to parse like any other bad JSON.
+    let is_empty = match request.get::<bodyparser::Raw>() {
+        Ok(Some(body)) => body.trim().is_empty(),
+        Ok(None) | Err(_) => false,
+    };
+
+    if is_empty {
+        return empty_body();
+    }
+
+    match error.cause {
+        BodyErrorCause::JsonError(cause) => {
+            ApiError::bad_json(format!("{}: {}", error.detail, cause))
+        }
+        BodyErrorCause::Utf8Error(_) | BodyErrorCause::IoError(_) => {
+            ApiError::not_json(error.detail)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use iron::headers::{ContentType, Headers};
+    use iron::method::Method;
+    use iron::status::Status;
+
+    use crate::test::Test;
+
+    fn create_room(test: &Test, headers: Headers) -> crate::test::Response {
+        let user = test.create_user();
+
+        test.request_with_headers(
+            Method::Post,
+            &format!("/_matrix/client/r0/createRoom?access_token={}", user.token),
+            "{}",
+            headers,
+        )
+    }
+
+    #[test]
+    fn json_content_type() {
+        let test = Test::new();
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+
+        let response = create_room(&test, headers);
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn missing_content_type() {
+        let test = Test::new();
+
+        let response = create_room(&test, Headers::new());
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_NOT_JSON"
+        );
+    }
+
+    #[test]
+    fn missing_content_type_in_lenient_mode() {
+        let test = Test::with_config(|config| config.require_json_content_type = false);
+
+        let response = create_room(&test, Headers::new());
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn wrong_content_type_in_lenient_mode() {
+        let test = Test::with_config(|config| config.require_json_content_type = false);
+        let mut headers = Headers::new();
+        headers.set(ContentType::plaintext());
+
+        let response = create_room(&test, headers);
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+}
@@ -0,0 +1,72 @@
+//! Iron middleware to count requests for the metrics endpoint.
+
+use iron::status::Status;
+use iron::{AfterMiddleware, IronError, IronResult, Request, Response};
+use mount::OriginalUrl;
+use router::Router;
+
+use crate::metrics::Metrics;
+
+/// Counts every request by method, route and response status.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestMetrics;
+
+/// Determines the route a request was dispatched to, with path parameters replaced by their
+/// names so that, e.g., every room is counted under `/rooms/:room_id/...`.
+fn route(request: &Request<'_, '_>) -> String {
+    let params = match request.extensions.get::<Router>() {
+        Some(params) => params,
+        None => return "unmatched".to_string(),
+    };
+
+    let url = request
+        .extensions
+        .get::<OriginalUrl>()
+        .unwrap_or(&request.url);
+
+    let segments: Vec<String> = url
+        .path()
+        .into_iter()
+        .map(
+            |segment| match params.iter().find(|&(_, value)| value == segment) {
+                Some((name, _)) => format!(":{}", name),
+                None => segment.to_string(),
+            },
+        )
+        .collect();
+
+    format!("/{}", segments.join("/"))
+}
+
+/// Records the request in the shared `Metrics`.
+fn record(request: &mut Request<'_, '_>, status: Option<Status>) {
+    let route = route(request);
+    let method = request.method.to_string();
+
+    match Metrics::from_request(request) {
+        Ok(metrics) => {
+            if let Err(error) = metrics.record_request(
+                &method,
+                &route,
+                status.unwrap_or(Status::InternalServerError),
+            ) {
+                debug!("Failed to record request metrics: {:?}", error);
+            }
+        }
+        Err(error) => debug!("Failed to record request metrics: {:?}", error),
+    }
+}
+
+impl AfterMiddleware for RequestMetrics {
+    fn after(&self, request: &mut Request<'_, '_>, response: Response) -> IronResult<Response> {
+        record(request, response.status);
+
+        Ok(response)
+    }
+
+    fn catch(&self, request: &mut Request<'_, '_>, error: IronError) -> IronResult<Response> {
+        record(request, error.response.status);
+
+        Err(error)
+    }
+}
@@ -3,28 +3,51 @@
 use iron::Chain;
 
 mod authentication;
+mod compression;
+mod idempotency;
 mod json;
+mod metrics;
 mod path_params;
+mod rate_limit;
 mod response_headers;
+mod shutdown;
+mod timeout;
+mod unrecognized;
 
-pub use self::authentication::{AccessTokenAuth, UIAuth};
-pub use self::json::JsonRequest;
+pub use self::authentication::{AccessTokenAuth, AdminAuth, UIAuth};
+pub use self::compression::ResponseCompression;
+pub use self::idempotency::Idempotent;
+pub use self::json::{parse_body, JsonRequest};
+pub use self::metrics::RequestMetrics;
 pub use self::path_params::{
-    DataTypeParam, EventTypeParam, FilterIdParam, RoomAliasIdParam, RoomIdOrAliasParam,
-    RoomIdParam, TagParam, TransactionIdParam, UserIdParam,
+    DataTypeParam, EventIdParam, EventTypeParam, FilterIdParam, RoomAliasIdParam,
+    RoomIdOrAliasParam, RoomIdParam, TagParam, TransactionIdParam, UserIdParam,
 };
+pub use self::rate_limit::RateLimited;
 pub use self::response_headers::ResponseHeaders;
+pub use self::shutdown::InFlightRequests;
+pub use self::timeout::{RequestTimeout, RouteTimeout};
+pub use self::unrecognized::UnrecognizedRequest;
 
 /// `middleware_chain!(JoinRoom, []);`
+///
+/// Middleware wrapping the handler itself, which runs after all of the before middleware, can be
+/// given in a second list: `middleware_chain!(SendMessageEvent, [AccessTokenAuth], [Idempotent]);`
 #[macro_export]
 macro_rules! middleware_chain {
     ($chain:ident) => {chain_impl!($chain, []);};
     ($chain:ident, [$($middleware:expr),*]) => {
+        middleware_chain!($chain, [$($middleware),*], []);
+    };
+    ($chain:ident, [$($middleware:expr),*], [$($around:expr),*]) => {
         impl MiddlewareChain for $chain {
             /// Create a `$chain` with all necessary middleware.
             fn chain() -> Chain {
+                // Not all chains have middleware to link.
+                #[allow(unused_mut)]
                 let mut chain = Chain::new($chain);
                 $(chain.link_before($middleware);)*
+                $(chain.link_around($around);)*
 
                 chain
             }
@@ -8,7 +8,7 @@ use iron::typemap::Key;
 use iron::{BeforeMiddleware, IronResult, Request};
 use router::Router;
 use ruma_events::EventType;
-use ruma_identifiers::{RoomAliasId, RoomId, RoomIdOrAliasId, UserId};
+use ruma_identifiers::{EventId, RoomAliasId, RoomId, RoomIdOrAliasId, UserId};
 
 use crate::config::Config;
 use crate::error::{ApiError, MapApiError};
@@ -231,6 +231,37 @@ impl BeforeMiddleware for EventTypeParam {
     }
 }
 
+/// Extracts an `EventId` from the URL path parameter `event_id`.
+#[derive(Clone, Copy, Debug)]
+pub struct EventIdParam;
+
+impl Key for EventIdParam {
+    type Value = EventId;
+}
+
+impl BeforeMiddleware for EventIdParam {
+    fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
+        let params = request
+            .extensions
+            .get::<Router>()
+            .expect("Params object is missing")
+            .clone();
+        let event_id = match params.find("event_id") {
+            Some(event_id) => {
+                let decoded_event_id = percent_decode(event_id.as_bytes())
+                    .decode_utf8()
+                    .map_err(|err| ApiError::invalid_param("event_id", err.description()))?;
+
+                EventId::try_from(decoded_event_id.as_ref())
+                    .map_api_err(|err| ApiError::invalid_param("event_id", err.description()))
+            }
+            None => Err(ApiError::missing_param("event_id")),
+        }?;
+        request.extensions.insert::<Self>(event_id);
+        Ok(())
+    }
+}
+
 /// Extracts the URL path paramater `tag`.
 #[derive(Clone, Copy, Debug)]
 pub struct TagParam;
@@ -0,0 +1,30 @@
+//! Iron middleware to limit how often clients can make requests to an endpoint.
+
+use iron::{BeforeMiddleware, IronResult, Request};
+
+use crate::config::Config;
+use crate::rate_limiter::{RateLimitedAction, RateLimiter};
+
+/// Rejects requests with `429 M_LIMIT_EXCEEDED` once the client, identified by its IP address,
+/// has exceeded the configured rate limit for the action.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimited(pub RateLimitedAction);
+
+impl BeforeMiddleware for RateLimited {
+    fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
+        let config = Config::from_request(request)?;
+
+        let limit = match self.0 {
+            RateLimitedAction::Login => config.login_rate_limit,
+            RateLimitedAction::Registration => config.registration_rate_limit,
+        };
+
+        if let Some(limit) = limit {
+            let ip = request.remote_addr.ip();
+
+            RateLimiter::from_request(request)?.check(self.0, ip, &limit)?;
+        }
+
+        Ok(())
+    }
+}
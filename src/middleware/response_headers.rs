@@ -1,15 +1,79 @@
 //! Iron middleware to add HTTP headers to API responses.
 
+use std::str::from_utf8;
+use std::sync::Arc;
+
 use iron::headers::{
-    AccessControlAllowHeaders, AccessControlAllowMethods, AccessControlAllowOrigin, Server,
+    AccessControlAllowHeaders, AccessControlAllowMethods, AccessControlAllowOrigin, Server, Vary,
 };
 use iron::method::Method;
 use iron::{status, AfterMiddleware, IronError, IronResult, Request, Response};
 use unicase::UniCase;
 
 /// Adds a number of response headers to Ruma HTTP responses.
-#[derive(Clone, Copy, Debug)]
-pub struct ResponseHeaders;
+#[derive(Clone, Debug)]
+pub struct ResponseHeaders {
+    /// The origins allowed to make cross-origin requests, or empty to allow any origin.
+    cors_allowed_origins: Arc<Vec<String>>,
+}
+
+impl ResponseHeaders {
+    /// Creates a new `ResponseHeaders` allowing cross-origin requests from the given origins, or
+    /// from any origin if `cors_allowed_origins` is empty.
+    pub fn new(cors_allowed_origins: Vec<String>) -> Self {
+        Self {
+            cors_allowed_origins: Arc::new(cors_allowed_origins),
+        }
+    }
+
+    /// The `Access-Control-Allow-Origin` header for a request, or `None` if the request's origin
+    /// is not allowed.
+    fn allowed_origin(&self, request: &Request<'_, '_>) -> Option<AccessControlAllowOrigin> {
+        if self.cors_allowed_origins.is_empty() {
+            return Some(AccessControlAllowOrigin::Any);
+        }
+
+        let origin = request
+            .headers
+            .get_raw("Origin")
+            .and_then(|values| values.first())
+            .and_then(|value| from_utf8(value).ok())?;
+
+        self.cors_allowed_origins
+            .iter()
+            .find(|allowed_origin| *allowed_origin == origin)
+            .map(|allowed_origin| AccessControlAllowOrigin::Value(allowed_origin.clone()))
+    }
+
+    /// Adds Cross-Origin Resource Sharing headers to HTTP responses, if the request's origin is
+    /// allowed.
+    fn add_cors_headers(&self, request: &Request<'_, '_>, response: &mut Response) {
+        // The headers depend on the origin when there is an allowlist, so caches must not serve
+        // the response to other origins.
+        if !self.cors_allowed_origins.is_empty() {
+            response
+                .headers
+                .set(Vary::Items(vec![UniCase("Origin".to_string())]));
+        }
+
+        let allowed_origin = match self.allowed_origin(request) {
+            Some(allowed_origin) => allowed_origin,
+            None => return,
+        };
+
+        response.headers.set(AccessControlAllowHeaders(vec![
+            UniCase("accept".to_string()),
+            UniCase("content-type".to_string()),
+        ]));
+        response.headers.set(AccessControlAllowMethods(vec![
+            Method::Get,
+            Method::Post,
+            Method::Put,
+            Method::Delete,
+        ]));
+        response.headers.set(allowed_origin);
+    }
+}
 
 /// Adds a Server header to HTTP responses
 fn add_server_header(response: &mut Response) {
@@ -20,35 +84,20 @@ fn add_server_header(response: &mut Response) {
     )));
 }
 
-/// Adds Cross-Origin Resource Sharing headers to HTTP responses.
-fn add_cors_headers(response: &mut Response) {
-    response.headers.set(AccessControlAllowHeaders(vec![
-        UniCase("accept".to_string()),
-        UniCase("content-type".to_string()),
-    ]));
-    response.headers.set(AccessControlAllowMethods(vec![
-        Method::Get,
-        Method::Post,
-        Method::Put,
-        Method::Delete,
-    ]));
-    response.headers.set(AccessControlAllowOrigin::Any);
-}
-
 impl AfterMiddleware for ResponseHeaders {
     fn after(&self, request: &mut Request<'_, '_>, mut response: Response) -> IronResult<Response> {
         if request.method == Method::Options {
             response = Response::with(status::Ok);
         }
         add_server_header(&mut response);
-        add_cors_headers(&mut response);
+        self.add_cors_headers(request, &mut response);
 
         Ok(response)
     }
 
-    fn catch(&self, _: &mut Request<'_, '_>, mut error: IronError) -> IronResult<Response> {
+    fn catch(&self, request: &mut Request<'_, '_>, mut error: IronError) -> IronResult<Response> {
         add_server_header(&mut error.response);
-        add_cors_headers(&mut error.response);
+        self.add_cors_headers(request, &mut error.response);
 
         Err(error)
     }
@@ -58,11 +107,28 @@ impl AfterMiddleware for ResponseHeaders {
 mod tests {
     use crate::test::{Response, Test};
     use iron::headers::{
-        AccessControlAllowHeaders, AccessControlAllowMethods, AccessControlAllowOrigin, Server,
+        AccessControlAllowHeaders, AccessControlAllowMethods, AccessControlAllowOrigin, Headers,
+        Server,
     };
     use iron::method::Method;
     use unicase::UniCase;
 
+    fn get_with_origin(test: &Test, origin: &str) -> Response {
+        let mut headers = Headers::new();
+        headers.set_raw("Origin", vec![origin.as_bytes().to_vec()]);
+
+        test.request_with_headers(Method::Get, "/_matrix/client/versions", "", headers)
+    }
+
+    fn allowlist_test() -> Test {
+        Test::with_config(|config| {
+            config.cors_allowed_origins = vec![
+                "https://app.example.com".to_string(),
+                "https://other.example.com".to_string(),
+            ]
+        })
+    }
+
     fn check_for_modified_headers(response: &Response) {
         assert_eq!(
             response.headers.get::<Server>().unwrap(),
@@ -120,4 +186,55 @@ mod tests {
         // Check to see if the expected headers have been added to the response.
         check_for_modified_headers(&response);
     }
+
+    #[test]
+    fn empty_allowlist_allows_any_origin() {
+        let test = Test::new();
+        let response = get_with_origin(&test, "https://app.example.com");
+
+        assert_eq!(
+            response.headers.get::<AccessControlAllowOrigin>().unwrap(),
+            &AccessControlAllowOrigin::Any
+        );
+    }
+
+    #[test]
+    fn allowed_origin_is_echoed() {
+        let test = allowlist_test();
+        let response = get_with_origin(&test, "https://other.example.com");
+
+        assert_eq!(
+            response.headers.get::<AccessControlAllowOrigin>().unwrap(),
+            &AccessControlAllowOrigin::Value("https://other.example.com".to_string())
+        );
+        assert!(response
+            .headers
+            .get::<AccessControlAllowMethods>()
+            .is_some());
+    }
+
+    #[test]
+    fn disallowed_origin_gets_no_cors_headers() {
+        let test = allowlist_test();
+        let response = get_with_origin(&test, "https://evil.example.com");
+
+        assert!(response.headers.get::<AccessControlAllowOrigin>().is_none());
+        assert!(response
+            .headers
+            .get::<AccessControlAllowMethods>()
+            .is_none());
+        assert!(response
+            .headers
+            .get::<AccessControlAllowHeaders>()
+            .is_none());
+        assert!(response.headers.get::<Server>().is_some());
+    }
+
+    #[test]
+    fn missing_origin_gets_no_cors_headers_with_allowlist() {
+        let test = allowlist_test();
+        let response = test.get("/_matrix/client/versions");
+
+        assert!(response.headers.get::<AccessControlAllowOrigin>().is_none());
+    }
 }
@@ -0,0 +1,142 @@
+//! Iron middleware to track in-flight requests for graceful shutdown and backpressure.
+
+use std::time::Duration;
+
+use iron::typemap::Key;
+use iron::{BeforeMiddleware, IronResult, Request};
+
+use crate::error::ApiError;
+use crate::shutdown::{InFlightRequest, Shutdown};
+
+/// How long clients are asked to wait before retrying a request rejected for exceeding
+/// `max_in_flight_requests`.
+const OVERLOADED_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Counts in-flight requests and rejects new ones once the server is shutting down, or while too
+/// many requests are already in flight.
+///
+/// A request stays in flight until Iron drops it after writing its response, whether its handler
+/// returned or panicked.
+#[derive(Clone, Debug)]
+pub struct InFlightRequests {
+    /// The shutdown state shared with the server.
+    shutdown: Shutdown,
+    /// The maximum number of requests in flight at once, or 0 for no limit.
+    max_in_flight: usize,
+}
+
+impl InFlightRequests {
+    /// Creates a new `InFlightRequests` from the given `Shutdown` and `max_in_flight_requests`.
+    pub fn new(shutdown: Shutdown, max_in_flight: usize) -> Self {
+        Self {
+            shutdown,
+            max_in_flight,
+        }
+    }
+}
+
+impl Key for InFlightRequests {
+    type Value = InFlightRequest;
+}
+
+impl BeforeMiddleware for InFlightRequests {
+    fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
+        let in_flight_request = self.shutdown.begin_request()?;
+
+        if self.max_in_flight > 0 && in_flight_request.position() > self.max_in_flight {
+            Err(
+                ApiError::unavailable("The server is too busy, try again later".to_string())
+                    .with_retry_after(OVERLOADED_RETRY_AFTER),
+            )?;
+        }
+
+        request.extensions.insert::<Self>(in_flight_request);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::{Arc, Mutex};
+
+    use iron::headers::Headers;
+    use iron::status::Status;
+    use iron::{Chain, IronResult, Request, Response};
+    use iron_test::request;
+
+    use super::InFlightRequests;
+    use crate::shutdown::Shutdown;
+    use crate::test::Test;
+
+    #[test]
+    fn requests_over_the_limit_are_rejected() {
+        let test = Test::with_config(|config| config.max_in_flight_requests = 2);
+        let shutdown = test.shutdown();
+
+        // Another request is in flight, so there is room for one more.
+        let _first = shutdown.begin_request().unwrap();
+
+        let response = test.get("/_matrix/client/r0/login");
+        assert_eq!(response.status, Status::Ok);
+
+        let second = shutdown.begin_request().unwrap();
+
+        let response = test.get("/_matrix/client/r0/login");
+        assert_eq!(response.status, Status::ServiceUnavailable);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_UNAVAILABLE"
+        );
+        assert_eq!(
+            response
+                .json()
+                .get("retry_after_ms")
+                .unwrap()
+                .as_u64()
+                .unwrap(),
+            1000
+        );
+        assert_eq!(
+            response.headers.get_raw("Retry-After").unwrap(),
+            &[b"1".to_vec()][..]
+        );
+
+        // Rejected requests don't count as in flight.
+        assert_eq!(shutdown.in_flight(), 2);
+
+        drop(second);
+
+        let response = test.get("/_matrix/client/r0/login");
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn no_limit_by_default() {
+        let test = Test::new();
+        let shutdown = test.shutdown();
+
+        let _in_flight: Vec<_> = (0..10).map(|_| shutdown.begin_request().unwrap()).collect();
+
+        let response = test.get("/_matrix/client/r0/login");
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn panicking_request_is_no_longer_in_flight() {
+        let shutdown = Shutdown::new(Arc::new(Mutex::new(None)));
+
+        let mut chain = Chain::new(|_: &mut Request<'_, '_>| -> IronResult<Response> {
+            panic!("The handler failed");
+        });
+        chain.link_before(InFlightRequests::new(shutdown.clone(), 0));
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            request::get("http://localhost/", Headers::new(), &chain)
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(shutdown.in_flight(), 0);
+    }
+}
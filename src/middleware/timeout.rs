@@ -0,0 +1,510 @@
+//! Iron middleware to put an upper bound on how long requests take.
+
+use std::error::Error;
+use std::io::{Cursor, Read, Result as IoResult, Write};
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hyper::buffer::BufReader;
+use hyper::net::NetworkStream;
+use hyper::server::request::Request as HttpRequest;
+use iron::headers::{ContentLength, Headers, TransferEncoding};
+use iron::response::WriteBody;
+use iron::status::Status;
+use iron::typemap::Key;
+use iron::{
+    AroundMiddleware, BeforeMiddleware, Handler, IronError, IronResult, Protocol, Request,
+    Response, Url,
+};
+use mount::OriginalUrl;
+
+use crate::error::ApiError;
+
+/// The largest request body that is read before handing the request to the handler, the same as
+/// the default limit of `bodyparser`.
+const MAX_BODY_LENGTH: u64 = 100 * 1024 * 1024;
+
+/// Responds with `504 M_UNKNOWN` to requests whose handler takes longer than the timeout.
+///
+/// Iron handlers borrow the connection they are responding to, so the request is read into
+/// memory and handled on a worker thread, while the connection's thread waits for the response
+/// until the timeout. A handler that times out isn't interrupted, so the changes it makes are
+/// still stored once it finishes. Routes can override the timeout with `RouteTimeout`.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestTimeout {
+    /// The longest a request may take, or `None` for no limit.
+    timeout: Option<Duration>,
+}
+
+/// The handler wrapped by `RequestTimeout`.
+struct RequestTimeoutHandler {
+    /// The longest a request may take, or `None` for no limit.
+    timeout: Option<Duration>,
+    /// The handler run on the worker threads.
+    handler: Arc<dyn Handler>,
+}
+
+/// A message from the worker thread handling a request.
+enum Message {
+    /// The route of the request overrides the timeout.
+    Timeout(Option<Duration>),
+    /// The handler finished.
+    Finished(Result<ResponseParts, (Box<dyn Error + Send>, ResponseParts)>),
+}
+
+/// Lets `RouteTimeout` tell the waiting thread about the timeout of the route.
+struct TimeoutSender;
+
+impl Key for TimeoutSender {
+    type Value = Sender<Message>;
+}
+
+/// A request read into memory, so that it can be handled on another thread.
+struct BufferedRequest {
+    /// The request line, headers and body as sent over HTTP.
+    bytes: Vec<u8>,
+    /// The originating address of the request.
+    remote_addr: SocketAddr,
+    /// The local address of the request.
+    local_addr: SocketAddr,
+    /// The requested URL, as seen by the handler.
+    url: Url,
+    /// The requested URL before it was changed by `Mount`, if it was.
+    original_url: Option<Url>,
+}
+
+/// A connection serving a request read into memory.
+struct BufferedStream {
+    /// The request.
+    bytes: Cursor<Vec<u8>>,
+    /// The originating address of the request.
+    remote_addr: SocketAddr,
+}
+
+/// The parts of a response, which unlike a `Response` can be sent to another thread.
+struct ResponseParts {
+    /// The status of the response.
+    status: Option<Status>,
+    /// The headers of the response.
+    headers: Headers,
+    /// The body of the response, if it has one.
+    body: Option<Vec<u8>>,
+}
+
+impl RequestTimeout {
+    /// Creates a new `RequestTimeout` from the timeout in milliseconds. Zero means no timeout.
+    pub fn from_millis(timeout_ms: u64) -> Self {
+        let timeout = if timeout_ms == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(timeout_ms))
+        };
+
+        Self { timeout }
+    }
+}
+
+impl AroundMiddleware for RequestTimeout {
+    fn around(self, handler: Box<dyn Handler>) -> Box<dyn Handler> {
+        Box::new(RequestTimeoutHandler {
+            timeout: self.timeout,
+            handler: Arc::from(handler),
+        })
+    }
+}
+
+impl Handler for RequestTimeoutHandler {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let mut timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return self.handler.handle(request),
+        };
+
+        let started = Instant::now();
+        let buffered_request = BufferedRequest::read(request)?;
+
+        let (sender, receiver) = channel();
+        let handler = self.handler.clone();
+
+        thread::Builder::new()
+            .name("request".to_string())
+            .spawn(move || {
+                let result = buffered_request.handle(&*handler, sender.clone());
+
+                if sender.send(Message::Finished(result)).is_err() {
+                    debug!("Finished handling a request after it timed out.");
+                }
+            })
+            .map_err(ApiError::from)?;
+
+        loop {
+            let message = match timeout.checked_sub(started.elapsed()) {
+                Some(remaining) => receiver.recv_timeout(remaining),
+                None => Err(RecvTimeoutError::Timeout),
+            };
+
+            match message {
+                Ok(Message::Timeout(Some(route_timeout))) => timeout = route_timeout,
+                Ok(Message::Timeout(None)) => {
+                    return match receiver.recv() {
+                        Ok(Message::Finished(result)) => into_result(result),
+                        Ok(Message::Timeout(_)) | Err(_) => Err(handler_failed())?,
+                    };
+                }
+                Ok(Message::Finished(result)) => return into_result(result),
+                Err(RecvTimeoutError::Timeout) => {
+                    warn!(
+                        "{} {} took longer than the timeout of {:?}",
+                        request.method, request.url, timeout
+                    );
+
+                    Err(ApiError::unknown("The request timed out".to_string())
+                        .with_status(Status::GatewayTimeout))?
+                }
+                Err(RecvTimeoutError::Disconnected) => Err(handler_failed())?,
+            }
+        }
+    }
+}
+
+/// The error for a request whose worker thread stopped without a response, e.g. because the
+/// handler panicked.
+fn handler_failed() -> ApiError {
+    ApiError::unknown(None)
+}
+
+/// Converts the result sent by a worker thread back into the handler's result.
+fn into_result(
+    result: Result<ResponseParts, (Box<dyn Error + Send>, ResponseParts)>,
+) -> IronResult<Response> {
+    match result {
+        Ok(response) => Ok(response.into_response()),
+        Err((error, response)) => Err(IronError {
+            error,
+            response: response.into_response(),
+        }),
+    }
+}
+
+impl BufferedRequest {
+    /// Reads the request into memory.
+    fn read(request: &mut Request<'_, '_>) -> Result<Self, ApiError> {
+        let mut body = Vec::new();
+
+        request
+            .body
+            .by_ref()
+            .take(MAX_BODY_LENGTH + 1)
+            .read_to_end(&mut body)
+            .map_err(ApiError::from)?;
+
+        if body.len() as u64 > MAX_BODY_LENGTH {
+            Err(ApiError::too_large(format!(
+                "The request body must not be larger than {} bytes",
+                MAX_BODY_LENGTH
+            )))?;
+        }
+
+        let mut headers = request.headers.clone();
+        headers.remove::<TransferEncoding>();
+        headers.set(ContentLength(body.len() as u64));
+
+        let mut bytes = format!(
+            "{} {} {}\r\n{}\r\n",
+            request.method, request.url, request.version, headers
+        )
+        .into_bytes();
+        bytes.extend(body);
+
+        Ok(Self {
+            bytes,
+            remote_addr: request.remote_addr,
+            local_addr: request.local_addr,
+            url: request.url.clone(),
+            original_url: request.extensions.get::<OriginalUrl>().cloned(),
+        })
+    }
+
+    /// Handles the request with the given handler, telling `sender` about the timeout of the
+    /// route.
+    fn handle(
+        self,
+        handler: &dyn Handler,
+        sender: Sender<Message>,
+    ) -> Result<ResponseParts, (Box<dyn Error + Send>, ResponseParts)> {
+        let local_addr = self.local_addr;
+        let mut stream = BufferedStream {
+            bytes: Cursor::new(self.bytes),
+            remote_addr: self.remote_addr,
+        };
+        let mut reader = BufReader::new(&mut stream as &mut dyn NetworkStream);
+
+        let request = HttpRequest::new(&mut reader, self.remote_addr)
+            .map_err(|error| error.to_string())
+            .and_then(|request| Request::from_http(request, local_addr, &Protocol::http()));
+
+        let mut request = match request {
+            Ok(request) => request,
+            Err(error) => {
+                debug!("Failed to read a buffered request: {}", error);
+
+                return ResponseParts::from_result(Err(IronError::from(handler_failed())));
+            }
+        };
+
+        request.url = self.url;
+
+        if let Some(original_url) = self.original_url {
+            request.extensions.insert::<OriginalUrl>(original_url);
+        }
+
+        request.extensions.insert::<TimeoutSender>(sender);
+
+        ResponseParts::from_result(handler.handle(&mut request))
+    }
+}
+
+impl Read for BufferedStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.bytes.read(buf)
+    }
+}
+
+impl Write for BufferedStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl NetworkStream for BufferedStream {
+    fn peer_addr(&mut self) -> IoResult<SocketAddr> {
+        Ok(self.remote_addr)
+    }
+
+    fn set_read_timeout(&self, _: Option<Duration>) -> IoResult<()> {
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, _: Option<Duration>) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl ResponseParts {
+    /// Splits the result of a handler into parts that can be sent to another thread.
+    fn from_result(
+        result: IronResult<Response>,
+    ) -> Result<Self, (Box<dyn Error + Send>, ResponseParts)> {
+        match result {
+            Ok(response) => match Self::from_response(response) {
+                Ok(response) => Ok(response),
+                Err(error) => Err((Box::new(error), Self::from_error(handler_failed()))),
+            },
+            Err(IronError { error, response }) => match Self::from_response(response) {
+                Ok(response) => Err((error, response)),
+                Err(_) => Err((error, Self::from_error(handler_failed()))),
+            },
+        }
+    }
+
+    /// Splits a response into parts, writing its body into memory.
+    fn from_response(mut response: Response) -> Result<Self, ApiError> {
+        let body = match response.body.take() {
+            Some(mut body) => {
+                let mut bytes = Vec::new();
+                body.write_body(&mut bytes).map_err(ApiError::from)?;
+                Some(bytes)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            status: response.status,
+            headers: response.headers,
+            body,
+        })
+    }
+
+    /// The parts of the response to an error.
+    fn from_error(error: ApiError) -> Self {
+        let response = IronError::from(error).response;
+
+        Self {
+            status: response.status,
+            headers: response.headers,
+            body: None,
+        }
+    }
+
+    /// Puts the parts back together into a response.
+    fn into_response(self) -> Response {
+        let mut response = Response::new();
+
+        response.status = self.status;
+        response.headers = self.headers;
+        response.body = self.body.map(|body| Box::new(body) as Box<dyn WriteBody>);
+
+        response
+    }
+}
+
+/// Overrides the timeout of `RequestTimeout` for a route, e.g. for long-polling endpoints.
+#[derive(Clone, Copy, Debug)]
+pub struct RouteTimeout {
+    /// The longest a request to the route may take, or `None` for no limit.
+    timeout: Option<Duration>,
+}
+
+impl RouteTimeout {
+    /// Allows requests to the route to take up to the given time.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+        }
+    }
+
+    /// Allows requests to the route to take as long as they need.
+    pub fn disabled() -> Self {
+        Self { timeout: None }
+    }
+}
+
+impl BeforeMiddleware for RouteTimeout {
+    fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
+        if let Some(sender) = request.extensions.get::<TimeoutSender>() {
+            if sender.send(Message::Timeout(self.timeout)).is_err() {
+                debug!("Overrode the timeout of a request after it timed out.");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use iron::headers::Headers;
+    use iron::status::Status;
+    use iron::{Chain, IronResult, Request, Response};
+    use iron_test::{request, response};
+    use serde_json::{from_str, Value};
+
+    use super::{RequestTimeout, RouteTimeout};
+    use crate::query::SyncOptions;
+    use crate::test::Test;
+
+    /// A handler that takes the given time to respond.
+    fn handler(duration: Duration) -> impl Fn(&mut Request<'_, '_>) -> IronResult<Response> {
+        move |_: &mut Request<'_, '_>| {
+            sleep(duration);
+
+            Ok(Response::with((Status::Ok, "{}")))
+        }
+    }
+
+    #[test]
+    fn slow_request_times_out() {
+        let mut chain = Chain::new(handler(Duration::from_millis(200)));
+        chain.link_around(RequestTimeout::from_millis(50));
+
+        let error = request::get("http://localhost/", Headers::new(), &chain).unwrap_err();
+        assert_eq!(error.response.status, Some(Status::GatewayTimeout));
+
+        let body: Value = from_str(&response::extract_body_to_string(error.response)).unwrap();
+        assert_eq!(body.get("errcode").unwrap().as_str().unwrap(), "M_UNKNOWN");
+    }
+
+    #[test]
+    fn fast_request_completes() {
+        let mut chain = Chain::new(handler(Duration::from_millis(0)));
+        chain.link_around(RequestTimeout::from_millis(1000));
+
+        let response = request::get("http://localhost/", Headers::new(), &chain).unwrap();
+        assert_eq!(response.status, Some(Status::Ok));
+    }
+
+    #[test]
+    fn route_without_timeout() {
+        let mut route = Chain::new(handler(Duration::from_millis(200)));
+        route.link_before(RouteTimeout::disabled());
+
+        let mut chain = Chain::new(route);
+        chain.link_around(RequestTimeout::from_millis(50));
+
+        let response = request::get("http://localhost/", Headers::new(), &chain).unwrap();
+        assert_eq!(response.status, Some(Status::Ok));
+    }
+
+    #[test]
+    fn route_with_longer_timeout() {
+        let mut route = Chain::new(handler(Duration::from_millis(200)));
+        route.link_before(RouteTimeout::new(Duration::from_secs(10)));
+
+        let mut chain = Chain::new(route);
+        chain.link_around(RequestTimeout::from_millis(50));
+
+        let response = request::get("http://localhost/", Headers::new(), &chain).unwrap();
+        assert_eq!(response.status, Some(Status::Ok));
+    }
+
+    #[test]
+    fn request_body_reaches_the_handler() {
+        let mut chain = Chain::new(|request: &mut Request<'_, '_>| {
+            let mut body = String::new();
+            request.body.read_to_string(&mut body).unwrap();
+
+            Ok(Response::with((Status::Ok, body)))
+        });
+        chain.link_around(RequestTimeout::from_millis(1000));
+
+        let response =
+            request::post("http://localhost/", Headers::new(), r#"{"a":"b"}"#, &chain).unwrap();
+        assert_eq!(response.status, Some(Status::Ok));
+        assert_eq!(response::extract_body_to_string(response), r#"{"a":"b"}"#);
+    }
+
+    #[test]
+    fn panicking_handler_fails() {
+        let mut chain = Chain::new(|_: &mut Request<'_, '_>| -> IronResult<Response> {
+            panic!("The handler failed");
+        });
+        chain.link_around(RequestTimeout::from_millis(1000));
+
+        let error = request::get("http://localhost/", Headers::new(), &chain).unwrap_err();
+        assert_eq!(error.response.status, Some(Status::InternalServerError));
+    }
+
+    #[test]
+    fn client_api_with_timeout() {
+        let test = Test::with_config(|config| config.request_timeout_ms = 10_000);
+        let carl = test.create_user();
+        let room_id = test.create_room(&carl.token);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.create?access_token={}",
+            room_id, carl.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&carl.token, options);
+        assert_eq!(response.status, Status::Ok);
+    }
+}
@@ -0,0 +1,75 @@
+//! Iron middleware to answer requests for unknown endpoints with a Matrix error.
+
+use iron::{AfterMiddleware, Handler, IronError, IronResult, Request, Response};
+use router::NoRoute;
+
+use crate::error::ApiError;
+
+/// Replaces the router's empty 404 for unknown endpoints with an `M_UNRECOGNIZED` error.
+///
+/// It can also be mounted as a handler to reject every request for a path prefix.
+#[derive(Clone, Copy, Debug)]
+pub struct UnrecognizedRequest;
+
+impl AfterMiddleware for UnrecognizedRequest {
+    fn catch(&self, _: &mut Request<'_, '_>, error: IronError) -> IronResult<Response> {
+        if error.error.is::<NoRoute>() {
+            Err(IronError::from(ApiError::unrecognized(None)))
+        } else {
+            Err(error)
+        }
+    }
+}
+
+impl Handler for UnrecognizedRequest {
+    fn handle(&self, _: &mut Request<'_, '_>) -> IronResult<Response> {
+        Err(IronError::from(ApiError::unrecognized(None)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use crate::test::Test;
+
+    #[test]
+    fn unknown_client_endpoint() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/r0/nonsense");
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNRECOGNIZED"
+        );
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Unrecognized request"
+        );
+    }
+
+    #[test]
+    fn unknown_matrix_namespace() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/nonsense/v1/nonsense");
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNRECOGNIZED"
+        );
+    }
+
+    #[test]
+    fn unknown_path_outside_matrix_namespace() {
+        let test = Test::new();
+
+        let response = test.get("/nonsense");
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(response.body, "");
+    }
+}
@@ -1,7 +1,11 @@
 //! User access tokens.
 
-use base64::encode;
-use chrono::{Duration, Utc};
+use std::convert::TryFrom;
+use std::str::from_utf8;
+
+use base64::{decode, encode};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use diesel::dsl::any;
 use diesel::pg::data_types::PgTimestamp;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
@@ -12,9 +16,18 @@ use macaroons::token::Token;
 use macaroons::v1::V1Token;
 use ruma_identifiers::UserId;
 
+use crate::config::{Config, TokenFormat};
+use crate::crypto::{constant_time_eq, generate_opaque_token, hash_opaque_token};
 use crate::error::ApiError;
+use crate::models::user::User;
 use crate::schema::access_tokens;
 
+/// The format of the expiration time in the `time` caveat of macaroons.
+const MACAROON_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f UTC";
+
+/// The maximum length of a device ID in bytes.
+const MAX_DEVICE_ID_LENGTH: usize = 255;
+
 /// A User access token.
 #[derive(AsChangeset, Clone, Debug, Identifiable, Queryable)]
 #[table_name = "access_tokens"]
@@ -23,7 +36,8 @@ pub struct AccessToken {
     pub id: i64,
     /// The ID of the user who owns the access token.
     pub user_id: UserId,
-    /// The value of the access token. This is a Base64-encoded macaroon.
+    /// The value of the access token. This is either a Base64-encoded macaroon, or the hash of
+    /// an opaque token. The opaque token itself is only available right after it is created.
     pub value: String,
     /// Whether or not the access token has been revoked.
     pub revoked: bool,
@@ -31,6 +45,17 @@ pub struct AccessToken {
     pub created_at: PgTimestamp,
     /// The time the access token was last modified.
     pub updated_at: PgTimestamp,
+    /// The ID of the device the access token was issued to.
+    pub device_id: String,
+}
+
+/// A user who has been authenticated by their access token.
+#[derive(Clone, Debug)]
+pub struct AuthedUser {
+    /// The authenticated user.
+    pub user: User,
+    /// The access token the user was authenticated by.
+    pub access_token: AccessToken,
 }
 
 /// A new access token, not yet saved.
@@ -39,38 +64,227 @@ pub struct AccessToken {
 pub struct NewAccessToken {
     /// The ID of the user who owns the access token.
     pub user_id: UserId,
-    /// The value of the access token. This is a Base64-encoded macaroon.
+    /// The value of the access token. This is either a Base64-encoded macaroon, or the hash of
+    /// an opaque token.
     pub value: String,
+    /// The ID of the device the access token was issued to.
+    pub device_id: String,
 }
 
 impl AccessToken {
-    /// Create a new `AccessToken` for the given user.
+    /// Create a new `AccessToken` for the given user and device, in the configured format.
+    ///
+    /// For opaque tokens, only the hash is saved, but the returned `AccessToken` holds the token
+    /// itself so it can be handed to the client.
     pub fn create(
         connection: &PgConnection,
         user_id: &UserId,
-        macaroon_secret_key: &[u8],
+        device_id: &str,
+        config: &Config,
     ) -> Result<Self, ApiError> {
+        validate_device_id("device_id", device_id)?;
+
+        let (token, value) = match config.token_format {
+            TokenFormat::Macaroon => {
+                // A lifetime too large to add to the current time means the token never expires.
+                let expiration = match config.access_token_lifetime_seconds {
+                    0 => None,
+                    lifetime => i64::try_from(lifetime)
+                        .ok()
+                        .filter(|seconds| *seconds <= i64::max_value() / 1000)
+                        .map(Duration::seconds)
+                        .and_then(|lifetime| Utc::now().checked_add_signed(lifetime)),
+                };
+
+                let macaroon = create_macaroon(
+                    &config.macaroon_secret_key,
+                    user_id,
+                    device_id,
+                    expiration.as_ref(),
+                )?;
+                (macaroon.clone(), macaroon)
+            }
+            TokenFormat::Opaque => {
+                let token = generate_opaque_token()?;
+                let hash = hash_opaque_token(&token);
+                (format!("{}{}", config.opaque_token_prefix, token), hash)
+            }
+        };
+
         let new_access_token = NewAccessToken {
             user_id: user_id.clone(),
-            value: create_macaroon(macaroon_secret_key, user_id)?,
+            value,
+            device_id: device_id.to_string(),
         };
 
-        diesel::insert_into(access_tokens::table)
-            .values(&new_access_token)
-            .get_result(connection)
-            .map_err(ApiError::from)
+        let mut access_token = connection
+            .transaction::<Self, ApiError, _>(|| {
+                let access_token: Self = diesel::insert_into(access_tokens::table)
+                    .values(&new_access_token)
+                    .get_result(connection)
+                    .map_err(ApiError::from)?;
+
+                if config.max_devices_per_user > 0 {
+                    Self::revoke_least_recent_devices(
+                        connection,
+                        user_id,
+                        config.max_devices_per_user,
+                    )?;
+                }
+
+                Ok(access_token)
+            })
+            .map_err(ApiError::from)?;
+
+        access_token.value = token;
+
+        Ok(access_token)
+    }
+
+    /// Revoke the access tokens of all but the `max_devices` devices of the given user that were
+    /// most recently issued an access token.
+    fn revoke_least_recent_devices(
+        connection: &PgConnection,
+        user_id: &UserId,
+        max_devices: usize,
+    ) -> Result<(), ApiError> {
+        let mut devices: Vec<String> = Vec::new();
+
+        for access_token in Self::find_valid_by_uid(connection, user_id)?
+            .into_iter()
+            .rev()
+        {
+            if !devices.contains(&access_token.device_id) {
+                devices.push(access_token.device_id);
+            }
+        }
+
+        if devices.len() <= max_devices {
+            return Ok(());
+        }
+
+        diesel::update(
+            access_tokens::table
+                .filter(access_tokens::user_id.eq(user_id))
+                .filter(access_tokens::device_id.eq(any(&devices[max_devices..])))
+                .filter(access_tokens::revoked.eq(false)),
+        )
+        .set(access_tokens::revoked.eq(true))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+
+        Ok(())
     }
 
-    /// Creates an `AccessToken` from an access token string value.
+    /// Authenticates a client by the access token it presented, in the configured format.
     ///
-    /// The access token cannot be revoked.
-    pub fn find_valid_by_token(
+    /// Macaroons must be signed with `macaroon_secret_key`, and their caveats must name the
+    /// user and device the token was issued to, the `access` type, and an expiration time that
+    /// hasn't passed yet. Tokens of either format must have been issued by this server and not
+    /// been revoked, and must belong to a user who hasn't been deactivated.
+    pub fn authenticate(
         connection: &PgConnection,
+        presented: &str,
+        config: &Config,
+    ) -> Result<AuthedUser, ApiError> {
+        let access_token = match config.token_format {
+            TokenFormat::Macaroon => {
+                let token = standard_base64(presented);
+
+                let macaroon = match decode(&token).map(V1Token::deserialize) {
+                    Ok(Ok(macaroon)) => macaroon,
+                    Ok(Err(_)) | Err(_) => Err(unknown_token())?,
+                };
+
+                if macaroon
+                    .authenticate_without_verifying(&config.macaroon_secret_key)
+                    .is_err()
+                {
+                    return Err(ApiError::unknown_token(
+                        "Invalid token signature".to_string(),
+                    ));
+                }
+
+                let access_token = match Self::find_by_macaroon(connection, &macaroon, &token)? {
+                    Some(access_token) => access_token,
+                    None => Err(unknown_token())?,
+                };
+
+                verify_caveats(&macaroon, &access_token, config.token_time_skew_seconds)?;
+
+                access_token
+            }
+            TokenFormat::Opaque => {
+                match Self::find_by_opaque_token(
+                    connection,
+                    presented,
+                    &config.opaque_token_prefix,
+                )? {
+                    Some(access_token) => access_token,
+                    None => Err(unknown_token())?,
+                }
+            }
+        };
+
+        if access_token.revoked {
+            return Err(ApiError::unknown_token("Revoked token".to_string()));
+        }
+
+        match User::find_active_user(connection, &access_token.user_id)? {
+            Some(user) => Ok(AuthedUser { user, access_token }),
+            None => Err(ApiError::unauthorized(
+                "No user with the given token was found".to_string(),
+            )),
+        }
+    }
+
+    /// Look up the access token holding the given Base64-encoded macaroon, whether or not it was
+    /// revoked.
+    ///
+    /// Macaroons are stored as they are, so rather than letting the database compare them, the
+    /// user's tokens are looked up by the user ID in the macaroon and compared in constant time.
+    /// The `user_id` caveat is only trustworthy once the macaroon's signature has been verified,
+    /// which `authenticate` does before calling this.
+    fn find_by_macaroon(
+        connection: &PgConnection,
+        macaroon: &V1Token,
         token: &str,
     ) -> Result<Option<Self>, ApiError> {
+        let user_id = match caveat_values(macaroon, "user_id")
+            .next()
+            .and_then(|user_id| UserId::try_from(user_id).ok())
+        {
+            Some(user_id) => user_id,
+            None => return Ok(None),
+        };
+
+        let access_tokens: Vec<Self> = access_tokens::table
+            .filter(access_tokens::user_id.eq(&user_id))
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(access_tokens
+            .into_iter()
+            .find(|access_token| constant_time_eq(access_token.value.as_bytes(), token.as_bytes())))
+    }
+
+    /// Look up the access token issued as the given opaque token, whether or not it was revoked.
+    ///
+    /// Opaque tokens must start with `opaque_token_prefix`, and are looked up by the hash of the
+    /// rest of the token, which an attacker can't choose.
+    fn find_by_opaque_token(
+        connection: &PgConnection,
+        token: &str,
+        opaque_token_prefix: &str,
+    ) -> Result<Option<Self>, ApiError> {
+        if !token.starts_with(opaque_token_prefix) {
+            return Ok(None);
+        }
+
+        let token = &token[opaque_token_prefix.len()..];
+
         let token = access_tokens::table
-            .filter(access_tokens::value.eq(token))
-            .filter(access_tokens::revoked.eq(false))
+            .filter(access_tokens::value.eq(hash_opaque_token(token)))
             .first(connection);
 
         match token {
@@ -80,14 +294,130 @@ impl AccessToken {
         }
     }
 
+    /// Return all access tokens of the given user that have not been revoked.
+    pub fn find_valid_by_uid(
+        connection: &PgConnection,
+        user_id: &UserId,
+    ) -> Result<Vec<Self>, ApiError> {
+        access_tokens::table
+            .filter(access_tokens::user_id.eq(user_id))
+            .filter(access_tokens::revoked.eq(false))
+            .order(access_tokens::id)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Revoke all access tokens of the given user, e.g. to log them out everywhere.
+    ///
+    /// The tokens are revoked in a single transaction, so either all of them or none are.
+    /// Returns the number of tokens that were revoked by this call.
+    pub fn revoke_all_for_user(
+        connection: &PgConnection,
+        user_id: &UserId,
+    ) -> Result<usize, ApiError> {
+        connection
+            .transaction::<usize, ApiError, _>(|| {
+                diesel::update(
+                    access_tokens::table
+                        .filter(access_tokens::user_id.eq(user_id))
+                        .filter(access_tokens::revoked.eq(false)),
+                )
+                .set(access_tokens::revoked.eq(true))
+                .execute(connection)
+                .map_err(ApiError::from)
+            })
+            .map_err(ApiError::from)
+    }
+
+    /// Move the valid access tokens of one of the user's devices to another device ID, e.g.
+    /// when migrating a session to a different device.
+    ///
+    /// Macaroons name the device they were issued to, so they are re-minted for the new device
+    /// with their original expiration time. Returns each moved `AccessToken` along with the value
+    /// it had before, so the new macaroons can be matched to the ones they replace. Opaque tokens
+    /// keep their value. Fails if the device has no valid access tokens, or if the new device ID
+    /// is used by another user.
+    pub fn rebind_device(
+        connection: &PgConnection,
+        user_id: &UserId,
+        device_id: &str,
+        new_device_id: &str,
+        config: &Config,
+    ) -> Result<Vec<(String, Self)>, ApiError> {
+        validate_device_id("new_device_id", new_device_id)?;
+
+        connection
+            .transaction::<Vec<(String, Self)>, ApiError, _>(|| {
+                let used_by_another_user = access_tokens::table
+                    .filter(access_tokens::device_id.eq(new_device_id))
+                    .filter(access_tokens::user_id.ne(user_id))
+                    .select(access_tokens::id)
+                    .first::<i64>(connection)
+                    .optional()
+                    .map_err(ApiError::from)?
+                    .is_some();
+
+                if used_by_another_user {
+                    return Err(ApiError::unauthorized(
+                        "The device ID belongs to another user".to_string(),
+                    ));
+                }
+
+                let access_tokens: Vec<Self> = access_tokens::table
+                    .filter(access_tokens::user_id.eq(user_id))
+                    .filter(access_tokens::device_id.eq(device_id))
+                    .filter(access_tokens::revoked.eq(false))
+                    .order(access_tokens::id)
+                    .get_results(connection)
+                    .map_err(ApiError::from)?;
+
+                if access_tokens.is_empty() {
+                    return Err(ApiError::not_found(format!(
+                        "The device {} has no active sessions",
+                        device_id
+                    )));
+                }
+
+                access_tokens
+                    .into_iter()
+                    .map(|access_token| {
+                        let value = match config.token_format {
+                            TokenFormat::Macaroon => create_macaroon(
+                                &config.macaroon_secret_key,
+                                user_id,
+                                new_device_id,
+                                macaroon_expiration(&access_token.value)?.as_ref(),
+                            )?,
+                            TokenFormat::Opaque => access_token.value.clone(),
+                        };
+
+                        let rebound_access_token =
+                            diesel::update(access_tokens::table.find(access_token.id))
+                                .set((
+                                    access_tokens::value.eq(value),
+                                    access_tokens::device_id.eq(new_device_id),
+                                    access_tokens::updated_at.eq(diesel::dsl::now),
+                                ))
+                                .get_result(connection)
+                                .map_err(ApiError::from)?;
+
+                        Ok((access_token.value, rebound_access_token))
+                    })
+                    .collect()
+            })
+            .map_err(ApiError::from)
+    }
+
     /// Revoke the access token so it cannot be used again.
     pub fn revoke(&mut self, connection: &PgConnection) -> Result<(), ApiError> {
+        diesel::update(access_tokens::table.find(self.id))
+            .set(access_tokens::revoked.eq(true))
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
         self.revoked = true;
 
-        match self.save_changes::<Self>(connection) {
-            Ok(_) => Ok(()),
-            Err(error) => Err(ApiError::from(error)),
-        }
+        Ok(())
     }
 }
 
@@ -95,18 +425,40 @@ impl Key for AccessToken {
     type Value = Self;
 }
 
-/// Creates a macaroon for the given user using the master cryptographic key.
-fn create_macaroon(macaroon_secret_key: &[u8], user_id: &UserId) -> Result<String, ApiError> {
-    let expiration = match Utc::now().checked_add_signed(Duration::hours(1)) {
-        Some(datetime) => datetime,
-        None => {
-            return Err(ApiError::unknown(
-                "Failed to generate access token expiration datetime.".to_string(),
-            ))
-        }
-    };
+/// Checks that a device ID chosen by a client can be stored and put in a macaroon caveat.
+///
+/// Device IDs are opaque to the server, but may not be empty, too long, or contain whitespace or
+/// control characters, which would end a caveat early in a macaroon.
+fn validate_device_id(param_name: &str, device_id: &str) -> Result<(), ApiError> {
+    if device_id.is_empty() || device_id.len() > MAX_DEVICE_ID_LENGTH {
+        return Err(ApiError::invalid_param(
+            param_name,
+            &format!("Must be 1 to {} bytes long", MAX_DEVICE_ID_LENGTH),
+        ));
+    }
 
-    let token = V1Token::new(macaroon_secret_key, b"key".to_vec(), None)
+    if device_id
+        .chars()
+        .any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(ApiError::invalid_param(
+            param_name,
+            "Must not contain whitespace or control characters",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Creates a macaroon for the given user and device using the master cryptographic key, which
+/// expires at `expiration` if given.
+fn create_macaroon(
+    macaroon_secret_key: &[u8],
+    user_id: &UserId,
+    device_id: &str,
+    expiration: Option<&DateTime<Utc>>,
+) -> Result<String, ApiError> {
+    let mut token = V1Token::new(macaroon_secret_key, b"key".to_vec(), None)
         .add_caveat(&Caveat::first_party(
             format!("user_id = {}", user_id.to_string())
                 .as_bytes()
@@ -114,10 +466,450 @@ fn create_macaroon(macaroon_secret_key: &[u8], user_id: &UserId) -> Result<Strin
         ))
         .add_caveat(&Caveat::first_party(b"type = access".to_vec()))
         .add_caveat(&Caveat::first_party(
-            format!("time < {}", expiration).as_bytes().to_owned(),
+            format!("device_id = {}", device_id).as_bytes().to_owned(),
         ));
 
+    if let Some(expiration) = expiration {
+        token = token.add_caveat(&Caveat::first_party(
+            format!("time < {}", expiration.format(MACAROON_TIME_FORMAT))
+                .as_bytes()
+                .to_owned(),
+        ));
+    }
+
     let serialized = token.serialize()?;
 
     Ok(encode(&serialized))
 }
+
+/// Returns the expiration time in the `time` caveat of a Base64-encoded macaroon, if it has one.
+fn macaroon_expiration(token: &str) -> Result<Option<DateTime<Utc>>, ApiError> {
+    let invalid_macaroon = || ApiError::unknown("Failed to read a stored macaroon".to_string());
+
+    let macaroon = match decode(token).map(V1Token::deserialize) {
+        Ok(Ok(macaroon)) => macaroon,
+        Ok(Err(_)) | Err(_) => return Err(invalid_macaroon()),
+    };
+
+    let expiration = match caveat_values(&macaroon, "time").next() {
+        Some(expiration) => NaiveDateTime::parse_from_str(expiration, MACAROON_TIME_FORMAT)
+            .map_err(|_| invalid_macaroon())?,
+        None => return Ok(None),
+    };
+
+    Ok(Some(DateTime::from_utc(expiration, Utc)))
+}
+
+/// Converts a macaroon given in URL-safe Base64, possibly without padding, back to the standard
+/// Base64 it was issued in.
+fn standard_base64(token: &str) -> String {
+    let token = token.replace('-', "+").replace('_', "/");
+    let padding = "=".repeat((4 - token.len() % 4) % 4);
+
+    token + &padding
+}
+
+/// The error for tokens that weren't issued by this server.
+fn unknown_token() -> ApiError {
+    ApiError::unknown_token("Unknown token".to_string())
+}
+
+/// Returns the values of the macaroon's first-party caveats of the form `<name> <op> <value>`,
+/// where `op` is `=` or `<`.
+fn caveat_values<'a>(macaroon: &'a V1Token, name: &'a str) -> impl Iterator<Item = &'a str> {
+    macaroon.caveats.iter().filter_map(move |caveat| {
+        let caveat = from_utf8(&caveat.caveat_id).ok()?;
+        let mut parts = caveat.splitn(3, ' ');
+
+        if parts.next()? != name {
+            return None;
+        }
+
+        match parts.next()? {
+            "=" | "<" => parts.next(),
+            _ => None,
+        }
+    })
+}
+
+/// Checks the caveats of a macaroon whose signature has been verified against the access token
+/// it was issued as.
+///
+/// The token is accepted until `time_skew_seconds` after the expiration time in its caveats.
+fn verify_caveats(
+    macaroon: &V1Token,
+    access_token: &AccessToken,
+    time_skew_seconds: u64,
+) -> Result<(), ApiError> {
+    let invalid_token = || ApiError::unauthorized("Invalid token".to_string());
+    let now = Utc::now().naive_utc();
+
+    for caveat in &macaroon.caveats {
+        let caveat = from_utf8(&caveat.caveat_id).map_err(|_| invalid_token())?;
+
+        if caveat == "type = access" {
+            continue;
+        }
+
+        if caveat.starts_with("type = ") {
+            return Err(ApiError::unauthorized(
+                "The token is not an access token".to_string(),
+            ));
+        }
+
+        if caveat == format!("user_id = {}", access_token.user_id)
+            || caveat == format!("device_id = {}", access_token.device_id)
+        {
+            continue;
+        }
+
+        if caveat.starts_with("time < ") {
+            let expiration = caveat.trim_start_matches("time < ");
+
+            let expiration = NaiveDateTime::parse_from_str(expiration, MACAROON_TIME_FORMAT)
+                .map_err(|_| invalid_token())?;
+
+            // A skew too large to add to the expiration time means the token never expires.
+            let accepted_until = i64::try_from(time_skew_seconds)
+                .ok()
+                .filter(|seconds| *seconds <= i64::max_value() / 1000)
+                .map(Duration::seconds)
+                .and_then(|time_skew| expiration.checked_add_signed(time_skew));
+
+            match accepted_until {
+                Some(accepted_until) if now >= accepted_until => {
+                    return Err(ApiError::unknown_token("Expired token".to_string()))
+                }
+                _ => continue,
+            }
+        }
+
+        return Err(invalid_token());
+    }
+
+    if caveat_values(macaroon, "type").next().is_none() {
+        return Err(invalid_token());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use base64::encode;
+    use chrono::{Duration, Utc};
+    use diesel::prelude::*;
+    use iron::status::Status;
+    use macaroons::caveat::Caveat;
+    use macaroons::token::Token;
+    use macaroons::v1::V1Token;
+    use ruma_identifiers::UserId;
+
+    use super::{macaroon_expiration, AccessToken, NewAccessToken, MACAROON_TIME_FORMAT};
+    use crate::config::TokenFormat;
+    use crate::crypto::hash_opaque_token;
+    use crate::schema::{access_tokens, users};
+    use crate::test::Test;
+
+    /// Creates a user and returns their ID.
+    fn user_id(test: &Test) -> UserId {
+        UserId::try_from(test.create_user().id.as_str()).unwrap()
+    }
+
+    /// Stores the given value as an access token of the user.
+    fn store(test: &Test, user_id: &UserId, value: &str) {
+        diesel::insert_into(access_tokens::table)
+            .values(&NewAccessToken {
+                user_id: user_id.clone(),
+                value: value.to_string(),
+                device_id: "DEVICE".to_string(),
+            })
+            .execute(&*test.connection())
+            .unwrap();
+    }
+
+    /// Creates a Base64-encoded macaroon with the given first-party caveats.
+    fn macaroon(key: &[u8], caveats: &[String]) -> String {
+        let token = caveats
+            .iter()
+            .fold(V1Token::new(key, b"key".to_vec(), None), |token, caveat| {
+                token.add_caveat(&Caveat::first_party(caveat.as_bytes().to_vec()))
+            });
+
+        encode(&token.serialize().unwrap())
+    }
+
+    /// The caveats of a macaroon for the user that expires after the given duration.
+    fn caveats(user_id: &UserId, token_type: &str, expires_in: Duration) -> Vec<String> {
+        vec![
+            format!("user_id = {}", user_id),
+            format!("type = {}", token_type),
+            "device_id = DEVICE".to_string(),
+            format!(
+                "time < {}",
+                (Utc::now() + expires_in).format(MACAROON_TIME_FORMAT)
+            ),
+        ]
+    }
+
+    /// Authenticates the token and returns the error message.
+    fn authentication_error(test: &Test, token: &str) -> String {
+        AccessToken::authenticate(&test.connection(), token, test.config())
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn valid_macaroon() {
+        let test = Test::new();
+        let user_id = user_id(&test);
+        let token = macaroon(
+            &test.config().macaroon_secret_key,
+            &caveats(&user_id, "access", Duration::hours(1)),
+        );
+        store(&test, &user_id, &token);
+
+        let authed_user =
+            AccessToken::authenticate(&test.connection(), &token, test.config()).unwrap();
+
+        assert_eq!(authed_user.user.id, user_id);
+        assert_eq!(authed_user.access_token.user_id, user_id);
+        assert_eq!(authed_user.access_token.device_id, "DEVICE");
+    }
+
+    #[test]
+    fn issued_macaroon() {
+        let test = Test::new();
+        let user_id = user_id(&test);
+        let access_token =
+            AccessToken::create(&test.connection(), &user_id, "DEVICE", test.config()).unwrap();
+
+        let authed_user =
+            AccessToken::authenticate(&test.connection(), &access_token.value, test.config())
+                .unwrap();
+
+        assert_eq!(authed_user.access_token.id, access_token.id);
+        assert_eq!(authed_user.user.id, user_id);
+    }
+
+    #[test]
+    fn issued_macaroon_does_not_expire_by_default() {
+        let test = Test::new();
+        let user_id = user_id(&test);
+        let access_token =
+            AccessToken::create(&test.connection(), &user_id, "DEVICE", test.config()).unwrap();
+
+        assert_eq!(macaroon_expiration(&access_token.value).unwrap(), None);
+    }
+
+    #[test]
+    fn issued_macaroon_expires_after_the_configured_lifetime() {
+        let test = Test::with_config(|config| config.access_token_lifetime_seconds = 3600);
+        let user_id = user_id(&test);
+        let before = Utc::now();
+        let access_token =
+            AccessToken::create(&test.connection(), &user_id, "DEVICE", test.config()).unwrap();
+
+        let expiration = macaroon_expiration(&access_token.value).unwrap().unwrap();
+
+        // The time caveat has a precision of seconds.
+        assert!(expiration >= before + Duration::seconds(3599));
+        assert!(expiration <= Utc::now() + Duration::seconds(3600));
+    }
+
+    #[test]
+    fn expired_macaroon_is_an_unknown_token() {
+        let test = Test::new();
+        let user_id = user_id(&test);
+        let token = macaroon(
+            &test.config().macaroon_secret_key,
+            &caveats(&user_id, "access", Duration::hours(-1)),
+        );
+        store(&test, &user_id, &token);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/account/3pid?access_token={}",
+            token
+        ));
+
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNKNOWN_TOKEN"
+        );
+    }
+
+    #[test]
+    fn expired_macaroon() {
+        let test = Test::new();
+        let user_id = user_id(&test);
+        let token = macaroon(
+            &test.config().macaroon_secret_key,
+            &caveats(&user_id, "access", Duration::hours(-1)),
+        );
+        store(&test, &user_id, &token);
+
+        assert_eq!(authentication_error(&test, &token), "Expired token");
+    }
+
+    #[test]
+    fn macaroon_expired_within_time_skew() {
+        let test = Test::with_config(|config| config.token_time_skew_seconds = 60);
+        let user_id = user_id(&test);
+        let token = macaroon(
+            &test.config().macaroon_secret_key,
+            &caveats(&user_id, "access", Duration::seconds(-30)),
+        );
+        store(&test, &user_id, &token);
+
+        let authed_user =
+            AccessToken::authenticate(&test.connection(), &token, test.config()).unwrap();
+
+        assert_eq!(authed_user.user.id, user_id);
+    }
+
+    #[test]
+    fn macaroon_expired_beyond_time_skew() {
+        let test = Test::with_config(|config| config.token_time_skew_seconds = 60);
+        let user_id = user_id(&test);
+        let token = macaroon(
+            &test.config().macaroon_secret_key,
+            &caveats(&user_id, "access", Duration::seconds(-90)),
+        );
+        store(&test, &user_id, &token);
+
+        assert_eq!(authentication_error(&test, &token), "Expired token");
+    }
+
+    #[test]
+    fn revoked_macaroon() {
+        let test = Test::new();
+        let user_id = user_id(&test);
+        let mut access_token =
+            AccessToken::create(&test.connection(), &user_id, "DEVICE", test.config()).unwrap();
+        let token = access_token.value.clone();
+        access_token.revoke(&test.connection()).unwrap();
+
+        assert_eq!(authentication_error(&test, &token), "Revoked token");
+    }
+
+    #[test]
+    fn macaroon_of_the_wrong_type() {
+        let test = Test::new();
+        let user_id = user_id(&test);
+        let token = macaroon(
+            &test.config().macaroon_secret_key,
+            &caveats(&user_id, "refresh", Duration::hours(1)),
+        );
+        store(&test, &user_id, &token);
+
+        assert_eq!(
+            authentication_error(&test, &token),
+            "The token is not an access token"
+        );
+    }
+
+    #[test]
+    fn macaroon_for_another_device() {
+        let test = Test::new();
+        let user_id = user_id(&test);
+        let mut caveats = caveats(&user_id, "access", Duration::hours(1));
+        caveats[2] = "device_id = OTHER".to_string();
+        let token = macaroon(&test.config().macaroon_secret_key, &caveats);
+        store(&test, &user_id, &token);
+
+        assert_eq!(authentication_error(&test, &token), "Invalid token");
+    }
+
+    #[test]
+    fn macaroon_with_a_bad_signature() {
+        let test = Test::new();
+        let user_id = user_id(&test);
+        let token = macaroon(
+            b"not the secret key",
+            &caveats(&user_id, "access", Duration::hours(1)),
+        );
+        store(&test, &user_id, &token);
+
+        assert_eq!(
+            authentication_error(&test, &token),
+            "Invalid token signature"
+        );
+    }
+
+    #[test]
+    fn unknown_macaroon() {
+        let test = Test::new();
+        let user_id = user_id(&test);
+        let token = macaroon(
+            &test.config().macaroon_secret_key,
+            &caveats(&user_id, "access", Duration::hours(1)),
+        );
+
+        assert_eq!(authentication_error(&test, &token), "Unknown token");
+        assert_eq!(
+            authentication_error(&test, "not a macaroon"),
+            "Unknown token"
+        );
+    }
+
+    #[test]
+    fn macaroon_of_a_deactivated_user() {
+        let test = Test::new();
+        let user_id = user_id(&test);
+        let access_token =
+            AccessToken::create(&test.connection(), &user_id, "DEVICE", test.config()).unwrap();
+
+        diesel::update(users::table.find(&user_id))
+            .set(users::active.eq(false))
+            .execute(&*test.connection())
+            .unwrap();
+
+        assert_eq!(
+            authentication_error(&test, &access_token.value),
+            "No user with the given token was found"
+        );
+    }
+
+    #[test]
+    fn opaque_token() {
+        let test = Test::with_config(|config| config.token_format = TokenFormat::Opaque);
+        let user_id = user_id(&test);
+        store(&test, &user_id, &hash_opaque_token("secret"));
+
+        let authed_user =
+            AccessToken::authenticate(&test.connection(), "secret", test.config()).unwrap();
+
+        assert_eq!(authed_user.user.id, user_id);
+        assert_eq!(authentication_error(&test, "unknown"), "Unknown token");
+    }
+
+    #[test]
+    fn revoke_all_for_user() {
+        let test = Test::new();
+        let other_user_id = user_id(&test);
+        let user_id = user_id(&test);
+
+        let mut first =
+            AccessToken::create(&test.connection(), &user_id, "FIRST", test.config()).unwrap();
+        AccessToken::create(&test.connection(), &user_id, "SECOND", test.config()).unwrap();
+        first.revoke(&test.connection()).unwrap();
+
+        assert_eq!(
+            AccessToken::revoke_all_for_user(&test.connection(), &user_id).unwrap(),
+            2
+        );
+        assert!(AccessToken::find_valid_by_uid(&test.connection(), &user_id)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            AccessToken::revoke_all_for_user(&test.connection(), &user_id).unwrap(),
+            0
+        );
+
+        let other_tokens = AccessToken::find_valid_by_uid(&test.connection(), &other_user_id);
+        assert_eq!(other_tokens.unwrap().len(), 1);
+    }
+}
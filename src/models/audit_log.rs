@@ -0,0 +1,104 @@
+//! A record of authentication events, for auditing.
+
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use iron::Request;
+use ruma_identifiers::UserId;
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::schema::audit_log;
+
+/// The kinds of events recorded in the audit log.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AuditEvent {
+    /// A user logged in.
+    Login,
+    /// A login attempt failed.
+    FailedLogin,
+    /// A user logged out.
+    Logout,
+    /// A user changed their password.
+    PasswordChange,
+    /// A user deactivated their account.
+    Deactivation,
+}
+
+impl AuditEvent {
+    /// The name of the event as stored in the audit log.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditEvent::Login => "login",
+            AuditEvent::FailedLogin => "failed_login",
+            AuditEvent::Logout => "logout",
+            AuditEvent::PasswordChange => "password_change",
+            AuditEvent::Deactivation => "deactivation",
+        }
+    }
+}
+
+/// An entry in the audit log.
+#[derive(Debug, Clone, Identifiable, Queryable)]
+#[table_name = "audit_log"]
+pub struct AuditLog {
+    /// The entry's ID.
+    pub id: i64,
+    /// The ID of the user the event concerns, if known. Failed logins may not name a user.
+    pub user_id: Option<UserId>,
+    /// The kind of event, see `AuditEvent`.
+    pub event_type: String,
+    /// The IP address of the client that caused the event.
+    pub ip: String,
+    /// The time of the event.
+    pub created_at: PgTimestamp,
+}
+
+/// A new audit log entry, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "audit_log"]
+pub struct NewAuditLog {
+    /// The ID of the user the event concerns, if known.
+    pub user_id: Option<UserId>,
+    /// The kind of event.
+    pub event_type: String,
+    /// The IP address of the client that caused the event.
+    pub ip: String,
+}
+
+impl AuditLog {
+    /// Record an event caused by the given request, if the audit log is enabled.
+    pub fn record(
+        connection: &PgConnection,
+        config: &Config,
+        request: &Request<'_, '_>,
+        user_id: Option<&UserId>,
+        event: AuditEvent,
+    ) -> Result<(), ApiError> {
+        if !config.enable_audit_log {
+            return Ok(());
+        }
+
+        let new_audit_log = NewAuditLog {
+            user_id: user_id.cloned(),
+            event_type: event.as_str().to_string(),
+            ip: request.remote_addr.ip().to_string(),
+        };
+
+        diesel::insert_into(audit_log::table)
+            .values(&new_audit_log)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Return the audit log entries concerning the given user, oldest first.
+    pub fn find_by_uid(connection: &PgConnection, user_id: &UserId) -> Result<Vec<Self>, ApiError> {
+        audit_log::table
+            .filter(audit_log::user_id.eq(user_id))
+            .order(audit_log::id)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+}
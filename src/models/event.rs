@@ -1,5 +1,6 @@
 //! Matrix events.
 
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 
 use diesel::dsl::{any, max};
@@ -11,7 +12,7 @@ use ruma_events::call::answer::AnswerEvent;
 use ruma_events::call::candidates::CandidatesEvent;
 use ruma_events::call::hangup::HangupEvent;
 use ruma_events::call::invite::InviteEvent;
-use ruma_events::collections::all::StateEvent;
+use ruma_events::collections::all::{RoomEvent as RoomEventCollection, StateEvent};
 use ruma_events::room::aliases::AliasesEvent;
 use ruma_events::room::avatar::AvatarEvent;
 use ruma_events::room::canonical_alias::CanonicalAliasEvent;
@@ -23,6 +24,7 @@ use ruma_events::room::member::MemberEvent;
 use ruma_events::room::message::MessageEvent;
 use ruma_events::room::name::NameEvent;
 use ruma_events::room::power_levels::PowerLevelsEvent;
+use ruma_events::room::redaction::RedactionEvent;
 use ruma_events::room::third_party_invite::ThirdPartyInviteEvent;
 use ruma_events::room::topic::TopicEvent;
 use ruma_events::stripped::{
@@ -36,7 +38,7 @@ use ruma_events::{
     StateEvent as RumaStateEventTrait,
 };
 use ruma_identifiers::{EventId, RoomId, UserId};
-use serde_json::{from_str, to_string};
+use serde_json::{from_str, to_string, Map, Value};
 
 use crate::error::ApiError;
 use crate::schema::events;
@@ -73,6 +75,8 @@ pub struct NewEvent {
     pub sender: UserId,
     /// An event subtype that determines whether or not the event will overwrite a previous one.
     pub state_key: Option<String>,
+    /// The event redacted by this event, if it is a redaction.
+    pub redacts: Option<EventId>,
 }
 
 /// A Matrix event.
@@ -94,6 +98,10 @@ pub struct Event {
     pub content: String,
     /// The time the event was created.
     pub created_at: PgTimestamp,
+    /// The event redacted by this event, if it is a redaction.
+    pub redacts: Option<EventId>,
+    /// The redaction that removed this event's content, if it has been redacted.
+    pub redacted_by: Option<EventId>,
 }
 
 impl Event {
@@ -150,6 +158,59 @@ impl Event {
             })
     }
 
+    /// Strip the content of the event down to the keys kept by the redaction algorithm, and
+    /// record the redaction that removed it.
+    pub fn redact(&self, connection: &PgConnection, redacted_by: &EventId) -> Result<(), ApiError> {
+        let content = redact_content(&self.event_type, &self.content)?;
+
+        diesel::update(events::table.find(&self.id))
+            .set((
+                events::content.eq(content),
+                events::redacted_by.eq(redacted_by),
+            ))
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+
+    /// Convert a redacted event into a `RoomEvent`.
+    ///
+    /// The content of a redacted event is usually no longer valid for its type, so it is
+    /// represented as a custom event of the same type.
+    pub fn into_redacted_room_event(self) -> Result<RoomEventCollection, ApiError> {
+        if self.state_key.is_some() {
+            return Ok(RoomEventCollection::CustomState(
+                self.into_redacted_state_event()?,
+            ));
+        }
+
+        Ok(RoomEventCollection::CustomRoom(CustomRoomEvent {
+            content: from_str(&self.content).map_err(ApiError::from)?,
+            event_id: self.id,
+            event_type: EventType::from(self.event_type.as_ref()),
+            origin_server_ts: 0,
+            room_id: self.room_id,
+            sender: self.sender,
+            unsigned: None,
+        }))
+    }
+
+    /// Convert a redacted state event into a custom state event of the same type, since its
+    /// content is usually no longer valid for its type.
+    fn into_redacted_state_event(self) -> Result<CustomStateEvent, ApiError> {
+        Ok(CustomStateEvent {
+            content: from_str(&self.content).map_err(ApiError::from)?,
+            event_id: self.id,
+            event_type: EventType::from(self.event_type.as_ref()),
+            origin_server_ts: 0,
+            prev_content: None,
+            room_id: self.room_id,
+            sender: self.sender,
+            state_key: self.state_key.unwrap_or_default(),
+            unsigned: None,
+        })
+    }
+
     /// Look up an event given its `EventId`.
     pub fn find(connection: &PgConnection, event_id: &EventId) -> Result<Option<Self>, ApiError> {
         match events::table.find(event_id).first(connection) {
@@ -159,6 +220,55 @@ impl Event {
         }
     }
 
+    /// Return the current state events with an empty state key and one of the given types in
+    /// any of the given rooms.
+    pub fn find_current_state_events(
+        connection: &PgConnection,
+        room_ids: &[RoomId],
+        event_types: &[EventType],
+    ) -> Result<Vec<Self>, ApiError> {
+        let event_types: Vec<String> = event_types.iter().map(EventType::to_string).collect();
+
+        let events: Vec<Self> = events::table
+            .filter(events::room_id.eq(any(room_ids)))
+            .filter(events::event_type.eq(any(event_types)))
+            .filter(events::state_key.eq(""))
+            .order(events::ordering.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        let mut current = HashMap::new();
+
+        for event in events {
+            let key = (event.room_id.clone(), event.event_type.clone());
+
+            current.insert(key, event);
+        }
+
+        Ok(current.into_iter().map(|(_, event)| event).collect())
+    }
+
+    /// Return the current state event of the given type and state key in a room, if any.
+    pub fn find_room_state_event(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        event_type: &EventType,
+        state_key: &str,
+    ) -> Result<Option<Self>, ApiError> {
+        let event = events::table
+            .filter(events::room_id.eq(room_id))
+            .filter(events::event_type.eq(event_type.to_string()))
+            .filter(events::state_key.eq(state_key))
+            .order(events::ordering.desc())
+            .first(connection);
+
+        match event {
+            Ok(event) => Ok(Some(event)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
     /// Return the room's state before a specified event.
     pub fn get_room_state_events_until(
         connection: &PgConnection,
@@ -214,6 +324,75 @@ impl Event {
     }
 }
 
+/// Remove all keys from the JSON content of an event of the given type, except those needed to
+/// authorize the room's later events, as described by the redaction algorithm.
+pub fn redact_content(event_type: &str, content: &str) -> Result<String, ApiError> {
+    let kept_keys: &[&str] = match EventType::from(event_type) {
+        EventType::RoomAliases => &["aliases"],
+        EventType::RoomCreate => &["creator"],
+        EventType::RoomHistoryVisibility => &["history_visibility"],
+        EventType::RoomJoinRules => &["join_rule"],
+        EventType::RoomMember => &["membership"],
+        EventType::RoomPowerLevels => &[
+            "ban",
+            "events",
+            "events_default",
+            "kick",
+            "redact",
+            "state_default",
+            "users",
+            "users_default",
+        ],
+        _ => &[],
+    };
+
+    let content: Map<String, Value> = from_str(content).map_err(ApiError::from)?;
+
+    let redacted: Map<String, Value> = content
+        .into_iter()
+        .filter(|(key, _)| kept_keys.contains(&key.as_str()))
+        .collect();
+
+    to_string(&redacted).map_err(ApiError::from)
+}
+
+impl TryFrom<RedactionEvent> for NewEvent {
+    type Error = ApiError;
+
+    fn try_from(event: RedactionEvent) -> Result<Self, Self::Error> {
+        Ok(Self {
+            content: to_string(&event.content).map_err(ApiError::from)?,
+            event_type: event.event_type.to_string(),
+            id: event.event_id,
+            room_id: event.room_id,
+            sender: event.sender,
+            state_key: None,
+            redacts: Some(event.redacts),
+        })
+    }
+}
+
+impl TryInto<RedactionEvent> for Event {
+    type Error = ApiError;
+
+    fn try_into(self) -> Result<RedactionEvent, Self::Error> {
+        let redacts = self.redacts.ok_or_else(|| {
+            ApiError::unknown("Redaction event does not record what it redacts".to_string())
+        })?;
+
+        Ok(RedactionEvent {
+            content: from_str(&self.content).map_err(ApiError::from)?,
+            event_id: self.id,
+            event_type: EventType::RoomRedaction,
+            origin_server_ts: 0,
+            redacts,
+            room_id: self.room_id,
+            sender: self.sender,
+            unsigned: None,
+        })
+    }
+}
+
 macro_rules! impl_try_from_room_event_for_new_event {
     ($ty:ty) => {
         impl TryFrom<$ty> for NewEvent {
@@ -227,6 +406,7 @@ macro_rules! impl_try_from_room_event_for_new_event {
                     room_id: event.room_id().map(|room_id| room_id.clone()),
                     sender: event.sender().clone(),
                     state_key: None,
+                    redacts: None,
                 })
             }
         }
@@ -246,6 +426,7 @@ macro_rules! impl_try_from_state_event_for_new_event {
                     room_id: event.room_id().map(|room_id| room_id.clone()),
                     sender: event.sender().clone(),
                     state_key: Some(event.state_key().to_string()),
+                    redacts: None,
                 })
             }
         }
@@ -396,7 +577,7 @@ impl TryInto<MemberEvent> for Event {
             prev_content: None,
             room_id: self.room_id,
             sender: self.sender,
-            state_key: "".to_string(),
+            state_key: self.state_key.unwrap_or_default(),
             unsigned: None,
         })
     }
@@ -406,6 +587,10 @@ impl TryInto<StateEvent> for Event {
     type Error = ApiError;
 
     fn try_into(self) -> Result<StateEvent, Self::Error> {
+        if self.redacted_by.is_some() {
+            return Ok(StateEvent::CustomState(self.into_redacted_state_event()?));
+        }
+
         let state_event = match EventType::from(self.event_type.as_ref()) {
             EventType::RoomAliases => StateEvent::RoomAliases(self.try_into()?),
             EventType::RoomAvatar => StateEvent::RoomAvatar(self.try_into()?),
@@ -2,6 +2,7 @@
 
 use std::fmt::{Formatter, Result as FmtResult};
 
+use diesel::dsl::max;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::result::Error as DieselError;
@@ -10,7 +11,7 @@ use serde::de::{Error as SerdeError, Unexpected, Visitor};
 use serde::{Deserializer, Serializer};
 
 use crate::error::ApiError;
-use crate::schema::filters;
+use crate::schema::{filters, users};
 
 /// Defines the default format of `Filter` for `account_data` and `presence`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,7 +20,10 @@ pub struct EventFilter {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
     pub not_types: Vec<String>,
-    /// The maximum number of events to return.
+    /// The maximum number of events to return, or 0 for no limit.
+    ///
+    /// Clients often leave it out, e.g. when excluding presence, and the spec makes it optional.
+    #[serde(default)]
     pub limit: usize,
     /// A list of senders IDs to include.
     #[serde(skip_serializing_if = "Vec::is_empty")]
@@ -35,6 +39,26 @@ pub struct EventFilter {
     pub not_senders: Vec<UserId>,
 }
 
+impl EventFilter {
+    /// Whether or not an event of the given type and sender passes the filter.
+    ///
+    /// A type ending in `*` matches any event type starting with the rest of it.
+    pub fn allows(&self, event_type: &str, sender: &UserId) -> bool {
+        let matches_type = |filter_type: &String| {
+            if filter_type.ends_with('*') {
+                event_type.starts_with(filter_type.trim_end_matches('*'))
+            } else {
+                filter_type == event_type
+            }
+        };
+
+        !self.not_types.iter().any(matches_type)
+            && (self.types.is_empty() || self.types.iter().any(matches_type))
+            && !self.not_senders.contains(sender)
+            && (self.senders.is_empty() || self.senders.contains(sender))
+    }
+}
+
 /// Defines the default format of a `RoomEventFilter`
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RoomEventFilter {
@@ -191,6 +215,8 @@ pub struct NewFilter {
     pub user_id: UserId,
     /// The contents.
     pub content: String,
+    /// The ID of the filter among the user's filters.
+    pub filter_id: i64,
 }
 
 /// A new Matrix filter.
@@ -203,28 +229,59 @@ pub struct Filter {
     pub user_id: UserId,
     /// The contents.
     pub content: String,
+    /// The ID of the filter among the user's filters, starting at 0.
+    pub filter_id: i64,
 }
 
 impl Filter {
-    /// Creates a new `Filter`
+    /// Creates a new `Filter`, returning its ID among the user's filters.
+    ///
+    /// The user's row is locked until the filter is saved, so that filters created at the same
+    /// time get different IDs.
     pub fn create(
         connection: &PgConnection,
         user_id: UserId,
         content: String,
     ) -> Result<i64, ApiError> {
-        let new_filter = NewFilter { user_id, content };
+        connection
+            .transaction::<i64, ApiError, _>(|| {
+                users::table
+                    .find(&user_id)
+                    .select(users::id)
+                    .for_update()
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                let last_filter_id: Option<i64> = filters::table
+                    .filter(filters::user_id.eq(&user_id))
+                    .select(max(filters::filter_id))
+                    .first(connection)
+                    .map_err(ApiError::from)?;
+
+                let new_filter = NewFilter {
+                    user_id,
+                    content,
+                    filter_id: last_filter_id.map_or(0, |filter_id| filter_id + 1),
+                };
 
-        let filter: Self = diesel::insert_into(filters::table)
-            .values(&new_filter)
-            .get_result(connection)
-            .map_err(ApiError::from)?;
-        Ok(filter.id)
+                let filter: Self = diesel::insert_into(filters::table)
+                    .values(&new_filter)
+                    .get_result(connection)
+                    .map_err(ApiError::from)?;
+
+                Ok(filter.filter_id)
+            })
+            .map_err(ApiError::from)
     }
 
-    /// Return `Filter`'s for given `UserId` and `id`.
-    pub fn find(connection: &PgConnection, user_id: UserId, id: i64) -> Result<Self, ApiError> {
+    /// Return the `Filter` with the given ID among the filters of the given `UserId`.
+    pub fn find(
+        connection: &PgConnection,
+        user_id: UserId,
+        filter_id: i64,
+    ) -> Result<Self, ApiError> {
         let filter = filters::table
-            .filter(filters::id.eq(id))
+            .filter(filters::filter_id.eq(filter_id))
             .filter(filters::user_id.eq(user_id))
             .first(connection);
 
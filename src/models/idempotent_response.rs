@@ -0,0 +1,155 @@
+//! Responses stored so that retried requests can be answered without repeating their effects.
+
+use diesel::dsl::{now, IntervalDsl};
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::UserId;
+
+use crate::error::ApiError;
+use crate::schema::idempotent_responses;
+
+/// How long a response is replayed for, in seconds.
+///
+/// Clients retry a request when they didn't see its response, and keep queued requests while
+/// they are offline, e.g. a phone that lost its connection overnight. A day covers those retries
+/// while keeping the table small.
+const IDEMPOTENT_RESPONSE_TTL: i32 = 24 * 60 * 60;
+
+/// How long a key stays reserved for a request that hasn't finished, in seconds.
+///
+/// This only matters when the server stopped while handling the request, since otherwise the
+/// reservation is either completed or released. Requests that take longer may run twice.
+const RESERVATION_TTL: i32 = 5 * 60;
+
+/// A request made with an idempotency key, along with its successful response once it has one.
+#[derive(Debug, Clone, Identifiable, Queryable)]
+#[table_name = "idempotent_responses"]
+#[primary_key(user_id, device_id, path, idempotency_key)]
+pub struct IdempotentResponse {
+    /// The user who made the request.
+    pub user_id: UserId,
+    /// The path of the endpoint that was requested.
+    pub path: String,
+    /// The transaction ID or `Idempotency-Key` header sent with the request.
+    pub idempotency_key: String,
+    /// The HTTP status code of the response, or `None` while the request is being handled.
+    pub status: Option<i32>,
+    /// The JSON body of the response, or `None` while the request is being handled.
+    pub body: Option<String>,
+    /// The time the key was reserved or the response was stored.
+    pub created_at: PgTimestamp,
+    /// The device of the access token the request was made with.
+    pub device_id: String,
+}
+
+/// The identity of a request made with an idempotency key.
+#[derive(Debug, Insertable)]
+#[table_name = "idempotent_responses"]
+pub struct NewIdempotentResponse {
+    /// The user who made the request.
+    pub user_id: UserId,
+    /// The device of the access token the request was made with.
+    pub device_id: String,
+    /// The path of the endpoint that was requested.
+    pub path: String,
+    /// The transaction ID or `Idempotency-Key` header sent with the request.
+    pub idempotency_key: String,
+}
+
+impl NewIdempotentResponse {
+    /// The primary key of the request's row.
+    fn primary_key(&self) -> (&UserId, &str, &str, &str) {
+        (
+            &self.user_id,
+            &self.device_id,
+            &self.path,
+            &self.idempotency_key,
+        )
+    }
+}
+
+impl IdempotentResponse {
+    /// Reserve the key of a request before handling it, so that a concurrent request with the
+    /// same key isn't handled as well.
+    ///
+    /// Returns `None` if the key was reserved for this request, or the earlier request with the
+    /// same key otherwise, which is still being handled if it has no response yet.
+    pub fn reserve(
+        connection: &PgConnection,
+        new_response: &NewIdempotentResponse,
+    ) -> Result<Option<Self>, ApiError> {
+        loop {
+            let expired = idempotent_responses::status
+                .is_not_null()
+                .and(idempotent_responses::created_at.lt(now - IDEMPOTENT_RESPONSE_TTL.seconds()))
+                .or(idempotent_responses::status
+                    .is_null()
+                    .and(idempotent_responses::created_at.lt(now - RESERVATION_TTL.seconds())));
+
+            diesel::delete(
+                idempotent_responses::table
+                    .find(new_response.primary_key())
+                    .filter(expired),
+            )
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+            // A concurrent insert of the same key waits for the other transaction to finish and
+            // then inserts nothing.
+            let inserted = diesel::insert_into(idempotent_responses::table)
+                .values(new_response)
+                .on_conflict_do_nothing()
+                .execute(connection)
+                .map_err(ApiError::from)?;
+
+            if inserted == 1 {
+                return Ok(None);
+            }
+
+            match idempotent_responses::table
+                .find(new_response.primary_key())
+                .first(connection)
+            {
+                Ok(response) => return Ok(Some(response)),
+                // The other request released its reservation in the meantime.
+                Err(DieselError::NotFound) => {}
+                Err(err) => return Err(ApiError::from(err)),
+            }
+        }
+    }
+
+    /// Store the response to a request whose key was reserved with `reserve`.
+    pub fn complete(
+        connection: &PgConnection,
+        new_response: &NewIdempotentResponse,
+        status: i32,
+        body: &str,
+    ) -> Result<(), ApiError> {
+        diesel::update(idempotent_responses::table.find(new_response.primary_key()))
+            .set((
+                idempotent_responses::status.eq(status),
+                idempotent_responses::body.eq(body),
+                idempotent_responses::created_at.eq(now),
+            ))
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+
+    /// Release the key of a request that failed, so that it can be retried.
+    pub fn release(
+        connection: &PgConnection,
+        new_response: &NewIdempotentResponse,
+    ) -> Result<(), ApiError> {
+        diesel::delete(
+            idempotent_responses::table
+                .find(new_response.primary_key())
+                .filter(idempotent_responses::status.is_null()),
+        )
+        .execute(connection)
+        .map(|_| ())
+        .map_err(ApiError::from)
+    }
+}
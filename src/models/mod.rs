@@ -1,17 +1,49 @@
 pub mod access_token;
 pub mod account_data;
+pub mod audit_log;
 pub mod event;
 pub mod filter;
+pub mod idempotent_response;
+pub mod one_time_key;
+pub mod openid_token;
+pub mod password_reset_session;
 pub mod presence_list;
 pub mod presence_status;
 pub mod profile;
 pub mod pusher;
+pub mod receipt;
+pub mod registration_token;
 pub mod room;
 pub mod room_alias;
 pub mod room_membership;
+pub mod server_notice;
 pub mod tags;
-pub mod transaction;
+pub mod three_pid;
 pub mod user;
+pub mod user_ip;
+
+use diesel::pg::data_types::PgTimestamp;
+
+/// Number of milliseconds between the Unix epoch and the PostgreSQL epoch (2000-01-01).
+const POSTGRES_EPOCH_MILLIS: i64 = 946_684_800_000;
+
+/// Converts a timestamp stored by PostgreSQL into milliseconds since the Unix epoch.
+pub fn unix_millis(timestamp: &PgTimestamp) -> i64 {
+    timestamp.0 / 1000 + POSTGRES_EPOCH_MILLIS
+}
+
+/// Converts milliseconds since the Unix epoch into a timestamp that can be stored by PostgreSQL.
+pub fn pg_timestamp(unix_millis: i64) -> PgTimestamp {
+    PgTimestamp((unix_millis - POSTGRES_EPOCH_MILLIS) * 1000)
+}
+
+/// Escapes the characters with a special meaning in SQL `LIKE` patterns.
+pub fn escape_like_pattern(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
 
 /// Helper function for skipping `false` fields when serializing with serde.
 // This signature is required by Serde. Sorry, clippy.
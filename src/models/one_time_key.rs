@@ -0,0 +1,132 @@
+//! One-time keys for end-to-end encryption.
+
+use std::collections::BTreeMap;
+
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::UserId;
+
+use crate::error::ApiError;
+use crate::schema::one_time_keys;
+
+/// A one-time key uploaded by a device.
+#[derive(Debug, Clone, Identifiable, Queryable)]
+#[table_name = "one_time_keys"]
+pub struct OneTimeKey {
+    /// The key's ID in the database.
+    pub id: i64,
+    /// The ID of the user who owns the device.
+    pub user_id: UserId,
+    /// The ID of the device the key belongs to.
+    pub device_id: String,
+    /// The key's algorithm, e.g. "signed_curve25519".
+    pub algorithm: String,
+    /// The ID of the key, unique for the device and algorithm.
+    pub key_id: String,
+    /// JSON of the key, either the Base64-encoded key itself or a signed key object.
+    pub key: String,
+    /// The time the key was uploaded.
+    pub created_at: PgTimestamp,
+}
+
+/// A new one-time key, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "one_time_keys"]
+pub struct NewOneTimeKey {
+    /// The ID of the user who owns the device.
+    pub user_id: UserId,
+    /// The ID of the device the key belongs to.
+    pub device_id: String,
+    /// The key's algorithm.
+    pub algorithm: String,
+    /// The ID of the key.
+    pub key_id: String,
+    /// JSON of the key.
+    pub key: String,
+}
+
+impl OneTimeKey {
+    /// Store one-time keys for a device. Keys that were already uploaded are left unchanged.
+    pub fn create(
+        connection: &PgConnection,
+        new_one_time_keys: &[NewOneTimeKey],
+    ) -> Result<(), ApiError> {
+        diesel::insert_into(one_time_keys::table)
+            .values(new_one_time_keys)
+            .on_conflict_do_nothing()
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Remove and return one of the device's one-time keys for the given algorithm, if it has
+    /// any left.
+    ///
+    /// The key is locked until it's deleted and keys locked by other claims are skipped, so a key
+    /// is never handed out twice.
+    pub fn claim(
+        connection: &PgConnection,
+        user_id: &UserId,
+        device_id: &str,
+        algorithm: &str,
+    ) -> Result<Option<Self>, ApiError> {
+        connection
+            .transaction::<Option<Self>, ApiError, _>(|| {
+                let claimed_key = one_time_keys::table
+                    .filter(one_time_keys::user_id.eq(user_id))
+                    .filter(one_time_keys::device_id.eq(device_id))
+                    .filter(one_time_keys::algorithm.eq(algorithm))
+                    .order(one_time_keys::id)
+                    .for_update()
+                    .skip_locked()
+                    .first::<Self>(connection);
+
+                let claimed_key = match claimed_key {
+                    Ok(claimed_key) => claimed_key,
+                    Err(DieselError::NotFound) => return Ok(None),
+                    Err(err) => return Err(ApiError::from(err)),
+                };
+
+                diesel::delete(one_time_keys::table.find(claimed_key.id))
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                Ok(Some(claimed_key))
+            })
+            .map_err(ApiError::from)
+    }
+
+    /// The number of one-time keys the device has left, keyed by algorithm.
+    pub fn count_by_device(
+        connection: &PgConnection,
+        user_id: &UserId,
+        device_id: &str,
+    ) -> Result<BTreeMap<String, u64>, ApiError> {
+        let algorithms: Vec<String> = one_time_keys::table
+            .filter(one_time_keys::user_id.eq(user_id))
+            .filter(one_time_keys::device_id.eq(device_id))
+            .select(one_time_keys::algorithm)
+            .load(connection)
+            .map_err(ApiError::from)?;
+
+        let mut counts = BTreeMap::new();
+
+        for algorithm in algorithms {
+            *counts.entry(algorithm).or_insert(0) += 1;
+        }
+
+        Ok(counts)
+    }
+
+    /// Delete the one-time keys of all devices of the user with the given `UserId`.
+    pub fn delete_by_uid(connection: &PgConnection, user_id: &UserId) -> Result<usize, ApiError> {
+        let rows = one_time_keys::table.filter(one_time_keys::user_id.eq(user_id));
+
+        diesel::delete(rows)
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+}
@@ -0,0 +1,77 @@
+//! Short-lived tokens that let third parties verify a user's identity via OpenID.
+
+use diesel::dsl::{now, IntervalDsl};
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use ruma_identifiers::UserId;
+
+use crate::crypto::{generate_opaque_token, hash_opaque_token};
+use crate::error::ApiError;
+use crate::schema::openid_tokens;
+
+/// How long an OpenID token is valid for, in seconds.
+pub const OPENID_TOKEN_LIFETIME: i32 = 60 * 60;
+
+/// A token a user has requested to prove their identity to a third party.
+#[derive(Debug, Clone, Identifiable, Queryable)]
+#[table_name = "openid_tokens"]
+#[primary_key(token_hash)]
+pub struct OpenIdToken {
+    /// A hash of the token given to the user.
+    pub token_hash: String,
+    /// The ID of the user whose identity the token proves.
+    pub user_id: UserId,
+    /// The time the token was issued.
+    pub created_at: PgTimestamp,
+}
+
+/// A new OpenID token, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "openid_tokens"]
+pub struct NewOpenIdToken {
+    /// A hash of the token given to the user.
+    pub token_hash: String,
+    /// The ID of the user whose identity the token proves.
+    pub user_id: UserId,
+}
+
+impl OpenIdToken {
+    /// Issue a new token for the given user.
+    ///
+    /// Only a hash of the token is stored, so the token itself is returned along with it.
+    pub fn create(connection: &PgConnection, user_id: &UserId) -> Result<(Self, String), ApiError> {
+        let token = generate_opaque_token()?;
+
+        let new_token = NewOpenIdToken {
+            token_hash: hash_opaque_token(&token),
+            user_id: user_id.clone(),
+        };
+
+        let openid_token = diesel::insert_into(openid_tokens::table)
+            .values(&new_token)
+            .get_result(connection)
+            .map_err(ApiError::from)?;
+
+        Ok((openid_token, token))
+    }
+
+    /// Look up a token given to a user, unless it has expired.
+    pub fn find_valid(connection: &PgConnection, token: &str) -> Result<Option<Self>, ApiError> {
+        openid_tokens::table
+            .find(hash_opaque_token(token))
+            .filter(openid_tokens::created_at.gt(now - OPENID_TOKEN_LIFETIME.seconds()))
+            .first(connection)
+            .optional()
+            .map_err(ApiError::from)
+    }
+
+    /// Delete all tokens issued to the user with the given `UserId`.
+    pub fn delete_by_uid(connection: &PgConnection, user_id: &UserId) -> Result<usize, ApiError> {
+        let rows = openid_tokens::table.filter(openid_tokens::user_id.eq(user_id));
+
+        diesel::delete(rows)
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+}
@@ -0,0 +1,120 @@
+//! Sessions for resetting a forgotten password via a bound email address.
+
+use diesel::dsl::now;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::UserId;
+
+use crate::crypto::{generate_opaque_token, hash_opaque_token};
+use crate::error::ApiError;
+use crate::schema::password_reset_sessions;
+
+/// A request to reset a user's password, identified by the client secret and email address it
+/// was requested with.
+#[derive(Debug, Clone, Identifiable, Queryable)]
+#[table_name = "password_reset_sessions"]
+#[primary_key(sid)]
+pub struct PasswordResetSession {
+    /// The session ID given to the client.
+    pub sid: String,
+    /// The secret chosen by the client to identify its requests.
+    pub client_secret: String,
+    /// The email address the token is sent to.
+    pub address: String,
+    /// The ID of the user whose password can be reset.
+    pub user_id: UserId,
+    /// A hash of the token sent to the email address.
+    pub token_hash: String,
+    /// The client's counter for requests, used to avoid sending the same token twice.
+    pub send_attempt: i64,
+    /// The time the session was created.
+    pub created_at: PgTimestamp,
+    /// The time the token was last rotated.
+    pub updated_at: PgTimestamp,
+}
+
+/// A new password reset session, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "password_reset_sessions"]
+pub struct NewPasswordResetSession {
+    /// The session ID given to the client.
+    pub sid: String,
+    /// The secret chosen by the client to identify its requests.
+    pub client_secret: String,
+    /// The email address the token is sent to.
+    pub address: String,
+    /// The ID of the user whose password can be reset.
+    pub user_id: UserId,
+    /// A hash of the token sent to the email address.
+    pub token_hash: String,
+    /// The client's counter for requests.
+    pub send_attempt: i64,
+}
+
+impl PasswordResetSession {
+    /// Start a session for the given client secret and address, or rotate the token of an
+    /// existing one if `send_attempt` is higher than the last one seen.
+    ///
+    /// Returns the session along with a new token to send, or `None` if the request was a retry
+    /// of one that has already been handled.
+    pub fn request(
+        connection: &PgConnection,
+        client_secret: &str,
+        address: &str,
+        user_id: &UserId,
+        send_attempt: i64,
+    ) -> Result<(Self, Option<String>), ApiError> {
+        let token = generate_opaque_token()?;
+
+        let existing = password_reset_sessions::table
+            .filter(password_reset_sessions::client_secret.eq(client_secret))
+            .filter(password_reset_sessions::address.eq(address))
+            .first::<Self>(connection);
+
+        match existing {
+            Ok(ref session) if session.send_attempt >= send_attempt => Ok((session.clone(), None)),
+            Ok(session) => {
+                let session = diesel::update(&session)
+                    .set((
+                        password_reset_sessions::token_hash.eq(hash_opaque_token(&token)),
+                        password_reset_sessions::send_attempt.eq(send_attempt),
+                        password_reset_sessions::updated_at.eq(now),
+                    ))
+                    .get_result(connection)
+                    .map_err(ApiError::from)?;
+
+                Ok((session, Some(token)))
+            }
+            Err(DieselError::NotFound) => {
+                let new_session = NewPasswordResetSession {
+                    sid: generate_opaque_token()?,
+                    client_secret: client_secret.to_string(),
+                    address: address.to_string(),
+                    user_id: user_id.clone(),
+                    token_hash: hash_opaque_token(&token),
+                    send_attempt,
+                };
+
+                let session = diesel::insert_into(password_reset_sessions::table)
+                    .values(&new_session)
+                    .get_result(connection)
+                    .map_err(ApiError::from)?;
+
+                Ok((session, Some(token)))
+            }
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// Delete all sessions for resetting the password of the user with the given `UserId`.
+    pub fn delete_by_uid(connection: &PgConnection, user_id: &UserId) -> Result<usize, ApiError> {
+        let rows =
+            password_reset_sessions::table.filter(password_reset_sessions::user_id.eq(user_id));
+
+        diesel::delete(rows)
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+}
@@ -142,6 +142,13 @@ impl Profile {
             .map_err(ApiError::from)
     }
 
+    /// Delete the `Profile` of the user with the given `UserId`, if any.
+    pub fn delete_by_uid(connection: &PgConnection, user_id: &UserId) -> Result<usize, ApiError> {
+        diesel::delete(profiles::table.find(user_id))
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+
     /// Return `Profile` for given `UserId`.
     pub fn find_by_uid(
         connection: &PgConnection,
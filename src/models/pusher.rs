@@ -79,7 +79,7 @@ fn default_append() -> bool {
 /// A matrix pusher.
 #[derive(AsChangeset, Clone, Debug, Identifiable, Insertable, Queryable)]
 #[table_name = "pushers"]
-#[primary_key(user_id, app_id)]
+#[primary_key(user_id, app_id, pushkey)]
 pub struct Pusher {
     /// The user's ID.
     pub user_id: UserId,
@@ -116,7 +116,8 @@ impl Pusher {
                     ));
                 }
                 if options.append {
-                    let maybe_pusher = Self::find(connection, user_id, &options.app_id)?;
+                    let maybe_pusher =
+                        Self::find(connection, user_id, &options.app_id, &options.pushkey)?;
 
                     if let Some(mut pusher) = maybe_pusher {
                         pusher.update(connection, options.clone())?;
@@ -135,14 +136,25 @@ impl Pusher {
             .map_err(ApiError::from)
     }
 
-    /// Delete a `Pusher`
+    /// Delete the user's `Pusher` for the given `app_id` and `pushkey`, or all of the user's
+    /// pushers for the `app_id` if no `pushkey` is given.
     pub fn delete(
         connection: &PgConnection,
         user_id: &UserId,
         app_id: &str,
+        pushkey: Option<&str>,
     ) -> Result<(), ApiError> {
-        let pusher = pushers::table.find((user_id, &app_id));
-        diesel::delete(pusher).execute(connection)?;
+        let pushers = pushers::table
+            .filter(pushers::user_id.eq(user_id))
+            .filter(pushers::app_id.eq(app_id));
+
+        match pushkey {
+            Some(pushkey) => {
+                diesel::delete(pushers.filter(pushers::pushkey.eq(pushkey))).execute(connection)?
+            }
+            None => diesel::delete(pushers).execute(connection)?,
+        };
+
         Ok(())
     }
 
@@ -194,9 +206,10 @@ impl Pusher {
         connection: &PgConnection,
         user_id: &UserId,
         app_id: &str,
+        pushkey: &str,
     ) -> Result<Option<Self>, ApiError> {
         let pusher = pushers::table
-            .find((user_id, app_id))
+            .find((user_id, app_id, pushkey))
             .get_result(connection);
 
         match pusher {
@@ -219,6 +232,15 @@ impl Pusher {
         Ok(())
     }
 
+    /// Delete all `Pusher`'s for given `UserId`.
+    pub fn delete_by_uid(connection: &PgConnection, user_id: &UserId) -> Result<usize, ApiError> {
+        let rows = pushers::table.filter(pushers::user_id.eq(user_id));
+
+        diesel::delete(rows)
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+
     /// Return all `Pusher`'s for given `UserId`.
     pub fn find_by_uid(connection: &PgConnection, user_id: &UserId) -> Result<Vec<Self>, ApiError> {
         pushers::table
@@ -0,0 +1,75 @@
+//! Receipts marking how far users have read in a room.
+
+use diesel::dsl::now;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::{EventId, RoomId, UserId};
+
+use crate::error::ApiError;
+use crate::schema::receipts;
+
+/// The latest receipt of a given type sent by a user in a room.
+#[derive(Debug, Clone, Identifiable, Queryable)]
+#[table_name = "receipts"]
+#[primary_key(room_id, user_id, receipt_type)]
+pub struct Receipt {
+    /// The ID of the room.
+    pub room_id: RoomId,
+    /// The ID of the user who sent the receipt.
+    pub user_id: UserId,
+    /// The type of the receipt, e.g. "m.read".
+    pub receipt_type: String,
+    /// The ID of the event the receipt is for.
+    pub event_id: EventId,
+    /// The time the receipt was last updated.
+    pub updated_at: PgTimestamp,
+}
+
+/// A new receipt, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "receipts"]
+pub struct NewReceipt {
+    /// The ID of the room.
+    pub room_id: RoomId,
+    /// The ID of the user who sent the receipt.
+    pub user_id: UserId,
+    /// The type of the receipt.
+    pub receipt_type: String,
+    /// The ID of the event the receipt is for.
+    pub event_id: EventId,
+}
+
+impl Receipt {
+    /// Store a receipt, replacing the user's previous receipt of the same type in the room.
+    pub fn upsert(connection: &PgConnection, new_receipt: &NewReceipt) -> Result<Self, ApiError> {
+        diesel::insert_into(receipts::table)
+            .values(new_receipt)
+            .on_conflict((receipts::room_id, receipts::user_id, receipts::receipt_type))
+            .do_update()
+            .set((
+                receipts::event_id.eq(&new_receipt.event_id),
+                receipts::updated_at.eq(now),
+            ))
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the user's latest receipt of the given type in a room, if any.
+    pub fn find(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        user_id: &UserId,
+        receipt_type: &str,
+    ) -> Result<Option<Self>, ApiError> {
+        match receipts::table
+            .find((room_id, user_id, receipt_type))
+            .first(connection)
+        {
+            Ok(receipt) => Ok(Some(receipt)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! Tokens that allow registering an account when registration requires one.
+
+use diesel::dsl::now;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+
+use crate::crypto::generate_opaque_token;
+use crate::error::ApiError;
+use crate::schema::registration_tokens;
+
+/// A token created by an administrator to let people register an account.
+#[derive(Debug, Clone, Identifiable, Queryable)]
+#[table_name = "registration_tokens"]
+#[primary_key(token)]
+pub struct RegistrationToken {
+    /// The token itself.
+    pub token: String,
+    /// The number of registrations the token can still be used for, or `None` for no limit.
+    pub uses_remaining: Option<i32>,
+    /// The time after which the token can no longer be used, if any.
+    pub expires_at: Option<PgTimestamp>,
+    /// The time the token was created.
+    pub created_at: PgTimestamp,
+}
+
+/// A new registration token, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "registration_tokens"]
+pub struct NewRegistrationToken {
+    /// The token itself.
+    pub token: String,
+    /// The number of registrations the token can be used for, or `None` for no limit.
+    pub uses_remaining: Option<i32>,
+    /// The time after which the token can no longer be used, if any.
+    pub expires_at: Option<PgTimestamp>,
+}
+
+impl RegistrationToken {
+    /// Create a new random token that can be used `uses_allowed` times until `expires_at`.
+    pub fn create(
+        connection: &PgConnection,
+        uses_allowed: Option<i32>,
+        expires_at: Option<PgTimestamp>,
+    ) -> Result<Self, ApiError> {
+        let new_token = NewRegistrationToken {
+            token: generate_opaque_token()?,
+            uses_remaining: uses_allowed,
+            expires_at,
+        };
+
+        diesel::insert_into(registration_tokens::table)
+            .values(&new_token)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return all registration tokens, oldest first.
+    pub fn list(connection: &PgConnection) -> Result<Vec<Self>, ApiError> {
+        registration_tokens::table
+            .order(registration_tokens::created_at.asc())
+            .then_order_by(registration_tokens::token.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Use the token for a registration, counting down its remaining uses.
+    ///
+    /// Fails with `M_INVALID_TOKEN` if the token doesn't exist, has expired or has been used up.
+    pub fn redeem(connection: &PgConnection, token: &str) -> Result<Self, ApiError> {
+        let redeemable = registration_tokens::table
+            .find(token)
+            .filter(
+                registration_tokens::expires_at
+                    .is_null()
+                    .or(registration_tokens::expires_at.gt(now)),
+            )
+            .filter(
+                registration_tokens::uses_remaining
+                    .is_null()
+                    .or(registration_tokens::uses_remaining.gt(0)),
+            );
+
+        diesel::update(redeemable)
+            .set(registration_tokens::uses_remaining.eq(registration_tokens::uses_remaining - 1))
+            .get_result(connection)
+            .optional()
+            .map_err(ApiError::from)?
+            .ok_or_else(|| ApiError::invalid_token(None))
+    }
+}
@@ -3,10 +3,12 @@
 use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 
+use diesel::dsl::sql;
 use diesel::pg::data_types::PgTimestamp;
-use diesel::pg::PgConnection;
+use diesel::pg::{Pg, PgConnection};
 use diesel::prelude::*;
 use diesel::result::Error as DieselError;
+use diesel::sql_types::{Bool, Text};
 use ruma_events::room::avatar::AvatarEvent;
 use ruma_events::room::canonical_alias::{CanonicalAliasEvent, CanonicalAliasEventContent};
 use ruma_events::room::create::{CreateEvent, CreateEventContent};
@@ -20,13 +22,37 @@ use ruma_events::room::topic::{TopicEvent, TopicEventContent};
 use ruma_events::stripped::StrippedState;
 use ruma_events::EventType;
 use ruma_identifiers::{EventId, RoomAliasId, RoomId, UserId};
+use serde_json::{from_str, Value};
 
 use crate::error::ApiError;
+use crate::models::escape_like_pattern;
 use crate::models::event::{Event, NewEvent};
 use crate::models::room_alias::{NewRoomAlias, RoomAlias};
 use crate::models::room_membership::RoomMembership;
 use crate::schema::{events, rooms};
 
+/// A condition on `rooms` that holds if the room's current name, topic or canonical alias matches
+/// an `ILIKE` pattern. The pattern must be bound after it, followed by a closing parenthesis.
+const PUBLIC_ROOM_SEARCH: &str = "EXISTS (
+    SELECT 1 FROM events
+    WHERE events.room_id = rooms.id
+    AND events.state_key = ''
+    AND events.event_type IN ('m.room.name', 'm.room.topic', 'm.room.canonical_alias')
+    AND events.ordering = (
+        SELECT max(latest.ordering) FROM events AS latest
+        WHERE latest.room_id = events.room_id
+        AND latest.event_type = events.event_type
+        AND latest.state_key = ''
+    )
+    AND CASE events.event_type
+        WHEN 'm.room.name' THEN events.content::json ->> 'name'
+        WHEN 'm.room.topic' THEN events.content::json ->> 'topic'
+        ELSE events.content::json ->> 'alias'
+    END ILIKE ";
+
+/// The room versions this homeserver is able to create rooms with.
+pub const SUPPORTED_ROOM_VERSIONS: &[&str] = &["1", "2"];
+
 /// Options provided by the user to customize the room upon creation.
 #[derive(Clone, Debug)]
 pub struct CreationOptions {
@@ -56,6 +82,8 @@ pub struct NewRoom {
     pub user_id: UserId,
     /// Whether or not the room is visible in the directory.
     pub public: bool,
+    /// The version of the room.
+    pub version: String,
 }
 
 /// A Matrix room.
@@ -69,6 +97,8 @@ pub struct Room {
     pub public: bool,
     /// The time the room was created.
     pub created_at: PgTimestamp,
+    /// The version of the room.
+    pub version: String,
 }
 
 /// A convenience parameter for setting a few default state events.
@@ -118,7 +148,7 @@ impl Room {
 
             let mut new_events = Vec::new();
 
-            let new_create_event: NewEvent = CreateEvent {
+            let mut new_create_event: NewEvent = CreateEvent {
                 content: CreateEventContent {
                     creator: new_room.user_id.clone(),
                     federate: creation_options.federate,
@@ -133,6 +163,11 @@ impl Room {
                 unsigned: None,
             }.try_into()?;
 
+            // ruma-events does not know about room versions yet, so add it to the content by hand.
+            let mut create_content: Value = from_str(&new_create_event.content).map_err(ApiError::from)?;
+            create_content["room_version"] = Value::String(new_room.version.clone());
+            new_create_event.content = create_content.to_string();
+
             new_events.push(new_create_event);
 
             let mut is_canonical_alias_set = false;
@@ -427,10 +462,14 @@ impl Room {
                     }
                 }
 
+                // Only the creator can change the power levels until they grant others the right.
+                let mut events = HashMap::new();
+                events.insert(EventType::RoomPowerLevels, 100);
+
                 let new_power_levels_event: NewEvent = PowerLevelsEvent {
                     content: PowerLevelsEventContent {
                         ban: 50,
-                        events: HashMap::new(),
+                        events,
                         events_default: 0,
                         invite: 50,
                         kick: 50,
@@ -536,6 +575,52 @@ impl Room {
         }
     }
 
+    /// Return the IDs of up to `limit` rooms that are visible in the room directory, ordered by
+    /// room ID and starting at `offset`, along with the number of such rooms.
+    ///
+    /// If `search_term` is given, only rooms whose current name, topic or canonical alias
+    /// contains it, ignoring case, are included.
+    pub fn find_public_page(
+        connection: &PgConnection,
+        search_term: Option<&str>,
+        offset: i64,
+        limit: Option<i64>,
+    ) -> Result<(Vec<RoomId>, i64), ApiError> {
+        let pattern =
+            search_term.map(|search_term| format!("%{}%", escape_like_pattern(search_term)));
+
+        let public_rooms = || -> rooms::BoxedQuery<'_, Pg> {
+            let query = rooms::table.filter(rooms::public.eq(true)).into_boxed();
+
+            match pattern {
+                Some(ref pattern) => query.filter(
+                    sql::<Bool>(PUBLIC_ROOM_SEARCH)
+                        .bind::<Text, _>(pattern.clone())
+                        .sql(")"),
+                ),
+                None => query,
+            }
+        };
+
+        let total: i64 = public_rooms()
+            .count()
+            .get_result(connection)
+            .map_err(ApiError::from)?;
+
+        let mut page = public_rooms()
+            .select(rooms::id)
+            .order(rooms::id.asc())
+            .offset(offset);
+
+        if let Some(limit) = limit {
+            page = page.limit(limit);
+        }
+
+        let room_ids = page.get_results(connection).map_err(ApiError::from)?;
+
+        Ok((room_ids, total))
+    }
+
     /// Look up a `Room` given the `RoomId`.
     pub fn find(connection: &PgConnection, room_id: &RoomId) -> Result<Option<Self>, ApiError> {
         let result = rooms::table.find(room_id).get_result(connection);
@@ -1,13 +1,15 @@
 //! Matrix room membership.
 
+use std::collections::BTreeSet;
 use std::convert::TryInto;
 use std::error::Error;
 
-use diesel::expression::dsl::any;
+use diesel::expression::dsl::{any, sql};
 use diesel::pg::data_types::PgTimestamp;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::result::Error as DieselError;
+use diesel::sql_types::BigInt;
 use ruma_events::room::join_rules::JoinRule;
 use ruma_events::room::member::{MemberEvent, MemberEventContent, MembershipState};
 use ruma_events::EventType;
@@ -67,6 +69,8 @@ pub struct RoomMembership {
     pub membership: String,
     /// The time the room was created.
     pub created_at: PgTimestamp,
+    /// Whether or not the user has forgotten the room after leaving it.
+    pub forgotten: bool,
 }
 
 impl RoomMembership {
@@ -210,6 +214,60 @@ impl RoomMembership {
         }
     }
 
+    /// Return the number of users who have joined the given room.
+    pub fn count_joined(connection: &PgConnection, room_id: &RoomId) -> Result<i64, ApiError> {
+        room_memberships::table
+            .filter(room_memberships::room_id.eq(room_id))
+            .filter(room_memberships::membership.eq("join"))
+            .count()
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the number of users who have joined each of the given rooms, leaving out rooms
+    /// nobody has joined.
+    pub fn count_joined_by_room(
+        connection: &PgConnection,
+        room_ids: &[RoomId],
+    ) -> Result<Vec<(RoomId, i64)>, ApiError> {
+        room_memberships::table
+            .filter(room_memberships::room_id.eq(any(room_ids)))
+            .filter(room_memberships::membership.eq("join"))
+            .group_by(room_memberships::room_id)
+            .select((room_memberships::room_id, sql::<BigInt>("count(*)")))
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the IDs of the users who have joined the given room.
+    pub fn find_joined_user_ids(
+        connection: &PgConnection,
+        room_id: &RoomId,
+    ) -> Result<Vec<UserId>, ApiError> {
+        room_memberships::table
+            .filter(room_memberships::room_id.eq(room_id))
+            .filter(room_memberships::membership.eq("join"))
+            .select(room_memberships::user_id)
+            .load(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the names of the servers with users who have joined the given room, in sorted
+    /// order.
+    pub fn find_joined_servers(
+        connection: &PgConnection,
+        room_id: &RoomId,
+    ) -> Result<Vec<String>, ApiError> {
+        let user_ids = Self::find_joined_user_ids(connection, room_id)?;
+
+        let servers: BTreeSet<String> = user_ids
+            .iter()
+            .map(|user_id| user_id.hostname().to_string())
+            .collect();
+
+        Ok(servers.into_iter().collect())
+    }
+
     /// Return `RoomMembership`'s for given `UserId`.
     pub fn find_by_uid(connection: &PgConnection, user_id: UserId) -> Result<Vec<Self>, ApiError> {
         let room_memberships: Vec<Self> = room_memberships::table
@@ -252,6 +310,7 @@ impl RoomMembership {
 
         self.membership = options.membership.clone();
         self.sender = options.sender.clone();
+        self.forgotten = false;
 
         connection
             .transaction::<Self, ApiError, _>(|| {
@@ -272,6 +331,16 @@ impl RoomMembership {
             .map_err(ApiError::from)
     }
 
+    /// Hide the room from the user's history. Only rooms the user isn't in can be forgotten.
+    pub fn forget(&mut self, connection: &PgConnection) -> Result<(), ApiError> {
+        self.forgotten = true;
+
+        self.save_changes::<Self>(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
     /// Create a new `MemberEvent`.
     pub fn create_new_room_member_event(
         homeserver_domain: &str,
@@ -302,8 +371,8 @@ impl RoomMembership {
             origin_server_ts: 0,
             prev_content: None,
             room_id: Some(options.room_id.clone()),
-            sender: options.user_id.clone(),
-            state_key: format!("@{}:{}", options.user_id.clone(), &homeserver_domain),
+            sender: options.sender.clone(),
+            state_key: options.user_id.to_string(),
             unsigned: None,
         }
         .try_into()?;
@@ -353,13 +422,29 @@ impl RoomMembership {
     }
 
     /// Return member events for a given `RoomId`.
+    ///
+    /// If `membership` is given, only members with that membership are included. Members with
+    /// the `not_membership` membership are left out.
     pub fn get_events_by_room(
         connection: &PgConnection,
         room_id: RoomId,
+        membership: Option<&str>,
+        not_membership: Option<&str>,
     ) -> Result<Vec<MemberEvent>, ApiError> {
-        let event_ids = room_memberships::table
+        let mut query = room_memberships::table
             .filter(room_memberships::room_id.eq(room_id))
-            .select(room_memberships::event_id);
+            .select(room_memberships::event_id)
+            .into_boxed();
+
+        if let Some(membership) = membership {
+            query = query.filter(room_memberships::membership.eq(membership));
+        }
+
+        if let Some(not_membership) = not_membership {
+            query = query.filter(room_memberships::membership.ne(not_membership));
+        }
+
+        let event_ids: Vec<EventId> = query.get_results(connection).map_err(ApiError::from)?;
 
         let events: Vec<Event> = events::table
             .filter(events::id.eq(any(event_ids)))
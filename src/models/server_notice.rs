@@ -0,0 +1,60 @@
+//! Notices from the server's administrators to all of its users.
+
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::UserId;
+
+use crate::error::ApiError;
+use crate::schema::server_notices;
+
+/// A notice set by a server administrator.
+#[derive(Debug, Clone, Identifiable, Queryable)]
+#[table_name = "server_notices"]
+pub struct ServerNotice {
+    /// The notice's ID, increasing with each new notice.
+    pub id: i64,
+    /// The text of the notice.
+    pub body: String,
+    /// The administrator who set the notice.
+    pub sender: UserId,
+    /// The time the notice was set.
+    pub created_at: PgTimestamp,
+}
+
+/// A new server notice, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "server_notices"]
+pub struct NewServerNotice {
+    /// The text of the notice.
+    pub body: String,
+    /// The administrator setting the notice.
+    pub sender: UserId,
+}
+
+impl ServerNotice {
+    /// Store a new notice, which replaces the previous one as the current notice.
+    pub fn create(
+        connection: &PgConnection,
+        new_server_notice: &NewServerNotice,
+    ) -> Result<Self, ApiError> {
+        diesel::insert_into(server_notices::table)
+            .values(new_server_notice)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the most recently set notice, if any.
+    pub fn find_current(connection: &PgConnection) -> Result<Option<Self>, ApiError> {
+        let server_notice = server_notices::table
+            .order(server_notices::id.desc())
+            .first(connection);
+
+        match server_notice {
+            Ok(server_notice) => Ok(Some(server_notice)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Third party identifiers, e.g. email addresses, bound to users.
+
+use diesel::dsl::now;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::UserId;
+
+use crate::email::{is_valid_email, normalize_email};
+use crate::error::ApiError;
+use crate::schema::three_pids;
+
+/// A third party identifier bound to a user.
+#[derive(Debug, Clone, Identifiable, Queryable)]
+#[table_name = "three_pids"]
+#[primary_key(medium, address)]
+pub struct ThreePid {
+    /// The medium of the identifier, e.g. "email".
+    pub medium: String,
+    /// The identifier itself, e.g. an email address.
+    pub address: String,
+    /// The ID of the user the identifier is bound to.
+    pub user_id: UserId,
+    /// The time the identifier was bound to the user.
+    pub created_at: PgTimestamp,
+    /// The time the user proved they own the identifier, or `None` while that is pending.
+    pub validated_at: Option<PgTimestamp>,
+}
+
+/// A new third party identifier, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "three_pids"]
+pub struct NewThreePid {
+    /// The medium of the identifier.
+    pub medium: String,
+    /// The identifier itself.
+    pub address: String,
+    /// The ID of the user the identifier is bound to.
+    pub user_id: UserId,
+}
+
+impl ThreePid {
+    /// Bind a third party identifier to the given user, who has proven they own it.
+    pub fn create(
+        connection: &PgConnection,
+        user_id: &UserId,
+        medium: &str,
+        address: &str,
+    ) -> Result<Self, ApiError> {
+        connection
+            .transaction::<Self, ApiError, _>(|| {
+                let three_pid = Self::create_pending(connection, user_id, medium, address)?;
+
+                diesel::update(three_pids::table.find((&three_pid.medium, &three_pid.address)))
+                    .set(three_pids::validated_at.eq(now))
+                    .get_result(connection)
+                    .map_err(ApiError::from)
+            })
+            .map_err(ApiError::from)
+    }
+
+    /// Bind a third party identifier to the given user, pending proof that they own it.
+    ///
+    /// Pending identifiers are listed with the user's identifiers, but can't be used to log in
+    /// or to reset the user's password.
+    pub fn create_pending(
+        connection: &PgConnection,
+        user_id: &UserId,
+        medium: &str,
+        address: &str,
+    ) -> Result<Self, ApiError> {
+        if medium == "email" && !is_valid_email(address) {
+            return Err(ApiError::invalid_param(
+                "address",
+                "Not a valid email address",
+            ));
+        }
+
+        let new_three_pid = NewThreePid {
+            medium: medium.to_string(),
+            address: normalize_address(medium, address),
+            user_id: user_id.clone(),
+        };
+
+        diesel::insert_into(three_pids::table)
+            .values(&new_three_pid)
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Unbind the given third party identifier from the user, if it is bound to them.
+    pub fn delete(
+        connection: &PgConnection,
+        user_id: &UserId,
+        medium: &str,
+        address: &str,
+    ) -> Result<usize, ApiError> {
+        let row = three_pids::table
+            .find((medium, normalize_address(medium, address)))
+            .filter(three_pids::user_id.eq(user_id));
+
+        diesel::delete(row)
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Unbind all third party identifiers of the user with the given `UserId`.
+    pub fn delete_by_uid(connection: &PgConnection, user_id: &UserId) -> Result<usize, ApiError> {
+        let rows = three_pids::table.filter(three_pids::user_id.eq(user_id));
+
+        diesel::delete(rows)
+            .execute(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return all third party identifiers bound to the user, pending or not, oldest first.
+    pub fn find_by_uid(connection: &PgConnection, user_id: &UserId) -> Result<Vec<Self>, ApiError> {
+        three_pids::table
+            .filter(three_pids::user_id.eq(user_id))
+            .order((
+                three_pids::created_at,
+                three_pids::medium,
+                three_pids::address,
+            ))
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the ID of the user the given third party identifier is bound to and validated for,
+    /// if any.
+    pub fn find_user_id(
+        connection: &PgConnection,
+        medium: &str,
+        address: &str,
+    ) -> Result<Option<UserId>, ApiError> {
+        let user_id = three_pids::table
+            .find((medium, normalize_address(medium, address)))
+            .filter(three_pids::validated_at.is_not_null())
+            .select(three_pids::user_id)
+            .first(connection);
+
+        match user_id {
+            Ok(user_id) => Ok(Some(user_id)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+}
+
+/// Email addresses are stored and compared in their normalized form.
+fn normalize_address(medium: &str, address: &str) -> String {
+    if medium == "email" {
+        normalize_email(address)
+    } else {
+        address.to_string()
+    }
+}
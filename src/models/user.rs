@@ -1,19 +1,27 @@
 //! Matrix users.
 
 use std::collections::HashSet;
+use std::convert::TryFrom;
 
 use diesel::dsl::any;
 use diesel::pg::data_types::PgTimestamp;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use diesel::result::Error as DieselError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use iron::typemap::Key;
 use ruma_identifiers::UserId;
 
-use crate::crypto::verify_password;
+use crate::config::{Config, InitialAdmin};
+use crate::crypto::{hash_password, verify_password};
 use crate::error::ApiError;
 use crate::models::access_token::AccessToken;
-use crate::schema::users;
+use crate::models::escape_like_pattern;
+use crate::models::profile::Profile;
+use crate::models::room_membership::RoomMembership;
+use crate::schema::{
+    access_tokens, account_data, filters, presence_list, presence_status, profiles, pushers,
+    room_account_data, room_aliases, room_memberships, room_tags, users,
+};
 
 /// A Matrix user.
 #[derive(AsChangeset, Debug, Clone, Identifiable, Queryable)]
@@ -29,6 +37,8 @@ pub struct User {
     pub created_at: PgTimestamp,
     /// The time the user was last modified.
     pub updated_at: PgTimestamp,
+    /// Whether or not the user is a server administrator.
+    pub admin: bool,
 }
 
 /// A new Matrix user, not yet saved.
@@ -42,38 +52,128 @@ pub struct NewUser {
 }
 
 impl User {
-    /// Creates a new user in the database.
+    /// Creates a new user in the database, along with an access token for the given device.
+    ///
+    /// Fails with `M_USER_IN_USE` if the user ID is already taken.
     pub fn create(
         connection: &PgConnection,
         new_user: &NewUser,
-        macaroon_secret_key: &[u8],
+        device_id: &str,
+        config: &Config,
     ) -> Result<(Self, AccessToken), ApiError> {
         connection
             .transaction::<(Self, AccessToken), ApiError, _>(|| {
                 let user: Self = diesel::insert_into(users::table)
                     .values(new_user)
                     .get_result(connection)
-                    .map_err(ApiError::from)?;
+                    .map_err(|error| match error {
+                        DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                            ApiError::user_in_use(None)
+                        }
+                        error => ApiError::from(error),
+                    })?;
 
-                let access_token = AccessToken::create(connection, &user.id, macaroon_secret_key)?;
+                let access_token = AccessToken::create(connection, &user.id, device_id, config)?;
 
                 Ok((user, access_token))
             })
             .map_err(ApiError::from)
     }
 
-    /// Verify that a `User` with the given `UserId` and plaintext password exists.
+    /// Creates users without access tokens, e.g. when migrating from another homeserver.
+    ///
+    /// All users are inserted in a single transaction, each behind its own savepoint, so a user
+    /// that can't be inserted (e.g. because the user ID is taken) is reported without preventing
+    /// the others from being inserted.
+    pub fn import(
+        connection: &PgConnection,
+        new_users: &[NewUser],
+    ) -> Result<Vec<Result<Self, ApiError>>, ApiError> {
+        connection
+            .transaction::<Vec<Result<Self, ApiError>>, ApiError, _>(|| {
+                let results = new_users
+                    .iter()
+                    .map(|new_user| {
+                        connection.transaction::<Self, ApiError, _>(|| {
+                            let user: Self = diesel::insert_into(users::table)
+                                .values(new_user)
+                                .get_result(connection)
+                                .map_err(|error| match error {
+                                    DieselError::DatabaseError(
+                                        DatabaseErrorKind::UniqueViolation,
+                                        _,
+                                    ) => ApiError::user_in_use(None),
+                                    error => ApiError::from(error),
+                                })?;
+
+                            let new_profile = Profile {
+                                id: user.id.clone(),
+                                avatar_url: None,
+                                displayname: None,
+                            };
+
+                            Profile::create(connection, &new_profile)?;
+
+                            Ok(user)
+                        })
+                    })
+                    .collect();
+
+                Ok(results)
+            })
+            .map_err(ApiError::from)
+    }
+
+    /// Creates the configured initial server administrator, unless a user with its username
+    /// already exists.
+    ///
+    /// Returns the new administrator, or `None` if the user already existed, in which case it is
+    /// left unchanged.
+    pub fn seed_admin(
+        connection: &PgConnection,
+        initial_admin: &InitialAdmin,
+        config: &Config,
+    ) -> Result<Option<Self>, ApiError> {
+        let user_id = Self::local_id(&initial_admin.username, "username", config)?;
+
+        if Self::find_registered_user(connection, &user_id)?.is_some() {
+            return Ok(None);
+        }
+
+        let new_user = NewUser {
+            id: user_id,
+            password_hash: hash_password(&initial_admin.password)?,
+        };
+
+        let user = Self::import(connection, &[new_user])?
+            .pop()
+            .expect("one result should be returned for each imported user")?;
+
+        diesel::update(&user)
+            .set(users::admin.eq(true))
+            .get_result(connection)
+            .map(Some)
+            .map_err(ApiError::from)
+    }
+
+    /// Verify that an active `User` with the given `UserId` and plaintext password exists.
+    ///
+    /// A deactivated user with the right password gets a `M_USER_DEACTIVATED` error.
     pub fn verify(
         connection: &PgConnection,
         id: &UserId,
         plaintext_password: &str,
     ) -> Result<Self, ApiError> {
-        match Self::find_active_user(connection, id)? {
+        match Self::find_registered_user(connection, id)? {
             Some(user) => {
                 if !verify_password(user.password_hash.as_bytes(), plaintext_password)? {
                     return Err(ApiError::unauthorized("Invalid credentials".to_string()));
                 }
 
+                if !user.active {
+                    return Err(ApiError::user_deactivated(None));
+                }
+
                 Ok(user)
             }
             None => Err(ApiError::not_found(format!(
@@ -83,6 +183,41 @@ impl User {
         }
     }
 
+    /// Build the ID of the user of this homeserver with the given localpart, e.g. a username given
+    /// when registering or logging in. `param_name` is the request parameter it came from.
+    ///
+    /// `UserId` lowercases localparts, so a localpart with uppercase letters refers to the same
+    /// user as its lowercase form.
+    pub fn local_id(
+        localpart: &str,
+        param_name: &str,
+        config: &Config,
+    ) -> Result<UserId, ApiError> {
+        let user_id = format!("@{}:{}", localpart, config.domain);
+
+        Self::ensure_id_length(&user_id, config)?;
+
+        UserId::try_from(user_id.as_ref())
+            .map_err(|_| ApiError::invalid_param(param_name, "Not a valid user ID localpart"))
+    }
+
+    /// Check that a user ID for this homeserver is no longer than the configured maximum,
+    /// counting the `@`, the localpart, the `:` and the domain.
+    ///
+    /// This is checked before parsing the ID, which would fail for IDs over 255 bytes.
+    pub fn ensure_id_length(id: &str, config: &Config) -> Result<(), ApiError> {
+        let length = id.len();
+
+        if length > config.max_user_id_length {
+            return Err(ApiError::invalid_username(format!(
+                "User IDs may be at most {} bytes long, this one would be {}.",
+                config.max_user_id_length, length
+            )));
+        }
+
+        Ok(())
+    }
+
     /// Look up a registered `User` using the given `UserId`.
     pub fn find_registered_user(
         connection: &PgConnection,
@@ -120,6 +255,180 @@ impl User {
         }
     }
 
+    /// Fail if the user still has a place in room state that a new user with the same ID would
+    /// inherit: a membership other than "leave", or a room alias they created.
+    pub fn ensure_reusable(connection: &PgConnection, id: &UserId) -> Result<(), ApiError> {
+        let memberships: i64 = room_memberships::table
+            .filter(room_memberships::user_id.eq(id))
+            .filter(room_memberships::membership.ne("leave"))
+            .count()
+            .get_result(connection)
+            .map_err(ApiError::from)?;
+
+        let aliases: i64 = room_aliases::table
+            .filter(room_aliases::user_id.eq(id))
+            .count()
+            .get_result(connection)
+            .map_err(ApiError::from)?;
+
+        if memberships > 0 || aliases > 0 {
+            return Err(ApiError::user_in_use(
+                "This user ID is still a member of rooms or owns room aliases.".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Delete a deactivated user along with everything stored for them, so that the user ID can
+    /// be registered again without the new user inheriting any of it.
+    ///
+    /// Tables with a foreign key on the user, such as 3PIDs, one-time keys and receipts, are
+    /// cleared by `ON DELETE CASCADE`. Events the user sent are kept, since they are part of the
+    /// history of rooms shared with other users.
+    pub fn delete_deactivated(connection: &PgConnection, id: &UserId) -> Result<(), ApiError> {
+        connection
+            .transaction::<(), ApiError, _>(|| {
+                Self::ensure_reusable(connection, id)?;
+
+                let deleted = diesel::delete(users::table.find(id).filter(users::active.eq(false)))
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                if deleted == 0 {
+                    return Err(ApiError::user_in_use(None));
+                }
+
+                diesel::delete(access_tokens::table.filter(access_tokens::user_id.eq(id)))
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                diesel::delete(profiles::table.find(id))
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                diesel::delete(room_memberships::table.filter(room_memberships::user_id.eq(id)))
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                diesel::delete(account_data::table.filter(account_data::user_id.eq(id)))
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                diesel::delete(room_account_data::table.filter(room_account_data::user_id.eq(id)))
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                diesel::delete(room_tags::table.filter(room_tags::user_id.eq(id)))
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                diesel::delete(filters::table.filter(filters::user_id.eq(id)))
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                diesel::delete(pushers::table.filter(pushers::user_id.eq(id)))
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                diesel::delete(presence_status::table.find(id))
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                diesel::delete(
+                    presence_list::table.filter(
+                        presence_list::user_id
+                            .eq(id)
+                            .or(presence_list::observed_user_id.eq(id)),
+                    ),
+                )
+                .execute(connection)
+                .map_err(ApiError::from)?;
+
+                Ok(())
+            })
+            .map_err(ApiError::from)
+    }
+
+    /// Return users on the given server whose localpart contains `search_term`, ignoring case,
+    /// ordered by user ID.
+    pub fn search(
+        connection: &PgConnection,
+        domain: &str,
+        search_term: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<Self>, ApiError> {
+        let pattern = format!(
+            "@%{}%:{}",
+            escape_like_pattern(search_term),
+            escape_like_pattern(domain)
+        );
+
+        users::table
+            .filter(users::id.ilike(pattern))
+            .order(users::id.asc())
+            .offset(offset)
+            .limit(limit)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return active users on the given server whose localpart or display name contains
+    /// `search_term`, ignoring case, ordered by user ID.
+    ///
+    /// If `shared_with` is given, only users who have joined a room that user has joined are
+    /// returned.
+    pub fn search_directory(
+        connection: &PgConnection,
+        domain: &str,
+        search_term: &str,
+        shared_with: Option<&UserId>,
+        limit: i64,
+    ) -> Result<Vec<Self>, ApiError> {
+        let search_term = escape_like_pattern(search_term);
+
+        let displayname_matches: Vec<UserId> = profiles::table
+            .filter(profiles::displayname.ilike(format!("%{}%", search_term)))
+            .select(profiles::id)
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        let mut query = users::table
+            .filter(users::active.eq(true))
+            .filter(
+                users::id
+                    .ilike(format!(
+                        "@%{}%:{}",
+                        search_term,
+                        escape_like_pattern(domain)
+                    ))
+                    .or(users::id.eq(any(displayname_matches))),
+            )
+            .into_boxed();
+
+        if let Some(user_id) = shared_with {
+            let room_ids =
+                RoomMembership::find_room_ids_by_uid_and_state(connection, user_id, "join")?;
+
+            let members: Vec<UserId> = room_memberships::table
+                .filter(room_memberships::room_id.eq(any(room_ids)))
+                .filter(room_memberships::membership.eq("join"))
+                .select(room_memberships::user_id)
+                .distinct()
+                .get_results(connection)
+                .map_err(ApiError::from)?;
+
+            query = query.filter(users::id.eq(any(members)));
+        }
+
+        query
+            .order(users::id.asc())
+            .limit(limit)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
     /// Return `UserId`s for given `user_ids` base on the existence of a single user.
     pub fn find_missing_users(
         connection: &PgConnection,
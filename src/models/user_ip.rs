@@ -0,0 +1,85 @@
+//! Client connections seen for each access token.
+
+use diesel::dsl::now;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use ruma_identifiers::UserId;
+
+use crate::error::ApiError;
+use crate::models::access_token::AccessToken;
+use crate::schema::user_ips;
+
+/// A client connection made with an access token.
+#[derive(Debug, Clone, Identifiable, Queryable)]
+#[table_name = "user_ips"]
+#[primary_key(access_token_id, ip, user_agent)]
+pub struct UserIp {
+    /// The ID of the access token used by the connection.
+    pub access_token_id: i64,
+    /// The ID of the user who owns the access token.
+    pub user_id: UserId,
+    /// The IP address the connection came from.
+    pub ip: String,
+    /// The user agent reported by the client, or an empty string if none was sent.
+    pub user_agent: String,
+    /// The time of the most recent request made over this connection.
+    pub last_seen: PgTimestamp,
+}
+
+/// A new client connection, not yet saved.
+#[derive(Debug, Insertable)]
+#[table_name = "user_ips"]
+pub struct NewUserIp {
+    /// The ID of the access token used by the connection.
+    pub access_token_id: i64,
+    /// The ID of the user who owns the access token.
+    pub user_id: UserId,
+    /// The IP address the connection came from.
+    pub ip: String,
+    /// The user agent reported by the client.
+    pub user_agent: String,
+}
+
+impl UserIp {
+    /// Record a request made with the given access token, refreshing the last seen time of an
+    /// already known connection.
+    ///
+    /// This writes to the database, so `AccessTokenAuth` only calls it when `UserIpThrottle`
+    /// allows it.
+    pub fn record(
+        connection: &PgConnection,
+        access_token: &AccessToken,
+        ip: String,
+        user_agent: String,
+    ) -> Result<(), ApiError> {
+        let new_user_ip = NewUserIp {
+            access_token_id: access_token.id,
+            user_id: access_token.user_id.clone(),
+            ip,
+            user_agent,
+        };
+
+        diesel::insert_into(user_ips::table)
+            .values(&new_user_ip)
+            .on_conflict((
+                user_ips::access_token_id,
+                user_ips::ip,
+                user_ips::user_agent,
+            ))
+            .do_update()
+            .set(user_ips::last_seen.eq(now))
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
+
+    /// Return all connections made by the given user, most recent first.
+    pub fn find_by_uid(connection: &PgConnection, user_id: &UserId) -> Result<Vec<Self>, ApiError> {
+        user_ips::table
+            .filter(user_ips::user_id.eq(user_id))
+            .order(user_ips::last_seen.desc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+}
@@ -195,12 +195,12 @@ impl Sync {
             }
         }
 
-        let filter_room = match options.filter {
-            Some(filter) => filter.room,
-            None => None,
+        let (filter_room, filter_presence) = match options.filter {
+            Some(filter) => (filter.room, filter.presence),
+            None => (None, None),
         };
 
-        let (presence_key, presence) = Self::get_presence_events(
+        let (presence_key, mut presence) = Self::get_presence_events(
             connection,
             homeserver_domain,
             user,
@@ -208,6 +208,14 @@ impl Sync {
             &context,
         )?;
 
+        if let Some(filter) = filter_presence {
+            presence.retain(|event| filter.allows("m.presence", &event.sender));
+
+            if filter.limit > 0 {
+                presence.truncate(filter.limit);
+            }
+        }
+
         let (room_key, rooms) = Self::get_rooms_events(connection, user, filter_room, &context)?;
         let batch = Batch::new(room_key, presence_key);
         let state = Self {
@@ -343,7 +351,7 @@ impl Sync {
                     );
                 }
                 "leave" | "ban" => {
-                    if !include_leave {
+                    if !include_leave || room_membership.forgotten {
                         continue;
                     }
 
@@ -427,6 +435,11 @@ impl Sync {
         for event in events.into_iter().skip(count) {
             room_ordering = cmp::max(room_ordering, event.ordering);
 
+            if event.redacted_by.is_some() {
+                timeline_events.push(event.into_redacted_room_event()?);
+                continue;
+            }
+
             let value = match EventType::from(event.event_type.as_ref()) {
                 EventType::CallAnswer => RoomEvent::CallAnswer(event.try_into()?),
                 EventType::CallCandidates => RoomEvent::CallCandidates(event.try_into()?),
@@ -445,6 +458,7 @@ impl Sync {
                 EventType::RoomMessage => RoomEvent::RoomMessage(event.try_into()?),
                 EventType::RoomName => RoomEvent::RoomName(event.try_into()?),
                 EventType::RoomPowerLevels => RoomEvent::RoomPowerLevels(event.try_into()?),
+                EventType::RoomRedaction => RoomEvent::RoomRedaction(event.try_into()?),
                 EventType::RoomThirdPartyInvite => {
                     RoomEvent::RoomThirdPartyInvite(event.try_into()?)
                 }
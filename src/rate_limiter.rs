@@ -0,0 +1,153 @@
+//! Limits on how often clients can make requests to expensive endpoints.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use iron::typemap::Key;
+use iron::{Plugin, Request};
+use persistent::Read as PersistentRead;
+
+use crate::config::RateLimit;
+use crate::error::ApiError;
+
+/// The number of clients to track before forgetting those whose periods have ended.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// The endpoints with a rate limit of their own.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RateLimitedAction {
+    /// Logging in.
+    Login,
+    /// Registering an account.
+    Registration,
+}
+
+/// Counts the requests clients have made in the current period of each rate limit.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimiter {
+    /// The current period of each client for each action.
+    periods: Arc<Mutex<HashMap<(RateLimitedAction, IpAddr), Period>>>,
+}
+
+/// The requests a client has made to an endpoint in the current period.
+#[derive(Clone, Copy, Debug)]
+struct Period {
+    /// When the period ends and the client can make requests again.
+    ends_at: Instant,
+    /// The number of requests made in the period.
+    requests: u32,
+}
+
+impl RateLimiter {
+    /// Counts a request to perform the action, failing with `M_LIMIT_EXCEEDED` if the client has
+    /// already made as many requests as the limit allows in the current period.
+    pub fn check(
+        &self,
+        action: RateLimitedAction,
+        ip: IpAddr,
+        limit: &RateLimit,
+    ) -> Result<(), ApiError> {
+        let now = Instant::now();
+        let mut periods = self.periods.lock()?;
+
+        if periods.len() >= MAX_TRACKED_CLIENTS {
+            periods.retain(|_, period| period.ends_at > now);
+        }
+
+        let period = periods.entry((action, ip)).or_insert(Period {
+            ends_at: now,
+            requests: 0,
+        });
+
+        if period.ends_at <= now {
+            *period = Period {
+                ends_at: now + Duration::from_millis(limit.period_ms),
+                requests: 0,
+            };
+        }
+
+        if period.requests >= limit.requests {
+            return Err(
+                ApiError::limited_rate("Too many requests, try again later".to_string())
+                    .with_retry_after(period.ends_at - now),
+            );
+        }
+
+        period.requests += 1;
+
+        Ok(())
+    }
+
+    /// Extract the `RateLimiter` stored in the request.
+    pub fn from_request(request: &mut Request<'_, '_>) -> Result<Arc<Self>, ApiError> {
+        request
+            .get::<PersistentRead<Self>>()
+            .map_err(ApiError::from)
+    }
+}
+
+impl Key for RateLimiter {
+    type Value = Self;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use super::{RateLimitedAction, RateLimiter};
+    use crate::config::RateLimit;
+
+    const LIMIT: RateLimit = RateLimit {
+        requests: 2,
+        period_ms: 100,
+    };
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    #[test]
+    fn limit_is_per_client_and_action() {
+        let rate_limiter = RateLimiter::default();
+
+        for _ in 0..2 {
+            rate_limiter
+                .check(RateLimitedAction::Registration, ip(1), &LIMIT)
+                .unwrap();
+        }
+
+        assert!(rate_limiter
+            .check(RateLimitedAction::Registration, ip(1), &LIMIT)
+            .is_err());
+        assert!(rate_limiter
+            .check(RateLimitedAction::Registration, ip(2), &LIMIT)
+            .is_ok());
+        assert!(rate_limiter
+            .check(RateLimitedAction::Login, ip(1), &LIMIT)
+            .is_ok());
+    }
+
+    #[test]
+    fn limit_resets_after_the_period() {
+        let rate_limiter = RateLimiter::default();
+
+        for _ in 0..2 {
+            rate_limiter
+                .check(RateLimitedAction::Login, ip(1), &LIMIT)
+                .unwrap();
+        }
+        assert!(rate_limiter
+            .check(RateLimitedAction::Login, ip(1), &LIMIT)
+            .is_err());
+
+        sleep(Duration::from_millis(LIMIT.period_ms));
+
+        assert!(rate_limiter
+            .check(RateLimitedAction::Login, ip(1), &LIMIT)
+            .is_ok());
+    }
+}
@@ -0,0 +1,157 @@
+//! Retrying outbound requests to other servers.
+
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::error::ApiError;
+
+/// The longest time to wait between two attempts, however many attempts have been made.
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The longest time to wait in total between the attempts of one request.
+///
+/// Requests to other servers are made while handling a client's request, which holds a worker
+/// thread and the client's connection until the retries are over.
+const MAX_TOTAL_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Why a request to another server failed.
+#[derive(Clone, Debug)]
+pub enum FetchError {
+    /// The request may succeed if it is made again, e.g. after a connection failure or a 5xx
+    /// response.
+    Transient(ApiError),
+    /// Making the request again would not help, e.g. after a 4xx response.
+    Permanent(ApiError),
+}
+
+/// How often to attempt a request and how long to wait between attempts.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// The number of attempts to make before giving up, at least one.
+    max_attempts: u32,
+    /// The time to wait after the first failed attempt, doubled after each further failure.
+    initial_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Creates a new `RetryPolicy`. A `max_attempts` of zero is treated as one.
+    pub fn new(max_attempts: u32, initial_backoff: Duration) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            initial_backoff,
+        }
+    }
+
+    /// Creates the `RetryPolicy` for federation requests from the configuration.
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            config.federation_max_attempts,
+            Duration::from_millis(config.federation_retry_backoff_ms),
+        )
+    }
+
+    /// The time to wait after the given failed attempt, counting from one.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2_u32
+            .checked_pow(attempt.saturating_sub(1))
+            .unwrap_or_else(u32::max_value);
+
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(MAX_BACKOFF)
+            .min(MAX_BACKOFF)
+    }
+
+    /// The time to wait after the given failed attempt, or `None` if no further attempt should
+    /// be made after having already waited for `waited` in total.
+    fn next_backoff(&self, attempt: u32, waited: Duration) -> Option<Duration> {
+        let backoff = self.backoff(attempt);
+
+        if attempt < self.max_attempts && waited + backoff <= MAX_TOTAL_BACKOFF {
+            Some(backoff)
+        } else {
+            None
+        }
+    }
+
+    /// Make a request, making it again after a transient failure until it succeeds, fails
+    /// permanently, the maximum number of attempts has been made, or waiting before the next
+    /// attempt would exceed `MAX_TOTAL_BACKOFF`.
+    pub fn retry<T, F>(&self, mut request: F) -> Result<T, ApiError>
+    where
+        F: FnMut() -> Result<T, FetchError>,
+    {
+        let mut attempt = 1;
+        let mut waited = Duration::from_secs(0);
+
+        loop {
+            match request() {
+                Ok(response) => return Ok(response),
+                Err(FetchError::Transient(error)) => {
+                    let backoff = match self.next_backoff(attempt, waited) {
+                        Some(backoff) => backoff,
+                        None => return Err(error),
+                    };
+
+                    debug!(
+                        "Attempt {} failed, retrying in {:?}: {}",
+                        attempt, backoff, error
+                    );
+
+                    sleep(backoff);
+                    waited += backoff;
+                    attempt += 1;
+                }
+                Err(FetchError::Permanent(error)) => return Err(error),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{RetryPolicy, MAX_BACKOFF};
+
+    #[test]
+    fn backoff_doubles() {
+        let policy = RetryPolicy::new(5, Duration::from_millis(100));
+
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let policy = RetryPolicy::new(100, Duration::from_secs(1));
+
+        assert_eq!(policy.backoff(10), MAX_BACKOFF);
+        assert_eq!(policy.backoff(100), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn total_backoff_is_bounded() {
+        let policy = RetryPolicy::new(100, Duration::from_secs(4));
+
+        assert_eq!(
+            policy.next_backoff(1, Duration::from_secs(0)),
+            Some(Duration::from_secs(4))
+        );
+        assert_eq!(
+            policy.next_backoff(2, Duration::from_secs(4)),
+            Some(MAX_BACKOFF)
+        );
+        assert_eq!(policy.next_backoff(3, Duration::from_secs(9)), None);
+    }
+
+    #[test]
+    fn attempts_are_bounded() {
+        let policy = RetryPolicy::new(2, Duration::from_millis(1));
+
+        assert!(policy.next_backoff(1, Duration::from_secs(0)).is_some());
+        assert_eq!(policy.next_backoff(2, Duration::from_millis(1)), None);
+    }
+}
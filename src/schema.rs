@@ -12,6 +12,7 @@ table! {
         revoked -> Bool,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        device_id -> Text,
     }
 }
 
@@ -24,6 +25,16 @@ table! {
     }
 }
 
+table! {
+    audit_log {
+        id -> BigSerial,
+        user_id -> Nullable<Text>,
+        event_type -> Text,
+        ip -> Text,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     events {
         id -> Text,
@@ -34,6 +45,53 @@ table! {
         state_key -> Nullable<Text>,
         content -> Text,
         created_at -> Timestamp,
+        redacts -> Nullable<Text>,
+        redacted_by -> Nullable<Text>,
+    }
+}
+
+table! {
+    idempotent_responses (user_id, device_id, path, idempotency_key) {
+        user_id -> Text,
+        path -> Text,
+        idempotency_key -> Text,
+        status -> Nullable<Integer>,
+        body -> Nullable<Text>,
+        created_at -> Timestamp,
+        device_id -> Text,
+    }
+}
+
+table! {
+    one_time_keys {
+        id -> BigSerial,
+        user_id -> Text,
+        device_id -> Text,
+        algorithm -> Text,
+        key_id -> Text,
+        key -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    openid_tokens (token_hash) {
+        token_hash -> Text,
+        user_id -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    password_reset_sessions (sid) {
+        sid -> Text,
+        client_secret -> Text,
+        address -> Text,
+        user_id -> Text,
+        token_hash -> Text,
+        send_attempt -> BigInt,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
     }
 }
 
@@ -45,6 +103,25 @@ table! {
     }
 }
 
+table! {
+    receipts (room_id, user_id, receipt_type) {
+        room_id -> Text,
+        user_id -> Text,
+        receipt_type -> Text,
+        event_id -> Text,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    registration_tokens (token) {
+        token -> Text,
+        uses_remaining -> Nullable<Integer>,
+        expires_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     room_aliases (alias) {
         alias -> Text,
@@ -64,6 +141,7 @@ table! {
         sender -> Text,
         membership -> Text,
         created_at -> Timestamp,
+        forgotten -> Bool,
     }
 }
 
@@ -73,6 +151,16 @@ table! {
         user_id -> Text,
         public -> Bool,
         created_at -> Timestamp,
+        version -> Text,
+    }
+}
+
+table! {
+    server_notices {
+        id -> BigSerial,
+        body -> Text,
+        sender -> Text,
+        created_at -> Timestamp,
     }
 }
 
@@ -83,6 +171,7 @@ table! {
         active -> Bool,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        admin -> Bool,
     }
 }
 
@@ -106,19 +195,12 @@ table! {
     }
 }
 
-table! {
-    transactions (path, access_token) {
-        path -> Text,
-        access_token -> Text,
-        response -> Text,
-    }
-}
-
 table! {
     filters {
         id -> BigSerial,
         user_id -> Text,
         content -> Text,
+        filter_id -> BigInt,
     }
 }
 
@@ -140,7 +222,7 @@ table! {
 }
 
 table! {
-    pushers(user_id, app_id) {
+    pushers(user_id, app_id, pushkey) {
         user_id -> Text,
         lang -> Text,
         kind -> Text,
@@ -153,6 +235,26 @@ table! {
     }
 }
 
+table! {
+    three_pids(medium, address) {
+        medium -> Text,
+        address -> Text,
+        user_id -> Text,
+        created_at -> Timestamp,
+        validated_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    user_ips(access_token_id, ip, user_agent) {
+        access_token_id -> BigInt,
+        user_id -> Text,
+        ip -> Text,
+        user_agent -> Text,
+        last_seen -> Timestamp,
+    }
+}
+
 // Diesel macros needed to enable queries with multiple tables involving foreign key relationships.
 
 allow_tables_to_appear_in_same_query!(events, room_memberships);
@@ -0,0 +1,1037 @@
+//! Publishing this server's signing key and fetching the signing keys of remote homeservers.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::net::HttpsConnector;
+use hyper::status::StatusCode;
+use hyper::Client;
+use hyper_native_tls::NativeTlsClient;
+use iron::headers::{CacheControl, CacheDirective};
+use iron::status::Status;
+use iron::typemap::Key;
+use iron::{Handler, IronResult, Plugin, Request, Response};
+use persistent::Read as PersistentRead;
+use router::Router;
+use serde::de::DeserializeOwned;
+use serde_json::{from_reader, to_value};
+use url::percent_encoding::percent_decode;
+
+use crate::config::Config;
+use crate::crypto::{public_key_base64, server_signing_key, sign_json, verify_json};
+use crate::error::{ApiError, MapApiError};
+use crate::modifier::SerializableResponse;
+use crate::retry::{FetchError, RetryPolicy};
+
+/// The ID of the key this server signs with.
+pub const SERVER_KEY_ID: &str = "ed25519:ruma";
+
+/// The signing keys a homeserver publishes, as returned by `/_matrix/key/v2/server`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ServerKeys {
+    /// The homeserver the keys belong to.
+    pub server_name: String,
+    /// The keys currently used by the homeserver, keyed by key ID.
+    pub verify_keys: BTreeMap<String, VerifyKey>,
+    /// Keys the homeserver used in the past, keyed by key ID.
+    #[serde(default)]
+    pub old_verify_keys: BTreeMap<String, OldVerifyKey>,
+    /// Signatures of this object, keyed by server name and then by key ID.
+    #[serde(default)]
+    pub signatures: BTreeMap<String, BTreeMap<String, String>>,
+    /// The Unix timestamp in milliseconds until which these keys may be cached.
+    pub valid_until_ts: u64,
+}
+
+/// A key currently used by a homeserver.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VerifyKey {
+    /// The Unpadded Base64 encoded public key.
+    pub key: String,
+}
+
+/// A key a homeserver used in the past.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OldVerifyKey {
+    /// The Unix timestamp in milliseconds at which the key stopped being used.
+    pub expired_ts: u64,
+    /// The Unpadded Base64 encoded public key.
+    pub key: String,
+}
+
+/// The body of the response of `/_matrix/key/v2/query`.
+#[derive(Debug, Deserialize, Serialize)]
+struct QueryResponse {
+    /// The keys of the queried servers.
+    server_keys: Vec<ServerKeys>,
+}
+
+/// Performs the HTTP requests needed to look up keys.
+///
+/// Failures are reported as transient or permanent, so that `KeyFetcher` knows which requests
+/// are worth retrying.
+pub trait KeyTransport {
+    /// Fetch the keys a homeserver publishes about itself, or `None` if it does not have the key.
+    fn fetch_from_origin(
+        &self,
+        server_name: &str,
+        key_id: &str,
+    ) -> Result<Option<ServerKeys>, FetchError>;
+
+    /// Ask a notary server for the keys of another homeserver.
+    fn fetch_from_notary(
+        &self,
+        notary: &str,
+        server_name: &str,
+        key_id: &str,
+    ) -> Result<Vec<ServerKeys>, FetchError>;
+}
+
+/// The current time in milliseconds since the Unix epoch.
+fn now_millis() -> Result<u64, ApiError> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+
+    Ok(now.as_secs() * 1000 + u64::from(now.subsec_millis()))
+}
+
+/// Looks up the keys of remote homeservers, preferring trusted notary servers over the origin.
+#[derive(Debug)]
+pub struct KeyFetcher<T> {
+    /// The notary servers to consult, in order, before asking the origin.
+    trusted_key_servers: Vec<String>,
+    /// How often each request is attempted.
+    retry_policy: RetryPolicy,
+    /// Performs the HTTP requests.
+    transport: T,
+}
+
+impl ServerKeys {
+    /// Whether or not these keys include the key with the given ID.
+    pub fn has_key(&self, key_id: &str) -> bool {
+        self.verify_keys.contains_key(key_id) || self.old_verify_keys.contains_key(key_id)
+    }
+
+    /// Whether or not these keys are signed by the homeserver they belong to.
+    ///
+    /// There must be at least one signature by the homeserver made with one of its current keys,
+    /// and every such signature must be valid.
+    pub fn is_self_signed(&self) -> bool {
+        let signatures = match self.signatures.get(&self.server_name) {
+            Some(signatures) => signatures,
+            None => return false,
+        };
+
+        let value = match to_value(self) {
+            Ok(value) => value,
+            Err(_) => return false,
+        };
+
+        let mut verified = false;
+
+        for (key_id, signature) in signatures {
+            if let Some(verify_key) = self.verify_keys.get(key_id) {
+                if !verify_json(&verify_key.key, signature, &value) {
+                    return false;
+                }
+
+                verified = true;
+            }
+        }
+
+        verified
+    }
+
+    /// Add this server's signature, as a notary vouching for keys it looked up.
+    fn add_signature(&mut self, config: &Config) -> Result<(), ApiError> {
+        let key_pair = server_signing_key(&config.server_signing_key)?;
+        let signature = sign_json(&key_pair, &to_value(&*self).map_err(ApiError::from)?)?;
+
+        self.signatures
+            .entry(config.domain.clone())
+            .or_default()
+            .insert(SERVER_KEY_ID.to_string(), signature);
+
+        Ok(())
+    }
+
+    /// The signed keys this server publishes about itself, valid for the configured time from
+    /// `now`, in milliseconds since the Unix epoch.
+    pub fn own(config: &Config, now: u64) -> Result<Self, ApiError> {
+        let key_pair = server_signing_key(&config.server_signing_key)?;
+
+        let mut verify_keys = BTreeMap::new();
+        verify_keys.insert(
+            SERVER_KEY_ID.to_string(),
+            VerifyKey {
+                key: public_key_base64(&key_pair),
+            },
+        );
+
+        let mut server_keys = Self {
+            server_name: config.domain.clone(),
+            verify_keys,
+            old_verify_keys: BTreeMap::new(),
+            signatures: BTreeMap::new(),
+            valid_until_ts: now + config.server_key_validity_seconds * 1000,
+        };
+
+        server_keys.add_signature(config)?;
+
+        Ok(server_keys)
+    }
+}
+
+/// The `/_matrix/key/v2/server` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetServerKeys;
+
+impl Handler for GetServerKeys {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let config = Config::from_request(request)?;
+
+        let server_keys = ServerKeys::own(&config, now_millis()?)?;
+
+        let mut response = Response::with((Status::Ok, SerializableResponse(server_keys)));
+
+        response.headers.set(CacheControl(vec![
+            CacheDirective::Public,
+            // `Config` ensures the validity fits.
+            CacheDirective::MaxAge(config.server_key_validity_seconds as u32),
+        ]));
+
+        Ok(response)
+    }
+}
+
+/// The `/_matrix/key/v2/query/:server_name/:key_id` endpoint.
+///
+/// This server acts as a notary: it looks up the keys of other homeservers through its
+/// `ServerKeyCache` and adds its own signature to them.
+#[derive(Clone, Copy, Debug)]
+pub struct QueryServerKeys;
+
+impl Handler for QueryServerKeys {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let params = request
+            .extensions
+            .get::<Router>()
+            .expect("Params object is missing")
+            .clone();
+
+        let decode_param = |name| match params.find(name) {
+            Some(value) => percent_decode(value.as_bytes())
+                .decode_utf8()
+                .map(|value| value.into_owned())
+                .map_err(|err| ApiError::invalid_param(name, err.description())),
+            None => Err(ApiError::missing_param(name)),
+        };
+
+        let server_name = decode_param("server_name")?;
+        let key_id = decode_param("key_id")?;
+
+        let config = Config::from_request(request)?;
+
+        let mut server_keys = Vec::new();
+
+        if server_name == config.domain {
+            let keys = ServerKeys::own(&config, now_millis()?)?;
+
+            if keys.has_key(&key_id) {
+                server_keys.push(keys);
+            }
+        } else {
+            let cache = ServerKeyCache::<HttpKeyTransport>::from_request(request)?;
+
+            match cache.get(&server_name, &key_id) {
+                Ok(mut keys) => {
+                    keys.add_signature(&config)?;
+                    server_keys.push(keys);
+                }
+                Err(error) => debug!(
+                    "Failed to look up key {} for {}: {}",
+                    key_id, server_name, error
+                ),
+            }
+        }
+
+        let response = QueryResponse { server_keys };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+impl KeyFetcher<HttpKeyTransport> {
+    /// Creates a `KeyFetcher` that consults the trusted key servers in the configuration.
+    pub fn from_config(config: &Config) -> Result<Self, ApiError> {
+        Ok(Self::new(
+            config.trusted_key_servers.clone(),
+            RetryPolicy::from_config(config),
+            HttpKeyTransport::new()?,
+        ))
+    }
+}
+
+impl<T> KeyFetcher<T>
+where
+    T: KeyTransport,
+{
+    /// Creates a new `KeyFetcher`.
+    pub fn new(trusted_key_servers: Vec<String>, retry_policy: RetryPolicy, transport: T) -> Self {
+        Self {
+            trusted_key_servers,
+            retry_policy,
+            transport,
+        }
+    }
+
+    /// Look up the keys of `server_name` that include the key `key_id`.
+    ///
+    /// Each trusted key server is consulted in order. If none of them has the key, it is
+    /// requested from `server_name` directly. Keys that aren't signed by `server_name` itself are
+    /// ignored. Requests that fail transiently are retried according to the `RetryPolicy`.
+    pub fn fetch(&self, server_name: &str, key_id: &str) -> Result<ServerKeys, ApiError> {
+        let is_requested_key = |keys: &ServerKeys| {
+            keys.server_name == server_name && keys.has_key(key_id) && keys.is_self_signed()
+        };
+
+        for notary in &self.trusted_key_servers {
+            match self.retry_policy.retry(|| {
+                self.transport
+                    .fetch_from_notary(notary, server_name, key_id)
+            }) {
+                Ok(server_keys) => {
+                    if let Some(keys) = server_keys.into_iter().find(is_requested_key) {
+                        return Ok(keys);
+                    }
+
+                    debug!(
+                        "Notary {} has no key {} signed by {}.",
+                        notary, key_id, server_name
+                    );
+                }
+                Err(error) => debug!(
+                    "Failed to query notary {} for {}: {}",
+                    notary, server_name, error
+                ),
+            }
+        }
+
+        match self
+            .retry_policy
+            .retry(|| self.transport.fetch_from_origin(server_name, key_id))?
+        {
+            Some(ref keys) if is_requested_key(keys) => Ok(keys.clone()),
+            _ => Err(ApiError::not_found(format!(
+                "No key {} signed by {} was found",
+                key_id, server_name
+            ))),
+        }
+    }
+}
+
+/// The number of keys a `ServerKeyCache` holds at most.
+const MAX_CACHED_KEYS: usize = 10_000;
+
+/// Caches the keys looked up by a `KeyFetcher` until their `valid_until_ts`.
+///
+/// Expired keys are evicted whenever keys are fetched. If the cache is still full, the keys that
+/// expire first make room.
+#[derive(Debug)]
+pub struct ServerKeyCache<T> {
+    /// Looks up keys that are not cached or have expired.
+    fetcher: KeyFetcher<T>,
+    /// The cached keys, keyed by server name and key ID.
+    entries: Mutex<HashMap<(String, String), ServerKeys>>,
+    /// The number of keys to hold at most.
+    max_entries: usize,
+}
+
+impl<T> ServerKeyCache<T>
+where
+    T: KeyTransport,
+{
+    /// Creates a new, empty `ServerKeyCache`.
+    pub fn new(fetcher: KeyFetcher<T>) -> Self {
+        Self::with_max_entries(fetcher, MAX_CACHED_KEYS)
+    }
+
+    /// Creates a new, empty `ServerKeyCache` that holds at most `max_entries` keys.
+    fn with_max_entries(fetcher: KeyFetcher<T>, max_entries: usize) -> Self {
+        Self {
+            fetcher,
+            entries: Mutex::new(HashMap::new()),
+            max_entries,
+        }
+    }
+
+    /// Look up the keys of `server_name` that include the key `key_id`, using the cached keys
+    /// if they are still valid.
+    pub fn get(&self, server_name: &str, key_id: &str) -> Result<ServerKeys, ApiError> {
+        self.get_at(server_name, key_id, now_millis()?)
+    }
+
+    /// Like `get`, but with the current time given as a Unix timestamp in milliseconds.
+    fn get_at(&self, server_name: &str, key_id: &str, now: u64) -> Result<ServerKeys, ApiError> {
+        let cache_key = (server_name.to_string(), key_id.to_string());
+
+        {
+            let entries = self.entries.lock().map_err(ApiError::from)?;
+
+            if let Some(keys) = entries.get(&cache_key) {
+                if keys.valid_until_ts > now {
+                    return Ok(keys.clone());
+                }
+            }
+        }
+
+        let keys = self.fetcher.fetch(server_name, key_id)?;
+
+        let mut entries = self.entries.lock().map_err(ApiError::from)?;
+
+        entries.retain(|_, cached_keys| cached_keys.valid_until_ts > now);
+
+        if keys.valid_until_ts <= now {
+            return Ok(keys);
+        }
+
+        for cached_key_id in keys.verify_keys.keys().chain(keys.old_verify_keys.keys()) {
+            let cache_key = (server_name.to_string(), cached_key_id.clone());
+
+            if entries.len() >= self.max_entries && !entries.contains_key(&cache_key) {
+                let first_to_expire = entries
+                    .iter()
+                    .min_by_key(|(_, cached_keys)| cached_keys.valid_until_ts)
+                    .map(|(cache_key, _)| cache_key.clone());
+
+                match first_to_expire {
+                    Some(first_to_expire) => {
+                        entries.remove(&first_to_expire);
+                    }
+                    None => break,
+                }
+            }
+
+            entries.insert(cache_key, keys.clone());
+        }
+
+        Ok(keys)
+    }
+}
+
+impl ServerKeyCache<HttpKeyTransport> {
+    /// Creates an empty `ServerKeyCache` that looks up keys as the configuration says.
+    pub fn from_config(config: &Config) -> Result<Self, ApiError> {
+        Ok(Self::new(KeyFetcher::from_config(config)?))
+    }
+
+    /// Extract the `ServerKeyCache` stored in the request.
+    pub fn from_request(request: &mut Request<'_, '_>) -> Result<Arc<Self>, ApiError> {
+        request
+            .get::<PersistentRead<Self>>()
+            .map_err(ApiError::from)
+    }
+}
+
+impl Key for ServerKeyCache<HttpKeyTransport> {
+    type Value = Self;
+}
+
+/// Fetches keys over HTTPS.
+#[derive(Debug)]
+pub struct HttpKeyTransport {
+    /// The HTTPS client.
+    client: Client,
+}
+
+impl HttpKeyTransport {
+    /// Creates a new `HttpKeyTransport`.
+    pub fn new() -> Result<Self, ApiError> {
+        let tls_client = NativeTlsClient::new().map_api_err(|_| ApiError::unknown(None))?;
+
+        Ok(Self {
+            client: Client::with_connector(HttpsConnector::new(tls_client)),
+        })
+    }
+
+    /// Send a GET request to the given URL and deserialize the JSON response, or return `None`
+    /// if the server responded with a 404.
+    ///
+    /// Connection failures and 5xx responses are transient, anything else is permanent.
+    fn get<R>(&self, url: &str) -> Result<Option<R>, FetchError>
+    where
+        R: DeserializeOwned,
+    {
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .map_api_err(|_| ApiError::unavailable(format!("Failed to connect to {}", url)))
+            .map_err(FetchError::Transient)?;
+
+        if response.status == StatusCode::NotFound {
+            return Ok(None);
+        }
+
+        if !response.status.is_success() {
+            let error =
+                ApiError::unavailable(format!("{} responded with {}", url, response.status));
+
+            return Err(if response.status.is_server_error() {
+                FetchError::Transient(error)
+            } else {
+                FetchError::Permanent(error)
+            });
+        }
+
+        from_reader(response)
+            .map(Some)
+            .map_err(|error| FetchError::Permanent(ApiError::from(error)))
+    }
+}
+
+impl KeyTransport for HttpKeyTransport {
+    fn fetch_from_origin(
+        &self,
+        server_name: &str,
+        key_id: &str,
+    ) -> Result<Option<ServerKeys>, FetchError> {
+        self.get(&format!(
+            "https://{}/_matrix/key/v2/server/{}",
+            server_name, key_id
+        ))
+    }
+
+    fn fetch_from_notary(
+        &self,
+        notary: &str,
+        server_name: &str,
+        key_id: &str,
+    ) -> Result<Vec<ServerKeys>, FetchError> {
+        let response: Option<QueryResponse> = self.get(&format!(
+            "https://{}/_matrix/key/v2/query/{}/{}",
+            notary, server_name, key_id
+        ))?;
+
+        Ok(response.map_or_else(Vec::new, |response| response.server_keys))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use base64::{decode_config, STANDARD_NO_PAD};
+    use iron::headers::{CacheControl, CacheDirective};
+    use iron::status::Status;
+    use ring::signature::{verify, Ed25519KeyPair, ED25519};
+    use serde_json::{from_value, to_value};
+    use untrusted::Input;
+
+    use super::{
+        now_millis, KeyFetcher, KeyTransport, ServerKeyCache, ServerKeys, VerifyKey, SERVER_KEY_ID,
+    };
+    use crate::crypto::{canonical_json, public_key_base64, sign_json};
+    use crate::error::ApiError;
+    use crate::retry::{FetchError, RetryPolicy};
+    use crate::test::Test;
+
+    /// A `KeyTransport` that serves keys from memory and records the requests made.
+    #[derive(Debug, Default)]
+    struct FakeTransport {
+        /// The keys each notary knows about.
+        notary_keys: BTreeMap<String, Vec<ServerKeys>>,
+        /// The keys each origin publishes.
+        origin_keys: BTreeMap<String, ServerKeys>,
+        /// The servers that were asked for keys, in order.
+        requests: RefCell<Vec<String>>,
+    }
+
+    impl KeyTransport for FakeTransport {
+        fn fetch_from_origin(
+            &self,
+            server_name: &str,
+            _key_id: &str,
+        ) -> Result<Option<ServerKeys>, FetchError> {
+            self.requests.borrow_mut().push(server_name.to_string());
+
+            Ok(self.origin_keys.get(server_name).cloned())
+        }
+
+        fn fetch_from_notary(
+            &self,
+            notary: &str,
+            _server_name: &str,
+            _key_id: &str,
+        ) -> Result<Vec<ServerKeys>, FetchError> {
+            self.requests.borrow_mut().push(notary.to_string());
+
+            Ok(self.notary_keys.get(notary).cloned().unwrap_or_default())
+        }
+    }
+
+    /// A `KeyTransport` whose origin fails the first `failures` requests with the given error.
+    struct FlakyTransport {
+        /// The number of requests that fail before the keys are served.
+        failures: u32,
+        /// The error to fail with.
+        error: FetchError,
+        /// The number of requests made so far.
+        attempts: Cell<u32>,
+    }
+
+    impl FlakyTransport {
+        fn new(failures: u32, error: FetchError) -> Self {
+            Self {
+                failures,
+                error,
+                attempts: Cell::new(0),
+            }
+        }
+    }
+
+    impl KeyTransport for FlakyTransport {
+        fn fetch_from_origin(
+            &self,
+            server_name: &str,
+            key_id: &str,
+        ) -> Result<Option<ServerKeys>, FetchError> {
+            self.attempts.set(self.attempts.get() + 1);
+
+            if self.attempts.get() <= self.failures {
+                return Err(self.error.clone());
+            }
+
+            Ok(Some(server_keys(server_name, key_id, ORIGIN_SEED)))
+        }
+
+        fn fetch_from_notary(
+            &self,
+            _notary: &str,
+            _server_name: &str,
+            _key_id: &str,
+        ) -> Result<Vec<ServerKeys>, FetchError> {
+            unreachable!("no notaries are configured")
+        }
+    }
+
+    /// A `RetryPolicy` that makes a single attempt.
+    fn no_retries() -> RetryPolicy {
+        RetryPolicy::new(1, Duration::from_millis(0))
+    }
+
+    /// The seed of the key served by origins.
+    const ORIGIN_SEED: u8 = 1;
+
+    /// The seed of the key served by notaries.
+    const NOTARY_SEED: u8 = 2;
+
+    /// The key pair generated from a seed of 32 `seed` bytes.
+    fn key_pair(seed: u8) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(Input::from(&[seed; 32])).unwrap()
+    }
+
+    /// The public key generated from `seed`, encoded as Unpadded Base64.
+    fn public_key(seed: u8) -> String {
+        public_key_base64(&key_pair(seed))
+    }
+
+    /// Creates the keys of `server_name` with a single key generated from `seed`, signed with
+    /// that key and valid until a far away time.
+    fn server_keys(server_name: &str, key_id: &str, seed: u8) -> ServerKeys {
+        let mut verify_keys = BTreeMap::new();
+        verify_keys.insert(
+            key_id.to_string(),
+            VerifyKey {
+                key: public_key(seed),
+            },
+        );
+
+        let mut keys = ServerKeys {
+            server_name: server_name.to_string(),
+            verify_keys,
+            old_verify_keys: BTreeMap::new(),
+            signatures: BTreeMap::new(),
+            valid_until_ts: 4_000_000_000_000,
+        };
+
+        sign(&mut keys, key_id, seed);
+
+        keys
+    }
+
+    /// Replaces the signatures of `keys` with a signature by `keys.server_name` with `key_id`,
+    /// made with the key generated from `seed`.
+    fn sign(keys: &mut ServerKeys, key_id: &str, seed: u8) {
+        keys.signatures.clear();
+
+        let signature = sign_json(&key_pair(seed), &to_value(&*keys).unwrap()).unwrap();
+
+        keys.signatures
+            .entry(keys.server_name.clone())
+            .or_default()
+            .insert(key_id.to_string(), signature);
+    }
+
+    #[test]
+    fn notary_is_consulted_first() {
+        let mut transport = FakeTransport::default();
+        transport.notary_keys.insert(
+            "notary.test".to_string(),
+            vec![server_keys("remote.test", "ed25519:1", NOTARY_SEED)],
+        );
+        transport.origin_keys.insert(
+            "remote.test".to_string(),
+            server_keys("remote.test", "ed25519:1", ORIGIN_SEED),
+        );
+
+        let fetcher = KeyFetcher::new(vec!["notary.test".to_string()], no_retries(), transport);
+        let keys = fetcher.fetch("remote.test", "ed25519:1").unwrap();
+
+        assert_eq!(keys.verify_keys["ed25519:1"].key, public_key(NOTARY_SEED));
+        assert_eq!(*fetcher.transport.requests.borrow(), vec!["notary.test"]);
+    }
+
+    #[test]
+    fn falls_back_to_origin() {
+        let mut transport = FakeTransport::default();
+        transport.notary_keys.insert(
+            "notary.test".to_string(),
+            vec![server_keys("remote.test", "ed25519:old", NOTARY_SEED)],
+        );
+        transport.origin_keys.insert(
+            "remote.test".to_string(),
+            server_keys("remote.test", "ed25519:1", ORIGIN_SEED),
+        );
+
+        let fetcher = KeyFetcher::new(vec!["notary.test".to_string()], no_retries(), transport);
+        let keys = fetcher.fetch("remote.test", "ed25519:1").unwrap();
+
+        assert_eq!(keys.verify_keys["ed25519:1"].key, public_key(ORIGIN_SEED));
+        assert_eq!(
+            *fetcher.transport.requests.borrow(),
+            vec!["notary.test", "remote.test"]
+        );
+    }
+
+    #[test]
+    fn keys_not_signed_by_their_server_are_ignored() {
+        let mut forged = server_keys("remote.test", "ed25519:1", NOTARY_SEED);
+        sign(&mut forged, "ed25519:1", ORIGIN_SEED);
+
+        let mut transport = FakeTransport::default();
+        transport
+            .notary_keys
+            .insert("notary.test".to_string(), vec![forged]);
+        transport.origin_keys.insert(
+            "remote.test".to_string(),
+            server_keys("remote.test", "ed25519:1", ORIGIN_SEED),
+        );
+
+        let fetcher = KeyFetcher::new(vec!["notary.test".to_string()], no_retries(), transport);
+        let keys = fetcher.fetch("remote.test", "ed25519:1").unwrap();
+
+        assert_eq!(keys.verify_keys["ed25519:1"].key, public_key(ORIGIN_SEED));
+    }
+
+    #[test]
+    fn tampered_keys_are_rejected() {
+        let mut tampered = server_keys("remote.test", "ed25519:1", ORIGIN_SEED);
+        tampered.valid_until_ts += 1;
+
+        let mut unsigned = server_keys("remote.test", "ed25519:1", ORIGIN_SEED);
+        unsigned.signatures.clear();
+
+        for keys in vec![tampered, unsigned] {
+            let mut transport = FakeTransport::default();
+            transport
+                .origin_keys
+                .insert("remote.test".to_string(), keys);
+
+            let fetcher = KeyFetcher::new(Vec::new(), no_retries(), transport);
+
+            assert!(fetcher.fetch("remote.test", "ed25519:1").is_err());
+        }
+    }
+
+    #[test]
+    fn key_not_found() {
+        let fetcher = KeyFetcher::new(
+            vec!["notary.test".to_string()],
+            no_retries(),
+            FakeTransport::default(),
+        );
+
+        assert!(fetcher.fetch("remote.test", "ed25519:1").is_err());
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        let transport = FlakyTransport::new(2, FetchError::Transient(ApiError::unavailable(None)));
+        let fetcher = KeyFetcher::new(
+            Vec::new(),
+            RetryPolicy::new(3, Duration::from_millis(0)),
+            transport,
+        );
+
+        let keys = fetcher.fetch("remote.test", "ed25519:1").unwrap();
+
+        assert_eq!(keys.verify_keys["ed25519:1"].key, public_key(ORIGIN_SEED));
+        assert_eq!(fetcher.transport.attempts.get(), 3);
+    }
+
+    #[test]
+    fn transient_failures_are_retried_up_to_the_limit() {
+        let transport = FlakyTransport::new(5, FetchError::Transient(ApiError::unavailable(None)));
+        let fetcher = KeyFetcher::new(
+            Vec::new(),
+            RetryPolicy::new(3, Duration::from_millis(0)),
+            transport,
+        );
+
+        assert!(fetcher.fetch("remote.test", "ed25519:1").is_err());
+        assert_eq!(fetcher.transport.attempts.get(), 3);
+    }
+
+    #[test]
+    fn permanent_failures_are_not_retried() {
+        let transport = FlakyTransport::new(1, FetchError::Permanent(ApiError::unavailable(None)));
+        let fetcher = KeyFetcher::new(
+            Vec::new(),
+            RetryPolicy::new(3, Duration::from_millis(0)),
+            transport,
+        );
+
+        assert!(fetcher.fetch("remote.test", "ed25519:1").is_err());
+        assert_eq!(fetcher.transport.attempts.get(), 1);
+    }
+
+    #[test]
+    fn cached_key_is_reused_until_it_expires() {
+        let mut keys = server_keys("remote.test", "ed25519:1", ORIGIN_SEED);
+        keys.valid_until_ts = 2000;
+        sign(&mut keys, "ed25519:1", ORIGIN_SEED);
+
+        let mut transport = FakeTransport::default();
+        transport
+            .origin_keys
+            .insert("remote.test".to_string(), keys);
+
+        let cache = ServerKeyCache::new(KeyFetcher::new(Vec::new(), no_retries(), transport));
+
+        cache.get_at("remote.test", "ed25519:1", 1000).unwrap();
+        cache.get_at("remote.test", "ed25519:1", 1999).unwrap();
+        assert_eq!(cache.fetcher.transport.requests.borrow().len(), 1);
+
+        cache.get_at("remote.test", "ed25519:1", 2000).unwrap();
+        assert_eq!(cache.fetcher.transport.requests.borrow().len(), 2);
+    }
+
+    #[test]
+    fn expired_keys_are_evicted() {
+        let mut short_lived = server_keys("short.test", "ed25519:1", ORIGIN_SEED);
+        short_lived.valid_until_ts = 2000;
+        sign(&mut short_lived, "ed25519:1", ORIGIN_SEED);
+
+        let mut transport = FakeTransport::default();
+        transport
+            .origin_keys
+            .insert("short.test".to_string(), short_lived);
+        transport.origin_keys.insert(
+            "long.test".to_string(),
+            server_keys("long.test", "ed25519:1", ORIGIN_SEED),
+        );
+
+        let cache = ServerKeyCache::new(KeyFetcher::new(Vec::new(), no_retries(), transport));
+
+        cache.get_at("short.test", "ed25519:1", 1000).unwrap();
+        assert_eq!(cache.entries.lock().unwrap().len(), 1);
+
+        cache.get_at("long.test", "ed25519:1", 3000).unwrap();
+
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries.contains_key(&("long.test".to_string(), "ed25519:1".to_string())));
+    }
+
+    #[test]
+    fn already_expired_keys_are_not_cached() {
+        let mut keys = server_keys("remote.test", "ed25519:1", ORIGIN_SEED);
+        keys.valid_until_ts = 2000;
+        sign(&mut keys, "ed25519:1", ORIGIN_SEED);
+
+        let mut transport = FakeTransport::default();
+        transport
+            .origin_keys
+            .insert("remote.test".to_string(), keys);
+
+        let cache = ServerKeyCache::new(KeyFetcher::new(Vec::new(), no_retries(), transport));
+
+        cache.get_at("remote.test", "ed25519:1", 3000).unwrap();
+
+        assert!(cache.entries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cache_size_is_bounded() {
+        let mut transport = FakeTransport::default();
+
+        for (server_name, valid_until_ts) in &[("a.test", 5000), ("b.test", 4000), ("c.test", 6000)]
+        {
+            let mut keys = server_keys(server_name, "ed25519:1", ORIGIN_SEED);
+            keys.valid_until_ts = *valid_until_ts;
+            sign(&mut keys, "ed25519:1", ORIGIN_SEED);
+
+            transport.origin_keys.insert(server_name.to_string(), keys);
+        }
+
+        let cache = ServerKeyCache::with_max_entries(
+            KeyFetcher::new(Vec::new(), no_retries(), transport),
+            2,
+        );
+
+        cache.get_at("a.test", "ed25519:1", 1000).unwrap();
+        cache.get_at("b.test", "ed25519:1", 1000).unwrap();
+        cache.get_at("c.test", "ed25519:1", 1000).unwrap();
+
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(!entries.contains_key(&("b.test".to_string(), "ed25519:1".to_string())));
+    }
+
+    #[test]
+    fn own_keys_are_valid_for_the_configured_time() {
+        let test = Test::with_config(|config| config.server_key_validity_seconds = 3600);
+        let before = now_millis().unwrap();
+
+        let response = test.get("/_matrix/key/v2/server");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.headers.get::<CacheControl>(),
+            Some(&CacheControl(vec![
+                CacheDirective::Public,
+                CacheDirective::MaxAge(3600)
+            ]))
+        );
+
+        let valid_until_ts = response
+            .json()
+            .get("valid_until_ts")
+            .unwrap()
+            .as_u64()
+            .unwrap();
+
+        assert!(valid_until_ts >= before + 3_600_000);
+        assert!(valid_until_ts <= now_millis().unwrap() + 3_600_000);
+    }
+
+    #[test]
+    fn own_keys_use_the_configured_signing_key() {
+        let test = Test::with_config(|config| {
+            config.server_signing_key = vec![NOTARY_SEED; 32];
+            config.macaroon_secret_key = b"a different macaroon secret key".to_vec();
+        });
+
+        let response = test.get("/_matrix/key/v2/server");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .pointer(&format!("/verify_keys/{}/key", SERVER_KEY_ID))
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            public_key(NOTARY_SEED)
+        );
+    }
+
+    #[test]
+    fn query_own_keys() {
+        let test = Test::new();
+
+        let response = test.get(&format!(
+            "/_matrix/key/v2/query/ruma.test/{}",
+            SERVER_KEY_ID
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+
+        let server_keys = response
+            .json()
+            .get("server_keys")
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(server_keys.len(), 1);
+
+        let keys: ServerKeys = from_value(server_keys[0].clone()).unwrap();
+        assert_eq!(keys.server_name, "ruma.test");
+        assert!(keys.is_self_signed());
+
+        let response = test.get("/_matrix/key/v2/query/ruma.test/ed25519:unknown");
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response
+            .json()
+            .get("server_keys")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn own_keys_are_signed() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/key/v2/server");
+
+        assert_eq!(response.status, Status::Ok);
+
+        let server_keys = response.json();
+        assert_eq!(
+            server_keys.get("server_name").unwrap().as_str().unwrap(),
+            "ruma.test"
+        );
+
+        let public_key = server_keys
+            .pointer(&format!("/verify_keys/{}/key", SERVER_KEY_ID))
+            .unwrap()
+            .as_str()
+            .unwrap();
+        let signature = server_keys
+            .pointer(&format!("/signatures/ruma.test/{}", SERVER_KEY_ID))
+            .unwrap()
+            .as_str()
+            .unwrap();
+
+        let public_key = decode_config(public_key, STANDARD_NO_PAD).unwrap();
+        let signature = decode_config(signature, STANDARD_NO_PAD).unwrap();
+        let signed = canonical_json(server_keys).unwrap();
+
+        assert!(signed.contains("valid_until_ts"));
+        assert!(verify(
+            &ED25519,
+            Input::from(&public_key),
+            Input::from(signed.as_bytes()),
+            Input::from(&signature),
+        )
+        .is_ok());
+
+        let mut tampered = server_keys.clone();
+        tampered["valid_until_ts"] = (server_keys["valid_until_ts"].as_u64().unwrap() + 1).into();
+
+        assert!(verify(
+            &ED25519,
+            Input::from(&public_key),
+            Input::from(canonical_json(&tampered).unwrap().as_bytes()),
+            Input::from(&signature),
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! Graceful shutdown of the server.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io::Result as IoResult;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use hyper::net::NetworkListener;
+use hyper::Result as HyperResult;
+
+use crate::error::ApiError;
+
+/// How often `Shutdown::drain` checks whether in-flight requests have finished.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Coordinates draining in-flight requests and closing the database connection pool when the
+/// server shuts down.
+///
+/// Requests to the APIs mounted by `Server::mount_client` are counted as in flight, including
+/// `/metrics` and `/versions`, with two exceptions. `/health` isn't, so that it can still report
+/// itself as degraded rather than be rejected while the server shuts down. The static Swagger
+/// document mounted by `Server::mount_extra` isn't either.
+#[derive(Clone)]
+pub struct Shutdown {
+    /// Whether or not the server has stopped accepting new requests.
+    draining: Arc<AtomicBool>,
+    /// The number of requests currently being handled.
+    in_flight: Arc<AtomicUsize>,
+    /// The connection pool shared with the `DB` middleware.
+    ///
+    /// This is the only long-lived handle to the pool, so that taking it out closes the pool.
+    connection_pool: Arc<Mutex<Option<Pool<ConnectionManager<PgConnection>>>>>,
+}
+
+/// A request counted as in flight until this is dropped.
+///
+/// Dropping it rather than calling a method ends the request even if its handler panicked,
+/// so a panic doesn't keep `Shutdown::drain` waiting for the full timeout.
+#[derive(Debug)]
+pub struct InFlightRequest {
+    /// The number of requests currently being handled, shared with `Shutdown`.
+    in_flight: Arc<AtomicUsize>,
+    /// The number of requests that were in flight when this one began, including this one.
+    position: usize,
+}
+
+/// Wraps the listener of the server so that connections accepted once shutdown has started are
+/// closed straight away.
+///
+/// Iron can't stop listening for connections, so this is the closest it gets to not accepting
+/// new ones.
+#[derive(Clone, Debug)]
+pub struct DrainingListener<L> {
+    /// The listener accepting the connections.
+    listener: L,
+    /// Whether or not the server has stopped accepting new requests, shared with `Shutdown`.
+    draining: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    /// Creates a new `Shutdown` for the given shared connection pool.
+    pub fn new(connection_pool: Arc<Mutex<Option<Pool<ConnectionManager<PgConnection>>>>>) -> Self {
+        Self {
+            draining: Arc::new(AtomicBool::new(false)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            connection_pool,
+        }
+    }
+
+    /// Registers a new in-flight request, unless the server is shutting down.
+    ///
+    /// The request is in flight until the returned `InFlightRequest` is dropped.
+    pub fn begin_request(&self) -> Result<InFlightRequest, ApiError> {
+        if self.draining.load(Ordering::SeqCst) {
+            return Err(ApiError::unavailable(
+                "The server is shutting down.".to_string(),
+            ));
+        }
+
+        Ok(InFlightRequest {
+            in_flight: self.in_flight.clone(),
+            position: self.in_flight.fetch_add(1, Ordering::SeqCst) + 1,
+        })
+    }
+
+    /// Wraps the server's listener so it stops handling new connections once draining begins.
+    pub fn listener<L>(&self, listener: L) -> DrainingListener<L> {
+        DrainingListener {
+            listener,
+            draining: self.draining.clone(),
+        }
+    }
+
+    /// The number of requests currently being handled.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Whether or not the connection pool has been closed.
+    pub fn is_closed(&self) -> Result<bool, ApiError> {
+        let connection_pool = self.connection_pool.lock().map_err(ApiError::from)?;
+
+        Ok(connection_pool.is_none())
+    }
+
+    /// Stops accepting new requests, waits up to `timeout` for in-flight requests to finish, and
+    /// closes the connection pool.
+    ///
+    /// Closing the pool drops its idle connections. Connections still held by requests that
+    /// outlived the timeout are closed once those requests finish.
+    ///
+    /// Returns whether or not all in-flight requests finished before the timeout.
+    pub fn drain(&self, timeout: Duration) -> Result<bool, ApiError> {
+        self.draining.store(true, Ordering::SeqCst);
+
+        let deadline = Instant::now() + timeout;
+
+        while self.in_flight() > 0 && Instant::now() < deadline {
+            sleep(DRAIN_POLL_INTERVAL);
+        }
+
+        let drained = self.in_flight() == 0;
+
+        let connection_pool = self.connection_pool.lock().map_err(ApiError::from)?.take();
+        drop(connection_pool);
+
+        Ok(drained)
+    }
+
+    /// The connection pool, unless it has been closed. Useful for testing.
+    pub fn connection_pool(
+        &self,
+    ) -> Result<Option<Pool<ConnectionManager<PgConnection>>>, ApiError> {
+        let connection_pool = self.connection_pool.lock().map_err(ApiError::from)?;
+
+        Ok(connection_pool.clone())
+    }
+}
+
+impl InFlightRequest {
+    /// The number of requests that were in flight when this one began, including this one.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<L: NetworkListener> NetworkListener for DrainingListener<L> {
+    type Stream = L::Stream;
+
+    fn accept(&mut self) -> HyperResult<Self::Stream> {
+        loop {
+            let stream = self.listener.accept()?;
+
+            if !self.draining.load(Ordering::SeqCst) {
+                return Ok(stream);
+            }
+
+            debug!("Closing a connection accepted during shutdown.");
+        }
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.listener.set_read_timeout(timeout);
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.listener.set_write_timeout(timeout);
+    }
+}
+
+impl Debug for Shutdown {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("Shutdown")
+            .field("draining", &self.draining)
+            .field("in_flight", &self.in_flight)
+            .field("connection_pool", &"Pool { ... }")
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::thread::{sleep, spawn};
+    use std::time::{Duration, Instant};
+
+    use diesel::dsl::sql;
+    use diesel::pg::PgConnection;
+    use diesel::r2d2::Pool;
+    use diesel::sql_types::BigInt;
+    use diesel::{select, Connection, RunQueryDsl};
+    use iron::status::Status;
+
+    use super::Shutdown;
+    use crate::db::DB;
+    use crate::test::Test;
+
+    #[test]
+    fn drain_closes_connection_pool() {
+        let test = Test::new();
+        let user = test.create_user();
+        let shutdown = test.shutdown();
+
+        assert_eq!(shutdown.in_flight(), 0);
+        assert!(shutdown.drain(Duration::from_secs(1)).unwrap());
+        assert!(shutdown.is_closed().unwrap());
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/sync?access_token={}",
+            user.token
+        ));
+
+        assert_eq!(response.status, Status::ServiceUnavailable);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_UNAVAILABLE"
+        );
+    }
+
+    #[test]
+    fn drain_waits_for_in_flight_requests() {
+        let test = Test::new();
+        let shutdown = test.shutdown();
+
+        let request = shutdown.begin_request().unwrap();
+
+        let handle = spawn(move || {
+            sleep(Duration::from_millis(50));
+            drop(request);
+        });
+
+        assert!(shutdown.drain(Duration::from_secs(5)).unwrap());
+        assert!(shutdown.is_closed().unwrap());
+        assert!(shutdown.begin_request().is_err());
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn drain_gives_up_after_timeout() {
+        let test = Test::new();
+        let shutdown = test.shutdown();
+
+        let _request = shutdown.begin_request().unwrap();
+
+        assert!(!shutdown.drain(Duration::from_millis(50)).unwrap());
+        assert!(shutdown.is_closed().unwrap());
+    }
+
+    #[test]
+    fn drain_closes_idle_connections() {
+        let test = Test::new();
+        let postgres_url = &test.config().postgres_url;
+
+        let connection_pool = DB::create_connection_pool(
+            Pool::builder().max_size(1),
+            &format!("{}?application_name=ruma_drain_test", postgres_url),
+        )
+        .unwrap();
+        let shutdown = Shutdown::new(Arc::new(Mutex::new(Some(connection_pool))));
+
+        // Count the connections from a connection of its own, as statistics are cached for the
+        // duration of the transaction each test connection is in.
+        let observer = PgConnection::establish(postgres_url).unwrap();
+        let connections = || -> i64 {
+            select(sql::<BigInt>(
+                "count(*) FROM pg_stat_activity WHERE application_name = 'ruma_drain_test'",
+            ))
+            .get_result(&observer)
+            .unwrap()
+        };
+
+        assert_eq!(connections(), 1);
+        assert!(shutdown.drain(Duration::from_secs(1)).unwrap());
+
+        // The backend exits shortly after the connection is closed.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while connections() > 0 && Instant::now() < deadline {
+            sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(connections(), 0);
+    }
+}
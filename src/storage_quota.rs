@@ -0,0 +1,152 @@
+//! Limits on how much data a user can store on the server.
+
+use diesel::dsl::sql;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use iron::status::Status;
+use ruma_identifiers::UserId;
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::schema::{account_data, filters, room_account_data, users};
+
+/// Selects the total size of the `content` column of the filtered rows, in bytes.
+const CONTENT_BYTES: &str = "COALESCE(SUM(OCTET_LENGTH(content)), 0)::BIGINT";
+
+/// The number of bytes the user currently stores across their account data and filters.
+pub fn used_bytes(connection: &PgConnection, user_id: &UserId) -> Result<i64, ApiError> {
+    let account_data_bytes: i64 = account_data::table
+        .filter(account_data::user_id.eq(user_id))
+        .select(sql::<BigInt>(CONTENT_BYTES))
+        .first(connection)?;
+
+    let room_account_data_bytes: i64 = room_account_data::table
+        .filter(room_account_data::user_id.eq(user_id))
+        .select(sql::<BigInt>(CONTENT_BYTES))
+        .first(connection)?;
+
+    let filter_bytes: i64 = filters::table
+        .filter(filters::user_id.eq(user_id))
+        .select(sql::<BigInt>(CONTENT_BYTES))
+        .first(connection)?;
+
+    Ok(account_data_bytes + room_account_data_bytes + filter_bytes)
+}
+
+/// Locks the user's row until the end of the current transaction.
+///
+/// Writes that count towards the quota take this lock before `ensure_within_quota`, and keep it
+/// until they are stored, so that concurrent writes can't all pass the check and together exceed
+/// the quota.
+pub fn lock_user(connection: &PgConnection, user_id: &UserId) -> Result<(), ApiError> {
+    users::table
+        .find(user_id)
+        .select(users::id)
+        .for_update()
+        .execute(connection)
+        .map(|_| ())
+        .map_err(ApiError::from)
+}
+
+/// Ensures that the user stays within `Config::max_storage_bytes_per_user` when `new_bytes` of
+/// data are stored in place of `replaced_bytes` of existing data.
+///
+/// This must run in the same transaction as the write, after `lock_user`.
+pub fn ensure_within_quota(
+    connection: &PgConnection,
+    config: &Config,
+    user_id: &UserId,
+    replaced_bytes: usize,
+    new_bytes: usize,
+) -> Result<(), ApiError> {
+    if config.max_storage_bytes_per_user == 0 {
+        return Ok(());
+    }
+
+    let used_bytes = used_bytes(connection, user_id)? as usize;
+
+    if used_bytes.saturating_sub(replaced_bytes) + new_bytes > config.max_storage_bytes_per_user {
+        return Err(ApiError::limited_rate(format!(
+            "Storing this would exceed the quota of {} bytes",
+            config.max_storage_bytes_per_user
+        ))
+        .with_status(Status::Forbidden));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::prelude::*;
+    use iron::status::Status;
+    use ruma_identifiers::UserId;
+
+    use super::used_bytes;
+    use crate::schema::filters;
+    use crate::test::{Response, Test, TestUser};
+
+    fn put_account_data(test: &Test, user: &TestUser, data_type: &str, content: &str) -> Response {
+        test.put(
+            &format!(
+                "/_matrix/client/r0/user/{}/account_data/{}?access_token={}",
+                user.id, data_type, user.token
+            ),
+            content,
+        )
+    }
+
+    #[test]
+    fn account_data_beyond_quota() {
+        let test = Test::with_config(|config| config.max_storage_bytes_per_user = 40);
+        let user = test.create_user();
+        let user_id = UserId::try_from(user.id.as_str()).unwrap();
+
+        // Each of these is stored as 18 bytes.
+        let response = put_account_data(&test, &user, "org.example.a", r#"{"a":"0123456789"}"#);
+        test.check_empty_response(response);
+        let response = put_account_data(&test, &user, "org.example.b", r#"{"b":"0123456789"}"#);
+        test.check_empty_response(response);
+
+        let response = put_account_data(&test, &user, "org.example.c", r#"{"c":"0123456789"}"#);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_LIMIT_EXCEEDED"
+        );
+        assert_eq!(used_bytes(&test.connection(), &user_id).unwrap(), 36);
+
+        // Replaced data doesn't count towards the quota.
+        let response = put_account_data(&test, &user, "org.example.a", r#"{"a":"0123456789abcd"}"#);
+        test.check_empty_response(response);
+        assert_eq!(used_bytes(&test.connection(), &user_id).unwrap(), 40);
+    }
+
+    #[test]
+    fn filter_beyond_quota() {
+        let test = Test::with_config(|config| config.max_storage_bytes_per_user = 20);
+        let user = test.create_user();
+
+        let response = put_account_data(&test, &user, "org.example.a", r#"{"a":"0123456789"}"#);
+        test.check_empty_response(response);
+
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/user/{}/filter?access_token={}",
+                user.id, user.token
+            ),
+            r#"{"room":{"timeline":{"limit":10}}}"#,
+        );
+        assert_eq!(response.status, Status::Forbidden);
+
+        let filter_count: i64 = filters::table
+            .filter(filters::user_id.eq(&user.id))
+            .count()
+            .get_result(&*test.connection())
+            .unwrap();
+        assert_eq!(filter_count, 0);
+    }
+}
@@ -4,7 +4,7 @@ use iron::headers::ContentType;
 use iron::modifiers::Header;
 use iron::{status, Chain, Handler, IronResult, Request, Response};
 
-use crate::middleware::{MiddlewareChain, ResponseHeaders};
+use crate::middleware::MiddlewareChain;
 
 /// Mounts the Swagger endpoint onto the given `Mount`.
 #[derive(Clone, Copy, Debug)]
@@ -24,11 +24,9 @@ impl Handler for Swagger {
 
 impl MiddlewareChain for Swagger {
     /// Create a `Swagger` with all necessary middleware.
+    ///
+    /// `ResponseHeaders` depends on the configuration and is linked by the server.
     fn chain() -> Chain {
-        let mut chain = Chain::new(Swagger);
-
-        chain.link_after(ResponseHeaders);
-
-        chain
+        Chain::new(Swagger)
     }
 }
@@ -1,14 +1,18 @@
 use std::convert::TryFrom;
 use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io::Read;
 use std::sync::{Once, ONCE_INIT};
 
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use diesel::r2d2::{CustomizeConnection, Error as R2d2DieselError, Pool};
+use diesel::r2d2::{
+    ConnectionManager, CustomizeConnection, Error as R2d2DieselError, Pool, PooledConnection,
+};
 use diesel_migrations::setup_database;
 use env_logger;
+use flate2::read::GzDecoder;
 use iron;
-use iron::headers::{ContentType, Headers};
+use iron::headers::{ContentEncoding, ContentType, Encoding, Headers};
 use iron::method::Method;
 use iron::status::Status;
 use iron_test::{request, response};
@@ -17,11 +21,16 @@ use ruma_events::presence::PresenceState;
 use ruma_identifiers::UserId;
 use serde_json::{from_str, to_string, Value};
 
-use crate::config::Config;
+use crate::config::{Config, TokenFormat};
 use crate::embedded_migrations::run as run_pending_migrations;
+use crate::hash_limiter::HashLimiter;
+use crate::models::one_time_key::{NewOneTimeKey, OneTimeKey};
 use crate::models::pusher::PusherOptions;
+use crate::models::three_pid::ThreePid;
 use crate::query::{Batch, SyncOptions};
+use crate::schema::users;
 use crate::server::Server;
+use crate::shutdown::Shutdown;
 
 static START: Once = ONCE_INIT;
 
@@ -50,12 +59,18 @@ impl TestUser {
 /// interacting with the Ruma API server.
 pub struct Test {
     mount: Mount,
+    shutdown: Shutdown,
+    hash_limiter: HashLimiter,
+    config: Config,
 }
 
 impl Debug for Test {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         f.debug_struct("Server")
             .field("mount", &"Mount { ... }")
+            .field("shutdown", &self.shutdown)
+            .field("hash_limiter", &self.hash_limiter)
+            .field("config", &self.config)
             .finish()
     }
 }
@@ -84,6 +99,14 @@ impl CustomizeConnection<PgConnection, R2d2DieselError> for TestTransactionConne
 impl Test {
     /// Creates a new `Test`.
     pub fn new() -> Self {
+        Self::with_config(|_| {})
+    }
+
+    /// Creates a new `Test` whose configuration is adjusted by the given function.
+    pub fn with_config<F>(configure: F) -> Self
+    where
+        F: FnOnce(&mut Config),
+    {
         // Since we don't have control of the `main` function during tests, we initialize the
         // logger here. It will only actually initialize on the first test that is run. Subsequent
         // calls will return an error, but we don't care, so just ignore the result.
@@ -115,14 +138,53 @@ impl Test {
             run_pending_migrations(&db_connection).expect("Failed to run migrations.");
         });
 
-        let config = Config {
+        let mut config = Config {
+            access_token_lifetime_seconds: 0,
             bind_address: "127.0.0.1".to_string(),
             bind_port: "0".to_string(),
+            cors_allowed_origins: Vec::new(),
+            default_room_version: "1".to_string(),
             domain: "ruma.test".to_string(),
+            enable_audit_log: false,
+            enable_metrics: true,
+            enable_response_compression: true,
+            federation_max_attempts: 1,
+            federation_retry_backoff_ms: 0,
+            initial_admin: None,
+            keep_username_as_displayname: false,
+            login_lockout: None,
+            login_rate_limit: None,
+            login_types: vec!["m.login.password".to_string()],
             macaroon_secret_key: "YymznQHmKdN9B4f7iBalJB1tWEDy9LdaFSQJEtB3R5w=".into(),
+            max_concurrent_hashes: 0,
+            max_devices_per_user: 0,
+            max_in_flight_requests: 0,
+            max_key_batch_size: 1000,
+            max_storage_bytes_per_user: 0,
+            max_user_id_length: 255,
+            metrics_require_admin: false,
+            opaque_token_prefix: String::new(),
             postgres_url: DATABASE_URL.to_string(),
+            public_base_url: None,
+            random_user_id_retries: 3,
+            registration_rate_limit: None,
+            request_timeout_ms: 0,
+            require_json_content_type: true,
+            require_registration_token: false,
+            reuse_deactivated_localparts: false,
+            server_key_validity_seconds: 86_400,
+            server_signing_key: vec![0x52; 32],
+            shutdown_timeout: 30,
+            tls_cert_path: None,
+            tls_key_path: None,
+            token_format: TokenFormat::Macaroon,
+            token_time_skew_seconds: 0,
+            trusted_key_servers: Vec::new(),
+            user_directory_search_all_users: false,
         };
 
+        configure(&mut config);
+
         let r2d2_pool_builder = Pool::builder()
             .max_size(1)
             .connection_customizer(Box::new(TestTransactionConnectionCustomizer));
@@ -132,11 +194,46 @@ impl Test {
             Err(error) => panic!("Failed to create Iron server: {}", error),
         };
 
+        let shutdown = server
+            .shutdown()
+            .expect("The client APIs should have been mounted");
+        let hash_limiter = server
+            .hash_limiter()
+            .expect("The client APIs should have been mounted");
+
         Test {
             mount: server.into_mount(),
+            shutdown,
+            hash_limiter,
+            config,
         }
     }
 
+    /// The graceful shutdown state of the server.
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// The limit on concurrent password hashing of the server.
+    pub fn hash_limiter(&self) -> HashLimiter {
+        self.hash_limiter.clone()
+    }
+
+    /// The configuration of the server.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Returns a connection to the test database.
+    pub fn connection(&self) -> PooledConnection<ConnectionManager<PgConnection>> {
+        self.shutdown
+            .connection_pool()
+            .expect("Failed to get the connection pool")
+            .expect("The connection pool has been closed")
+            .get()
+            .expect("Failed to get a database connection")
+    }
+
     /// Makes a GET request to the server.
     pub fn get(&self, path: &str) -> Response {
         self.request(Method::Get, path, "")
@@ -163,6 +260,17 @@ impl Test {
 
         headers.set(ContentType::json());
 
+        self.request_with_headers(method, path, body, headers)
+    }
+
+    /// Makes a request to the server with the given headers.
+    pub fn request_with_headers(
+        &self,
+        method: Method,
+        path: &str,
+        body: &str,
+        headers: Headers,
+    ) -> Response {
         let response = match request::request(
             method,
             &format!("http://ruma.test{}", path)[..],
@@ -212,6 +320,46 @@ impl Test {
         TestUser::new(UserId::try_from(user_id.as_ref()).unwrap(), access_token)
     }
 
+    /// Registers a new user account with a random user id, grants it server administrator
+    /// privileges, and returns the `TestUser`.
+    pub fn create_admin(&self) -> TestUser {
+        let user = self.create_user();
+        let connection = self.connection();
+
+        diesel::update(users::table.find(&user.id))
+            .set(users::admin.eq(true))
+            .execute(&*connection)
+            .expect("Failed to make the user an administrator");
+
+        user
+    }
+
+    /// Binds a third party identifier to the given user.
+    pub fn add_three_pid(&self, user_id: &str, medium: &str, address: &str) {
+        let connection = self.connection();
+
+        let user_id = UserId::try_from(user_id).expect("Invalid user ID");
+
+        ThreePid::create(&connection, &user_id, medium, address)
+            .expect("Failed to bind the third party identifier");
+    }
+
+    /// Stores a one-time key for the given user's device. The key is `<key_id>-key`.
+    pub fn add_one_time_key(&self, user_id: &str, device_id: &str, algorithm: &str, key_id: &str) {
+        let connection = self.connection();
+
+        let new_one_time_key = NewOneTimeKey {
+            user_id: UserId::try_from(user_id).expect("Invalid user ID"),
+            device_id: device_id.to_string(),
+            algorithm: algorithm.to_string(),
+            key_id: key_id.to_string(),
+            key: format!(r#"{{"key": "{}-key"}}"#, key_id),
+        };
+
+        OneTimeKey::create(&connection, &[new_one_time_key])
+            .expect("Failed to store the one-time key");
+    }
+
     /// Creates a room given the body parameters and returns the room ID as a string.
     pub fn create_room_with_params(&self, access_token: &str, body: &str) -> String {
         self.post(
@@ -276,6 +424,28 @@ impl Test {
         self.post(&path, &body)
     }
 
+    /// Ban a `User` from a `Room`.
+    pub fn ban_from_room(&self, access_token: &str, room_id: &str, user_id: &str) -> Response {
+        let body = format!(r#"{{"user_id": "{}", "reason": "Spam"}}"#, user_id);
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/ban?access_token={}",
+            room_id, access_token
+        );
+
+        self.post(&path, &body)
+    }
+
+    /// Unban a `User` from a `Room`.
+    pub fn unban_from_room(&self, access_token: &str, room_id: &str, user_id: &str) -> Response {
+        let body = format!(r#"{{"user_id": "{}"}}"#, user_id);
+        let path = format!(
+            "/_matrix/client/r0/rooms/{}/unban?access_token={}",
+            room_id, access_token
+        );
+
+        self.post(&path, &body)
+    }
+
     /// Look up a `RoomId` using an alias.
     pub fn get_room_by_alias(&self, alias: &str) -> Response {
         self.get(&format!("/_matrix/client/r0/directory/room/{}", alias))
@@ -454,7 +624,18 @@ impl Response {
     pub fn from_iron_response(response: iron::response::Response) -> Response {
         let headers = response.headers.clone();
         let status = response.status.expect("Response had no status").clone();
-        let body = response::extract_body_to_string(response);
+        let bytes = response::extract_body_to_bytes(response);
+
+        let body = match headers.get::<ContentEncoding>() {
+            Some(ContentEncoding(encodings)) if encodings.contains(&Encoding::Gzip) => {
+                let mut body = String::new();
+                GzDecoder::new(&bytes[..])
+                    .read_to_string(&mut body)
+                    .expect("Response was not valid gzip");
+                body
+            }
+            _ => String::from_utf8(bytes).expect("Response was not UTF-8"),
+        };
 
         let json = match from_str(&body) {
             Ok(json) => Some(json),
@@ -0,0 +1,86 @@
+//! Limits on how often the client connections seen for each access token are saved.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use iron::typemap::Key;
+use iron::{Plugin, Request};
+use persistent::Read as PersistentRead;
+
+use crate::error::ApiError;
+
+/// How long to wait before saving the last seen time of a connection again.
+const RECORD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The number of connections to track before forgetting those saved longer than
+/// `RECORD_INTERVAL` ago.
+const MAX_TRACKED_CONNECTIONS: usize = 10_000;
+
+/// A connection, identified by its access token ID, IP address and user agent.
+type Connection = (i64, String, String);
+
+/// Remembers when each connection was last saved, so that its last seen time is updated at most
+/// once every `RECORD_INTERVAL` rather than on every request.
+#[derive(Clone, Debug, Default)]
+pub struct UserIpThrottle {
+    /// When each connection was last saved.
+    recorded_at: Arc<Mutex<HashMap<Connection, Instant>>>,
+}
+
+impl UserIpThrottle {
+    /// Whether or not the connection should be saved now, because it hasn't been saved within
+    /// `RECORD_INTERVAL`. If so, it is counted as saved from now on.
+    pub fn should_record(
+        &self,
+        access_token_id: i64,
+        ip: &str,
+        user_agent: &str,
+    ) -> Result<bool, ApiError> {
+        let now = Instant::now();
+        let mut recorded_at = self.recorded_at.lock()?;
+
+        if recorded_at.len() >= MAX_TRACKED_CONNECTIONS {
+            recorded_at.retain(|_, recorded_at| now.duration_since(*recorded_at) < RECORD_INTERVAL);
+        }
+
+        let key = (access_token_id, ip.to_string(), user_agent.to_string());
+
+        match recorded_at.get(&key) {
+            Some(recorded_at) if now.duration_since(*recorded_at) < RECORD_INTERVAL => Ok(false),
+            _ => {
+                recorded_at.insert(key, now);
+
+                Ok(true)
+            }
+        }
+    }
+
+    /// Extract the `UserIpThrottle` stored in the request.
+    pub fn from_request(request: &mut Request<'_, '_>) -> Result<Arc<Self>, ApiError> {
+        request
+            .get::<PersistentRead<Self>>()
+            .map_err(ApiError::from)
+    }
+}
+
+impl Key for UserIpThrottle {
+    type Value = Self;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UserIpThrottle;
+
+    #[test]
+    fn connection_is_recorded_once_per_interval() {
+        let throttle = UserIpThrottle::default();
+
+        assert!(throttle.should_record(1, "10.0.0.1", "Riot").unwrap());
+        assert!(!throttle.should_record(1, "10.0.0.1", "Riot").unwrap());
+
+        assert!(throttle.should_record(1, "10.0.0.2", "Riot").unwrap());
+        assert!(throttle.should_record(1, "10.0.0.1", "curl").unwrap());
+        assert!(throttle.should_record(2, "10.0.0.1", "Riot").unwrap());
+    }
+}